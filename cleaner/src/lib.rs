@@ -1,4 +1,10 @@
-use std::time::Duration;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
 use regex::Regex;
@@ -9,6 +15,8 @@ pub struct Cleaner {
     pub folder_name: String,
     pub file_regex: Regex,
     pub expire_time: Duration,
+    /// Files removed so far.
+    pub deleted: Arc<AtomicU64>,
 }
 
 impl Cleaner {
@@ -17,6 +25,7 @@ impl Cleaner {
             folder_name,
             file_regex,
             expire_time,
+            deleted: Arc::default(),
         }
     }
 
@@ -25,6 +34,7 @@ impl Cleaner {
             folder_name,
             file_regex: Regex::new(r"\d{4}-\d{2}-\d{2}").unwrap(),
             expire_time,
+            deleted: Arc::default(),
         }
     }
 
@@ -33,9 +43,16 @@ impl Cleaner {
             folder_name,
             file_regex: Regex::new(r"\d{4}-\d{2}-\d{2} \d{2}_\d{2}_\d{2}").unwrap(),
             expire_time,
+            deleted: Arc::default(),
         }
     }
 
+    /// Counts removed files in `deleted` instead of a counter of its own.
+    pub fn with_deleted_counter(mut self, deleted: Arc<AtomicU64>) -> Self {
+        self.deleted = deleted;
+        self
+    }
+
     pub async fn clean(&self) {
         loop {
             tokio::time::sleep(Duration::from_secs(5)).await;
@@ -64,8 +81,13 @@ impl Cleaner {
                             expire_time + file_timestamp,
                             now
                         );
-                        if let Err(e) = tokio::fs::remove_file(path).await {
-                            error!("Failed to remove file with error {:?}", e);
+                        match tokio::fs::remove_file(path).await {
+                            Ok(_) => {
+                                self.deleted.fetch_add(1, Ordering::Relaxed);
+                            }
+                            Err(e) => {
+                                error!("Failed to remove file with error {:?}", e);
+                            }
                         }
                     }
                 }
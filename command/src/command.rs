@@ -1,10 +1,9 @@
 #![allow(dead_code)]
-use std::process::Stdio;
+use std::process::{ExitStatus, Stdio};
 
 use serde::{Deserialize, Serialize};
 use tokio::task::JoinHandle;
 use tracing::error;
-use utils::snowflake::SNOWFLAKE;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Command {
@@ -14,12 +13,29 @@ pub struct Command {
 }
 
 impl Command {
-    fn new(command: String, args: Vec<String>) -> Self {
-        Command {
-            id: SNOWFLAKE.lock().unwrap().generate().to_string(),
-            command,
-            args,
-        }
+    fn new(id: String, command: String, args: Vec<String>) -> Self {
+        Command { id, command, args }
+    }
+
+    /// Starts the command without waiting for it to exit, for long running
+    /// processes such as the player.
+    pub fn spawn(&self) -> std::io::Result<()> {
+        tokio::process::Command::new(&self.command)
+            .args(&self.args)
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+        Ok(())
+    }
+
+    /// Runs the command to completion.
+    pub async fn status(&self) -> std::io::Result<ExitStatus> {
+        tokio::process::Command::new(&self.command)
+            .args(&self.args)
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .status()
+            .await
     }
 
     pub fn execute_command(&self) -> JoinHandle<CommandResult> {
@@ -55,44 +71,47 @@ impl Command {
 
 impl Command {
     #[cfg(target_os = "linux")]
-    pub fn kill_command(process_name: &str) -> Self {
+    pub fn kill_command(id: String, process_name: &str) -> Self {
         Command::new(
+            id,
             "pkill".to_string(),
             vec!["-f".to_string(), process_name.to_string()],
         )
     }
 
     #[cfg(target_os = "windows")]
-    pub fn kill_command(process_name: &str) -> Self {
+    pub fn kill_command(id: String, process_name: &str) -> Self {
         let process_name = format!("{}{}", process_name, ".exe");
         Command::new(
+            id,
             "taskkill".to_string(),
             vec!["/f".to_string(), "/im".to_string(), process_name],
         )
     }
 
     #[cfg(target_os = "macos")]
-    pub fn kill_command(process_name: &str) -> Self {
+    pub fn kill_command(id: String, process_name: &str) -> Self {
         Command::new(
+            id,
             "pkill".to_string(),
             vec!["-f".to_string(), process_name.to_string()],
         )
     }
 
     #[cfg(target_os = "linux")]
-    pub fn open_command(process_name: &str) -> Self {
-        Command::new(process_name.to_string(), vec![])
+    pub fn open_command(id: String, process_name: &str) -> Self {
+        Command::new(id, process_name.to_string(), vec![])
     }
 
     #[cfg(target_os = "windows")]
-    pub fn open_command(process_name: &str) -> Self {
+    pub fn open_command(id: String, process_name: &str) -> Self {
         let process_name = format!("{}{}", process_name, ".exe");
-        Command::new(process_name, vec![])
+        Command::new(id, process_name, vec![])
     }
 
     #[cfg(target_os = "macos")]
-    pub fn open_command(process_name: &str) -> Self {
-        Command::new(process_name.to_string(), vec![])
+    pub fn open_command(id: String, process_name: &str) -> Self {
+        Command::new(id, process_name.to_string(), vec![])
     }
 }
 
@@ -2,10 +2,10 @@ use command::Command;
 
 pub mod command;
 
-pub fn kill_player() -> Command {
-    Command::kill_command("player")
+pub fn kill_player(id: String) -> Command {
+    Command::kill_command(id, "player")
 }
 
-pub fn open_player() -> Command {
-    Command::open_command("player")
+pub fn open_player(id: String) -> Command {
+    Command::open_command(id, "player")
 }
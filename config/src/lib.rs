@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 
 use storage::Storage;
 use tokio::sync::Mutex;
@@ -6,17 +6,69 @@ use tokio::sync::Mutex;
 use crate::model::Config;
 
 pub mod model;
+pub mod patch;
 
-lazy_static::lazy_static! {
-    static ref STORAGE: Arc<Mutex<Storage<Config>>> = Arc::new(Mutex::new(Storage::new("config.json".into())));
+#[derive(Debug, Clone)]
+pub struct ConfigStore {
+    storage: Arc<Mutex<Storage<Config>>>,
 }
 
-pub async fn get_config() -> Config {
-    let mut storage = STORAGE.lock().await;
-    storage.get().await.unwrap_or_default()
+impl ConfigStore {
+    pub fn new(path: PathBuf) -> Self {
+        ConfigStore {
+            storage: Arc::new(Mutex::new(Storage::new(path))),
+        }
+    }
+
+    pub async fn get_config(&self) -> Config {
+        let mut storage = self.storage.lock().await;
+        storage.get().await.unwrap_or_default()
+    }
+
+    pub async fn update_config(&self, config: Config) -> anyhow::Result<()> {
+        let mut storage = self.storage.lock().await;
+        storage.set(config).await
+    }
+
+    /// Changes the stored config with `f` and saves it, holding the lock
+    /// throughout so concurrent updates can't overwrite each other.
+    pub async fn update<R>(&self, f: impl FnOnce(&mut Config) -> R) -> anyhow::Result<R> {
+        let mut storage = self.storage.lock().await;
+        let mut config = storage.get().await?;
+        let result = f(&mut config);
+        storage.set(config).await?;
+        Ok(result)
+    }
 }
 
-pub(crate) async fn update_config(config: Config) {
-    let mut storage = STORAGE.lock().await;
-    storage.set(config).await.unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_config_store_in_temp_dir() {
+        let path = std::env::temp_dir().join(format!("config-{}.json", std::process::id()));
+        let store = ConfigStore::new(path.clone());
+        let mut config = store.get_config().await;
+        config.set_node_name("temp".to_string());
+        store.update_config(config).await.unwrap();
+
+        let reloaded = ConfigStore::new(path.clone());
+        assert_eq!(reloaded.get_config().await.node_name(), "temp");
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_updates_are_kept() {
+        let path = std::env::temp_dir().join(format!("config-update-{}.json", std::process::id()));
+        let store = ConfigStore::new(path.clone());
+        let renamed = store.update(|cfg| cfg.set_node_name("renamed".to_string()));
+        let listed = store.update(|cfg| cfg.set_node_list(vec![]));
+        let (renamed, listed) = tokio::join!(renamed, listed);
+        renamed.unwrap();
+        listed.unwrap();
+
+        assert_eq!(store.get_config().await.node_name(), "renamed");
+        let _ = std::fs::remove_file(path);
+    }
 }
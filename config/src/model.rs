@@ -1,11 +1,24 @@
 #![allow(dead_code)]
 
-use domain::node::Node;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+};
+
+use domain::node::{Node, DEFAULT_HTTP_PORT};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utils::snowflake::IdGenerator;
+use utoipa::ToSchema;
 
-use crate::update_config;
+/// 16 GiB, enough for long recordings in high resolution.
+pub const DEFAULT_MAX_UPLOAD_BYTES: u64 = 16 << 30;
+/// 64 MiB, several hundred thousand audit entries.
+pub const DEFAULT_AUDIT_MAX_BYTES: u64 = 64 << 20;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Config {
     id: i64,
     board_ip: String,
@@ -14,6 +27,293 @@ pub struct Config {
     node_name: String,
     #[serde(default)]
     node_list: Vec<Node>,
+    #[serde(default)]
+    discovery: DiscoveryConfig,
+    #[serde(default)]
+    server: ServerConfig,
+    #[serde(default)]
+    auth: AuthConfig,
+    /// Node ids by group name, for addressing several nodes at once.
+    #[serde(default)]
+    groups: BTreeMap<String, Vec<i64>>,
+    #[serde(default)]
+    sync: SyncConfig,
+}
+
+/// How the node keeps its media root in line with the published manifest.
+/// Files are pulled from peers with the peer key, see [`AuthConfig::peer_key`].
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct SyncConfig {
+    /// Seconds between two comparisons of the media root with the manifest.
+    pub interval_secs: u64,
+    /// Id of the node that publishes the schedule to the others. Nodes only
+    /// accept a schedule from it, and without one every node follows its own
+    /// rules.
+    pub coordinator: Option<i64>,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 60,
+            coordinator: None,
+        }
+    }
+}
+
+/// Limits applied to inbound discovery traffic.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    /// Sustained datagrams per second accepted from one source address.
+    pub packets_per_second: u32,
+    /// Datagrams a source may send in a burst before being throttled.
+    pub packet_burst: u32,
+    /// Source addresses tracked by the rate limiter at once.
+    pub max_sources: usize,
+    /// Nodes kept in the node list; heartbeats from new ids beyond this are dropped.
+    pub max_nodes: usize,
+    /// Partially received multi-fragment frames kept at once.
+    pub max_pending_frames: usize,
+}
+
+/// Settings of the HTTP server and the folders it works on.
+///
+/// Relative paths are resolved against the working directory.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct ServerConfig {
+    #[schema(value_type = String)]
+    pub bind_address: IpAddr,
+    pub http_port: u16,
+    /// Folder holding the videos served under `/video_list`.
+    #[schema(value_type = String)]
+    pub media_root: PathBuf,
+    /// Folder holding the web interface.
+    #[schema(value_type = String)]
+    pub static_root: PathBuf,
+    #[schema(value_type = String)]
+    pub log_folder: PathBuf,
+    /// Append-only record of control actions, one JSON document per line.
+    #[schema(value_type = String)]
+    pub audit_log: PathBuf,
+    /// Size in bytes the audit log may reach before it is moved to
+    /// `<audit_log>.1`, replacing the entries moved there before.
+    pub audit_max_bytes: u64,
+    /// Folder holding unfinished uploads. Keep it on the same filesystem as
+    /// `media_root` so finished uploads are moved instead of copied.
+    #[schema(value_type = String)]
+    pub upload_dir: PathBuf,
+    /// Largest file accepted by an upload, in bytes.
+    pub max_upload_bytes: u64,
+    /// Seconds an unfinished upload or transfer is kept after it was last
+    /// written to.
+    pub upload_ttl_secs: u64,
+    /// Hash, size and uploader of every file in `media_root`.
+    #[schema(value_type = String)]
+    pub catalogue: PathBuf,
+    /// The media manifest this node was last given.
+    #[schema(value_type = String)]
+    pub sync_manifest: PathBuf,
+    /// Manifests published to groups from this node, and the members that took them.
+    #[schema(value_type = String)]
+    pub group_manifests: PathBuf,
+    /// Playlists and the nodes and groups they are assigned to.
+    #[schema(value_type = String)]
+    pub playlists: PathBuf,
+    /// Rules choosing when the playlists play.
+    #[schema(value_type = String)]
+    pub schedule: PathBuf,
+    /// The part of the schedule this node follows, as its coordinator last sent it.
+    #[schema(value_type = String)]
+    pub schedule_cache: PathBuf,
+    /// The schedules the coordinator published and the nodes it can't reach.
+    #[schema(value_type = String)]
+    pub coordination: PathBuf,
+    /// Base url of the local player's control API.
+    pub player_url: String,
+    pub tls: TlsConfig,
+}
+
+/// HTTPS settings. A self-signed pair is generated at the configured paths
+/// when neither file exists, otherwise the files supplied there are used.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    /// PEM certificate chain, leaf first.
+    #[schema(value_type = String)]
+    pub cert_path: PathBuf,
+    /// PEM private key in PKCS#8, PKCS#1 or SEC1 form.
+    #[schema(value_type = String)]
+    pub key_path: PathBuf,
+    /// Certificate fingerprints of the peers, pinned on first connection.
+    #[schema(value_type = String)]
+    pub pins_path: PathBuf,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            cert_path: PathBuf::from("tls/cert.pem"),
+            key_path: PathBuf::from("tls/key.pem"),
+            pins_path: PathBuf::from("tls/pins.json"),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            http_port: DEFAULT_HTTP_PORT,
+            media_root: PathBuf::from("video"),
+            static_root: PathBuf::from("static"),
+            log_folder: PathBuf::from("broadcast_log"),
+            audit_log: PathBuf::from("audit.jsonl"),
+            audit_max_bytes: DEFAULT_AUDIT_MAX_BYTES,
+            upload_dir: PathBuf::from("uploads"),
+            max_upload_bytes: DEFAULT_MAX_UPLOAD_BYTES,
+            upload_ttl_secs: 24 * 3600,
+            catalogue: PathBuf::from("catalogue.json"),
+            sync_manifest: PathBuf::from("manifest.json"),
+            group_manifests: PathBuf::from("group_manifests.json"),
+            playlists: PathBuf::from("playlists.json"),
+            schedule: PathBuf::from("schedule.json"),
+            schedule_cache: PathBuf::from("schedule_cache.json"),
+            coordination: PathBuf::from("coordination.json"),
+            player_url: "http://localhost:8082".to_string(),
+            tls: TlsConfig::default(),
+        }
+    }
+}
+
+/// Credentials accepted by the HTTP server for state-changing endpoints.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// When false every endpoint is public, as it was before authentication.
+    pub enabled: bool,
+    pub api_keys: Vec<ApiKey>,
+    /// Role granted to requests without credentials, `None` to turn them away.
+    pub anonymous_role: Option<Role>,
+    /// Lifetime of the session tokens issued by `/auth/login`.
+    pub session_ttl_secs: u64,
+    /// Hex encoded SHA-256 of the peer keys accepted from other nodes. A call
+    /// carrying one acts as the `peer` administrator, the calling node has
+    /// already checked its own caller.
+    pub peer_key_hashes: Vec<String>,
+    /// File holding the key this node sends when it calls peers, readable by
+    /// its owner only. Generated on first start, its hash is accepted by this
+    /// node, so copying the file to every node lets them call each other.
+    #[schema(value_type = String)]
+    pub peer_key: PathBuf,
+    /// Where the key generated for a node without API keys is written,
+    /// readable by its owner only.
+    #[schema(value_type = String)]
+    pub bootstrap_key: PathBuf,
+}
+
+/// What a caller may do, each role from viewer on includes everything the
+/// previous one may.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Another node, may call only the routes nodes call on each other.
+    Peer,
+    /// Sees nodes, screens and media.
+    Viewer,
+    /// Also controls playback and the player process and manages media.
+    Operator,
+    /// Also reads and changes the config.
+    Administrator,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Role::Peer => "peer",
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Administrator => "administrator",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A named API key. Only the hex encoded SHA-256 of the secret is kept.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ApiKey {
+    pub name: String,
+    /// Keys created before roles existed keep full access.
+    #[serde(default = "default_key_role")]
+    pub role: Role,
+    pub key_hash: String,
+}
+
+fn default_key_role() -> Role {
+    Role::Administrator
+}
+
+impl ApiKey {
+    pub fn new(name: impl Into<String>, role: Role, secret: &str) -> Self {
+        Self {
+            name: name.into(),
+            role,
+            key_hash: hash_secret(secret),
+        }
+    }
+
+    pub fn matches(&self, secret: &str) -> bool {
+        matches_hash(&self.key_hash, secret)
+    }
+}
+
+pub fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+/// Whether `secret` hashes to the hex encoded `hash`. The digests are
+/// compared in constant time, so the time taken tells nothing about the hash.
+pub fn matches_hash(hash: &str, secret: &str) -> bool {
+    let Ok(expected) = hex::decode(hash) else {
+        return false;
+    };
+    let actual = Sha256::digest(secret.as_bytes());
+    expected.len() == actual.len()
+        && expected
+            .iter()
+            .zip(actual.iter())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            api_keys: Vec::new(),
+            anonymous_role: Some(Role::Viewer),
+            session_ttl_secs: 3600,
+            peer_key_hashes: Vec::new(),
+            peer_key: PathBuf::from("peer.key"),
+            bootstrap_key: PathBuf::from("admin.key"),
+        }
+    }
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            packets_per_second: 20,
+            packet_burst: 40,
+            max_sources: 1024,
+            max_nodes: 512,
+            max_pending_frames: 256,
+        }
+    }
 }
 
 impl Config {
@@ -41,44 +341,125 @@ impl Config {
         self.node_list.as_ref()
     }
 
-    pub async fn set_board_ip(&mut self, board_ip: String) {
+    pub fn discovery(&self) -> &DiscoveryConfig {
+        &self.discovery
+    }
+
+    pub fn server(&self) -> &ServerConfig {
+        &self.server
+    }
+
+    pub fn auth(&self) -> &AuthConfig {
+        &self.auth
+    }
+
+    pub fn groups(&self) -> &BTreeMap<String, Vec<i64>> {
+        &self.groups
+    }
+
+    pub fn sync(&self) -> &SyncConfig {
+        &self.sync
+    }
+
+    pub fn set_board_ip(&mut self, board_ip: String) {
         self.board_ip = board_ip;
-        update_config(self.clone()).await;
     }
 
-    pub async fn set_board_port(&mut self, board_port: u16) {
+    pub fn set_board_port(&mut self, board_port: u16) {
         self.board_port = board_port;
-        update_config(self.clone()).await;
     }
 
-    pub async fn set_node_timeout(&mut self, node_timeout: u16) {
+    pub fn set_node_timeout(&mut self, node_timeout: u16) {
         self.node_timeout = node_timeout;
-        update_config(self.clone()).await;
     }
 
-    pub async fn set_node_name(&mut self, node_name: String) {
+    pub fn set_node_name(&mut self, node_name: String) {
         self.node_name = node_name;
-        update_config(self.clone()).await;
     }
 
-    pub async fn set_node_list(&mut self, node_list: Vec<Node>) {
+    pub fn set_node_list(&mut self, node_list: Vec<Node>) {
         self.node_list = node_list;
-        update_config(self.clone()).await;
+    }
+
+    pub fn set_discovery(&mut self, discovery: DiscoveryConfig) {
+        self.discovery = discovery;
+    }
+
+    pub fn set_server(&mut self, server: ServerConfig) {
+        self.server = server;
+    }
+
+    pub fn set_auth(&mut self, auth: AuthConfig) {
+        self.auth = auth;
+    }
+
+    pub fn set_groups(&mut self, groups: BTreeMap<String, Vec<i64>>) {
+        self.groups = groups;
+    }
+
+    pub fn set_sync(&mut self, sync: SyncConfig) {
+        self.sync = sync;
     }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
-            id: utils::snowflake::SNOWFLAKE
-                .lock()
-                .unwrap()
-                .real_time_generate(),
+            id: IdGenerator::new().real_time_generate(),
             board_ip: "224.0.0.1".to_string(),
             board_port: 8081,
             node_timeout: 10,
             node_name: utils::safe_get_ip(),
             node_list: Vec::new(),
+            discovery: DiscoveryConfig::default(),
+            server: ServerConfig::default(),
+            auth: AuthConfig::default(),
+            groups: BTreeMap::new(),
+            sync: SyncConfig::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_key_stores_only_hash() {
+        let key = ApiKey::new("admin", Role::Administrator, "secret");
+        assert_ne!(key.key_hash, "secret");
+        assert_eq!(key.key_hash.len(), 64);
+        assert!(key.matches("secret"));
+        assert!(!key.matches("Secret"));
+        assert!(matches_hash(&key.key_hash.to_uppercase(), "secret"));
+        assert!(!matches_hash("00", "secret"));
+        assert!(!matches_hash("not hex", "secret"));
+    }
+
+    #[test]
+    fn test_auth_defaults_when_missing() {
+        let config: Config = serde_json::from_str(
+            r#"{"id":1,"board_ip":"224.0.0.1","board_port":8081,"node_timeout":10,"node_name":"a"}"#,
+        )
+        .unwrap();
+        assert!(config.auth().enabled);
+        assert!(config.auth().api_keys.is_empty());
+    }
+
+    #[test]
+    fn test_old_sync_secret_is_dropped() {
+        let config: Config = serde_json::from_str(
+            r#"{"id":1,"board_ip":"224.0.0.1","board_port":8081,"node_timeout":10,"node_name":"a","sync":{"interval_secs":30,"api_key":"secret"}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.sync().interval_secs, 30);
+        assert!(!serde_json::to_string(&config).unwrap().contains("secret"));
+    }
+
+    #[test]
+    fn test_key_without_role_is_administrator() {
+        let key: ApiKey = serde_json::from_str(r#"{"name":"old","key_hash":"00"}"#).unwrap();
+        assert_eq!(key.role, Role::Administrator);
+        assert!(Role::Viewer < Role::Operator && Role::Operator < Role::Administrator);
+    }
+}
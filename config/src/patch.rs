@@ -0,0 +1,255 @@
+use std::{collections::BTreeMap, net::Ipv4Addr};
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::model::{Config, DiscoveryConfig};
+
+const MAX_NODE_NAME_LEN: usize = 64;
+const MAX_NODE_TIMEOUT_SECS: u16 = 3600;
+const MAX_GROUP_NAME_LEN: usize = 64;
+
+/// A partial update of [`Config`], absent fields are left untouched.
+#[derive(Debug, Clone, Default, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigPatch {
+    pub board_ip: Option<String>,
+    pub board_port: Option<u16>,
+    pub node_timeout: Option<u16>,
+    pub node_name: Option<String>,
+    pub discovery: Option<DiscoveryPatch>,
+    /// Replaces every group.
+    pub groups: Option<BTreeMap<String, Vec<i64>>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct DiscoveryPatch {
+    pub packets_per_second: Option<u32>,
+    pub packet_burst: Option<u32>,
+    pub max_sources: Option<usize>,
+    pub max_nodes: Option<usize>,
+    pub max_pending_frames: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: &str, message: impl Into<String>) -> Self {
+        FieldError {
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+/// Which running subsystems have to pick up an applied patch.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PatchEffects {
+    /// Multicast address, port or rate limits changed, the socket must be rebound.
+    pub restart_discovery: bool,
+    pub rename_node: bool,
+    pub node_timeout: bool,
+    pub max_nodes: bool,
+}
+
+impl ConfigPatch {
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = vec![];
+        if let Some(board_ip) = &self.board_ip {
+            match board_ip.parse::<Ipv4Addr>() {
+                Ok(ip) if ip.is_multicast() => {}
+                Ok(_) => errors.push(FieldError::new(
+                    "board_ip",
+                    format!("{board_ip} is not an IPv4 multicast address (224.0.0.0/4)"),
+                )),
+                Err(_) => errors.push(FieldError::new(
+                    "board_ip",
+                    format!("{board_ip} is not a valid IPv4 address"),
+                )),
+            }
+        }
+        if let Some(board_port) = self.board_port {
+            if board_port < 1024 {
+                errors.push(FieldError::new(
+                    "board_port",
+                    "must be between 1024 and 65535",
+                ));
+            }
+        }
+        if let Some(node_timeout) = self.node_timeout {
+            if node_timeout == 0 || node_timeout > MAX_NODE_TIMEOUT_SECS {
+                errors.push(FieldError::new(
+                    "node_timeout",
+                    format!("must be between 1 and {MAX_NODE_TIMEOUT_SECS} seconds"),
+                ));
+            }
+        }
+        if let Some(node_name) = &self.node_name {
+            if node_name.trim().is_empty() || node_name.chars().count() > MAX_NODE_NAME_LEN {
+                errors.push(FieldError::new(
+                    "node_name",
+                    format!("must be between 1 and {MAX_NODE_NAME_LEN} characters"),
+                ));
+            }
+        }
+        if let Some(discovery) = &self.discovery {
+            let limits = [
+                (
+                    "discovery.packets_per_second",
+                    discovery.packets_per_second.map(|it| it as usize),
+                ),
+                (
+                    "discovery.packet_burst",
+                    discovery.packet_burst.map(|it| it as usize),
+                ),
+                ("discovery.max_sources", discovery.max_sources),
+                ("discovery.max_nodes", discovery.max_nodes),
+                ("discovery.max_pending_frames", discovery.max_pending_frames),
+            ];
+            for (field, value) in limits {
+                if value == Some(0) {
+                    errors.push(FieldError::new(field, "must be greater than 0"));
+                }
+            }
+        }
+        for name in self.groups.iter().flat_map(|it| it.keys()) {
+            if name.trim().is_empty() || name.chars().count() > MAX_GROUP_NAME_LEN {
+                errors.push(FieldError::new(
+                    &format!("groups.{name}"),
+                    format!("group names must be between 1 and {MAX_GROUP_NAME_LEN} characters"),
+                ));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Applies the patch to `config`; call [`ConfigPatch::validate`] first.
+    pub fn apply(&self, config: &mut Config) -> PatchEffects {
+        let mut effects = PatchEffects::default();
+        if let Some(board_ip) = &self.board_ip {
+            effects.restart_discovery |= board_ip != config.board_ip();
+            config.set_board_ip(board_ip.clone());
+        }
+        if let Some(board_port) = self.board_port {
+            effects.restart_discovery |= board_port != config.board_port();
+            config.set_board_port(board_port);
+        }
+        if let Some(node_timeout) = self.node_timeout {
+            effects.node_timeout = node_timeout != config.node_timeout();
+            config.set_node_timeout(node_timeout);
+        }
+        if let Some(node_name) = &self.node_name {
+            effects.rename_node = node_name != config.node_name();
+            config.set_node_name(node_name.clone());
+        }
+        if let Some(patch) = &self.discovery {
+            let mut discovery = config.discovery().clone();
+            let before = discovery.clone();
+            patch.apply(&mut discovery);
+            effects.max_nodes = discovery.max_nodes != before.max_nodes;
+            effects.restart_discovery |= discovery.packets_per_second != before.packets_per_second
+                || discovery.packet_burst != before.packet_burst
+                || discovery.max_sources != before.max_sources
+                || discovery.max_pending_frames != before.max_pending_frames;
+            config.set_discovery(discovery);
+        }
+        if let Some(groups) = &self.groups {
+            config.set_groups(groups.clone());
+        }
+        effects
+    }
+}
+
+impl DiscoveryPatch {
+    fn apply(&self, discovery: &mut DiscoveryConfig) {
+        if let Some(packets_per_second) = self.packets_per_second {
+            discovery.packets_per_second = packets_per_second;
+        }
+        if let Some(packet_burst) = self.packet_burst {
+            discovery.packet_burst = packet_burst;
+        }
+        if let Some(max_sources) = self.max_sources {
+            discovery.max_sources = max_sources;
+        }
+        if let Some(max_nodes) = self.max_nodes {
+            discovery.max_nodes = max_nodes;
+        }
+        if let Some(max_pending_frames) = self.max_pending_frames {
+            discovery.max_pending_frames = max_pending_frames;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let patch: ConfigPatch = serde_json::from_str(
+            r#"{"board_ip":"192.168.1.1","board_port":80,"node_timeout":0,"node_name":" "}"#,
+        )
+        .unwrap();
+        let errors = patch.validate().unwrap_err();
+        let fields: Vec<&str> = errors.iter().map(|it| it.field.as_str()).collect();
+        assert_eq!(
+            fields,
+            vec!["board_ip", "board_port", "node_timeout", "node_name"]
+        );
+
+        let patch: ConfigPatch =
+            serde_json::from_str(r#"{"board_ip":"239.1.2.3","discovery":{"max_nodes":10}}"#)
+                .unwrap();
+        assert!(patch.validate().is_ok());
+
+        let patch: ConfigPatch =
+            serde_json::from_str(r#"{"groups":{"lobby":[1,2]," ":[3]}}"#).unwrap();
+        let errors = patch.validate().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].field, "groups. ");
+    }
+
+    #[test]
+    fn test_reject_unknown_fields() {
+        assert!(serde_json::from_str::<ConfigPatch>(r#"{"id":1}"#).is_err());
+    }
+
+    #[test]
+    fn test_apply_reports_effects() {
+        let mut config = Config::default();
+        let patch = ConfigPatch {
+            board_port: Some(config.board_port()),
+            node_name: Some("lobby".to_string()),
+            discovery: Some(DiscoveryPatch {
+                max_nodes: Some(10),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let effects = patch.apply(&mut config);
+        assert_eq!(
+            effects,
+            PatchEffects {
+                restart_discovery: false,
+                rename_node: true,
+                node_timeout: false,
+                max_nodes: true,
+            }
+        );
+        assert_eq!(config.node_name(), "lobby");
+        assert_eq!(config.discovery().max_nodes, 10);
+
+        let patch: ConfigPatch = serde_json::from_str(r#"{"groups":{"lobby":[1,2]}}"#).unwrap();
+        assert_eq!(patch.apply(&mut config), PatchEffects::default());
+        assert_eq!(config.groups()["lobby"], vec![1, 2]);
+    }
+}
@@ -1,76 +1,110 @@
 #![allow(dead_code)]
-use std::{sync::Arc, thread, time::Duration};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
 
 use config::model::Config;
-use domain::{node::Node, udp_frame::UDPFrame};
-use tokio::{net::UdpSocket, sync::Mutex, time::sleep};
+use domain::{
+    node::Node,
+    udp_frame::{FrameError, FrameRef, UDPFrame, MAX_DATAGRAM_LEN},
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc::error::TrySendError, Mutex},
+    time::sleep,
+};
 use tracing::{error, info, trace};
+use utils::snowflake::IdGenerator;
 
 use crate::{
     frame_cache::FrameReceiverCache,
-    node_holder::{self, NodeOperation},
+    node_holder::{NodeHoder, NodeOperation},
+    rate_limiter::RateLimiter,
 };
 
 #[derive(Debug, Clone)]
 pub struct BroadcastServer {
     pub port: u16,
+    pub multicast_addr: SocketAddr,
     pub node: Arc<Mutex<Node>>,
     pub socket: Arc<UdpSocket>,
     frame_receiver_cache: FrameReceiverCache,
+    rate_limiter: Arc<std::sync::Mutex<RateLimiter>>,
+    node_holder: Arc<NodeHoder>,
+    ids: IdGenerator,
 }
 
 impl BroadcastServer {
-    pub async fn from_config(config: Config) -> Self {
+    pub async fn from_config(
+        config: Config,
+        node_holder: Arc<NodeHoder>,
+        ids: IdGenerator,
+    ) -> anyhow::Result<Self> {
         let name = config.node_name().to_string();
         let port = config.board_port();
-        let board_ip = config.board_ip();
+        let board_ip: Ipv4Addr = config
+            .board_ip()
+            .parse()
+            .with_context(|| format!("Invalid multicast address {}", config.board_ip()))?;
         let id = config.id();
-        let node = Node::new_self_node(id, name.clone(), port);
+        let mut node = Node::new_self_node(id, name.clone(), port);
+        node.http_port = config.server().http_port;
         // TODO: try to kill port if it is already in use and try again
         let socket = UdpSocket::bind(&format!("0.0.0.0:{port}"))
             .await
-            .unwrap_or_else(|e| {
-                error!("Failed to bind socket to port {} with error {}", port, e);
-                thread::sleep(Duration::from_secs(10));
-                panic!("Failed to bind socket to port {port}")
-            });
+            .with_context(|| format!("Failed to bind socket to port {port}"))?;
         socket
             .set_multicast_loop_v4(true)
-            .expect("Failed to set broadcast");
-        // TODO: set multicast address from config
+            .context("Failed to set broadcast")?;
         socket
-            .join_multicast_v4(board_ip.parse().unwrap(), "0.0.0.0".parse().unwrap())
-            .expect("Failed to join multicast group");
-        info!("Joined multicast group 224.0.0.1 successfully");
+            .join_multicast_v4(board_ip, Ipv4Addr::UNSPECIFIED)
+            .with_context(|| format!("Failed to join multicast group {board_ip}"))?;
+        info!("Joined multicast group {} successfully", board_ip);
         //TODO: set timeout from config
-        BroadcastServer {
+        let limits = config.discovery();
+        let rate_limiter = RateLimiter::new(
+            limits.packets_per_second,
+            limits.packet_burst,
+            limits.max_sources,
+        );
+        Ok(BroadcastServer {
             port,
+            multicast_addr: SocketAddr::from((board_ip, port)),
             node: Arc::new(Mutex::new(node)),
             socket: Arc::new(socket),
-            frame_receiver_cache: FrameReceiverCache::new(),
-        }
+            frame_receiver_cache: FrameReceiverCache::new(
+                limits.max_pending_frames,
+                node_holder.stats().clone(),
+            ),
+            rate_limiter: Arc::new(std::sync::Mutex::new(rate_limiter)),
+            node_holder,
+            ids,
+        })
     }
 }
 
 impl BroadcastServer {
+    /// Announces this node and listens for peers until the future is dropped.
     pub async fn scan_node(&self) {
-        let cloned = Arc::new(self.clone());
-        tokio::spawn(async move {
-            cloned.notify_node().await;
-        });
-        let cloned = self.clone();
-        let _ = tokio::spawn(async move {
-            cloned.listen_notify().await;
-        })
-        .await;
+        tokio::join!(self.notify_node(), self.listen_notify());
     }
 
     async fn listen_notify(&self) {
-        let sender = node_holder::get_sender();
+        let sender = self.node_holder.get_sender();
+        let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
         loop {
-            if let Some(frame) = self.receive_frame().await {
-                if let Ok(node) = Node::try_from(&frame.data) {
-                    if let Err(e) = sender.send(NodeOperation::Active(node)).await {
+            if let Some(node) = self.receive_node(&mut buf).await {
+                self.node_holder.stats().inc_heartbeats_received();
+                match sender.try_send(NodeOperation::Active(node)) {
+                    Ok(_) => {}
+                    Err(TrySendError::Full(_)) => {
+                        self.node_holder.stats().inc_operations_dropped();
+                    }
+                    Err(e) => {
                         error!("Failed to send node to node holder with error {}", e);
                     }
                 }
@@ -79,26 +113,23 @@ impl BroadcastServer {
     }
 
     async fn notify_node(&self) {
+        let mut buf = Vec::with_capacity(MAX_DATAGRAM_LEN);
         while let Ok(node_bytes) = self.node.lock().await.clone().try_into() {
-            let frame = UDPFrame::new(node_bytes);
-            self.send_frame(frame).await;
+            let frame = UDPFrame::new(self.ids.generate() as u64, node_bytes);
+            self.send_frame(frame, &mut buf).await;
+            self.node_holder.stats().inc_heartbeats_sent();
             //TODO: set notify interval from config
             sleep(Duration::from_secs(3)).await;
         }
     }
 
-    async fn send_frame(&self, frame: UDPFrame) {
+    async fn send_frame(&self, frame: UDPFrame, buf: &mut Vec<u8>) {
         let frames = frame.split_frame();
         for frame in frames {
-            let frame_bytes = frame.to_bytes();
-            let frame_bytes = frame_bytes.as_slice();
-            trace!("Send frame: {:?}", frame_bytes.len());
-            match self
-                .clone()
-                .socket
-                .send_to(frame_bytes, &format!("224.0.0.1:{}", self.port.clone()))
-                .await
-            {
+            buf.clear();
+            frame.encode_into(buf);
+            trace!("Send frame: {:?}", buf.len());
+            match self.socket.send_to(buf, self.multicast_addr).await {
                 Ok(_) => {}
                 Err(e) => {
                     error!("Failed to send broadcast with error {}", e)
@@ -107,24 +138,83 @@ impl BroadcastServer {
         }
     }
 
-    async fn receive_frame(&self) -> Option<UDPFrame> {
-        let mut buf = vec![0u8; 1500];
-        let recive = self.clone().socket.recv_from(&mut buf).await;
-        let (len, _addr) = match recive {
+    /// Receives one datagram into `buf` and returns the node it completes, if any.
+    ///
+    /// Unfragmented heartbeats are decoded straight from the receive buffer;
+    /// only fragments are copied so they can wait in the frame cache.
+    async fn receive_node(&self, buf: &mut [u8]) -> Option<Node> {
+        let recive = self.socket.recv_from(buf).await;
+        let (len, addr) = match recive {
             Ok((len, addr)) => (len, addr),
             Err(e) => {
                 error!("Failed to receive broadcast with error {}", e);
                 return None;
             }
         };
-        buf.truncate(len);
-        match UDPFrame::from_vec(buf) {
-            Some(frame) => self
-                .frame_receiver_cache
-                .is_complete(frame)
-                .await
-                .map(UDPFrame::merge_frames),
-            None => None,
+        let stats = self.node_holder.stats();
+        stats.inc_received();
+        if !self.allow(addr) {
+            stats.inc_rate_limited();
+            return None;
+        }
+        let frame = match FrameRef::decode(&buf[..len]) {
+            Ok(frame) => frame,
+            Err(e) => {
+                self.record_frame_error(addr, e);
+                return None;
+            }
+        };
+        if !frame.header.is_fragment() {
+            return self.decode_node(addr, frame.data);
+        }
+        let frames = self
+            .frame_receiver_cache
+            .is_complete(addr, frame.to_owned_frame())
+            .await?;
+        match UDPFrame::merge_frames(frames) {
+            Ok(merged) => self.decode_node(addr, &merged.data),
+            Err(e) => {
+                self.record_frame_error(addr, e);
+                None
+            }
+        }
+    }
+
+    fn record_frame_error(&self, addr: SocketAddr, e: FrameError) {
+        trace!("Prase frame from {} error: {}", addr, e);
+        let stats = self.node_holder.stats();
+        if e.is_checksum() {
+            stats.record_checksum_failure(addr.ip());
+        } else {
+            stats.inc_parse_failed();
+        }
+    }
+
+    /// Decodes the node a heartbeat announces. Its address is the one the
+    /// datagram came from, a node can't name another host for peers to call.
+    fn decode_node(&self, addr: SocketAddr, data: &[u8]) -> Option<Node> {
+        match Node::try_from(data) {
+            Ok(mut node) => {
+                node.ipaddress = addr.ip().to_string();
+                Some(node)
+            }
+            Err(e) => {
+                trace!("Prase node error: {:?}", e);
+                self.node_holder.stats().inc_parse_failed();
+                None
+            }
+        }
+    }
+
+    fn allow(&self, addr: SocketAddr) -> bool {
+        let allowed = self
+            .rate_limiter
+            .lock()
+            .unwrap()
+            .allow(addr.ip(), Instant::now());
+        if !allowed {
+            trace!("Rate limited discovery traffic from {}", addr);
         }
+        allowed
     }
 }
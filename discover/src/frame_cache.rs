@@ -1,12 +1,17 @@
-use std::{collections::HashMap, sync::Arc, time};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time};
 
 use domain::udp_frame::UDPFrame;
 use tokio::sync::Mutex;
 
+use crate::stats::DiscoveryStats;
+
+/// Fragments are grouped per sender, so a node can't complete or spoil the
+/// frame of another that happens to use the same id.
 #[derive(Debug, Clone, Hash, Eq, PartialEq, PartialOrd, Ord)]
 pub struct FrameReceiverCacheKey {
-    id: String,
-    order_count: u8,
+    source: SocketAddr,
+    id: u64,
+    order_count: u16,
 }
 
 type InnderCache =
@@ -15,30 +20,51 @@ type InnderCache =
 #[derive(Debug, Clone)]
 pub struct FrameReceiverCache {
     cache: InnderCache,
+    max_groups: usize,
+    stats: Arc<DiscoveryStats>,
 }
 
 impl FrameReceiverCache {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(max_groups: usize, stats: Arc<DiscoveryStats>) -> Self {
         FrameReceiverCache {
             cache: Arc::new(Mutex::new(HashMap::new())),
+            max_groups,
+            stats,
         }
     }
 
-    pub(crate) async fn is_complete(&self, frame: UDPFrame) -> Option<Vec<UDPFrame>> {
+    pub(crate) async fn is_complete(
+        &self,
+        source: SocketAddr,
+        frame: UDPFrame,
+    ) -> Option<Vec<UDPFrame>> {
         if frame.order_count == 0 {
             return Some(vec![frame]);
         }
+        if frame.order >= frame.order_count {
+            self.stats.inc_fragments_dropped();
+            return None;
+        }
         self.clean_timeout_cache().await;
         let mut cache = self.cache.lock().await;
         let key = FrameReceiverCacheKey {
-            id: frame.id.clone(),
+            source,
+            id: frame.id,
             order_count: frame.order_count,
         };
         let complete = if let Some(cache_vec) = cache.get(&key) {
             let mut cache_vec = cache_vec.1.lock().await;
+            if cache_vec.iter().any(|it| it.order == frame.order) {
+                self.stats.inc_fragments_dropped();
+                return None;
+            }
             cache_vec.push(frame.clone());
             cache_vec.len() == frame.order_count as usize
         } else {
+            if cache.len() >= self.max_groups {
+                self.stats.inc_fragments_dropped();
+                return None;
+            }
             cache.insert(
                 key.clone(),
                 (
@@ -48,9 +74,11 @@ impl FrameReceiverCache {
             );
             false
         };
+        self.stats.set_pending_fragment_groups(cache.len());
 
         if complete {
             let cache_vec = cache.remove(&key).unwrap();
+            self.stats.set_pending_fragment_groups(cache.len());
             let cache_vec = cache_vec.1.lock().await;
             Some(cache_vec.to_vec())
         } else {
@@ -58,6 +86,10 @@ impl FrameReceiverCache {
         }
     }
 
+    pub async fn pending_groups(&self) -> usize {
+        self.cache.lock().await.len()
+    }
+
     async fn clean_timeout_cache(&self) {
         let mut cache = self.cache.lock().await;
         cache.retain(|_, v| {
@@ -67,6 +99,89 @@ impl FrameReceiverCache {
             //TODO: set timeout from config
             duration.as_secs() < 5
         });
+        self.stats.set_pending_fragment_groups(cache.len());
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(id: u64, order: u16, order_count: u16) -> UDPFrame {
+        let mut frame = UDPFrame::new(id, vec![order as u8]);
+        frame.order = order;
+        frame.order_count = order_count;
+        frame
+    }
+
+    fn addr(last: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, last], 4001))
+    }
+
+    #[tokio::test]
+    async fn test_pending_groups_are_capped() {
+        let stats = Arc::new(DiscoveryStats::default());
+        let cache = FrameReceiverCache::new(2, stats.clone());
+        assert!(cache
+            .is_complete(addr(1), fragment(1, 0, 2))
+            .await
+            .is_none());
+        assert!(cache
+            .is_complete(addr(1), fragment(2, 0, 2))
+            .await
+            .is_none());
+        assert!(cache
+            .is_complete(addr(1), fragment(3, 0, 2))
+            .await
+            .is_none());
+        assert_eq!(cache.pending_groups().await, 2);
+        assert_eq!(stats.snapshot().fragments_dropped, 1);
+        assert!(cache
+            .is_complete(addr(1), fragment(1, 1, 2))
+            .await
+            .is_some());
+        assert_eq!(cache.pending_groups().await, 1);
+        assert_eq!(stats.snapshot().pending_fragment_groups, 1);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_fragment_does_not_complete() {
+        let stats = Arc::new(DiscoveryStats::default());
+        let cache = FrameReceiverCache::new(2, stats.clone());
+        assert!(cache
+            .is_complete(addr(1), fragment(1, 0, 2))
+            .await
+            .is_none());
+        assert!(cache
+            .is_complete(addr(1), fragment(1, 0, 2))
+            .await
+            .is_none());
+        assert_eq!(stats.snapshot().fragments_dropped, 1);
+        assert!(cache
+            .is_complete(addr(1), fragment(1, 1, 2))
+            .await
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_senders_are_kept_apart() {
+        let stats = Arc::new(DiscoveryStats::default());
+        let cache = FrameReceiverCache::new(4, stats.clone());
+        assert!(cache
+            .is_complete(addr(1), fragment(1, 0, 2))
+            .await
+            .is_none());
+        assert!(cache
+            .is_complete(addr(2), fragment(1, 0, 2))
+            .await
+            .is_none());
+        assert_eq!(stats.snapshot().fragments_dropped, 0);
+        let frames = cache.is_complete(addr(2), fragment(1, 1, 2)).await.unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(cache.pending_groups().await, 1);
+        assert!(cache
+            .is_complete(addr(1), fragment(1, 1, 2))
+            .await
+            .is_some());
+    }
+}
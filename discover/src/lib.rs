@@ -1,3 +1,5 @@
 pub mod broadcast_server;
 pub mod frame_cache;
 pub mod node_holder;
+mod rate_limiter;
+pub mod stats;
@@ -1,10 +1,13 @@
 use std::{
-    sync::Arc,
-    time::{self, Duration, SystemTime},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
+use config::ConfigStore;
 use domain::node::Node;
-use lazy_static::lazy_static;
 use tokio::{
     sync::{
         mpsc::{channel, Receiver, Sender},
@@ -12,36 +15,10 @@ use tokio::{
     },
     time::sleep,
 };
-use tracing::{error, info};
-
-lazy_static! {
-    pub static ref NODE_HOLDER: NodeHoder = NodeHoder::new();
-}
-
-pub async fn run_node_holder() {
-    tokio::spawn(async {
-        NODE_HOLDER.clean_node().await;
-    });
-    tokio::spawn(async {
-        NODE_HOLDER.start().await;
-    });
-}
-
-pub fn get_node_holder() -> &'static NodeHoder {
-    &NODE_HOLDER
-}
+use tracing::{error, info, warn};
+use utils::clock::Clock;
 
-pub async fn set_node_list(node_list: Vec<Node>) {
-    NODE_HOLDER.set_node_list(node_list).await;
-}
-
-pub async fn get_node_list() -> Vec<Node> {
-    NODE_HOLDER.get_node_list().await
-}
-
-pub fn get_sender() -> Sender<NodeOperation> {
-    NODE_HOLDER.get_senders()
-}
+use crate::stats::DiscoveryStats;
 
 #[derive(Debug)]
 pub enum NodeOperation {
@@ -56,35 +33,70 @@ pub struct NodeHoder {
     node_list: Arc<RwLock<Vec<Node>>>,
     sender: Sender<NodeOperation>,
     receiver: Mutex<Receiver<NodeOperation>>,
-    timeout: Duration,
-}
-
-impl Default for NodeHoder {
-    fn default() -> Self {
-        Self::new()
-    }
+    timeout_ms: AtomicU64,
+    max_nodes: AtomicUsize,
+    config: ConfigStore,
+    clock: Arc<dyn Clock>,
+    stats: Arc<DiscoveryStats>,
 }
 
 impl NodeHoder {
-    pub fn new() -> Self {
+    pub fn new(config: ConfigStore, clock: Arc<dyn Clock>, stats: Arc<DiscoveryStats>) -> Self {
         let (tx, rs) = channel(100);
         NodeHoder {
             node_list: Arc::new(RwLock::new(Vec::new())),
             sender: tx,
             receiver: Mutex::new(rs),
-            timeout: Duration::from_secs(5),
+            timeout_ms: AtomicU64::new(5_000),
+            max_nodes: AtomicUsize::new(usize::MAX),
+            config,
+            clock,
+            stats,
         }
     }
 
-    fn get_senders(&self) -> Sender<NodeOperation> {
+    pub fn run(self: &Arc<Self>) {
+        let cloned = self.clone();
+        tokio::spawn(async move {
+            cloned.clean_node().await;
+        });
+        let cloned = self.clone();
+        tokio::spawn(async move {
+            cloned.start().await;
+        });
+    }
+
+    pub fn get_sender(&self) -> Sender<NodeOperation> {
         self.sender.clone()
     }
 
-    async fn get_node_list(&self) -> Vec<Node> {
+    pub fn stats(&self) -> &Arc<DiscoveryStats> {
+        &self.stats
+    }
+
+    pub fn set_timeout(&self, timeout: Duration) {
+        self.timeout_ms
+            .store(timeout.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_max_nodes(&self, max_nodes: usize) {
+        self.max_nodes.store(max_nodes, Ordering::Relaxed);
+    }
+
+    pub async fn get_node_list(&self) -> Vec<Node> {
         self.node_list.read().await.clone()
     }
 
-    async fn set_node_list(&self, node_list: Vec<Node>) {
+    pub async fn get_node(&self, id: i64) -> Option<Node> {
+        self.node_list
+            .read()
+            .await
+            .iter()
+            .find(|it| it.id == id)
+            .cloned()
+    }
+
+    pub async fn set_node_list(&self, node_list: Vec<Node>) {
         *self.node_list.write().await = node_list;
     }
 
@@ -108,19 +120,14 @@ impl NodeHoder {
             //TODO: set clean interval from config
             sleep(Duration::from_secs(6)).await;
             let node_list = self.node_list.read().await;
-            let now: Duration = match SystemTime::now().duration_since(time::UNIX_EPOCH) {
-                Ok(it) => it,
-                Err(e) => {
-                    error!("Failed to get current time with error {}", e);
-                    continue;
-                }
-            };
+            let now = Duration::from_millis(self.clock.now_millis() as u64);
+            let timeout = Duration::from_millis(self.timeout_ms.load(Ordering::Relaxed));
             let inactivity: Vec<Node> = node_list
                 .iter()
                 .filter(|it| it.active)
                 .filter(|node| {
                     let hit_timestamp = Duration::from_millis(node.hit_timestamp as u64);
-                    now - hit_timestamp > self.timeout
+                    now.saturating_sub(hit_timestamp) > timeout
                 })
                 .cloned()
                 .collect();
@@ -144,48 +151,59 @@ impl NodeHoder {
                     NodeOperation::Remove(node) => {
                         let mut node_list = self.node_list.write().await;
                         node_list.retain(|it| it.id != node.id);
-                        info_and_update_config(node_list.clone(), "re").await;
+                        self.info_and_update_config(node_list.clone(), "re");
                     }
                     NodeOperation::InActive(mut node) => {
                         let mut node_list = self.node_list.write().await;
                         node_list.retain(|it| it.id != node.id);
                         node.inactive();
                         node_list.push(node);
-                        info_and_update_config(node_list.clone(), "in").await;
+                        self.info_and_update_config(node_list.clone(), "in");
                     }
                     NodeOperation::Active(mut node) => {
                         let mut node_list = self.node_list.write().await;
+                        let known = node_list.iter().any(|it| it.id == node.id);
+                        if !known && node_list.len() >= self.max_nodes.load(Ordering::Relaxed) {
+                            warn!("Node list is full, dropped heartbeat from node {}", node.id);
+                            self.stats.inc_nodes_rejected();
+                            continue;
+                        }
                         node_list.retain(|it| it.id != node.id);
                         node.active();
-                        node.update_hit_timestamp();
+                        node.update_hit_timestamp(self.clock.as_ref());
                         node_list.push(node);
-                        info_and_update_config(node_list.clone(), "ac").await;
+                        self.info_and_update_config(node_list.clone(), "ac");
                     }
                     NodeOperation::Init(node) => {
                         let mut node_list = self.node_list.write().await;
                         node_list.retain(|it| it.id != node.id);
                         node_list.push(node);
-                        info_and_update_config(node_list.clone(), "init").await;
+                        self.info_and_update_config(node_list.clone(), "init");
                     }
                 }
             }
         }
     }
-}
 
-async fn info_and_update_config(vec: Vec<Node>, op: &str) {
-    info!(
-        "op: {}, In server node list: {:#?}",
-        op,
-        vec.iter()
-            .filter(|n| n.active)
-            .map(|n| {
-                let n = n.clone();
-                format!("Node: id: {}, name: {}, ip: {}", n.id, n.name, n.ipaddress)
-            })
-            .collect::<Vec<String>>()
-    );
-    tokio::spawn(async move {
-        config::get_config().await.set_node_list(vec).await;
-    });
+    fn info_and_update_config(&self, vec: Vec<Node>, op: &str) {
+        let active = vec.iter().filter(|n| n.active).count();
+        self.stats.set_node_counts(vec.len(), active);
+        info!(
+            "op: {}, In server node list: {:#?}",
+            op,
+            vec.iter()
+                .filter(|n| n.active)
+                .map(|n| {
+                    let n = n.clone();
+                    format!("Node: id: {}, name: {}, ip: {}", n.id, n.name, n.ipaddress)
+                })
+                .collect::<Vec<String>>()
+        );
+        let config = self.config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = config.update(|cfg| cfg.set_node_list(vec)).await {
+                error!("Failed to persist node list with error {}", e);
+            }
+        });
+    }
 }
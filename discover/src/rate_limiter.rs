@@ -0,0 +1,89 @@
+use std::{collections::HashMap, net::IpAddr, time::Instant};
+
+/// How long a source may stay silent before its bucket can be evicted to make
+/// room for a new source.
+const IDLE_SECS: u64 = 60;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// Token bucket per source address.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    max_sources: usize,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate: u32, burst: u32, max_sources: usize) -> Self {
+        RateLimiter {
+            rate: rate as f64,
+            burst: burst.max(1) as f64,
+            max_sources,
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn allow(&mut self, source: IpAddr, now: Instant) -> bool {
+        if !self.buckets.contains_key(&source) {
+            if self.buckets.len() >= self.max_sources {
+                self.buckets
+                    .retain(|_, bucket| now.duration_since(bucket.last).as_secs() < IDLE_SECS);
+            }
+            if self.buckets.len() >= self.max_sources {
+                return false;
+            }
+            self.buckets.insert(
+                source,
+                Bucket {
+                    tokens: self.burst,
+                    last: now,
+                },
+            );
+        }
+        let bucket = self.buckets.get_mut(&source).expect("bucket just inserted");
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_burst_then_refill() {
+        let mut limiter = RateLimiter::new(10, 3, 8);
+        let source: IpAddr = "192.168.1.2".parse().unwrap();
+        let now = Instant::now();
+        assert!(limiter.allow(source, now));
+        assert!(limiter.allow(source, now));
+        assert!(limiter.allow(source, now));
+        assert!(!limiter.allow(source, now));
+        assert!(limiter.allow(source, now + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn test_max_sources() {
+        let mut limiter = RateLimiter::new(10, 3, 1);
+        let now = Instant::now();
+        assert!(limiter.allow("10.0.0.1".parse().unwrap(), now));
+        assert!(!limiter.allow("10.0.0.2".parse().unwrap(), now));
+        let later = now + Duration::from_secs(IDLE_SECS);
+        assert!(limiter.allow("10.0.0.2".parse().unwrap(), later));
+    }
+}
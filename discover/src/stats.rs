@@ -0,0 +1,122 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// Peers tracked for per-peer counters; failures from further peers are only
+/// counted in the totals.
+const MAX_TRACKED_PEERS: usize = 1024;
+
+/// Counters for inbound discovery traffic, shared by the socket loop and the
+/// node holder.
+#[derive(Debug, Default)]
+pub struct DiscoveryStats {
+    received: AtomicU64,
+    rate_limited: AtomicU64,
+    parse_failed: AtomicU64,
+    fragments_dropped: AtomicU64,
+    nodes_rejected: AtomicU64,
+    operations_dropped: AtomicU64,
+    checksum_failed: AtomicU64,
+    checksum_failed_by_peer: Mutex<HashMap<IpAddr, u64>>,
+    heartbeats_sent: AtomicU64,
+    heartbeats_received: AtomicU64,
+    pending_fragment_groups: AtomicU64,
+    known_nodes: AtomicU64,
+    active_nodes: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DiscoveryStatsSnapshot {
+    pub received: u64,
+    pub rate_limited: u64,
+    pub parse_failed: u64,
+    pub fragments_dropped: u64,
+    pub nodes_rejected: u64,
+    pub operations_dropped: u64,
+    pub checksum_failed: u64,
+    #[schema(value_type = HashMap<String, u64>)]
+    pub checksum_failed_by_peer: HashMap<IpAddr, u64>,
+    pub heartbeats_sent: u64,
+    pub heartbeats_received: u64,
+    pub pending_fragment_groups: u64,
+    pub known_nodes: u64,
+    pub active_nodes: u64,
+}
+
+impl DiscoveryStats {
+    pub fn inc_received(&self) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_parse_failed(&self) {
+        self.parse_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_fragments_dropped(&self) {
+        self.fragments_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_nodes_rejected(&self) {
+        self.nodes_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_operations_dropped(&self) {
+        self.operations_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_heartbeats_sent(&self) {
+        self.heartbeats_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_heartbeats_received(&self) {
+        self.heartbeats_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_pending_fragment_groups(&self, groups: usize) {
+        self.pending_fragment_groups
+            .store(groups as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_node_counts(&self, known: usize, active: usize) {
+        self.known_nodes.store(known as u64, Ordering::Relaxed);
+        self.active_nodes.store(active as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_checksum_failure(&self, peer: IpAddr) {
+        self.checksum_failed.fetch_add(1, Ordering::Relaxed);
+        let mut by_peer = self.checksum_failed_by_peer.lock().unwrap();
+        if by_peer.len() < MAX_TRACKED_PEERS || by_peer.contains_key(&peer) {
+            *by_peer.entry(peer).or_default() += 1;
+        }
+    }
+
+    pub fn snapshot(&self) -> DiscoveryStatsSnapshot {
+        DiscoveryStatsSnapshot {
+            received: self.received.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+            parse_failed: self.parse_failed.load(Ordering::Relaxed),
+            fragments_dropped: self.fragments_dropped.load(Ordering::Relaxed),
+            nodes_rejected: self.nodes_rejected.load(Ordering::Relaxed),
+            operations_dropped: self.operations_dropped.load(Ordering::Relaxed),
+            checksum_failed: self.checksum_failed.load(Ordering::Relaxed),
+            checksum_failed_by_peer: self.checksum_failed_by_peer.lock().unwrap().clone(),
+            heartbeats_sent: self.heartbeats_sent.load(Ordering::Relaxed),
+            heartbeats_received: self.heartbeats_received.load(Ordering::Relaxed),
+            pending_fragment_groups: self.pending_fragment_groups.load(Ordering::Relaxed),
+            known_nodes: self.known_nodes.load(Ordering::Relaxed),
+            active_nodes: self.active_nodes.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Compares the fixed binary header against the previous postcard encoded frame.
+//!
+//! Run with `cargo bench -p domain`.
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use domain::udp_frame::{FrameRef, UDPFrame, MAX_DATAGRAM_LEN};
+use serde::{Deserialize, Serialize};
+
+/// The frame layout used before the binary header, kept here as a baseline.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct PostcardFrame {
+    id: String,
+    version: u8,
+    frame_type: u8,
+    length: u16,
+    order: u8,
+    order_count: u8,
+    data: Vec<u8>,
+}
+
+fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|it| it as u8).collect()
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for len in [64usize, 256, 1000] {
+        let data = payload(len);
+        let binary = UDPFrame::new(1676142600000, data.clone()).to_bytes();
+        let postcard = postcard::to_allocvec(&PostcardFrame {
+            id: "1676142600000".to_string(),
+            version: 1,
+            frame_type: 1,
+            length: len as u16,
+            order: 0,
+            order_count: 0,
+            data,
+        })
+        .unwrap();
+        group.throughput(Throughput::Bytes(binary.len() as u64));
+        group.bench_with_input(BenchmarkId::new("postcard", len), &postcard, |b, bytes| {
+            b.iter(|| {
+                // the old receive path copied the datagram into a fresh buffer first
+                let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+                buf[..bytes.len()].copy_from_slice(bytes);
+                buf.truncate(bytes.len());
+                let frame: PostcardFrame = postcard::from_bytes(&buf).unwrap();
+                black_box(frame)
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("binary", len), &binary, |b, bytes| {
+            let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+            b.iter(|| {
+                buf[..bytes.len()].copy_from_slice(bytes);
+                let frame = FrameRef::decode(&buf[..bytes.len()]).unwrap();
+                black_box(frame.data.len())
+            })
+        });
+    }
+    group.finish();
+}
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    let frame = UDPFrame::new(1676142600000, payload(1000));
+    group.throughput(Throughput::Bytes(frame.data.len() as u64));
+    group.bench_function("to_bytes", |b| b.iter(|| black_box(frame.to_bytes())));
+    group.bench_function("encode_into", |b| {
+        let mut buf = Vec::with_capacity(MAX_DATAGRAM_LEN);
+        b.iter(|| {
+            buf.clear();
+            frame.encode_into(&mut buf);
+            black_box(buf.len())
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, decode, encode);
+criterion_main!(benches);
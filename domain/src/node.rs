@@ -1,18 +1,23 @@
-use std::{
-    time::{self, SystemTime},
-    u128,
-};
-
 use postcard::Error;
 use serde::{Deserialize, Serialize};
-use utils::{get_mac_address, safe_get_ip};
+use utils::{clock::Clock, get_mac_address, safe_get_ip};
+use utoipa::ToSchema;
+
+/// Port of the HTTP API when a node doesn't announce one.
+pub const DEFAULT_HTTP_PORT: u16 = 8081;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct Node {
     pub id: i64,
     pub name: String,
     pub ipaddress: String,
     pub port: u16,
+    /// Port the node serves its HTTP API on, used to forward control calls to it.
+    #[serde(default = "default_http_port")]
+    pub http_port: u16,
+    /// SHA-256 of the certificate the node serves HTTPS with, `None` for plain HTTP.
+    #[serde(default)]
+    pub cert_fingerprint: Option<String>,
     pub hit_timestamp: u128,
     pub mac_address: Vec<String>,
     pub active: bool,
@@ -25,6 +30,8 @@ impl Node {
             name,
             ipaddress: safe_get_ip(),
             port,
+            http_port: DEFAULT_HTTP_PORT,
+            cert_fingerprint: None,
             hit_timestamp,
             mac_address: get_mac_address(),
             active: true,
@@ -35,11 +42,8 @@ impl Node {
         Node::new(id, name, port, 0)
     }
 
-    pub fn update_hit_timestamp(&mut self) {
-        self.hit_timestamp = SystemTime::now()
-            .duration_since(time::UNIX_EPOCH)
-            .expect("Failed to get duration since unix epoch")
-            .as_millis();
+    pub fn update_hit_timestamp(&mut self, clock: &dyn Clock) {
+        self.hit_timestamp = clock.now_millis();
     }
 
     pub fn update_name(&mut self, name: String) {
@@ -55,6 +59,10 @@ impl Node {
     }
 }
 
+fn default_http_port() -> u16 {
+    DEFAULT_HTTP_PORT
+}
+
 impl TryFrom<Node> for Vec<u8> {
     type Error = Error;
     fn try_from(value: Node) -> Result<Self, Self::Error> {
@@ -62,9 +70,9 @@ impl TryFrom<Node> for Vec<u8> {
     }
 }
 
-impl TryFrom<&Vec<u8>> for Node {
+impl TryFrom<&[u8]> for Node {
     type Error = Error;
-    fn try_from(value: &Vec<u8>) -> Result<Self, Self::Error> {
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
         postcard::from_bytes(value)
     }
 }
@@ -75,10 +83,7 @@ mod tests {
     #[test]
     fn test_node() {
         let node = Node::new(
-            utils::snowflake::SNOWFLAKE
-                .lock()
-                .unwrap()
-                .real_time_generate(),
+            utils::snowflake::IdGenerator::new().real_time_generate(),
             "server".to_string(),
             8080,
             0,
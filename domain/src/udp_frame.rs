@@ -1,83 +1,307 @@
-use serde::Deserialize;
-use serde::Serialize;
+//! Wire format of discovery datagrams.
+//!
+//! Every datagram starts with a fixed little-endian header followed by the
+//! payload:
+//!
+//! | offset | size | field                       |
+//! |--------|------|-----------------------------|
+//! | 0      | 2    | magic `b"BC"`               |
+//! | 2      | 1    | version                     |
+//! | 3      | 1    | frame type                  |
+//! | 4      | 8    | message id                  |
+//! | 12     | 2    | fragment index              |
+//! | 14     | 2    | fragment count (0 = whole)  |
+//! | 16     | 2    | payload length              |
+//! | 18     | 4    | message checksum            |
+//! | 22     | 4    | checksum                    |
+//!
+//! `checksum` is a CRC-32 over the header (with the checksum field zeroed) and
+//! the payload of this datagram. `message checksum` is a CRC-32 over the whole
+//! payload before fragmentation and is verified after reassembly.
+use std::fmt;
+
+use crc32fast::Hasher;
 use tracing::error;
-use utils::snowflake::SNOWFLAKE;
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub const MAGIC: [u8; 2] = *b"BC";
+pub const VERSION: u8 = 3;
+pub const HEADER_LEN: usize = 26;
+const CHECKSUM_OFFSET: usize = 22;
+/// Payload bytes carried by one fragment.
+pub const FRAGMENT_LEN: usize = 1000;
+/// Receive buffer size that fits any datagram we send.
+pub const MAX_DATAGRAM_LEN: usize = 1500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FrameType {
     Command,
     Data,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+impl From<FrameType> for u8 {
+    fn from(value: FrameType) -> Self {
+        match value {
+            FrameType::Command => 0,
+            FrameType::Data => 1,
+        }
+    }
+}
+
+impl TryFrom<u8> for FrameType {
+    type Error = FrameError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(FrameType::Command),
+            1 => Ok(FrameType::Data),
+            other => Err(FrameError::UnknownType(other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    TooShort(usize),
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnknownType(u8),
+    LengthMismatch { header: u16, actual: usize },
+    BadFragment { order: u16, order_count: u16 },
+    ChecksumMismatch { expected: u32, actual: u32 },
+    IncompleteMessage(u64),
+}
+
+impl FrameError {
+    pub fn is_checksum(&self) -> bool {
+        matches!(self, FrameError::ChecksumMismatch { .. })
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooShort(len) => write!(f, "datagram too short: {len} bytes"),
+            FrameError::BadMagic => write!(f, "bad magic"),
+            FrameError::UnsupportedVersion(version) => {
+                write!(f, "unsupported frame version {version}")
+            }
+            FrameError::UnknownType(frame_type) => write!(f, "unknown frame type {frame_type}"),
+            FrameError::LengthMismatch { header, actual } => {
+                write!(f, "header length {header} but payload has {actual} bytes")
+            }
+            FrameError::BadFragment { order, order_count } => {
+                write!(
+                    f,
+                    "fragment {order} out of range for {order_count} fragments"
+                )
+            }
+            FrameError::ChecksumMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "checksum mismatch: expected {expected:#010x}, got {actual:#010x}"
+                )
+            }
+            FrameError::IncompleteMessage(id) => {
+                write!(f, "fragments of message {id} do not form a whole message")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub version: u8,
+    pub frame_type: FrameType,
+    pub id: u64,
+    pub order: u16,
+    pub order_count: u16,
+    pub length: u16,
+    pub message_checksum: u32,
+    pub checksum: u32,
+}
+
+impl FrameHeader {
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&MAGIC);
+        buf.push(self.version);
+        buf.push(self.frame_type.into());
+        buf.extend_from_slice(&self.id.to_le_bytes());
+        buf.extend_from_slice(&self.order.to_le_bytes());
+        buf.extend_from_slice(&self.order_count.to_le_bytes());
+        buf.extend_from_slice(&self.length.to_le_bytes());
+        buf.extend_from_slice(&self.message_checksum.to_le_bytes());
+        buf.extend_from_slice(&self.checksum.to_le_bytes());
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, FrameError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(FrameError::TooShort(bytes.len()));
+        }
+        if bytes[0..2] != MAGIC {
+            return Err(FrameError::BadMagic);
+        }
+        let version = bytes[2];
+        if version != VERSION {
+            return Err(FrameError::UnsupportedVersion(version));
+        }
+        let header = FrameHeader {
+            version,
+            frame_type: FrameType::try_from(bytes[3])?,
+            id: u64::from_le_bytes(bytes[4..12].try_into().unwrap()),
+            order: u16::from_le_bytes(bytes[12..14].try_into().unwrap()),
+            order_count: u16::from_le_bytes(bytes[14..16].try_into().unwrap()),
+            length: u16::from_le_bytes(bytes[16..18].try_into().unwrap()),
+            message_checksum: u32::from_le_bytes(bytes[18..22].try_into().unwrap()),
+            checksum: u32::from_le_bytes(bytes[22..26].try_into().unwrap()),
+        };
+        if header.order_count != 0 && header.order >= header.order_count {
+            return Err(FrameError::BadFragment {
+                order: header.order,
+                order_count: header.order_count,
+            });
+        }
+        Ok(header)
+    }
+
+    pub fn is_fragment(&self) -> bool {
+        self.order_count != 0
+    }
+}
+
+/// A decoded datagram borrowing its payload from the receive buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameRef<'a> {
+    pub header: FrameHeader,
+    pub data: &'a [u8],
+}
+
+impl<'a> FrameRef<'a> {
+    pub fn decode(bytes: &'a [u8]) -> Result<Self, FrameError> {
+        let header = FrameHeader::decode(bytes)?;
+        let data = &bytes[HEADER_LEN..];
+        if data.len() != header.length as usize {
+            return Err(FrameError::LengthMismatch {
+                header: header.length,
+                actual: data.len(),
+            });
+        }
+        let actual = datagram_checksum(&bytes[..CHECKSUM_OFFSET], data);
+        if actual != header.checksum {
+            return Err(FrameError::ChecksumMismatch {
+                expected: header.checksum,
+                actual,
+            });
+        }
+        Ok(FrameRef { header, data })
+    }
+
+    pub fn to_owned_frame(&self) -> UDPFrame {
+        UDPFrame {
+            id: self.header.id,
+            version: self.header.version,
+            frame_type: self.header.frame_type,
+            length: self.header.length,
+            order: self.header.order,
+            order_count: self.header.order_count,
+            message_checksum: self.header.message_checksum,
+            data: self.data.to_vec(),
+        }
+    }
+}
+
+fn datagram_checksum(header: &[u8], data: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(header);
+    hasher.update(&[0u8; 4]);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+fn message_checksum(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UDPFrame {
-    pub id: String,
+    pub id: u64,
     pub version: u8,
     pub frame_type: FrameType,
     pub length: u16,
-    pub order: u8,
-    pub order_count: u8,
+    pub order: u16,
+    pub order_count: u16,
+    pub message_checksum: u32,
     pub data: Vec<u8>,
 }
 
 impl UDPFrame {
-    pub fn new(data: Vec<u8>) -> Self {
+    pub fn new(id: u64, data: Vec<u8>) -> Self {
         let length = data.len() as u16;
         UDPFrame {
-            id: SNOWFLAKE.lock().unwrap().generate().to_string(),
-            version: 1u8,
+            id,
+            version: VERSION,
             frame_type: FrameType::Data,
             length,
             order: 0,
             order_count: 0,
+            message_checksum: message_checksum(&data),
             data,
         }
     }
 
-    pub fn new_from<T>(data: T) -> Self
+    pub fn new_from<T>(id: u64, data: T) -> Self
     where
         T: Into<Vec<u8>>,
     {
-        let data: Vec<u8> = data.into();
-        //read u8
-        let length = data.len() as u16;
-        UDPFrame {
-            id: SNOWFLAKE.lock().unwrap().generate().to_string(),
-            version: 1u8,
-            frame_type: FrameType::Data,
-            length,
-            order: 0,
-            order_count: 0,
-            data,
-        }
+        UDPFrame::new(id, data.into())
     }
 
-    fn new_from_frame_bytes_order(frame: &Self, data: Vec<u8>, order: u8, order_count: u8) -> Self {
+    fn new_from_frame_bytes_order(
+        frame: &Self,
+        data: Vec<u8>,
+        order: u16,
+        order_count: u16,
+    ) -> Self {
         let length = data.len() as u16;
         UDPFrame {
-            id: frame.id.clone(),
+            id: frame.id,
             version: frame.version,
-            frame_type: frame.frame_type.clone(),
+            frame_type: frame.frame_type,
             length,
             order,
             order_count,
+            message_checksum: frame.message_checksum,
             data,
         }
     }
+
+    /// Header of this frame with the checksum left at zero, see [`UDPFrame::encode_into`].
+    pub fn header(&self) -> FrameHeader {
+        FrameHeader {
+            version: self.version,
+            frame_type: self.frame_type,
+            id: self.id,
+            order: self.order,
+            order_count: self.order_count,
+            length: self.length,
+            message_checksum: self.message_checksum,
+            checksum: 0,
+        }
+    }
 }
 
 impl UDPFrame {
     pub fn split_frame(&self) -> Vec<UDPFrame> {
         let mut result = vec![];
-        if self.data.len() > 1000 {
-            let chunks = self.data.chunks(1000);
+        if self.data.len() > FRAGMENT_LEN {
+            let chunks = self.data.chunks(FRAGMENT_LEN);
             let len = chunks.len();
             for (index, chunk) in chunks.enumerate() {
                 let frame = UDPFrame::new_from_frame_bytes_order(
                     self,
                     chunk.to_vec(),
-                    index as u8,
-                    len as u8,
+                    index as u16,
+                    len as u16,
                 );
                 result.push(frame);
             }
@@ -87,29 +311,65 @@ impl UDPFrame {
         result
     }
 
-    pub fn merge_frames(mut frames: Vec<UDPFrame>) -> Self {
-        let mut data = vec![];
+    /// Reassembles fragments and checks the result against the message checksum.
+    pub fn merge_frames(mut frames: Vec<UDPFrame>) -> Result<Self, FrameError> {
         frames.sort();
+        let first = match frames.first() {
+            Some(first) => first,
+            None => return Err(FrameError::IncompleteMessage(0)),
+        };
+        let (id, expected) = (first.id, first.message_checksum);
+        let whole = frames.len() == 1 && first.order_count == 0;
+        let complete = frames.iter().enumerate().all(|(index, frame)| {
+            frame.id == id
+                && frame.message_checksum == expected
+                && (whole || frame.order_count as usize == frames.len())
+                && frame.order as usize == index
+        });
+        if !complete {
+            return Err(FrameError::IncompleteMessage(id));
+        }
+        let mut data = Vec::with_capacity(frames.iter().map(|frame| frame.data.len()).sum());
         for frame in frames {
             data.extend_from_slice(&frame.data);
         }
-        UDPFrame::new_from(data)
+        let actual = message_checksum(&data);
+        if actual != expected {
+            return Err(FrameError::ChecksumMismatch { expected, actual });
+        }
+        Ok(UDPFrame::new(id, data))
     }
 }
 
 impl UDPFrame {
     pub fn from_vec(bytes: Vec<u8>) -> Option<Self> {
-        match postcard::from_bytes(&bytes) {
-            Ok(frame) => Some(frame),
+        UDPFrame::from_slice(&bytes)
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        match FrameRef::decode(bytes) {
+            Ok(frame) => Some(frame.to_owned_frame()),
             Err(e) => {
-                error!("Prase frame error: {:?}, data length: {:?}", e, bytes.len());
+                error!("Prase frame error: {}, data length: {:?}", e, bytes.len());
                 None
             }
         }
     }
 
+    /// Appends the encoded datagram to `buf`, so callers can reuse one buffer.
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.reserve(HEADER_LEN + self.data.len());
+        let start = buf.len();
+        self.header().encode(buf);
+        let checksum = datagram_checksum(&buf[start..start + CHECKSUM_OFFSET], &self.data);
+        buf[start + CHECKSUM_OFFSET..start + HEADER_LEN].copy_from_slice(&checksum.to_le_bytes());
+        buf.extend_from_slice(&self.data);
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
-        postcard::to_allocvec(self).unwrap()
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.data.len());
+        self.encode_into(&mut buf);
+        buf
     }
 }
 
@@ -136,19 +396,91 @@ mod tests {
     use super::*;
     #[test]
     fn test_frame() {
-        let frame = UDPFrame::new_from("hello world".to_string());
+        let frame = UDPFrame::new_from(1, "hello world".to_string());
         let bytes = frame.to_bytes();
+        assert_eq!(bytes.len(), HEADER_LEN + 11);
         let frame = UDPFrame::from_vec(bytes);
         assert!(frame.is_some());
         let frame = frame.unwrap();
+        assert_eq!(frame.id, 1);
+        assert_eq!(frame.data, "hello world".as_bytes());
+    }
+
+    #[test]
+    fn test_borrowed_decode() {
+        let bytes = UDPFrame::new_from(7, "hello world".to_string()).to_bytes();
+        let frame = FrameRef::decode(&bytes).unwrap();
+        assert_eq!(frame.header.id, 7);
+        assert!(!frame.header.is_fragment());
         assert_eq!(frame.data, "hello world".as_bytes());
     }
 
+    #[test]
+    fn test_reject_malformed() {
+        let bytes = UDPFrame::new_from(7, "hello world".to_string()).to_bytes();
+        assert_eq!(
+            FrameRef::decode(&bytes[..10]),
+            Err(FrameError::TooShort(10))
+        );
+        assert!(matches!(
+            FrameRef::decode(&bytes[..bytes.len() - 1]),
+            Err(FrameError::LengthMismatch { .. })
+        ));
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(FrameRef::decode(&bad_magic), Err(FrameError::BadMagic));
+        let mut bad_fragment = bytes;
+        bad_fragment[12] = 3;
+        bad_fragment[14] = 2;
+        assert!(matches!(
+            FrameRef::decode(&bad_fragment),
+            Err(FrameError::BadFragment { .. })
+        ));
+    }
+
     #[test]
     fn test_split_and_merge_frame() {
-        let frame = UDPFrame::new_from("hello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello world".to_string());
+        let data = "hello world".repeat(400);
+        let frame = UDPFrame::new_from(1, data.clone());
         let frames = frame.split_frame();
-        let frame = UDPFrame::merge_frames(frames);
-        assert_eq!(frame.data, "hello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello worldhello world".as_bytes());
+        assert_eq!(frames.len(), 5);
+        let frames = frames
+            .into_iter()
+            .rev()
+            .map(|frame| UDPFrame::from_vec(frame.to_bytes()).unwrap())
+            .collect();
+        let frame = UDPFrame::merge_frames(frames).unwrap();
+        assert_eq!(frame.data, data.as_bytes());
+    }
+
+    #[test]
+    fn test_checksum_detects_corruption() {
+        let mut bytes = UDPFrame::new_from(7, "hello world".to_string()).to_bytes();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x01;
+        assert!(matches!(
+            FrameRef::decode(&bytes),
+            Err(FrameError::ChecksumMismatch { .. })
+        ));
+        assert!(UDPFrame::from_vec(bytes).is_none());
+    }
+
+    #[test]
+    fn test_merge_rejects_mismatched_fragments() {
+        let data = "hello world".repeat(200);
+        let mut frames = UDPFrame::new_from(1, data.clone()).split_frame();
+        frames[1].data[0] ^= 0x01;
+        assert!(matches!(
+            UDPFrame::merge_frames(frames),
+            Err(FrameError::ChecksumMismatch { .. })
+        ));
+
+        let mut frames = UDPFrame::new_from(1, data.clone()).split_frame();
+        frames.pop();
+        frames.extend(UDPFrame::new_from(2, data).split_frame().pop());
+        assert_eq!(
+            UDPFrame::merge_frames(frames),
+            Err(FrameError::IncompleteMessage(1))
+        );
     }
 }
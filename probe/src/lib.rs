@@ -0,0 +1,121 @@
+//! Reads duration, resolution and codecs from the headers of MP4/MOV and
+//! Matroska/WebM files, without decoding any media.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+mod mkv;
+mod mp4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Container {
+    Mp4,
+    /// QuickTime.
+    Mov,
+    Webm,
+    Matroska,
+}
+
+/// What the headers of a media file tell. Fields a file doesn't carry are `None`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct MediaInfo {
+    pub container: Container,
+    pub duration_ms: Option<u64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Codec of the first video track, e.g. `h264`, `hevc` or `vp9`.
+    pub video_codec: Option<String>,
+    /// Codec of the first audio track, e.g. `aac` or `opus`.
+    pub audio_codec: Option<String>,
+    /// Average over the whole file, in bits per second.
+    pub bitrate: Option<u64>,
+}
+
+impl MediaInfo {
+    fn new(container: Container) -> Self {
+        Self {
+            container,
+            duration_ms: None,
+            width: None,
+            height: None,
+            video_codec: None,
+            audio_codec: None,
+            bitrate: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ProbeError {
+    /// Not a container this crate reads.
+    Unsupported,
+    /// The container is recognised but its headers are broken.
+    Malformed(&'static str),
+    Io(io::Error),
+}
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeError::Unsupported => write!(f, "Unsupported container"),
+            ProbeError::Malformed(reason) => write!(f, "Malformed container: {reason}"),
+            ProbeError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ProbeError {}
+
+impl From<io::Error> for ProbeError {
+    fn from(e: io::Error) -> Self {
+        ProbeError::Io(e)
+    }
+}
+
+pub fn probe_file(path: &Path) -> Result<MediaInfo, ProbeError> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+    probe(&mut BufReader::new(file), size)
+}
+
+/// Probes a file of `size` bytes. Only the headers are read, the media data
+/// in between is skipped.
+pub fn probe<R: Read + Seek>(reader: &mut R, size: u64) -> Result<MediaInfo, ProbeError> {
+    let mut magic = [0; 8];
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut magic).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => ProbeError::Unsupported,
+        _ => e.into(),
+    })?;
+    let mut info = if mkv::is_ebml(&magic) {
+        mkv::probe(reader, size)?
+    } else if mp4::is_box(&magic) {
+        mp4::probe(reader, size)?
+    } else {
+        return Err(ProbeError::Unsupported);
+    };
+    info.bitrate = info
+        .duration_ms
+        .filter(|it| *it > 0)
+        .map(|ms| size * 8 * 1000 / ms);
+    Ok(info)
+}
+
+/// Reads `len` bytes at the current position, refusing headers too large to
+/// be plausible so a broken size field can't exhaust memory.
+fn read_body<R: Read>(reader: &mut R, len: u64, max: u64) -> Result<Vec<u8>, ProbeError> {
+    if len > max {
+        return Err(ProbeError::Malformed("header too large"));
+    }
+    let mut body = vec![0; len as usize];
+    reader.read_exact(&mut body)?;
+    Ok(body)
+}
@@ -0,0 +1,323 @@
+//! Matroska and WebM files: EBML elements, each a variable length id and
+//! size. The `Info` and `Tracks` elements of the segment hold everything read
+//! here, muxers write them before the first cluster.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::{read_body, Container, MediaInfo, ProbeError};
+
+const EBML: u64 = 0x1A45_DFA3;
+const DOC_TYPE: u64 = 0x4282;
+const SEGMENT: u64 = 0x1853_8067;
+const INFO: u64 = 0x1549_A966;
+const TIMECODE_SCALE: u64 = 0x2A_D7B1;
+const DURATION: u64 = 0x4489;
+const TRACKS: u64 = 0x1654_AE6B;
+const TRACK_ENTRY: u64 = 0xAE;
+const TRACK_TYPE: u64 = 0x83;
+const CODEC_ID: u64 = 0x86;
+const VIDEO: u64 = 0xE0;
+const PIXEL_WIDTH: u64 = 0xB0;
+const PIXEL_HEIGHT: u64 = 0xBA;
+const CLUSTER: u64 = 0x1F43_B675;
+
+const TRACK_VIDEO: u64 = 1;
+const TRACK_AUDIO: u64 = 2;
+/// Nanoseconds per timestamp tick when the file doesn't say.
+const DEFAULT_TIMECODE_SCALE: u64 = 1_000_000;
+const MAX_HEADER: u64 = 16 << 20;
+
+pub fn is_ebml(magic: &[u8; 8]) -> bool {
+    magic[..4] == EBML.to_be_bytes()[4..]
+}
+
+pub fn probe<R: Read + Seek>(reader: &mut R, size: u64) -> Result<MediaInfo, ProbeError> {
+    reader.seek(SeekFrom::Start(0))?;
+    let (_, header_len) = read_vint(reader, true)?;
+    let (len, len_len) = read_vint(reader, false)?;
+    let header = read_body(
+        reader,
+        len.ok_or(ProbeError::Malformed("EBML header size"))?,
+        4096,
+    )?;
+    let mut info = match elements(&header).find(|(id, _)| *id == DOC_TYPE) {
+        Some((_, b"webm")) => MediaInfo::new(Container::Webm),
+        _ => MediaInfo::new(Container::Matroska),
+    };
+
+    let mut pos = (header_len + len_len) as u64 + header.len() as u64;
+    reader.seek(SeekFrom::Start(pos))?;
+    let (id, id_len) = read_vint(reader, true)?;
+    let (segment_len, segment_len_len) = read_vint(reader, false)?;
+    if id != Some(SEGMENT) {
+        return Err(ProbeError::Malformed("no segment after the EBML header"));
+    }
+    pos += (id_len + segment_len_len) as u64;
+    let end = segment_len.map_or(size, |it| (pos + it).min(size));
+
+    let (mut has_info, mut has_tracks) = (false, false);
+    while pos < end && !(has_info && has_tracks) {
+        reader.seek(SeekFrom::Start(pos))?;
+        let (id, id_len) = read_vint(reader, true)?;
+        let (len, len_len) = read_vint(reader, false)?;
+        // a child of unknown size can't be skipped, only clusters have one in practice
+        let Some(len) = len else {
+            break;
+        };
+        match id {
+            Some(INFO) => {
+                parse_info(&read_body(reader, len, MAX_HEADER)?, &mut info);
+                has_info = true;
+            }
+            Some(TRACKS) => {
+                parse_tracks(&read_body(reader, len, MAX_HEADER)?, &mut info);
+                has_tracks = true;
+            }
+            Some(CLUSTER) if has_info => break,
+            _ => {}
+        }
+        pos += (id_len + len_len) as u64 + len;
+    }
+    if !has_info && !has_tracks {
+        return Err(ProbeError::Malformed("no segment info or tracks"));
+    }
+    Ok(info)
+}
+
+fn parse_info(data: &[u8], info: &mut MediaInfo) {
+    let mut scale = DEFAULT_TIMECODE_SCALE;
+    let mut duration = None;
+    for (id, body) in elements(data) {
+        match id {
+            TIMECODE_SCALE => scale = uint(body),
+            DURATION => duration = float(body),
+            _ => {}
+        }
+    }
+    info.duration_ms = duration
+        .filter(|it| it.is_finite() && *it >= 0.0)
+        .map(|it| (it * scale as f64 / 1_000_000.0) as u64);
+}
+
+fn parse_tracks(data: &[u8], info: &mut MediaInfo) {
+    for (_, entry) in elements(data).filter(|(id, _)| *id == TRACK_ENTRY) {
+        let mut kind = 0;
+        let mut codec = None;
+        let mut size = (None, None);
+        for (id, body) in elements(entry) {
+            match id {
+                TRACK_TYPE => kind = uint(body),
+                CODEC_ID => codec = Some(codec_name(body)),
+                VIDEO => {
+                    for (id, body) in elements(body) {
+                        match id {
+                            PIXEL_WIDTH => size.0 = u32::try_from(uint(body)).ok(),
+                            PIXEL_HEIGHT => size.1 = u32::try_from(uint(body)).ok(),
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        match kind {
+            TRACK_VIDEO if info.video_codec.is_none() => {
+                info.video_codec = codec;
+                (info.width, info.height) = size;
+            }
+            TRACK_AUDIO if info.audio_codec.is_none() => info.audio_codec = codec,
+            _ => {}
+        }
+    }
+}
+
+fn codec_name(id: &[u8]) -> String {
+    let id = String::from_utf8_lossy(id);
+    let id = id.trim_end_matches('\0');
+    match id {
+        "V_MPEG4/ISO/AVC" => "h264",
+        "V_MPEGH/ISO/HEVC" => "hevc",
+        "V_AV1" => "av1",
+        "V_VP8" => "vp8",
+        "V_VP9" => "vp9",
+        "A_OPUS" => "opus",
+        "A_VORBIS" => "vorbis",
+        "A_AC3" => "ac3",
+        "A_EAC3" => "eac3",
+        "A_FLAC" => "flac",
+        "A_MPEG/L3" => "mp3",
+        id if id.starts_with("A_AAC") => "aac",
+        id => return id.to_ascii_lowercase(),
+    }
+    .to_string()
+}
+
+/// A variable length integer: the leading zeros of the first byte tell how
+/// many bytes follow. Ids keep the length marker, sizes drop it and use all
+/// ones for an unknown size, returned as `None`.
+fn read_vint<R: Read>(
+    reader: &mut R,
+    keep_marker: bool,
+) -> Result<(Option<u64>, usize), ProbeError> {
+    let mut first = [0; 1];
+    reader.read_exact(&mut first)?;
+    let len = first[0].leading_zeros() as usize + 1;
+    if len > 8 {
+        return Err(ProbeError::Malformed("invalid variable length integer"));
+    }
+    let mut rest = [0; 7];
+    reader.read_exact(&mut rest[..len - 1])?;
+    Ok((vint(first[0], &rest[..len - 1], keep_marker), len))
+}
+
+fn vint(first: u8, rest: &[u8], keep_marker: bool) -> Option<u64> {
+    let len = rest.len() + 1;
+    let marker = 0x80u8 >> (len - 1);
+    let first = if keep_marker { first } else { first & !marker };
+    let value = rest.iter().fold(u64::from(first), |value, byte| {
+        value << 8 | u64::from(*byte)
+    });
+    let unknown = (1u64 << (7 * len)) - 1;
+    if !keep_marker && value == unknown {
+        return None;
+    }
+    Some(value)
+}
+
+/// Children of an element already read into memory, until the first one
+/// that doesn't fit.
+fn elements(mut data: &[u8]) -> impl Iterator<Item = (u64, &[u8])> {
+    std::iter::from_fn(move || {
+        let (id, id_len) = vint_at(data, true)?;
+        let (len, len_len) = vint_at(&data[id_len..], false)?;
+        let start = id_len + len_len;
+        let end = start.checked_add(usize::try_from(len?).ok()?)?;
+        let body = data.get(start..end)?;
+        data = &data[end..];
+        Some((id?, body))
+    })
+}
+
+fn vint_at(data: &[u8], keep_marker: bool) -> Option<(Option<u64>, usize)> {
+    let first = *data.first()?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 {
+        return None;
+    }
+    Some((vint(first, data.get(1..len)?, keep_marker), len))
+}
+
+fn uint(data: &[u8]) -> u64 {
+    data.iter()
+        .take(8)
+        .fold(0, |value, byte| value << 8 | u64::from(*byte))
+}
+
+fn float(data: &[u8]) -> Option<f64> {
+    match data.len() {
+        4 => Some(f64::from(f32::from_be_bytes(data.try_into().ok()?))),
+        8 => Some(f64::from_be_bytes(data.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::probe;
+
+    fn id_bytes(id: u64) -> Vec<u8> {
+        let bytes = id.to_be_bytes();
+        let skip = bytes.iter().position(|it| *it != 0).unwrap();
+        bytes[skip..].to_vec()
+    }
+
+    /// Sizes are written with eight bytes, which EBML allows for any value.
+    fn el(id: u64, body: &[u8]) -> Vec<u8> {
+        let mut size = (body.len() as u64).to_be_bytes();
+        size[0] = 0x01;
+        [id_bytes(id), size.to_vec(), body.to_vec()].concat()
+    }
+
+    fn unknown_size(id: u64, body: &[u8]) -> Vec<u8> {
+        [
+            id_bytes(id),
+            vec![0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF],
+            body.to_vec(),
+        ]
+        .concat()
+    }
+
+    fn file(doc_type: &str, duration: &[u8]) -> Vec<u8> {
+        let header = el(EBML, &el(DOC_TYPE, doc_type.as_bytes()));
+        let info = el(
+            INFO,
+            &[
+                el(TIMECODE_SCALE, &[0x0F, 0x42, 0x40]),
+                el(DURATION, duration),
+            ]
+            .concat(),
+        );
+        let video = el(
+            TRACK_ENTRY,
+            &[
+                el(TRACK_TYPE, &[1]),
+                el(CODEC_ID, b"V_VP9"),
+                el(
+                    VIDEO,
+                    &[
+                        el(PIXEL_WIDTH, &[0x0F, 0x00]),
+                        el(PIXEL_HEIGHT, &[0x08, 0x70]),
+                    ]
+                    .concat(),
+                ),
+            ]
+            .concat(),
+        );
+        let audio = el(
+            TRACK_ENTRY,
+            &[el(TRACK_TYPE, &[2]), el(CODEC_ID, b"A_OPUS")].concat(),
+        );
+        let tracks = el(TRACKS, &[video, audio].concat());
+        let cluster = unknown_size(CLUSTER, &[0; 500]);
+        [
+            header,
+            unknown_size(SEGMENT, &[info, tracks, cluster].concat()),
+        ]
+        .concat()
+    }
+
+    fn probe_bytes(data: Vec<u8>) -> Result<MediaInfo, ProbeError> {
+        let size = data.len() as u64;
+        probe(&mut Cursor::new(data), size)
+    }
+
+    #[test]
+    fn test_webm() {
+        let info = probe_bytes(file("webm", &12_345.0f64.to_be_bytes())).unwrap();
+        assert_eq!(info.container, Container::Webm);
+        assert_eq!(info.duration_ms, Some(12_345));
+        assert_eq!((info.width, info.height), (Some(3840), Some(2160)));
+        assert_eq!(info.video_codec.as_deref(), Some("vp9"));
+        assert_eq!(info.audio_codec.as_deref(), Some("opus"));
+    }
+
+    #[test]
+    fn test_matroska_with_float_duration() {
+        let info = probe_bytes(file("matroska", &2_000.5f32.to_be_bytes())).unwrap();
+        assert_eq!(info.container, Container::Matroska);
+        assert_eq!(info.duration_ms, Some(2_000));
+    }
+
+    #[test]
+    fn test_vints() {
+        assert_eq!(vint(0x81, &[], false), Some(1));
+        assert_eq!(vint(0x40, &[0x02], false), Some(2));
+        assert_eq!(vint(0xFF, &[], false), None);
+        assert_eq!(vint(0x1A, &[0x45, 0xDF, 0xA3], true), Some(EBML));
+        let truncated = file("webm", &1.0f64.to_be_bytes())[..40].to_vec();
+        assert!(probe_bytes(truncated).is_err());
+    }
+}
@@ -0,0 +1,317 @@
+//! ISO base media (MP4) and QuickTime files: a tree of boxes, each a 32-bit
+//! size and a four character type. The `moov` box holds everything read here.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::{read_body, Container, MediaInfo, ProbeError};
+
+/// Large movies with many samples have a `moov` of a few megabytes.
+const MAX_MOOV: u64 = 64 << 20;
+
+/// Boxes a file may start with.
+const FIRST_BOXES: [&[u8; 4]; 7] = [
+    b"ftyp", b"moov", b"mdat", b"free", b"skip", b"wide", b"pnot",
+];
+
+pub fn is_box(magic: &[u8; 8]) -> bool {
+    FIRST_BOXES.iter().any(|it| magic[4..] == it[..])
+}
+
+pub fn probe<R: Read + Seek>(reader: &mut R, size: u64) -> Result<MediaInfo, ProbeError> {
+    let mut info = MediaInfo::new(Container::Mp4);
+    let mut found_moov = false;
+    let mut pos = 0;
+    while pos + 8 <= size && !found_moov {
+        reader.seek(SeekFrom::Start(pos))?;
+        let mut header = [0; 8];
+        reader.read_exact(&mut header)?;
+        let kind = &header[4..8];
+        let (header_len, box_len) =
+            match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+                0 => (8, size - pos),
+                1 => {
+                    let mut large = [0; 8];
+                    reader.read_exact(&mut large)?;
+                    (16, u64::from_be_bytes(large))
+                }
+                len => (8, u64::from(len)),
+            };
+        if box_len < header_len {
+            return Err(ProbeError::Malformed("box shorter than its header"));
+        }
+        if box_len > size - pos {
+            return Err(ProbeError::Malformed("box longer than the file"));
+        }
+        match kind {
+            b"ftyp" => {
+                let brand = read_body(reader, 4.min(box_len - header_len), 4)?;
+                if brand == b"qt  " {
+                    info.container = Container::Mov;
+                }
+            }
+            b"moov" => {
+                let moov = read_body(reader, box_len - header_len, MAX_MOOV)?;
+                parse_moov(&moov, &mut info);
+                found_moov = true;
+            }
+            _ => {}
+        }
+        pos += box_len;
+    }
+    if !found_moov {
+        return Err(ProbeError::Malformed("no moov box"));
+    }
+    Ok(info)
+}
+
+/// Children of a box already read into memory. Iteration stops at the first
+/// box that doesn't fit, so a truncated header yields what came before it.
+struct Boxes<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Boxes<'a> {
+    type Item = (&'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let len = u32_at(self.data, 0)?;
+        let kind = self.data.get(4..8)?;
+        let (header_len, len) = match len {
+            0 => (8, self.data.len()),
+            1 => (16, usize::try_from(u64_at(self.data, 8)?).ok()?),
+            len => (8, len as usize),
+        };
+        let body = self.data.get(header_len..len)?;
+        self.data = &self.data[len..];
+        Some((kind, body))
+    }
+}
+
+fn children(data: &[u8]) -> Boxes<'_> {
+    Boxes { data }
+}
+
+fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    children(data)
+        .find(|(it, _)| it == kind)
+        .map(|(_, body)| body)
+}
+
+fn parse_moov(moov: &[u8], info: &mut MediaInfo) {
+    for (kind, body) in children(moov) {
+        match kind {
+            b"mvhd" => info.duration_ms = movie_duration(body),
+            b"trak" => parse_trak(body, info),
+            _ => {}
+        }
+    }
+}
+
+/// Duration in the movie header, in its own timescale.
+fn movie_duration(mvhd: &[u8]) -> Option<u64> {
+    let (timescale, duration) = match mvhd.first()? {
+        1 => (u32_at(mvhd, 20)?, u64_at(mvhd, 24)?),
+        _ => (u32_at(mvhd, 12)?, u64::from(u32_at(mvhd, 16)?)),
+    };
+    // all ones means unknown
+    if timescale == 0 || duration == u64::MAX || duration == u64::from(u32::MAX) {
+        return None;
+    }
+    u64::try_from(u128::from(duration) * 1000 / u128::from(timescale)).ok()
+}
+
+fn parse_trak(trak: &[u8], info: &mut MediaInfo) {
+    let Some(mdia) = child(trak, b"mdia") else {
+        return;
+    };
+    let handler = child(mdia, b"hdlr").and_then(|it| it.get(8..12));
+    let entry = child(mdia, b"minf")
+        .and_then(|it| child(it, b"stbl"))
+        .and_then(|it| child(it, b"stsd"))
+        .and_then(|it| it.get(8..));
+    let Some(entry) = entry else {
+        return;
+    };
+    let Some(format) = entry.get(4..8) else {
+        return;
+    };
+    match handler {
+        Some(b"vide") if info.video_codec.is_none() => {
+            info.video_codec = Some(codec(format));
+            // the track header holds the display size as 16.16 fixed point,
+            // the sample entry the coded size
+            let display = child(trak, b"tkhd")
+                .filter(|it| it.len() >= 8)
+                .and_then(|it| {
+                    Some((
+                        u32_at(it, it.len() - 8)? >> 16,
+                        u32_at(it, it.len() - 4)? >> 16,
+                    ))
+                })
+                .filter(|(width, height)| *width > 0 && *height > 0);
+            let coded = u16_at(entry, 32)
+                .zip(u16_at(entry, 34))
+                .map(|(width, height)| (u32::from(width), u32::from(height)));
+            if let Some((width, height)) = display.or(coded) {
+                info.width = Some(width);
+                info.height = Some(height);
+            }
+        }
+        Some(b"soun") if info.audio_codec.is_none() => info.audio_codec = Some(codec(format)),
+        _ => {}
+    }
+}
+
+fn codec(format: &[u8]) -> String {
+    match format {
+        b"avc1" | b"avc3" => "h264".to_string(),
+        b"hvc1" | b"hev1" => "hevc".to_string(),
+        b"av01" => "av1".to_string(),
+        b"vp08" => "vp8".to_string(),
+        b"vp09" => "vp9".to_string(),
+        b"mp4v" => "mpeg4".to_string(),
+        b"apch" | b"apcn" | b"apcs" | b"apco" | b"ap4h" => "prores".to_string(),
+        b"mp4a" => "aac".to_string(),
+        b"ac-3" => "ac3".to_string(),
+        b"ec-3" => "eac3".to_string(),
+        b"Opus" => "opus".to_string(),
+        b"fLaC" => "flac".to_string(),
+        b".mp3" => "mp3".to_string(),
+        other => String::from_utf8_lossy(other).trim().to_string(),
+    }
+}
+
+fn u16_at(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u32_at(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u64_at(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::probe;
+
+    fn bx(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(body);
+        data
+    }
+
+    fn full_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        bx(kind, &[&[0, 0, 0, 0], body].concat())
+    }
+
+    fn track(handler: &[u8; 4], format: &[u8; 4], size: Option<(u16, u16)>) -> Vec<u8> {
+        let mut tkhd = vec![0; 80];
+        if let Some((width, height)) = size {
+            tkhd[72..76].copy_from_slice(&(u32::from(width) << 16).to_be_bytes());
+            tkhd[76..80].copy_from_slice(&(u32::from(height) << 16).to_be_bytes());
+        }
+        let hdlr = full_box(b"hdlr", &[&[0; 4], &handler[..], &[0; 12]].concat());
+        let mut entry = vec![0; 70];
+        entry[24..26].copy_from_slice(&1280u16.to_be_bytes());
+        entry[26..28].copy_from_slice(&720u16.to_be_bytes());
+        let stsd = full_box(
+            b"stsd",
+            &[&1u32.to_be_bytes(), &bx(format, &entry)[..]].concat(),
+        );
+        let minf = bx(b"minf", &bx(b"stbl", &stsd));
+        let mdia = bx(b"mdia", &[hdlr, minf].concat());
+        bx(b"trak", &[full_box(b"tkhd", &tkhd), mdia].concat())
+    }
+
+    fn movie(brand: &[u8; 4], moov_first: bool) -> Vec<u8> {
+        let mut mvhd = vec![0; 96];
+        mvhd[8..12].copy_from_slice(&600u32.to_be_bytes());
+        mvhd[12..16].copy_from_slice(&(600u32 * 90 + 300).to_be_bytes());
+        let moov = bx(
+            b"moov",
+            &[
+                full_box(b"mvhd", &mvhd),
+                track(b"vide", b"avc1", Some((1920, 1080))),
+                track(b"soun", b"mp4a", None),
+            ]
+            .concat(),
+        );
+        let ftyp = bx(b"ftyp", &[&brand[..], &[0; 4]].concat());
+        let mdat = bx(b"mdat", &[7; 1000]);
+        if moov_first {
+            [ftyp, moov, mdat].concat()
+        } else {
+            [ftyp, mdat, moov].concat()
+        }
+    }
+
+    fn probe_bytes(data: Vec<u8>) -> Result<MediaInfo, ProbeError> {
+        let size = data.len() as u64;
+        probe(&mut Cursor::new(data), size)
+    }
+
+    #[test]
+    fn test_mp4() {
+        let info = probe_bytes(movie(b"isom", false)).unwrap();
+        assert_eq!(info.container, Container::Mp4);
+        assert_eq!(info.duration_ms, Some(90_500));
+        assert_eq!((info.width, info.height), (Some(1920), Some(1080)));
+        assert_eq!(info.video_codec.as_deref(), Some("h264"));
+        assert_eq!(info.audio_codec.as_deref(), Some("aac"));
+        assert!(info.bitrate.is_some());
+    }
+
+    #[test]
+    fn test_mov_falls_back_to_coded_size() {
+        let mut data = movie(b"qt  ", true);
+        // blank the display size of the video track header
+        let tkhd = data.windows(4).position(|it| it == b"tkhd").unwrap();
+        data[tkhd + 80..tkhd + 88].fill(0);
+        let info = probe_bytes(data).unwrap();
+        assert_eq!(info.container, Container::Mov);
+        assert_eq!((info.width, info.height), (Some(1280), Some(720)));
+    }
+
+    #[test]
+    fn test_broken_files() {
+        let data = bx(b"ftyp", b"isom");
+        assert!(matches!(probe_bytes(data), Err(ProbeError::Malformed(_))));
+        // an unknown duration leaves the rest readable
+        let mut data = movie(b"isom", true);
+        data[48..52].copy_from_slice(&u32::MAX.to_be_bytes());
+        let info = probe_bytes(data).unwrap();
+        assert_eq!(info.duration_ms, None);
+        assert_eq!(info.bitrate, None);
+        assert_eq!(info.video_codec.as_deref(), Some("h264"));
+        let data = [&4u32.to_be_bytes()[..], b"ftyp"].concat();
+        assert!(matches!(probe_bytes(data), Err(ProbeError::Malformed(_))));
+        assert!(matches!(
+            probe_bytes(b"not a video at all".to_vec()),
+            Err(ProbeError::Unsupported)
+        ));
+        // a 64-bit size past the end of the file
+        let data = [
+            bx(b"ftyp", b"isom\0\0\0\0"),
+            [&1u32.to_be_bytes()[..], b"free", &u64::MAX.to_be_bytes()].concat(),
+        ]
+        .concat();
+        assert!(matches!(probe_bytes(data), Err(ProbeError::Malformed(_))));
+        // a version 1 movie header whose duration overflows in milliseconds
+        let mvhd = |timescale: u32, duration: u64| {
+            let mut body = vec![0; 108];
+            body[0] = 1;
+            body[20..24].copy_from_slice(&timescale.to_be_bytes());
+            body[24..32].copy_from_slice(&duration.to_be_bytes());
+            body
+        };
+        assert_eq!(movie_duration(&mvhd(1, u64::MAX - 1)), None);
+        assert_eq!(movie_duration(&mvhd(1000, 1 << 60)), Some(1 << 60));
+    }
+}
@@ -1,20 +1,35 @@
 #![allow(dead_code)]
 
-use std::time;
+use std::{fmt::Debug, sync::Arc, time};
 
 use image_compressor::{compressor::Compressor, Factor};
 use screenshots::Screen;
 use tokio::{sync::mpsc::Sender, task::spawn_blocking};
 use tracing::{error, trace};
 
+/// Told how long each step of a capture took, e.g. to export metrics.
+pub trait CaptureObserver: Debug + Send + Sync {
+    fn captured(&self, elapsed: time::Duration);
+    fn compressed(&self, elapsed: time::Duration);
+}
+
 #[derive(Debug)]
 pub struct ScreenCapture {
     sender: Sender<Vec<u8>>,
+    observer: Option<Arc<dyn CaptureObserver>>,
 }
 
 impl ScreenCapture {
     pub fn new(sender: Sender<Vec<u8>>) -> Self {
-        Self { sender }
+        Self {
+            sender,
+            observer: None,
+        }
+    }
+
+    pub fn with_observer(mut self, observer: Arc<dyn CaptureObserver>) -> Self {
+        self.observer = Some(observer);
+        self
     }
 
     pub fn get_sender(&self) -> Sender<Vec<u8>> {
@@ -38,7 +53,16 @@ impl ScreenCapture {
     async fn do_capture_channel(&self, screen: Screen) {
         let capture_start_time = time::Instant::now();
         if let Ok(image) = spawn_blocking(move || screen.capture()).await {
-            let image = image.expect("Failed to capture image");
+            let image = match image {
+                Ok(image) => image,
+                Err(e) => {
+                    error!("Failed to capture image with error {:?}", e);
+                    return;
+                }
+            };
+            if let Some(observer) = &self.observer {
+                observer.captured(capture_start_time.elapsed());
+            }
             trace!(
                 "Capture image with size: {}x{} in {}ms",
                 image.width(),
@@ -47,7 +71,12 @@ impl ScreenCapture {
             );
             let compress_start_time = time::Instant::now();
             let buffer = image.buffer();
-            let image = self.do_compress(buffer.clone()).await;
+            let Some(image) = self.do_compress(buffer.clone()).await else {
+                return;
+            };
+            if let Some(observer) = &self.observer {
+                observer.compressed(compress_start_time.elapsed());
+            }
             trace!(
                 "Compress image with size: {} to {} in {}ms",
                 convert_bytes_size_to_readable(buffer.len()),
@@ -62,16 +91,24 @@ impl ScreenCapture {
         }
     }
 
-    async fn do_compress(&self, image: Vec<u8>) -> Vec<u8> {
-        spawn_blocking(|| {
+    async fn do_compress(&self, image: Vec<u8>) -> Option<Vec<u8>> {
+        let compressed = spawn_blocking(|| {
             let mut compressor = Compressor::new(image);
             compressor.set_factor(Factor::new(80., 0.8));
-            compressor
-                .compress_image()
-                .expect("Failed to compress image")
+            compressor.compress_image().map_err(|e| e.to_string())
         })
-        .await
-        .expect("Failed to spawn blocking")
+        .await;
+        match compressed {
+            Ok(Ok(image)) => Some(image),
+            Ok(Err(e)) => {
+                error!("Failed to compress image with error {}", e);
+                None
+            }
+            Err(e) => {
+                error!("Compression task failed with error {:?}", e);
+                None
+            }
+        }
     }
 }
 
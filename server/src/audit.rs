@@ -0,0 +1,384 @@
+use std::{
+    collections::VecDeque,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    get,
+    http::StatusCode,
+    web, HttpMessage, HttpResponse,
+};
+use config::model::DEFAULT_AUDIT_MAX_BYTES;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    sync::Mutex,
+};
+use tracing::{error, info};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    auth::Principal,
+    context::AppContext,
+    error::ApiError,
+    permission::{is_mutating, requirement, target_node},
+};
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Succeeded,
+    /// Rejected for missing credentials or an insufficient role.
+    Denied,
+    Failed,
+}
+
+impl From<StatusCode> for Outcome {
+    fn from(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Outcome::Denied,
+            status if status.is_success() || status.is_redirection() => Outcome::Succeeded,
+            _ => Outcome::Failed,
+        }
+    }
+}
+
+/// One recorded control action.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct AuditEntry {
+    /// Milliseconds since the unix epoch.
+    pub timestamp: u128,
+    #[schema(value_type = Option<String>)]
+    pub source_ip: Option<IpAddr>,
+    /// Name of the API key, `anonymous`, or `None` when no credential was checked.
+    pub principal: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub action: String,
+    /// The node the action was aimed at, `None` for bulk requests.
+    pub node_id: Option<i64>,
+    pub status: u16,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditFilter {
+    /// Entries at or after this timestamp.
+    pub from: Option<u128>,
+    /// Entries before this timestamp.
+    pub to: Option<u128>,
+    pub principal: Option<String>,
+    #[param(value_type = Option<String>)]
+    pub source_ip: Option<IpAddr>,
+    pub node_id: Option<i64>,
+    pub action: Option<String>,
+    pub outcome: Option<Outcome>,
+    /// Newest entries returned, defaults to 100.
+    pub limit: Option<usize>,
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.from.is_none_or(|from| entry.timestamp >= from)
+            && self.to.is_none_or(|to| entry.timestamp < to)
+            && self
+                .principal
+                .as_ref()
+                .is_none_or(|it| entry.principal.as_ref() == Some(it))
+            && self.source_ip.is_none_or(|it| entry.source_ip == Some(it))
+            && self.node_id.is_none_or(|it| entry.node_id == Some(it))
+            && self.action.as_ref().is_none_or(|it| &entry.action == it)
+            && self.outcome.is_none_or(|it| entry.outcome == it)
+    }
+}
+
+/// Append-only store of control actions, one JSON document per line. Once
+/// the file would grow past its size cap it is moved to `<file>.1`, so the
+/// log never takes more than twice the cap.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: Arc<Mutex<PathBuf>>,
+    max_bytes: Arc<AtomicU64>,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path: Arc::new(Mutex::new(path)),
+            max_bytes: Arc::new(AtomicU64::new(DEFAULT_AUDIT_MAX_BYTES)),
+        }
+    }
+
+    /// Points the log at the file named in the config.
+    pub async fn set_path(&self, path: PathBuf) {
+        *self.path.lock().await = path;
+    }
+
+    /// Sets the size the log may reach before it is rotated.
+    pub fn set_max_bytes(&self, max_bytes: u64) {
+        self.max_bytes.store(max_bytes, Ordering::Relaxed);
+    }
+
+    pub async fn append(&self, entry: &AuditEntry) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let path = self.path.lock().await;
+        let max_bytes = self.max_bytes.load(Ordering::Relaxed);
+        match fs::metadata(&*path).await {
+            Ok(metadata)
+                if metadata.len() > 0 && metadata.len() + line.len() as u64 > max_bytes =>
+            {
+                fs::rename(&*path, rotated(&path)).await?;
+                info!(
+                    "Moved the audit log {:?} aside, it reached {} bytes",
+                    path,
+                    metadata.len()
+                );
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&*path)
+            .await?;
+        file.write_all(&line).await?;
+        Ok(())
+    }
+
+    /// Matching entries, newest first, from the log and then the rotated
+    /// log. Both are read line by line and only `limit` entries are held.
+    pub async fn query(&self, filter: &AuditFilter) -> anyhow::Result<Vec<AuditEntry>> {
+        let path = self.path.lock().await.clone();
+        let limit = filter.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+        let mut entries: Vec<AuditEntry> = scan(&path, filter, limit)
+            .await?
+            .into_iter()
+            .rev()
+            .collect();
+        if entries.len() < limit {
+            let older = scan(&rotated(&path), filter, limit - entries.len()).await?;
+            entries.extend(older.into_iter().rev());
+        }
+        Ok(entries)
+    }
+}
+
+/// Where the log is moved once it is full.
+fn rotated(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".1");
+    PathBuf::from(name)
+}
+
+/// The last `limit` entries of the file at `path` that match `filter`, oldest
+/// first. Lines that aren't entries are skipped.
+async fn scan(
+    path: &Path,
+    filter: &AuditFilter,
+    limit: usize,
+) -> anyhow::Result<VecDeque<AuditEntry>> {
+    let mut newest = VecDeque::new();
+    let file = match File::open(path).await {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(newest),
+        Err(e) => return Err(e.into()),
+    };
+    if limit == 0 {
+        return Ok(newest);
+    }
+    let mut lines = BufReader::new(file).split(b'\n');
+    while let Some(line) = lines.next_segment().await? {
+        let Ok(entry) = serde_json::from_slice::<AuditEntry>(&line) else {
+            continue;
+        };
+        if !filter.matches(&entry) {
+            continue;
+        }
+        if newest.len() == limit {
+            newest.pop_front();
+        }
+        newest.push_back(entry);
+    }
+    Ok(newest)
+}
+
+/// What is known about a mutating request before it is handled.
+pub struct PendingEntry {
+    ctx: web::Data<AppContext>,
+    entry: AuditEntry,
+}
+
+/// Starts an entry for `req` if it changes state.
+pub fn begin(req: &ServiceRequest) -> Option<PendingEntry> {
+    if !is_mutating(req.method(), req.path()) {
+        return None;
+    }
+    let ctx = req.app_data::<web::Data<AppContext>>()?.clone();
+    let entry = AuditEntry {
+        timestamp: ctx.clock().now_millis(),
+        source_ip: req.peer_addr().map(|addr| addr.ip()),
+        principal: None,
+        method: req.method().to_string(),
+        path: req.path().to_string(),
+        action: requirement(req.method(), req.path()).action.to_string(),
+        node_id: target_node(req.path()),
+        status: 0,
+        outcome: Outcome::Failed,
+    };
+    Some(PendingEntry { ctx, entry })
+}
+
+/// Completes the entry with who made the request and how it ended, and stores it.
+pub async fn finish<B>(pending: PendingEntry, res: &ServiceResponse<B>) {
+    let PendingEntry { ctx, mut entry } = pending;
+    entry.principal = res
+        .request()
+        .extensions()
+        .get::<Principal>()
+        .map(|it| it.name.clone());
+    entry.status = res.status().as_u16();
+    entry.outcome = res.status().into();
+    if entry.node_id.is_none() && !entry.path.starts_with("/bulk/") {
+        entry.node_id = Some(ctx.config().get_config().await.id());
+    }
+    if let Err(e) = ctx.audit().append(&entry).await {
+        error!("Failed to write audit entry {:?} with error {:?}", entry, e);
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/audit",
+    tag = "audit",
+    params(AuditFilter),
+    responses(
+        (status = 200, description = "Matching entries, newest first", body = [AuditEntry]),
+        (status = 500, description = "The audit log could not be read", body = ErrorBody),
+    )
+)]
+#[get("/audit")]
+pub async fn get_audit(
+    ctx: web::Data<AppContext>,
+    filter: web::Query<AuditFilter>,
+) -> Result<HttpResponse, ApiError> {
+    let entries = ctx
+        .audit()
+        .query(&filter)
+        .await
+        .map_err(ApiError::Internal)?;
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(timestamp: u128, principal: &str, outcome: Outcome) -> AuditEntry {
+        AuditEntry {
+            timestamp,
+            source_ip: Some("10.0.0.9".parse().unwrap()),
+            principal: Some(principal.to_string()),
+            method: "DELETE".to_string(),
+            path: "/video_list/a.mp4".to_string(),
+            action: "delete media".to_string(),
+            node_id: Some(1),
+            status: 200,
+            outcome,
+        }
+    }
+
+    #[test]
+    fn test_outcome_from_status() {
+        assert_eq!(Outcome::from(StatusCode::NO_CONTENT), Outcome::Succeeded);
+        assert_eq!(Outcome::from(StatusCode::FORBIDDEN), Outcome::Denied);
+        assert_eq!(Outcome::from(StatusCode::NOT_FOUND), Outcome::Failed);
+    }
+
+    #[tokio::test]
+    async fn test_append_and_query() {
+        let path = std::env::temp_dir().join(format!("audit-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let log = AuditLog::new(path.clone());
+        log.append(&entry(1, "ops", Outcome::Succeeded))
+            .await
+            .unwrap();
+        log.append(&entry(2, "reception", Outcome::Denied))
+            .await
+            .unwrap();
+        log.append(&entry(3, "ops", Outcome::Failed)).await.unwrap();
+
+        let all = log.query(&AuditFilter::default()).await.unwrap();
+        assert_eq!(
+            all.iter().map(|it| it.timestamp).collect::<Vec<_>>(),
+            vec![3, 2, 1]
+        );
+
+        let filter = AuditFilter {
+            principal: Some("ops".to_string()),
+            from: Some(2),
+            ..Default::default()
+        };
+        let ops = log.query(&filter).await.unwrap();
+        assert_eq!(ops, vec![entry(3, "ops", Outcome::Failed)]);
+
+        let filter = AuditFilter {
+            outcome: Some(Outcome::Denied),
+            limit: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(log.query(&filter).await.unwrap().len(), 1);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn test_full_log_is_rotated() {
+        let path = std::env::temp_dir().join(format!("audit-rotate-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(rotated(&path));
+        let log = AuditLog::new(path.clone());
+        let size = serde_json::to_vec(&entry(1, "ops", Outcome::Succeeded))
+            .unwrap()
+            .len() as u64
+            + 1;
+        log.set_max_bytes(2 * size);
+        for timestamp in 1..=5 {
+            log.append(&entry(timestamp, "ops", Outcome::Succeeded))
+                .await
+                .unwrap();
+        }
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+        assert_eq!(std::fs::metadata(rotated(&path)).unwrap().len(), 2 * size);
+
+        let timestamps =
+            |entries: Vec<AuditEntry>| entries.iter().map(|it| it.timestamp).collect::<Vec<_>>();
+        let all = log.query(&AuditFilter::default()).await.unwrap();
+        assert_eq!(timestamps(all), vec![5, 4, 3]);
+        let filter = AuditFilter {
+            limit: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(timestamps(log.query(&filter).await.unwrap()), vec![5, 4]);
+        let filter = AuditFilter {
+            to: Some(5),
+            limit: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(timestamps(log.query(&filter).await.unwrap()), vec![4]);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(rotated(&path));
+    }
+}
@@ -0,0 +1,514 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use actix_web::{
+    cookie::{time::Duration as CookieDuration, Cookie, SameSite},
+    dev::ServiceRequest,
+    http::{
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        StatusCode,
+    },
+    post, web, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+};
+use anyhow::Context;
+use config::model::{hash_secret, matches_hash, ApiKey, AuthConfig, Role};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use utils::clock::Clock;
+use utoipa::ToSchema;
+
+use crate::{
+    context::AppContext,
+    error::ErrorBody,
+    permission::{peer_may, requirement, Access},
+    tls::write_private,
+};
+
+pub const SESSION_COOKIE: &str = "broadcast_session";
+/// Name of the principal calls carrying an accepted peer key act as.
+const PEER: &str = "peer";
+
+/// The caller a request was authenticated as, stored in the request extensions.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Principal {
+    pub name: String,
+    pub role: Role,
+}
+
+impl Principal {
+    fn anonymous(role: Role) -> Self {
+        Self {
+            name: "anonymous".to_string(),
+            role,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Session {
+    principal: Principal,
+    expires_at: u128,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct IssuedSession {
+    pub token: String,
+    /// Milliseconds since the unix epoch.
+    pub expires_at: u128,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    MissingCredentials,
+    InvalidCredentials,
+    Forbidden {
+        principal: Principal,
+        required: Role,
+        action: &'static str,
+    },
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingCredentials => write!(f, "Authentication required"),
+            AuthError::InvalidCredentials => write!(f, "Invalid or expired token"),
+            AuthError::Forbidden {
+                principal,
+                required,
+                action,
+            } => write!(
+                f,
+                "You need the {required} role to {action}, {} has the {} role",
+                principal.name, principal.role
+            ),
+        }
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::Forbidden { .. } => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let code = match self {
+            AuthError::MissingCredentials => "unauthenticated",
+            AuthError::InvalidCredentials => "invalid_credentials",
+            AuthError::Forbidden { .. } => "forbidden",
+        };
+        let mut response = HttpResponse::build(self.status_code());
+        if self.status_code() == StatusCode::UNAUTHORIZED {
+            response.insert_header((WWW_AUTHENTICATE, "Bearer"));
+        }
+        response.json(ErrorBody::new(code, self.to_string()))
+    }
+}
+
+/// Checks API keys and the session tokens handed out in exchange for them.
+///
+/// Sessions live in memory only, they are lost on restart and are not known
+/// to other nodes.
+#[derive(Debug, Clone)]
+pub struct AuthService {
+    settings: Arc<RwLock<AuthConfig>>,
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    /// The key this node sends to peers.
+    peer_key: Arc<RwLock<Option<String>>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl AuthService {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            settings: Arc::new(RwLock::new(AuthConfig::default())),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            peer_key: Arc::new(RwLock::new(None)),
+            clock,
+        }
+    }
+
+    pub fn configure(&self, settings: AuthConfig) {
+        *self.settings.write().unwrap() = settings;
+    }
+
+    /// The `Authorization` header sent with calls to a peer over HTTPS, never
+    /// the credentials of the caller that caused them.
+    pub fn peer_authorization(&self) -> Option<String> {
+        let peer_key = self.peer_key.read().unwrap();
+        peer_key.as_ref().map(|key| format!("Bearer {key}"))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.settings.read().unwrap().enabled
+    }
+
+    pub fn anonymous_role(&self) -> Option<Role> {
+        self.settings.read().unwrap().anonymous_role
+    }
+
+    fn find_key(&self, secret: &str) -> Option<Principal> {
+        self.settings
+            .read()
+            .unwrap()
+            .api_keys
+            .iter()
+            .find(|key| key.matches(secret))
+            .map(|key| Principal {
+                name: key.name.clone(),
+                role: key.role,
+            })
+    }
+
+    fn find_peer_key(&self, secret: &str) -> Option<Principal> {
+        self.settings
+            .read()
+            .unwrap()
+            .peer_key_hashes
+            .iter()
+            .any(|hash| matches_hash(hash, secret))
+            .then(|| Principal {
+                name: PEER.to_string(),
+                role: Role::Peer,
+            })
+    }
+
+    /// Resolves a bearer token, which is a session token, an API key or a
+    /// peer key.
+    pub fn verify(&self, token: &str) -> Option<Principal> {
+        let now = self.clock.now_millis();
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(token) {
+            Some(session) if session.expires_at > now => return Some(session.principal.clone()),
+            Some(_) => {
+                sessions.remove(token);
+            }
+            None => {}
+        }
+        drop(sessions);
+        self.find_key(token).or_else(|| self.find_peer_key(token))
+    }
+
+    pub fn login(&self, api_key: &str) -> Option<IssuedSession> {
+        let principal = self.find_key(api_key)?;
+        let now = self.clock.now_millis();
+        let ttl = self.settings.read().unwrap().session_ttl_secs as u128 * 1000;
+        let token = generate_secret();
+        let expires_at = now + ttl;
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.expires_at > now);
+        sessions.insert(
+            token.clone(),
+            Session {
+                principal,
+                expires_at,
+            },
+        );
+        Some(IssuedSession { token, expires_at })
+    }
+
+    pub fn logout(&self, token: &str) {
+        self.sessions.lock().unwrap().remove(token);
+    }
+}
+
+/// 32 random bytes, hex encoded.
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<String> {
+    let header = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    header
+        .strip_prefix("Bearer ")
+        .map(|token| token.trim().to_string())
+}
+
+fn credential(req: &HttpRequest) -> Option<String> {
+    bearer_token(req).or_else(|| req.cookie(SESSION_COOKIE).map(|it| it.value().to_string()))
+}
+
+fn identify(auth: &AuthService, token: Option<String>) -> Result<Principal, AuthError> {
+    match token {
+        Some(token) => auth.verify(&token).ok_or(AuthError::InvalidCredentials),
+        None => auth
+            .anonymous_role()
+            .map(Principal::anonymous)
+            .ok_or(AuthError::MissingCredentials),
+    }
+}
+
+/// Checks the caller's role against the permission matrix. A peer may call
+/// only the routes nodes call on each other.
+pub fn authenticate(req: &ServiceRequest) -> Result<(), AuthError> {
+    let requirement = requirement(req.method(), req.path());
+    let Access::Role(required) = requirement.access else {
+        return Ok(());
+    };
+    let Some(ctx) = req.app_data::<web::Data<AppContext>>() else {
+        return Ok(());
+    };
+    let auth = ctx.auth();
+    if !auth.is_enabled() {
+        return Ok(());
+    }
+    let principal = identify(auth, credential(req.request()))?;
+    req.extensions_mut().insert(principal.clone());
+    let allowed = match principal.role {
+        Role::Peer => peer_may(req.method(), req.path()),
+        role => role >= required,
+    };
+    if !allowed {
+        return Err(AuthError::Forbidden {
+            principal,
+            required,
+            action: requirement.action,
+        });
+    }
+    Ok(())
+}
+
+/// Reads the key this node sends to peers, generating it on first start.
+fn load_or_generate_peer_key(path: &Path) -> anyhow::Result<String> {
+    if !path.exists() {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_private(path, generate_secret().as_bytes())
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        warn!(
+            "Generated the peer key at {}, copy it to every node so they accept each other's calls",
+            path.display()
+        );
+    }
+    let key =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let key = key.trim();
+    if key.is_empty() {
+        anyhow::bail!("The peer key at {} is empty", path.display());
+    }
+    Ok(key.to_string())
+}
+
+/// Creates a first API key when authentication is on but none is configured,
+/// so a fresh node is never left open. The secret is written once to the
+/// `auth.bootstrap_key` file. Also loads the peer key and accepts its hash.
+pub async fn bootstrap(ctx: &AppContext) -> anyhow::Result<()> {
+    let settings = ctx.config().get_config().await.auth().clone();
+    let peer_key = load_or_generate_peer_key(&settings.peer_key)?;
+    let peer_hash = hash_secret(&peer_key);
+    let admin = if settings.enabled && settings.api_keys.is_empty() {
+        let secret = generate_secret();
+        let path = &settings.bootstrap_key;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_private(path, secret.as_bytes()).with_context(|| {
+            format!(
+                "Failed to write the generated API key to {}, remove the file if it is left over",
+                path.display()
+            )
+        })?;
+        Some((secret, path.clone()))
+    } else {
+        None
+    };
+    let settings = ctx
+        .config()
+        .update(|config| {
+            let mut settings = config.auth().clone();
+            if let Some((secret, _)) = &admin {
+                if settings.api_keys.is_empty() {
+                    settings
+                        .api_keys
+                        .push(ApiKey::new("admin", Role::Administrator, secret));
+                }
+            }
+            if !settings
+                .peer_key_hashes
+                .iter()
+                .any(|hash| matches_hash(hash, &peer_key))
+            {
+                settings.peer_key_hashes.push(peer_hash);
+            }
+            config.set_auth(settings.clone());
+            settings
+        })
+        .await?;
+    if let Some((_, path)) = admin {
+        warn!(
+            "No API key configured, generated the key \"admin\" and wrote it to {}. Only its hash is stored, move the file somewhere safe",
+            path.display()
+        );
+    }
+    if !settings.enabled {
+        warn!("Authentication is disabled, every endpoint is public");
+    }
+    ctx.auth().configure(settings);
+    *ctx.auth().peer_key.write().unwrap() = Some(peer_key);
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct LoginRequest {
+    pub api_key: String,
+}
+
+/// Exchanges an API key for a session token, returned in the body and as a cookie.
+#[utoipa::path(
+    post,
+    path = "/auth/login",
+    tag = "auth",
+    security(()),
+    request_body = LoginRequest,
+    responses(
+        (status = 200, description = "The session token, also set as the session cookie", body = IssuedSession),
+        (status = 401, description = "The API key is unknown", body = ErrorBody),
+    )
+)]
+#[post("/auth/login")]
+pub async fn login(
+    ctx: web::Data<AppContext>,
+    body: web::Json<LoginRequest>,
+) -> Result<HttpResponse, AuthError> {
+    let session = ctx
+        .auth()
+        .login(&body.api_key)
+        .ok_or(AuthError::InvalidCredentials)?;
+    let ttl = ctx.config().get_config().await.auth().session_ttl_secs;
+    info!("Issued a session token valid for {}s", ttl);
+    let cookie = Cookie::build(SESSION_COOKIE, session.token.clone())
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .max_age(CookieDuration::seconds(ttl as i64))
+        .finish();
+    Ok(HttpResponse::Ok().cookie(cookie).json(session))
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/logout",
+    tag = "auth",
+    responses((status = 204, description = "The session was ended and its cookie removed"))
+)]
+#[post("/auth/logout")]
+pub async fn logout(ctx: web::Data<AppContext>, req: HttpRequest) -> HttpResponse {
+    if let Some(token) = credential(&req) {
+        ctx.auth().logout(&token);
+    }
+    let mut cookie = Cookie::build(SESSION_COOKIE, "").path("/").finish();
+    cookie.make_removal();
+    HttpResponse::NoContent().cookie(cookie).finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct ManualClock(AtomicU64);
+
+    impl Clock for ManualClock {
+        fn now_millis(&self) -> u128 {
+            self.0.load(Ordering::SeqCst) as u128
+        }
+    }
+
+    fn service(clock: Arc<ManualClock>) -> AuthService {
+        let auth = AuthService::new(clock);
+        auth.configure(AuthConfig {
+            enabled: true,
+            api_keys: vec![ApiKey::new("ops", Role::Operator, "key-1")],
+            anonymous_role: None,
+            session_ttl_secs: 60,
+            peer_key_hashes: vec![hash_secret("peer-1")],
+            ..AuthConfig::default()
+        });
+        auth
+    }
+
+    #[test]
+    fn test_api_key_and_session() {
+        let clock = Arc::new(ManualClock::default());
+        let auth = service(clock.clone());
+        let principal = auth.verify("key-1").unwrap();
+        assert_eq!(principal.name, "ops");
+        assert_eq!(principal.role, Role::Operator);
+        assert!(auth.verify("key-2").is_none());
+        assert!(auth.login("key-2").is_none());
+
+        let session = auth.login("key-1").unwrap();
+        assert_eq!(session.expires_at, 60_000);
+        assert_eq!(auth.verify(&session.token).unwrap().name, "ops");
+
+        clock.0.store(60_000, Ordering::SeqCst);
+        assert!(auth.verify(&session.token).is_none());
+    }
+
+    #[test]
+    fn test_peer_key() {
+        let auth = service(Arc::new(ManualClock::default()));
+        let principal = auth.verify("peer-1").unwrap();
+        assert_eq!(principal.name, PEER);
+        assert_eq!(principal.role, Role::Peer);
+        assert!(auth.verify("peer-2").is_none());
+        assert!(auth.login("peer-1").is_none());
+        assert!(auth.peer_authorization().is_none());
+
+        *auth.peer_key.write().unwrap() = Some("peer-1".to_string());
+        assert_eq!(auth.peer_authorization().unwrap(), "Bearer peer-1");
+    }
+
+    #[test]
+    fn test_logout() {
+        let auth = service(Arc::new(ManualClock::default()));
+        let session = auth.login("key-1").unwrap();
+        auth.logout(&session.token);
+        assert!(auth.verify(&session.token).is_none());
+    }
+
+    #[test]
+    fn test_anonymous_role() {
+        let auth = service(Arc::new(ManualClock::default()));
+        assert_eq!(identify(&auth, None), Err(AuthError::MissingCredentials));
+        assert_eq!(
+            identify(&auth, Some("nope".to_string())),
+            Err(AuthError::InvalidCredentials)
+        );
+
+        let mut settings = auth.settings.read().unwrap().clone();
+        settings.anonymous_role = Some(Role::Viewer);
+        auth.configure(settings);
+        assert_eq!(identify(&auth, None).unwrap().role, Role::Viewer);
+    }
+
+    #[test]
+    fn test_forbidden_reason() {
+        let error = AuthError::Forbidden {
+            principal: Principal {
+                name: "reception".to_string(),
+                role: Role::Viewer,
+            },
+            required: Role::Operator,
+            action: "delete media",
+        };
+        assert_eq!(error.status_code(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            error.to_string(),
+            "You need the operator role to delete media, reception has the viewer role"
+        );
+    }
+}
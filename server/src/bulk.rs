@@ -0,0 +1,320 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use actix_web::{web, HttpResponse};
+use domain::node::Node;
+use futures::future::join_all;
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use tokio::time::timeout;
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::{client, context::AppContext, error::ApiError};
+
+const DEFAULT_TIMEOUT_MS: u64 = 5_000;
+const MAX_TIMEOUT_MS: u64 = 60_000;
+
+/// Which nodes a bulk request is sent to.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkTarget {
+    All,
+    Active,
+    Ids(Vec<i64>),
+    /// Members of the groups named in the config.
+    Groups(Vec<String>),
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct BulkRequest {
+    pub target: BulkTarget,
+    /// Per-node timeout, defaults to 5 seconds.
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct NodeResult {
+    pub id: i64,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct BulkReport {
+    pub succeeded: Vec<NodeResult>,
+    pub failed: Vec<NodeResult>,
+    pub unreachable: Vec<NodeResult>,
+}
+
+enum Outcome {
+    Succeeded(u16),
+    Failed(u16, String),
+    Unreachable(String),
+}
+
+fn action_path(action: &str) -> Option<&'static str> {
+    match action {
+        "play" => Some("play"),
+        "pause" => Some("pause"),
+        "open_player" => Some("open_player"),
+        "kill_player" => Some("kill_player"),
+        _ => None,
+    }
+}
+
+/// Node ids of the named groups, each id once.
+fn group_members(
+    groups: &BTreeMap<String, Vec<i64>>,
+    names: &[String],
+) -> Result<Vec<i64>, ApiError> {
+    let mut ids = vec![];
+    for name in names {
+        let members = groups
+            .get(name)
+            .ok_or_else(|| ApiError::NotFound(format!("Group {name} not found")))?;
+        for id in members {
+            if !ids.contains(id) {
+                ids.push(*id);
+            }
+        }
+    }
+    Ok(ids)
+}
+
+/// Splits the requested target into known nodes and ids nobody has announced.
+fn select_targets(
+    nodes: Vec<Node>,
+    target: &BulkTarget,
+    groups: &BTreeMap<String, Vec<i64>>,
+) -> Result<(Vec<Node>, Vec<i64>), ApiError> {
+    let ids = match target {
+        BulkTarget::All => return Ok((nodes, vec![])),
+        BulkTarget::Active => {
+            return Ok((nodes.into_iter().filter(|it| it.active).collect(), vec![]))
+        }
+        BulkTarget::Ids(ids) => ids.clone(),
+        BulkTarget::Groups(names) => group_members(groups, names)?,
+    };
+    let unknown = ids
+        .iter()
+        .filter(|id| !nodes.iter().any(|it| it.id == **id))
+        .copied()
+        .collect();
+    let selected = nodes
+        .into_iter()
+        .filter(|it| ids.contains(&it.id))
+        .collect();
+    Ok((selected, unknown))
+}
+
+/// The known nodes `target` names, and the ids nobody has announced.
+pub(crate) async fn resolve_targets(
+    ctx: &AppContext,
+    target: &BulkTarget,
+) -> Result<(Vec<Node>, Vec<i64>), ApiError> {
+    let nodes = ctx.node_holder().get_node_list().await;
+    let config = ctx.config().get_config().await;
+    select_targets(nodes, target, config.groups())
+}
+
+async fn call_node(
+    ctx: &AppContext,
+    node: &Node,
+    method: Method,
+    route: &[String],
+    json: Option<&[u8]>,
+    limit: Duration,
+) -> Outcome {
+    let content_type = json.map(|_| "application/json");
+    let body = json.map(<[u8]>::to_vec).unwrap_or_default();
+    let request = client::forward(ctx, node, method, route, None, content_type, body);
+    match timeout(limit, request).await {
+        Ok(Ok(response)) => {
+            let status = response.status();
+            if status.is_success() {
+                Outcome::Succeeded(status.as_u16())
+            } else {
+                let message = response.text().await.unwrap_or_default();
+                Outcome::Failed(status.as_u16(), message)
+            }
+        }
+        Ok(Err(e)) => Outcome::Unreachable(e.to_string()),
+        Err(_) => Outcome::Unreachable(format!("timed out after {}ms", limit.as_millis())),
+    }
+}
+
+/// Calls the route made of `route` on every targeted node at once, with
+/// `json` as the body when given.
+pub(crate) async fn fan_out(
+    ctx: &AppContext,
+    request: &BulkRequest,
+    method: Method,
+    route: &[String],
+    json: Option<&[u8]>,
+) -> Result<BulkReport, ApiError> {
+    let (nodes, unknown) = resolve_targets(ctx, &request.target).await?;
+    let limit = Duration::from_millis(
+        request
+            .timeout_ms
+            .unwrap_or(DEFAULT_TIMEOUT_MS)
+            .min(MAX_TIMEOUT_MS),
+    );
+    let outcomes = join_all(
+        nodes
+            .iter()
+            .map(|node| call_node(ctx, node, method.clone(), route, json, limit)),
+    )
+    .await;
+
+    let mut report = BulkReport::default();
+    for (node, outcome) in nodes.into_iter().zip(outcomes) {
+        let (status, message, bucket) = match outcome {
+            Outcome::Succeeded(status) => (Some(status), None, &mut report.succeeded),
+            Outcome::Failed(status, message) => (Some(status), Some(message), &mut report.failed),
+            Outcome::Unreachable(message) => (None, Some(message), &mut report.unreachable),
+        };
+        bucket.push(NodeResult {
+            id: node.id,
+            name: node.name,
+            status,
+            message,
+        });
+    }
+    for id in unknown {
+        report.unreachable.push(NodeResult {
+            id,
+            name: String::new(),
+            status: None,
+            message: Some("unknown node".to_string()),
+        });
+    }
+    info!(
+        "Bulk {} {}: {} succeeded, {} failed, {} unreachable",
+        method,
+        route.join("/"),
+        report.succeeded.len(),
+        report.failed.len(),
+        report.unreachable.len()
+    );
+    Ok(report)
+}
+
+#[utoipa::path(
+    post,
+    path = "/bulk/{action}",
+    tag = "player",
+    params(("action" = String, Path, description = "One of `play`, `pause`, `open_player` or `kill_player`")),
+    request_body = BulkRequest,
+    responses(
+        (status = 200, description = "What each targeted node answered", body = BulkReport),
+        (status = 404, description = "The action or a targeted group is unknown", body = ErrorBody),
+    )
+)]
+pub async fn bulk_action(
+    ctx: web::Data<AppContext>,
+    path: web::Path<String>,
+    body: web::Json<BulkRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let action = path.into_inner();
+    let path = action_path(&action)
+        .ok_or_else(|| ApiError::NotFound(format!("Unknown bulk action {action}")))?;
+    let report = fan_out(&ctx, &body, Method::GET, &[path.to_string()], None).await?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/bulk/video_list/{video}",
+    tag = "media",
+    params(("video" = String, Path, description = "File name of the video")),
+    request_body = BulkRequest,
+    responses(
+        (status = 200, description = "What each targeted node answered", body = BulkReport),
+        (status = 404, description = "A targeted group is unknown", body = ErrorBody),
+    )
+)]
+pub async fn bulk_delete_video(
+    ctx: web::Data<AppContext>,
+    path: web::Path<String>,
+    body: web::Json<BulkRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let video = path.into_inner();
+    let report = fan_out(
+        &ctx,
+        &body,
+        Method::DELETE,
+        &["video_list".to_string(), video],
+        None,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: i64, active: bool) -> Node {
+        let mut node = Node::new(id, format!("node-{id}"), 8081, 0);
+        node.active = active;
+        node
+    }
+
+    #[test]
+    fn test_select_targets() {
+        let nodes = vec![node(1, true), node(2, false)];
+        let groups = BTreeMap::new();
+        let (selected, unknown) = select_targets(nodes.clone(), &BulkTarget::All, &groups).unwrap();
+        assert_eq!(selected.len(), 2);
+        assert!(unknown.is_empty());
+
+        let (selected, _) = select_targets(nodes.clone(), &BulkTarget::Active, &groups).unwrap();
+        assert_eq!(selected.iter().map(|it| it.id).collect::<Vec<_>>(), vec![1]);
+
+        let (selected, unknown) =
+            select_targets(nodes, &BulkTarget::Ids(vec![2, 3]), &groups).unwrap();
+        assert_eq!(selected.iter().map(|it| it.id).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(unknown, vec![3]);
+    }
+
+    #[test]
+    fn test_select_groups() {
+        let nodes = vec![node(1, true), node(2, false), node(3, true)];
+        let groups = BTreeMap::from([
+            ("lobby".to_string(), vec![1, 2]),
+            ("hall".to_string(), vec![2, 4]),
+        ]);
+        let target = BulkTarget::Groups(vec!["lobby".to_string(), "hall".to_string()]);
+        let (selected, unknown) = select_targets(nodes.clone(), &target, &groups).unwrap();
+        assert_eq!(
+            selected.iter().map(|it| it.id).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(unknown, vec![4]);
+
+        let target = BulkTarget::Groups(vec!["stage".to_string()]);
+        assert!(matches!(
+            select_targets(nodes, &target, &groups),
+            Err(ApiError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_target() {
+        let request: BulkRequest = serde_json::from_str(r#"{"target":"active"}"#).unwrap();
+        assert_eq!(request.target, BulkTarget::Active);
+        let request: BulkRequest =
+            serde_json::from_str(r#"{"target":{"ids":[1,2]},"timeout_ms":100}"#).unwrap();
+        assert_eq!(request.target, BulkTarget::Ids(vec![1, 2]));
+        assert_eq!(request.timeout_ms, Some(100));
+        let request: BulkRequest =
+            serde_json::from_str(r#"{"target":{"groups":["lobby"]}}"#).unwrap();
+        assert_eq!(
+            request.target,
+            BulkTarget::Groups(vec!["lobby".to_string()])
+        );
+    }
+}
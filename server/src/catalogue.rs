@@ -0,0 +1,407 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{get, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use storage::Storage;
+use tokio::{
+    fs,
+    sync::{Mutex, OwnedMutexGuard},
+};
+use tracing::{error, info, warn};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{context::AppContext, error::ApiError, media::MediaRoot, upload::sha256_file};
+
+/// Items by file name.
+type Items = BTreeMap<String, CatalogueItem>;
+
+/// A file in the media root and where it came from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct CatalogueItem {
+    pub name: String,
+    /// Hex SHA-256 of the content.
+    pub sha256: String,
+    pub size: u64,
+    /// Milliseconds since the unix epoch. For files that were not uploaded
+    /// through the API, their modification time when first catalogued.
+    pub uploaded_at: u128,
+    /// Name of the API key that uploaded the file, `None` when not uploaded
+    /// through the API.
+    pub uploader: Option<String>,
+    /// Modification time the hash belongs to, a file modified since is hashed again.
+    pub modified: u128,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CatalogueFilter {
+    /// Items whose name contains this text.
+    pub name: Option<String>,
+    pub sha256: Option<String>,
+    pub uploader: Option<String>,
+    /// Items uploaded at or after this timestamp.
+    pub from: Option<u128>,
+    /// Items uploaded before this timestamp.
+    pub to: Option<u128>,
+}
+
+impl CatalogueFilter {
+    fn matches(&self, item: &CatalogueItem) -> bool {
+        self.name.as_ref().is_none_or(|it| item.name.contains(it))
+            && self
+                .sha256
+                .as_ref()
+                .is_none_or(|it| item.sha256.eq_ignore_ascii_case(it))
+            && self
+                .uploader
+                .as_ref()
+                .is_none_or(|it| item.uploader.as_ref() == Some(it))
+            && self.from.is_none_or(|from| item.uploaded_at >= from)
+            && self.to.is_none_or(|to| item.uploaded_at < to)
+    }
+}
+
+/// Content hash, size and origin of every file in the media root, persisted
+/// through [`Storage`]. Uploads are recorded as they finish, files put into
+/// the folder by other means are hashed by [`Catalogue::reconcile`].
+#[derive(Debug, Clone, Default)]
+pub struct Catalogue {
+    storage: Arc<Mutex<Option<Storage<Items>>>>,
+    /// Held by the running reconcile, so two never hash the same files.
+    reconciling: Arc<Mutex<()>>,
+    /// Held from checking that content is new until it is recorded.
+    admitting: Arc<Mutex<()>>,
+}
+
+impl Catalogue {
+    /// Loads the catalogue from the file named in the config.
+    pub async fn open(&self, path: PathBuf) {
+        *self.storage.lock().await = Some(Storage::open(path).await);
+    }
+
+    async fn update<R>(&self, f: impl FnOnce(&mut Items) -> R) -> anyhow::Result<R> {
+        let mut storage = self.storage.lock().await;
+        let storage = storage
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("The catalogue is not open"))?;
+        let mut items = storage.get().await?;
+        let result = f(&mut items);
+        storage.set(items).await?;
+        Ok(result)
+    }
+
+    pub async fn items(&self) -> anyhow::Result<Items> {
+        let mut storage = self.storage.lock().await;
+        match storage.as_mut() {
+            Some(storage) => storage.get().await,
+            None => Err(anyhow::anyhow!("The catalogue is not open")),
+        }
+    }
+
+    /// Held from [`Catalogue::ensure_unique`] until the file is moved into
+    /// the media root and recorded, so two stores of the same content can't
+    /// both pass the check.
+    pub async fn admission(&self) -> OwnedMutexGuard<()> {
+        self.admitting.clone().lock_owned().await
+    }
+
+    /// Refuses content the media root already holds under any name. Checked
+    /// under [`Catalogue::admission`] before storing the content.
+    pub async fn ensure_unique(
+        &self,
+        sha256: &str,
+        media_root: &MediaRoot,
+    ) -> Result<(), ApiError> {
+        let items = self.items().await.map_err(ApiError::Internal)?;
+        let existing = items
+            .into_values()
+            .filter(|it| it.sha256.eq_ignore_ascii_case(sha256))
+            // the entry may be stale until the next reconcile
+            .find(|it| media_root.resolve(&it.name).is_ok());
+        match existing {
+            Some(item) => Err(ApiError::Duplicate(item.name)),
+            None => Ok(()),
+        }
+    }
+
+    /// Records a file just stored in the media root.
+    pub async fn record(
+        &self,
+        media_root: &MediaRoot,
+        name: &str,
+        sha256: &str,
+        uploaded_at: u128,
+        uploader: Option<String>,
+    ) -> anyhow::Result<()> {
+        let metadata = fs::metadata(media_root.resolve(name)?).await?;
+        let item = CatalogueItem {
+            name: name.to_string(),
+            sha256: sha256.to_ascii_lowercase(),
+            size: metadata.len(),
+            uploaded_at,
+            uploader,
+            modified: millis(metadata.modified()?),
+        };
+        self.update(|items| items.insert(item.name.clone(), item))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn forget(&self, name: &str) -> anyhow::Result<()> {
+        self.update(|items| items.remove(name)).await?;
+        Ok(())
+    }
+
+    /// Brings the catalogue in line with the media root: files that are new
+    /// or modified since they were hashed are hashed, files that are gone are
+    /// dropped. Returns the items afterwards.
+    ///
+    /// The files are hashed without holding the catalogue, so uploads are
+    /// recorded meanwhile. Entries recorded during the run win over what the
+    /// run found for the same name.
+    pub async fn reconcile(&self, media_root: &MediaRoot) -> anyhow::Result<Items> {
+        let _reconciling = self.reconciling.lock().await;
+        let known = self.items().await?;
+        let names = {
+            let media_root = media_root.clone();
+            tokio::task::spawn_blocking(move || media_root.list()).await??
+        };
+        let mut found = Items::new();
+        for name in names {
+            let path = media_root.path().join(&name);
+            // removed since it was listed
+            let Ok(metadata) = fs::metadata(&path).await else {
+                continue;
+            };
+            let modified = millis(metadata.modified()?);
+            match known.get(&name) {
+                Some(item) if item.size == metadata.len() && item.modified == modified => {
+                    found.insert(name, item.clone());
+                }
+                _ => {
+                    let sha256 = match sha256_file(path).await {
+                        Ok(sha256) => sha256,
+                        Err(e) => {
+                            warn!("Failed to hash {:?} with error {:?}", name, e);
+                            continue;
+                        }
+                    };
+                    info!("Catalogued {:?} with SHA-256 {}", name, sha256);
+                    let item = CatalogueItem {
+                        name: name.clone(),
+                        sha256,
+                        size: metadata.len(),
+                        uploaded_at: modified,
+                        uploader: None,
+                        modified,
+                    };
+                    found.insert(name, item);
+                }
+            }
+        }
+        self.update(|items| {
+            *items = merge(&known, found, items);
+            items.clone()
+        })
+        .await
+    }
+}
+
+/// What a reconcile `found`, with the entries recorded or forgotten since it
+/// read `known` from `current` applied on top.
+fn merge(known: &Items, mut found: Items, current: &Items) -> Items {
+    for name in known.keys().filter(|it| !current.contains_key(*it)) {
+        found.remove(name);
+    }
+    for (name, item) in current {
+        if known.get(name) != Some(item) {
+            found.insert(name.clone(), item.clone());
+        }
+    }
+    found
+}
+
+/// Reconciles the catalogue in the background, so files put into the media
+/// root by other means are hashed without holding up a request.
+pub fn reconcile_later(ctx: &AppContext) {
+    let ctx = ctx.clone();
+    tokio::spawn(async move {
+        let result = match ctx.media_root().await {
+            Ok(media_root) => ctx.catalogue().reconcile(&media_root).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            error!("Failed to reconcile the catalogue with error {:?}", e);
+        }
+    });
+}
+
+fn millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |it| it.as_millis())
+}
+
+/// Items sharing their content with another, grouped by hash.
+fn duplicates(items: Items) -> Vec<Vec<CatalogueItem>> {
+    let mut by_hash = BTreeMap::<String, Vec<CatalogueItem>>::new();
+    for item in items.into_values() {
+        by_hash.entry(item.sha256.clone()).or_default().push(item);
+    }
+    by_hash.into_values().filter(|it| it.len() > 1).collect()
+}
+
+async fn stored(ctx: &AppContext) -> Result<Items, ApiError> {
+    ctx.catalogue().items().await.map_err(ApiError::Internal)
+}
+
+#[utoipa::path(
+    get,
+    path = "/catalogue",
+    tag = "media",
+    params(CatalogueFilter),
+    responses(
+        (status = 200, description = "Matching files in the media root, by name, as of the last reconcile", body = [CatalogueItem]),
+        (status = 500, description = "The catalogue could not be read", body = ErrorBody),
+    )
+)]
+#[get("/catalogue")]
+pub async fn get_catalogue(
+    ctx: web::Data<AppContext>,
+    filter: web::Query<CatalogueFilter>,
+) -> Result<HttpResponse, ApiError> {
+    let items: Vec<_> = stored(&ctx)
+        .await?
+        .into_values()
+        .filter(|it| filter.matches(it))
+        .collect();
+    Ok(HttpResponse::Ok().json(items))
+}
+
+#[utoipa::path(
+    get,
+    path = "/catalogue/duplicates",
+    tag = "media",
+    responses(
+        (status = 200, description = "Files stored more than once, grouped by content, as of the last reconcile", body = [Vec<CatalogueItem>]),
+        (status = 500, description = "The catalogue could not be read", body = ErrorBody),
+    )
+)]
+#[get("/catalogue/duplicates")]
+pub async fn get_duplicates(ctx: web::Data<AppContext>) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(duplicates(stored(&ctx).await?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_reconcile_and_duplicates() {
+        let dir = std::env::temp_dir().join(format!("catalogue-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let media_root = MediaRoot::open(&dir.join("video")).unwrap();
+        std::fs::write(media_root.path().join("a.mp4"), b"same").unwrap();
+        std::fs::write(media_root.path().join("b.mp4"), b"same").unwrap();
+        std::fs::write(media_root.path().join("c.mp4"), b"other").unwrap();
+        let path = dir.join("catalogue.json");
+        let catalogue = Catalogue::default();
+        catalogue.open(path.clone()).await;
+
+        let items = catalogue.reconcile(&media_root).await.unwrap();
+        assert_eq!(items.len(), 3);
+        assert_eq!(items["a.mp4"].sha256, items["b.mp4"].sha256);
+        assert_eq!(items["c.mp4"].uploader, None);
+        let groups = duplicates(items.clone());
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].len(), 2);
+        let sha256 = items["c.mp4"].sha256.to_ascii_uppercase();
+        assert!(matches!(
+            catalogue.ensure_unique(&sha256, &media_root).await,
+            Err(ApiError::Duplicate(name)) if name == "c.mp4"
+        ));
+
+        std::fs::remove_file(media_root.path().join("b.mp4")).unwrap();
+        std::fs::write(media_root.path().join("c.mp4"), b"changed").unwrap();
+        std::fs::write(media_root.path().join("d.mp4"), b"new").unwrap();
+        catalogue
+            .record(&media_root, "d.mp4", "ABC", 42, Some("ops".to_string()))
+            .await
+            .unwrap();
+
+        // a reopened catalogue keeps what was recorded
+        let reopened = Catalogue::default();
+        reopened.open(path).await;
+        let items = reopened.reconcile(&media_root).await.unwrap();
+        assert_eq!(
+            items.keys().collect::<Vec<_>>(),
+            ["a.mp4", "c.mp4", "d.mp4"]
+        );
+        assert_ne!(items["c.mp4"].sha256, sha256.to_ascii_lowercase());
+        assert_eq!(items["d.mp4"].sha256, "abc");
+        assert_eq!(items["d.mp4"].uploader.as_deref(), Some("ops"));
+        assert!(duplicates(items).is_empty());
+        assert!(reopened.ensure_unique(&sha256, &media_root).await.is_ok());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_merge_keeps_changes_made_meanwhile() {
+        let item = |name: &str, sha256: &str| CatalogueItem {
+            name: name.to_string(),
+            sha256: sha256.to_string(),
+            size: 1,
+            uploaded_at: 1,
+            uploader: None,
+            modified: 1,
+        };
+        let known = Items::from([
+            ("a".to_string(), item("a", "1")),
+            ("b".to_string(), item("b", "2")),
+        ]);
+        let found = Items::from([
+            ("a".to_string(), item("a", "1")),
+            ("b".to_string(), item("b", "2")),
+            ("c".to_string(), item("c", "3")),
+        ]);
+        // b was forgotten and d recorded while the files were hashed
+        let current = Items::from([
+            ("a".to_string(), item("a", "1")),
+            ("d".to_string(), item("d", "4")),
+        ]);
+        let merged = merge(&known, found, &current);
+        assert_eq!(merged.keys().collect::<Vec<_>>(), ["a", "c", "d"]);
+    }
+
+    #[test]
+    fn test_filter() {
+        let item = CatalogueItem {
+            name: "intro.mp4".to_string(),
+            sha256: "abc".to_string(),
+            size: 3,
+            uploaded_at: 100,
+            uploader: Some("ops".to_string()),
+            modified: 100,
+        };
+        let filter = |filter: CatalogueFilter| filter.matches(&item);
+        assert!(filter(CatalogueFilter::default()));
+        assert!(filter(CatalogueFilter {
+            name: Some("intro".to_string()),
+            sha256: Some("ABC".to_string()),
+            from: Some(100),
+            ..Default::default()
+        }));
+        assert!(!filter(CatalogueFilter {
+            uploader: Some("admin".to_string()),
+            ..Default::default()
+        }));
+        assert!(!filter(CatalogueFilter {
+            to: Some(100),
+            ..Default::default()
+        }));
+    }
+}
@@ -1,52 +1,142 @@
-use futures::TryStreamExt;
+use domain::node::Node;
 use reqwest::{
-    multipart::{Form, Part},
-    Body, Client,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Body, Client, Method, RequestBuilder, Response, Url,
 };
-use tokio::fs::File;
-use tokio_util::codec::{BytesCodec, FramedRead};
 use tracing::{error, info};
 
-lazy_static::lazy_static! {
-    static ref CLIENT: Client = Client::new();
-}
-
-pub async fn upload_file(file_path: &str, filename: &str) -> anyhow::Result<()> {
-    let file = File::open(file_path).await?;
-    let multipart = Form::new().part(
-        "file",
-        Part::stream(Body::wrap_stream(
-            FramedRead::new(file, BytesCodec::new()).map_ok(|bytes| bytes.freeze()),
-        )),
-    );
-    CLIENT
-        .post(format!("http://localhost:8081/video_list/{filename}"))
-        .multipart(multipart)
-        .send()
-        .await?;
+use crate::{context::AppContext, error::ApiError, playlist::Playlist};
+
+pub async fn pause(client: &Client, player_url: &str) -> Result<(), ApiError> {
+    player_call(client, player_url, "pause").await
+}
+
+pub async fn play(client: &Client, player_url: &str) -> Result<(), ApiError> {
+    player_call(client, player_url, "play").await
+}
+
+/// Hands `playlist` to the local player, or clears its playlist when `None`.
+pub async fn load_playlist(
+    client: &Client,
+    player_url: &str,
+    playlist: Option<&Playlist>,
+) -> Result<(), ApiError> {
+    let url = format!("{player_url}/playlist");
+    let request = match playlist {
+        Some(playlist) => client.put(url).json(playlist),
+        None => client.delete(url),
+    };
+    player_send(request, "load the playlist").await
+}
+
+/// Calls `action` on the local player's control API.
+async fn player_call(client: &Client, player_url: &str, action: &str) -> Result<(), ApiError> {
+    player_send(client.get(format!("{player_url}/{action}")), action).await
+}
+
+async fn player_send(request: RequestBuilder, action: &str) -> Result<(), ApiError> {
+    let response = request.send().await.map_err(|e| {
+        error!("{}: {:?}", action, e);
+        ApiError::Player(format!("The player is unreachable: {e}"))
+    })?;
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    if !status.is_success() {
+        error!("{}: player answered {} {:?}", action, status, body);
+        return Err(ApiError::Player(format!(
+            "The player refused to {action} with {status}"
+        )));
+    }
+    info!("{}: {:?}", action, body);
     Ok(())
 }
 
-pub async fn pause() {
-    let result = CLIENT.get("http://localhost:8082/pause").send().await;
-    match result {
-        Ok(body) => {
-            info!("pause: {:?}", body.text().await.unwrap());
-        }
-        Err(e) => {
-            error!("pause: {:?}", e);
-        }
+/// Nodes announcing a certificate fingerprint serve HTTPS. A node serving
+/// HTTPS itself calls every peer over HTTPS, and a pinned node is always
+/// called over HTTPS, so neither can be downgraded by announcing none.
+fn node_base_url(ctx: &AppContext, node: &Node) -> String {
+    let ip = node.ipaddress.parse().ok();
+    if let Some(ip) = ip {
+        // the certificate it presents is checked against this before it is pinned
+        ctx.peer_pins()
+            .announce(ip, node.cert_fingerprint.as_deref());
     }
+    let pinned = ip.is_some_and(|ip| ctx.peer_pins().is_pinned(&ip));
+    let https = ctx.tls_fingerprint().is_some() || node.cert_fingerprint.is_some() || pinned;
+    let scheme = if https { "https" } else { "http" };
+    format!("{scheme}://{}:{}", node.ipaddress, node.http_port)
 }
 
-pub async fn play() {
-    let result = CLIENT.get("http://localhost:8082/play").send().await;
-    match result {
-        Ok(body) => {
-            info!("play: {:?}", body.text().await.unwrap());
-        }
-        Err(e) => {
-            error!("play: {:?}", e);
-        }
+/// `base` followed by `segments`, each percent-encoded on its own so a name
+/// holding `/`, `?`, `#` or `%` names the same resource on the peer.
+fn segments_url(base: &str, segments: &[String], query: Option<&str>) -> anyhow::Result<Url> {
+    let mut url = Url::parse(base)?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("{base} can't hold a path"))?
+        .extend(segments);
+    url.set_query(query);
+    Ok(url)
+}
+
+/// Sends a request to the route made of `segments` on the HTTP API of `node`.
+pub async fn forward(
+    ctx: &AppContext,
+    node: &Node,
+    method: Method,
+    segments: &[String],
+    query: Option<&str>,
+    content_type: Option<&str>,
+    body: impl Into<Body>,
+) -> anyhow::Result<Response> {
+    let url = segments_url(&node_base_url(ctx, node), segments, query)?;
+    let https = url.scheme() == "https";
+    let mut request = authorize(ctx, ctx.client().request(method, url), https);
+    if let Some(content_type) = content_type {
+        request = request.header(CONTENT_TYPE, content_type);
+    }
+    Ok(request.body(body).send().await?)
+}
+
+/// A request to `path` on the HTTP API of `node`. Over HTTPS it carries this
+/// node's peer key and the peer must present its pinned certificate.
+pub fn peer_request(ctx: &AppContext, node: &Node, method: Method, path: &str) -> RequestBuilder {
+    let base = node_base_url(ctx, node);
+    let https = base.starts_with("https:");
+    authorize(
+        ctx,
+        ctx.client().request(method, format!("{base}/{path}")),
+        https,
+    )
+}
+
+/// Adds the peer key, only over HTTPS so it never crosses the network in the clear.
+fn authorize(ctx: &AppContext, request: RequestBuilder, https: bool) -> RequestBuilder {
+    match ctx.auth().peer_authorization() {
+        Some(authorization) if https => request.header(AUTHORIZATION, authorization),
+        _ => request,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segments_url() {
+        let url = |segments: &[&str], query| {
+            let segments: Vec<String> = segments.iter().map(|it| it.to_string()).collect();
+            segments_url("http://10.0.0.2:8080", &segments, query)
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(url(&["play"], None), "http://10.0.0.2:8080/play");
+        assert_eq!(
+            url(&["video_list", "a b#1?%.mp4"], None),
+            "http://10.0.0.2:8080/video_list/a%20b%231%3F%25.mp4"
+        );
+        assert_eq!(
+            url(&["video_list", "dir/é.mp4"], Some("at=3")),
+            "http://10.0.0.2:8080/video_list/dir%2F%C3%A9.mp4?at=3"
+        );
     }
 }
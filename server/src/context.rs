@@ -0,0 +1,236 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, OnceLock, RwLock},
+};
+
+use config::{model::ServerConfig, ConfigStore};
+use discover::{node_holder::NodeHoder, stats::DiscoveryStats};
+use reqwest::Client;
+use utils::{
+    clock::{Clock, SystemClock},
+    snowflake::IdGenerator,
+};
+
+use crate::{
+    audit::AuditLog,
+    auth::AuthService,
+    catalogue::Catalogue,
+    discovery::DiscoveryService,
+    distribution::DistributionStore,
+    library::ProbeCache,
+    media::MediaRoot,
+    metrics::Metrics,
+    playlist::{PlayerPlaylist, PlaylistStore},
+    schedule::Scheduler,
+    sync::SyncService,
+    tls::{self, PeerPins},
+    transfer::{ChunkCache, Transfers},
+    upload::UploadStore,
+};
+
+/// Everything a running node shares between discovery and the HTTP handlers.
+///
+/// Cloning is cheap, every field is reference counted, so one context can be
+/// handed to each actix worker and background task.
+#[derive(Debug, Clone)]
+pub struct AppContext {
+    node_holder: Arc<NodeHoder>,
+    config: ConfigStore,
+    clock: Arc<dyn Clock>,
+    ids: IdGenerator,
+    client: Client,
+    latest_screenshot: Arc<RwLock<String>>,
+    discovery: DiscoveryService,
+    auth: AuthService,
+    peer_pins: PeerPins,
+    tls_fingerprint: Arc<OnceLock<String>>,
+    audit: AuditLog,
+    uploads: UploadStore,
+    probes: ProbeCache,
+    catalogue: Catalogue,
+    distributions: DistributionStore,
+    sync: SyncService,
+    chunks: ChunkCache,
+    transfers: Transfers,
+    playlists: PlaylistStore,
+    schedule: Scheduler,
+    player_playlist: PlayerPlaylist,
+    metrics: Metrics,
+}
+
+impl AppContext {
+    pub fn builder() -> AppContextBuilder {
+        AppContextBuilder::default()
+    }
+
+    pub fn node_holder(&self) -> &Arc<NodeHoder> {
+        &self.node_holder
+    }
+
+    pub fn config(&self) -> &ConfigStore {
+        &self.config
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    pub fn ids(&self) -> &IdGenerator {
+        &self.ids
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn latest_screenshot(&self) -> &Arc<RwLock<String>> {
+        &self.latest_screenshot
+    }
+
+    pub fn discovery(&self) -> &DiscoveryService {
+        &self.discovery
+    }
+
+    pub fn auth(&self) -> &AuthService {
+        &self.auth
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn audit(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    pub fn uploads(&self) -> &UploadStore {
+        &self.uploads
+    }
+
+    pub fn probes(&self) -> &ProbeCache {
+        &self.probes
+    }
+
+    pub fn catalogue(&self) -> &Catalogue {
+        &self.catalogue
+    }
+
+    pub fn distributions(&self) -> &DistributionStore {
+        &self.distributions
+    }
+
+    pub fn sync(&self) -> &SyncService {
+        &self.sync
+    }
+
+    pub fn chunks(&self) -> &ChunkCache {
+        &self.chunks
+    }
+
+    pub fn transfers(&self) -> &Transfers {
+        &self.transfers
+    }
+
+    pub fn playlists(&self) -> &PlaylistStore {
+        &self.playlists
+    }
+
+    pub fn schedule(&self) -> &Scheduler {
+        &self.schedule
+    }
+
+    pub fn player_playlist(&self) -> &PlayerPlaylist {
+        &self.player_playlist
+    }
+
+    /// The configured media folder, created on first use.
+    pub async fn media_root(&self) -> std::io::Result<MediaRoot> {
+        let root = self.config.get_config().await.server().media_root.clone();
+        MediaRoot::open(&root)
+    }
+
+    pub fn peer_pins(&self) -> &PeerPins {
+        &self.peer_pins
+    }
+
+    /// Fingerprint of the certificate this node serves, once HTTPS is set up.
+    pub fn tls_fingerprint(&self) -> Option<&String> {
+        self.tls_fingerprint.get()
+    }
+
+    pub fn set_tls_fingerprint(&self, fingerprint: String) {
+        let _ = self.tls_fingerprint.set(fingerprint);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AppContextBuilder {
+    config_path: Option<PathBuf>,
+    clock: Option<Arc<dyn Clock>>,
+    ids: Option<IdGenerator>,
+    client: Option<Client>,
+}
+
+impl AppContextBuilder {
+    pub fn config_path(mut self, config_path: impl Into<PathBuf>) -> Self {
+        self.config_path = Some(config_path.into());
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    pub fn ids(mut self, ids: IdGenerator) -> Self {
+        self.ids = Some(ids);
+        self
+    }
+
+    pub fn client(mut self, client: Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Builds the context. Without an explicit client, peers are called with
+    /// one that trusts only the certificates pinned on first connection.
+    pub fn build(self) -> anyhow::Result<AppContext> {
+        let config = ConfigStore::new(
+            self.config_path
+                .unwrap_or_else(|| PathBuf::from("config.json")),
+        );
+        let clock = self.clock.unwrap_or_else(|| Arc::new(SystemClock));
+        let stats = Arc::new(DiscoveryStats::default());
+        let metrics = Metrics::new(stats.clone())?;
+        let node_holder = Arc::new(NodeHoder::new(config.clone(), clock.clone(), stats));
+        let peer_pins = PeerPins::default();
+        let client = match self.client {
+            Some(client) => client,
+            None => tls::peer_client(peer_pins.clone())?,
+        };
+        Ok(AppContext {
+            node_holder,
+            config,
+            auth: AuthService::new(clock.clone()),
+            clock,
+            ids: self.ids.unwrap_or_default(),
+            client,
+            latest_screenshot: Arc::new(RwLock::new(String::new())),
+            discovery: DiscoveryService::default(),
+            peer_pins,
+            tls_fingerprint: Arc::new(OnceLock::new()),
+            audit: AuditLog::new(ServerConfig::default().audit_log),
+            uploads: UploadStore::new(ServerConfig::default().upload_dir),
+            probes: ProbeCache::default(),
+            catalogue: Catalogue::default(),
+            distributions: DistributionStore::default(),
+            sync: SyncService::default(),
+            chunks: ChunkCache::default(),
+            transfers: Transfers::default(),
+            playlists: PlaylistStore::default(),
+            schedule: Scheduler::default(),
+            player_playlist: PlayerPlaylist::default(),
+            metrics,
+        })
+    }
+}
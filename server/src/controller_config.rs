@@ -1,16 +1,79 @@
-use actix_web::{get, put, web, HttpResponse, Responder};
+use std::time::Duration;
 
+use actix_web::{get, patch, web, HttpResponse, Responder};
+use config::patch::ConfigPatch;
+use tracing::{error, info};
+
+use crate::{context::AppContext, error::ApiError};
+
+#[utoipa::path(
+    get,
+    path = "/config",
+    tag = "config",
+    responses((status = 200, description = "The node's config", body = Config))
+)]
 #[get("/config")]
-pub async fn get_config() -> impl Responder {
-    let cfg = config::get_config().await;
+pub async fn get_config(ctx: web::Data<AppContext>) -> impl Responder {
+    let cfg = ctx.config().get_config().await;
     HttpResponse::Ok().json(cfg)
 }
 
-#[put("/config/{name}")]
-pub async fn put_node_name(path: web::Path<String>) -> impl Responder {
-    todo!("update node name by node holder sender");
-    let name = path.into_inner();
-    let mut cfg = config::get_config().await;
-    cfg.set_node_name(name).await;
-    HttpResponse::Ok().json(cfg)
+/// Applies a partial config document and pushes the changes to the running subsystems.
+#[utoipa::path(
+    patch,
+    path = "/config",
+    tag = "config",
+    request_body = ConfigPatch,
+    responses(
+        (status = 200, description = "The config after the patch was applied", body = Config),
+        (status = 400, description = "The body is not a valid patch document", body = ErrorBody),
+        (status = 422, description = "A field failed validation", body = ErrorBody),
+        (status = 500, description = "The config could not be saved, or discovery failed to restart with it and the previous config was kept", body = ErrorBody),
+    )
+)]
+#[patch("/config")]
+pub async fn patch_config(
+    ctx: web::Data<AppContext>,
+    body: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let patch: ConfigPatch =
+        serde_json::from_slice(&body).map_err(|e| ApiError::InvalidJson(e.to_string()))?;
+    patch.validate().map_err(ApiError::InvalidConfig)?;
+
+    let (previous, cfg, effects) = match ctx
+        .config()
+        .update(|cfg| {
+            let previous = cfg.clone();
+            let effects = patch.apply(cfg);
+            (previous, cfg.clone(), effects)
+        })
+        .await
+    {
+        Ok(updated) => updated,
+        Err(e) => {
+            error!("Failed to persist config with error {:?}", e);
+            return Err(ApiError::Config(e));
+        }
+    };
+    info!("Config updated: {:?}", effects);
+
+    if effects.restart_discovery {
+        if let Err(e) = ctx.discovery().restart(&ctx, cfg.clone()).await {
+            // discovery still runs with the previous settings, so keep them
+            if let Err(e) = ctx.config().update(|cfg| *cfg = previous).await {
+                error!("Failed to restore the previous config with error {:?}", e);
+            }
+            return Err(ApiError::Discovery(e));
+        }
+    } else if effects.rename_node {
+        ctx.discovery().rename(cfg.node_name().to_string()).await;
+    }
+    let node_holder = ctx.node_holder();
+    if effects.node_timeout {
+        node_holder.set_timeout(Duration::from_secs(cfg.node_timeout() as u64));
+    }
+    if effects.max_nodes {
+        node_holder.set_max_nodes(cfg.discovery().max_nodes);
+    }
+    Ok(HttpResponse::Ok().json(cfg))
 }
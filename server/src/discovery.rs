@@ -0,0 +1,99 @@
+use std::sync::Arc;
+
+use config::model::Config;
+use discover::broadcast_server::BroadcastServer;
+use domain::node::Node;
+use tokio::{sync::Mutex, task::JoinHandle};
+use tracing::{error, info, warn};
+
+use crate::context::AppContext;
+
+#[derive(Debug)]
+struct Running {
+    node: Arc<Mutex<Node>>,
+    task: JoinHandle<()>,
+    /// The config the server was bound with, to bind it again when a
+    /// replacement fails.
+    config: Config,
+}
+
+impl Running {
+    async fn stop(self) {
+        self.task.abort();
+        // wait for the socket to be released before binding the port again
+        let _ = self.task.await;
+    }
+}
+
+/// Owns the broadcast server task so it can be restarted when its settings change.
+#[derive(Debug, Clone, Default)]
+pub struct DiscoveryService {
+    running: Arc<Mutex<Option<Running>>>,
+}
+
+impl DiscoveryService {
+    /// Binds the discovery socket from `config` and starts announcing this
+    /// node, replacing any previously running server. The previous server
+    /// keeps running when the new one can't be bound.
+    pub async fn start(&self, context: &AppContext, config: Config) -> anyhow::Result<()> {
+        let mut running = self.running.lock().await;
+        let holds_port = running
+            .as_ref()
+            .is_some_and(|it| it.config.board_port() == config.board_port());
+        let server = match bind(context, config.clone()).await {
+            Ok(server) => server,
+            // the running server holds the port, it is free only once it stopped
+            Err(_) if holds_port => {
+                let previous = running.take().unwrap();
+                let previous_config = previous.config.clone();
+                previous.stop().await;
+                match bind(context, config.clone()).await {
+                    Ok(server) => server,
+                    Err(e) => {
+                        warn!("Discovery can't be bound with the new settings, binding the previous ones again");
+                        match bind(context, previous_config.clone()).await {
+                            Ok(server) => {
+                                *running = Some(spawn(context, server, previous_config).await)
+                            }
+                            Err(e) => error!("Failed to bind discovery again with error {:?}", e),
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(previous) = running.take() {
+            previous.stop().await;
+        }
+        *running = Some(spawn(context, server, config).await);
+        info!("Discovery started");
+        Ok(())
+    }
+
+    pub async fn restart(&self, context: &AppContext, config: Config) -> anyhow::Result<()> {
+        self.start(context, config).await.inspect_err(|e| {
+            error!("Failed to restart discovery with error {:?}", e);
+        })
+    }
+
+    /// Changes the name announced in heartbeats without rebinding the socket.
+    pub async fn rename(&self, name: String) {
+        if let Some(running) = self.running.lock().await.as_ref() {
+            running.node.lock().await.update_name(name);
+        }
+    }
+}
+
+async fn bind(context: &AppContext, config: Config) -> anyhow::Result<BroadcastServer> {
+    BroadcastServer::from_config(config, context.node_holder().clone(), context.ids().clone()).await
+}
+
+async fn spawn(context: &AppContext, server: BroadcastServer, config: Config) -> Running {
+    let node = server.node.clone();
+    node.lock().await.cert_fingerprint = context.tls_fingerprint().cloned();
+    let task = tokio::spawn(async move {
+        server.scan_node().await;
+    });
+    Running { node, task, config }
+}
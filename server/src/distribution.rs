@@ -0,0 +1,672 @@
+use std::{
+    collections::BTreeMap,
+    io::SeekFrom,
+    path::PathBuf,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use actix_web::{get, post, web, HttpMessage, HttpRequest, HttpResponse};
+use domain::node::Node;
+use futures::StreamExt;
+use reqwest::{header::CONTENT_TYPE, Method, RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncSeekExt},
+    time::{sleep, sleep_until, Instant},
+};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    auth::Principal,
+    bulk::{resolve_targets, BulkTarget},
+    client,
+    context::AppContext,
+    error::ApiError,
+    upload::{sha256_file, NewUpload, StoredMedia, UPLOAD_OFFSET},
+};
+
+const DEFAULT_CONCURRENCY: usize = 2;
+const MAX_CONCURRENCY: usize = 16;
+const DEFAULT_ATTEMPTS: u32 = 3;
+const MAX_ATTEMPTS: u32 = 10;
+/// Bytes sent to a peer in one `PATCH`.
+const CHUNK_SIZE: usize = 1 << 20;
+/// Pause before the second attempt, growing with every further one.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+/// Limit for a single call to a peer, a chunk included.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+/// Distributions kept for the API, the oldest finished ones are dropped first.
+const MAX_KEPT: usize = 100;
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct NewDistribution {
+    /// File name of the video in the media root.
+    pub video: String,
+    pub target: BulkTarget,
+    /// Peers sent to at once, defaults to 2.
+    pub concurrency: Option<usize>,
+    /// Bytes per second sent to all peers together, unlimited when absent.
+    pub bandwidth: Option<u64>,
+    /// Tries per peer before it is given up, defaults to 3.
+    pub attempts: Option<u32>,
+    /// Replace a different file of the same name on the peers, which is
+    /// otherwise a failure.
+    #[serde(default)]
+    pub overwrite: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferState {
+    Pending,
+    Sending,
+    /// Waiting to try again, the next attempt resumes the peer's upload.
+    Retrying,
+    /// The peer stored the video and hashed it to the same SHA-256.
+    Verified,
+    /// The peer already holds the same content, possibly under another name.
+    AlreadyPresent,
+    Failed,
+}
+
+/// Progress of the video towards one peer.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct NodeTransfer {
+    pub id: i64,
+    pub name: String,
+    pub state: TransferState,
+    /// Bytes the peer has stored so far.
+    pub sent: u64,
+    pub attempts: u32,
+    /// SHA-256 the peer reported for the stored file.
+    pub sha256: Option<String>,
+    /// Why the last attempt failed, or what the peer said about a duplicate.
+    pub message: Option<String>,
+}
+
+/// A video being sent to a set of peers.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Distribution {
+    pub id: i64,
+    pub video: String,
+    pub size: u64,
+    /// Hex SHA-256 of the video, `None` until it has been hashed.
+    pub sha256: Option<String>,
+    /// Milliseconds since the unix epoch.
+    pub created_at: u128,
+    /// Set once every peer is verified or given up.
+    pub finished_at: Option<u128>,
+    /// Name of the API key that started the distribution.
+    pub principal: Option<String>,
+    pub nodes: Vec<NodeTransfer>,
+}
+
+/// Distributions of this node by id, held in memory.
+#[derive(Debug, Clone, Default)]
+pub struct DistributionStore {
+    distributions: Arc<Mutex<BTreeMap<i64, Distribution>>>,
+}
+
+impl DistributionStore {
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<i64, Distribution>> {
+        self.distributions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn insert(&self, distribution: Distribution) {
+        let mut distributions = self.lock();
+        distributions.insert(distribution.id, distribution);
+        while distributions.len() > MAX_KEPT {
+            let oldest = distributions
+                .values()
+                .find(|it| it.finished_at.is_some())
+                .map(|it| it.id);
+            match oldest {
+                Some(id) => distributions.remove(&id),
+                None => break,
+            };
+        }
+    }
+
+    pub fn get(&self, id: i64) -> Option<Distribution> {
+        self.lock().get(&id).cloned()
+    }
+
+    /// Every kept distribution, newest first.
+    pub fn list(&self) -> Vec<Distribution> {
+        self.lock().values().rev().cloned().collect()
+    }
+
+    fn update(&self, id: i64, f: impl FnOnce(&mut Distribution)) {
+        if let Some(distribution) = self.lock().get_mut(&id) {
+            f(distribution);
+        }
+    }
+
+    fn update_node(&self, id: i64, node: i64, f: impl FnOnce(&mut NodeTransfer)) {
+        self.update(id, |distribution| {
+            if let Some(transfer) = distribution.nodes.iter_mut().find(|it| it.id == node) {
+                f(transfer);
+            }
+        });
+    }
+}
+
+/// Spreads the bytes sent over time so they stay under a rate.
+#[derive(Debug)]
+struct Throttle {
+    bytes_per_sec: u64,
+    next: Mutex<Instant>,
+}
+
+impl Throttle {
+    fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// When `bytes` may be sent, the time they take at the rate is booked
+    /// after that.
+    fn reserve(&self, now: Instant, bytes: usize) -> Instant {
+        let mut next = self.next.lock().unwrap_or_else(PoisonError::into_inner);
+        let start = (*next).max(now);
+        *next = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+        start
+    }
+
+    async fn wait(&self, bytes: usize) {
+        sleep_until(self.reserve(Instant::now(), bytes)).await;
+    }
+}
+
+/// How an attempt ended without an error.
+enum Done {
+    Verified(String),
+    AlreadyPresent(String),
+}
+
+/// What the peer made of a new upload.
+enum Created {
+    /// The url of the upload.
+    Upload(String),
+    /// What the peer said when it already holds the content.
+    Duplicate(String),
+}
+
+/// Why an attempt failed, and whether trying again can help.
+enum Failure {
+    Retry(String),
+    Fatal(String),
+}
+
+/// What the transfers of one distribution share.
+struct Job {
+    ctx: AppContext,
+    id: i64,
+    path: PathBuf,
+    upload: NewUpload,
+    throttle: Option<Throttle>,
+    attempts: u32,
+}
+
+impl Job {
+    fn request(&self, node: &Node, method: Method, path: &str) -> RequestBuilder {
+        let path = path.trim_start_matches('/');
+        client::peer_request(&self.ctx, node, method, path).timeout(REQUEST_TIMEOUT)
+    }
+
+    fn progress(&self, node: &Node, f: impl FnOnce(&mut NodeTransfer)) {
+        self.ctx.distributions().update_node(self.id, node.id, f);
+    }
+
+    /// Tries to get the video onto `node`, resuming the peer's upload after
+    /// a failed attempt.
+    async fn transfer(&self, node: Node) {
+        let mut location = None;
+        for attempt in 1..=self.attempts {
+            self.progress(&node, |it| {
+                it.state = TransferState::Sending;
+                it.attempts = attempt;
+            });
+            let (state, sha256, message) = match self.attempt(&node, &mut location).await {
+                Ok(Done::Verified(sha256)) => (TransferState::Verified, Some(sha256), None),
+                Ok(Done::AlreadyPresent(message)) => {
+                    (TransferState::AlreadyPresent, None, Some(message))
+                }
+                Err(Failure::Fatal(message)) => (TransferState::Failed, None, Some(message)),
+                Err(Failure::Retry(message)) if attempt < self.attempts => {
+                    warn!(
+                        "Attempt {} to send {:?} to node {} failed: {}",
+                        attempt, self.upload.name, node.id, message
+                    );
+                    self.progress(&node, |it| {
+                        it.state = TransferState::Retrying;
+                        it.message = Some(message);
+                    });
+                    sleep(RETRY_DELAY * attempt).await;
+                    continue;
+                }
+                Err(Failure::Retry(message)) => (TransferState::Failed, None, Some(message)),
+            };
+            info!(
+                "Distribution {} to node {} ended as {:?}",
+                self.id, node.id, state
+            );
+            self.progress(&node, |it| {
+                it.state = state;
+                it.sha256 = sha256;
+                it.message = message;
+            });
+            return;
+        }
+    }
+
+    async fn attempt(&self, node: &Node, location: &mut Option<String>) -> Result<Done, Failure> {
+        let resumed = match location.as_deref() {
+            Some(upload) => self
+                .offset(node, upload)
+                .await?
+                .map(|offset| (upload.to_string(), offset)),
+            None => None,
+        };
+        let (upload, mut offset) = match resumed {
+            Some(resumed) => resumed,
+            None => match self.create(node).await? {
+                Created::Upload(upload) => (upload, 0),
+                Created::Duplicate(message) => return Ok(Done::AlreadyPresent(message)),
+            },
+        };
+        *location = Some(upload.clone());
+
+        let local = |e: std::io::Error| Failure::Fatal(format!("Failed to read the video: {e}"));
+        let mut file = File::open(&self.path).await.map_err(local)?;
+        file.seek(SeekFrom::Start(offset)).await.map_err(local)?;
+        let mut buffer = vec![0; CHUNK_SIZE];
+        while offset < self.upload.size {
+            let len = (self.upload.size - offset).min(CHUNK_SIZE as u64) as usize;
+            file.read_exact(&mut buffer[..len]).await.map_err(local)?;
+            if let Some(throttle) = &self.throttle {
+                throttle.wait(len).await;
+            }
+            let response = self
+                .request(node, Method::PATCH, &upload)
+                .header(UPLOAD_OFFSET, offset.to_string())
+                .header(CONTENT_TYPE, "application/offset+octet-stream")
+                .body(buffer[..len].to_vec())
+                .send()
+                .await
+                .map_err(unreachable)?;
+            if !response.status().is_success() {
+                // a conflict means the offset moved, the next attempt asks for it
+                return Err(refused(response).await);
+            }
+            offset = stored_offset(&response).unwrap_or(offset + len as u64);
+            self.ctx.metrics().add_distribution_bytes(len);
+            self.progress(node, |it| it.sent = offset);
+        }
+        self.finish(node, &upload, location).await
+    }
+
+    /// Where the peer's upload continues, `None` when it is gone.
+    async fn offset(&self, node: &Node, upload: &str) -> Result<Option<u64>, Failure> {
+        let response = self
+            .request(node, Method::HEAD, upload)
+            .send()
+            .await
+            .map_err(unreachable)?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(stored_offset(&response).unwrap_or(0))),
+            _ => Err(refused(response).await),
+        }
+    }
+
+    async fn create(&self, node: &Node) -> Result<Created, Failure> {
+        let response = self
+            .request(node, Method::POST, "uploads")
+            .json(&self.upload)
+            .send()
+            .await
+            .map_err(unreachable)?;
+        if response.status() == StatusCode::CONFLICT {
+            return match peer_error(response).await {
+                (Some(code), message) if code == "duplicate_media" => {
+                    Ok(Created::Duplicate(message))
+                }
+                (Some(code), message) if code == "media_exists" => Err(Failure::Fatal(message)),
+                (_, message) => Err(Failure::Retry(message)),
+            };
+        }
+        if !response.status().is_success() {
+            return Err(refused(response).await);
+        }
+        let upload = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|it| it.to_str().ok())
+            .map(str::to_string)
+            .ok_or_else(|| Failure::Fatal("The peer did not name the upload".to_string()))?;
+        self.progress(node, |it| it.sent = 0);
+        Ok(Created::Upload(upload))
+    }
+
+    async fn finish(
+        &self,
+        node: &Node,
+        upload: &str,
+        location: &mut Option<String>,
+    ) -> Result<Done, Failure> {
+        let response = self
+            .request(node, Method::POST, &format!("{upload}/finish"))
+            .send()
+            .await
+            .map_err(unreachable)?;
+        let status = response.status();
+        if status.is_success() {
+            let stored: StoredMedia = response
+                .json()
+                .await
+                .map_err(|e| Failure::Retry(format!("Unreadable answer from the peer: {e}")))?;
+            return if stored.sha256.eq_ignore_ascii_case(&self.upload.sha256) {
+                Ok(Done::Verified(stored.sha256))
+            } else {
+                Err(Failure::Fatal(format!(
+                    "The peer stored a file hashing to {}",
+                    stored.sha256
+                )))
+            };
+        }
+        // the peer removes the upload when it fails the hash or duplicates content
+        let (code, message) = peer_error(response).await;
+        match (status, code.as_deref()) {
+            (StatusCode::UNPROCESSABLE_ENTITY, _) => {
+                *location = None;
+                Err(Failure::Retry(message))
+            }
+            (StatusCode::CONFLICT, Some("duplicate_media")) => {
+                *location = None;
+                Ok(Done::AlreadyPresent(message))
+            }
+            (StatusCode::CONFLICT, Some("media_exists")) => Err(Failure::Fatal(message)),
+            (status, _) if retryable(status) => Err(Failure::Retry(message)),
+            _ => Err(Failure::Fatal(message)),
+        }
+    }
+}
+
+fn unreachable(e: reqwest::Error) -> Failure {
+    Failure::Retry(format!("The peer is unreachable: {e}"))
+}
+
+/// Server errors and conflicts may pass, anything else the peer refuses the
+/// same way again.
+fn retryable(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::CONFLICT
+        || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+async fn refused(response: Response) -> Failure {
+    let status = response.status();
+    let (_, message) = peer_error(response).await;
+    if retryable(status) {
+        Failure::Retry(message)
+    } else {
+        Failure::Fatal(message)
+    }
+}
+
+/// The `code` and a readable message of a peer's error response.
+async fn peer_error(response: Response) -> (Option<String>, String) {
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    let body: Option<serde_json::Value> = serde_json::from_str(&body).ok();
+    let field = |name: &str| {
+        body.as_ref()
+            .and_then(|it| it[name].as_str())
+            .map(str::to_string)
+    };
+    let message = match field("message") {
+        Some(message) => format!("The peer answered {status}: {message}"),
+        None => format!("The peer answered {status}"),
+    };
+    (field("code"), message)
+}
+
+fn stored_offset(response: &Response) -> Option<u64> {
+    response
+        .headers()
+        .get(UPLOAD_OFFSET)
+        .and_then(|it| it.to_str().ok())
+        .and_then(|it| it.parse().ok())
+}
+
+/// Hashes the video and sends it to every targeted node, `concurrency` at a time.
+async fn run(mut job: Job, nodes: Vec<Node>, concurrency: usize) {
+    let id = job.id;
+    match sha256_file(job.path.clone()).await {
+        Ok(sha256) => {
+            job.upload.sha256 = sha256.clone();
+            job.ctx
+                .distributions()
+                .update(id, |it| it.sha256 = Some(sha256));
+            let job = &job;
+            futures::stream::iter(nodes)
+                .for_each_concurrent(concurrency, |node| job.transfer(node))
+                .await;
+        }
+        Err(e) => {
+            warn!("Failed to hash {:?} with error {:?}", job.path, e);
+            let message = format!("Failed to hash the video: {e}");
+            for node in &nodes {
+                job.progress(node, |it| {
+                    it.state = TransferState::Failed;
+                    it.message = Some(message.clone());
+                });
+            }
+        }
+    }
+    let finished_at = job.ctx.clock().now_millis();
+    job.ctx
+        .distributions()
+        .update(id, |it| it.finished_at = Some(finished_at));
+    info!("Distribution {} of {:?} finished", id, job.upload.name);
+}
+
+fn pending(id: i64, name: String) -> NodeTransfer {
+    NodeTransfer {
+        id,
+        name,
+        state: TransferState::Pending,
+        sent: 0,
+        attempts: 0,
+        sha256: None,
+        message: None,
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/distributions",
+    tag = "media",
+    request_body = NewDistribution,
+    responses(
+        (status = 202, description = "The video is being sent, follow its progress at the url in the Location header", body = Distribution),
+        (status = 400, description = "The name tries to leave the media root, or a limit is 0", body = ErrorBody),
+        (status = 404, description = "The video or a targeted group doesn't exist", body = ErrorBody),
+    )
+)]
+#[post("/distributions")]
+pub async fn create_distribution(
+    ctx: web::Data<AppContext>,
+    req: HttpRequest,
+    body: web::Json<NewDistribution>,
+) -> Result<HttpResponse, ApiError> {
+    let request = body.into_inner();
+    if request.concurrency == Some(0) || request.bandwidth == Some(0) || request.attempts == Some(0)
+    {
+        return Err(ApiError::BadRequest(
+            "concurrency, bandwidth and attempts must be greater than 0".to_string(),
+        ));
+    }
+    let path = ctx.media_root().await?.resolve(&request.video)?;
+    let size = fs::metadata(&path).await?.len();
+    let (nodes, unknown) = resolve_targets(&ctx, &request.target).await?;
+
+    let id = ctx.ids().generate();
+    let principal = req
+        .extensions()
+        .get::<Principal>()
+        .map(|it| it.name.clone());
+    let mut transfers: Vec<NodeTransfer> = nodes
+        .iter()
+        .map(|it| pending(it.id, it.name.clone()))
+        .collect();
+    transfers.extend(unknown.into_iter().map(|id| NodeTransfer {
+        state: TransferState::Failed,
+        message: Some("unknown node".to_string()),
+        ..pending(id, String::new())
+    }));
+    let distribution = Distribution {
+        id,
+        video: request.video.clone(),
+        size,
+        sha256: None,
+        created_at: ctx.clock().now_millis(),
+        finished_at: None,
+        principal,
+        nodes: transfers,
+    };
+    ctx.distributions().insert(distribution.clone());
+
+    let job = Job {
+        ctx: ctx.get_ref().clone(),
+        id,
+        path,
+        upload: NewUpload {
+            name: request.video,
+            size,
+            sha256: String::new(),
+            overwrite: request.overwrite,
+        },
+        throttle: request.bandwidth.map(Throttle::new),
+        attempts: request
+            .attempts
+            .unwrap_or(DEFAULT_ATTEMPTS)
+            .min(MAX_ATTEMPTS),
+    };
+    let concurrency = request
+        .concurrency
+        .unwrap_or(DEFAULT_CONCURRENCY)
+        .min(MAX_CONCURRENCY);
+    info!(
+        "Distributing {:?} to {} nodes as {}",
+        job.upload.name,
+        nodes.len(),
+        id
+    );
+    tokio::spawn(run(job, nodes, concurrency));
+    Ok(HttpResponse::Accepted()
+        .insert_header(("Location", format!("/distributions/{id}")))
+        .json(distribution))
+}
+
+#[utoipa::path(
+    get,
+    path = "/distributions",
+    tag = "media",
+    responses((status = 200, description = "Distributions started on this node, newest first", body = [Distribution]))
+)]
+#[get("/distributions")]
+pub async fn get_distributions(ctx: web::Data<AppContext>) -> HttpResponse {
+    HttpResponse::Ok().json(ctx.distributions().list())
+}
+
+#[utoipa::path(
+    get,
+    path = "/distributions/{id}",
+    tag = "media",
+    params(("id" = i64, Path, description = "Id of the distribution")),
+    responses(
+        (status = 200, description = "Progress towards every targeted node", body = Distribution),
+        (status = 404, description = "No such distribution", body = ErrorBody),
+    )
+)]
+#[get("/distributions/{id}")]
+pub async fn get_distribution(
+    ctx: web::Data<AppContext>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let distribution = ctx
+        .distributions()
+        .get(id)
+        .ok_or_else(|| ApiError::NotFound(format!("Distribution {id} not found")))?;
+    Ok(HttpResponse::Ok().json(distribution))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn distribution(id: i64, finished_at: Option<u128>) -> Distribution {
+        Distribution {
+            id,
+            video: "intro.mp4".to_string(),
+            size: 10,
+            sha256: None,
+            created_at: 0,
+            finished_at,
+            principal: None,
+            nodes: vec![pending(1, "node-1".to_string())],
+        }
+    }
+
+    #[test]
+    fn test_throttle_spreads_bytes() {
+        let throttle = Throttle::new(1000);
+        let now = Instant::now();
+        assert_eq!(throttle.reserve(now, 500), now);
+        assert_eq!(throttle.reserve(now, 500), now + Duration::from_millis(500));
+        assert_eq!(throttle.reserve(now, 1), now + Duration::from_secs(1));
+        // idle time is not saved up for a burst
+        let later = now + Duration::from_secs(10);
+        assert_eq!(throttle.reserve(later, 1), later);
+    }
+
+    #[test]
+    fn test_store_drops_oldest_finished() {
+        let store = DistributionStore::default();
+        store.insert(distribution(0, None));
+        for id in 1..=MAX_KEPT as i64 {
+            store.insert(distribution(id, Some(1)));
+        }
+        assert!(store.get(0).is_some());
+        assert!(store.get(1).is_none());
+        assert_eq!(store.list().len(), MAX_KEPT);
+        assert_eq!(store.list()[0].id, MAX_KEPT as i64);
+
+        store.update_node(0, 1, |it| it.sent = 4);
+        assert_eq!(store.get(0).unwrap().nodes[0].sent, 4);
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let request: NewDistribution = serde_json::from_str(
+            r#"{"video":"intro.mp4","target":{"groups":["lobby"]},"bandwidth":1048576}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            request.target,
+            BulkTarget::Groups(vec!["lobby".to_string()])
+        );
+        assert_eq!(request.bandwidth, Some(1 << 20));
+        assert_eq!(request.concurrency, None);
+    }
+}
@@ -0,0 +1,254 @@
+use std::{fmt, io};
+
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use config::patch::FieldError;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::media::MediaError;
+
+/// The body of every error response, `code` is stable and meant for clients
+/// to match on, `message` is for people.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    #[schema(value_type = String)]
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+impl ErrorBody {
+    pub fn new(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            errors: vec![],
+        }
+    }
+}
+
+/// What went wrong while handling a request.
+#[derive(Debug)]
+pub enum ApiError {
+    /// A path parameter or body that can't be used.
+    BadRequest(String),
+    NotFound(String),
+    /// The body is not the JSON document the endpoint expects.
+    InvalidJson(String),
+    InvalidConfig(Vec<FieldError>),
+    /// A file name that tries to leave the media root.
+    InvalidPath(String),
+    OutsideMediaRoot(String),
+    /// A chunk doesn't start where the stored part of the upload ends.
+    OffsetMismatch {
+        expected: u64,
+    },
+    /// Another request is writing to the same upload.
+    UploadBusy(i64),
+    UploadIncomplete {
+        received: u64,
+        size: u64,
+    },
+    /// The finished upload doesn't hash to the announced SHA-256, it was discarded.
+    ChecksumMismatch {
+        expected: String,
+        actual: String,
+    },
+    /// A file or chunk beyond the allowed or announced size.
+    TooLarge(String),
+    /// The media root already holds a file with the same content, by its name.
+    Duplicate(String),
+    /// The media root already holds a file of this name and the upload
+    /// doesn't replace it.
+    Exists(String),
+    Io(io::Error),
+    /// The config file could not be written.
+    Config(anyhow::Error),
+    Discovery(anyhow::Error),
+    /// The player's control API is unreachable or refused the request.
+    Player(String),
+    /// Starting or stopping the player process failed.
+    PlayerCommand(String),
+    NodeUnreachable(i64),
+    /// A playlist that schedule rules still play, by the ids of the rules.
+    PlaylistInUse(String),
+    /// A schedule that doesn't come from the configured coordinator.
+    NotCoordinator(String),
+    Internal(anyhow::Error),
+}
+
+impl ApiError {
+    fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::InvalidJson(_) => "invalid_json",
+            ApiError::InvalidConfig(_) => "invalid_config",
+            ApiError::InvalidPath(_) => "invalid_path",
+            ApiError::OutsideMediaRoot(_) => "outside_media_root",
+            ApiError::OffsetMismatch { .. } => "offset_mismatch",
+            ApiError::UploadBusy(_) => "upload_busy",
+            ApiError::UploadIncomplete { .. } => "upload_incomplete",
+            ApiError::ChecksumMismatch { .. } => "checksum_mismatch",
+            ApiError::TooLarge(_) => "too_large",
+            ApiError::Duplicate(_) => "duplicate_media",
+            ApiError::Exists(_) => "media_exists",
+            ApiError::Io(e) => match e.kind() {
+                io::ErrorKind::NotFound => "not_found",
+                io::ErrorKind::PermissionDenied => "permission_denied",
+                _ => "io_error",
+            },
+            ApiError::Config(_) => "config_not_saved",
+            ApiError::Discovery(_) => "discovery_failed",
+            ApiError::Player(_) => "player_unavailable",
+            ApiError::PlayerCommand(_) => "player_command_failed",
+            ApiError::NodeUnreachable(_) => "node_unreachable",
+            ApiError::PlaylistInUse(_) => "playlist_in_use",
+            ApiError::NotCoordinator(_) => "not_coordinator",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::NotFound(message)
+            | ApiError::InvalidJson(message)
+            | ApiError::InvalidPath(message)
+            | ApiError::OutsideMediaRoot(message)
+            | ApiError::TooLarge(message)
+            | ApiError::Player(message)
+            | ApiError::PlayerCommand(message)
+            | ApiError::NotCoordinator(message) => f.write_str(message),
+            ApiError::InvalidConfig(_) => write!(f, "Config validation failed"),
+            ApiError::OffsetMismatch { expected } => {
+                write!(f, "The upload continues at byte {expected}")
+            }
+            ApiError::UploadBusy(id) => {
+                write!(f, "Upload {id} is being written by another request")
+            }
+            ApiError::UploadIncomplete { received, size } => {
+                write!(f, "Only {received} of {size} bytes were uploaded")
+            }
+            ApiError::Duplicate(name) => write!(f, "The same file is already stored as {name:?}"),
+            ApiError::Exists(name) => {
+                write!(f, "{name:?} already exists, set overwrite to replace it")
+            }
+            ApiError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "The upload hashes to {actual} instead of {expected} and was discarded"
+            ),
+            ApiError::Io(e) if e.kind() == io::ErrorKind::NotFound => write!(f, "File not found"),
+            ApiError::Io(e) => write!(f, "File operation failed: {e}"),
+            ApiError::Config(e) => write!(f, "Failed to save the config: {e}"),
+            ApiError::Discovery(e) => write!(f, "Discovery failed: {e}"),
+            ApiError::NodeUnreachable(id) => write!(f, "Node {id} is unreachable"),
+            ApiError::PlaylistInUse(rules) => {
+                write!(f, "The schedule rules {rules} play the playlist")
+            }
+            ApiError::Internal(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(e: io::Error) -> Self {
+        ApiError::Io(e)
+    }
+}
+
+impl From<MediaError> for ApiError {
+    fn from(e: MediaError) -> Self {
+        match e {
+            MediaError::InvalidName(_) => ApiError::InvalidPath(e.to_string()),
+            MediaError::OutsideRoot(_) => ApiError::OutsideMediaRoot(e.to_string()),
+            MediaError::Io(e) => ApiError::Io(e),
+        }
+    }
+}
+
+impl From<actix_web::error::BlockingError> for ApiError {
+    fn from(e: actix_web::error::BlockingError) -> Self {
+        ApiError::Internal(anyhow::anyhow!("{e}"))
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) | ApiError::InvalidJson(_) | ApiError::InvalidPath(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            ApiError::OutsideMediaRoot(_) | ApiError::NotCoordinator(_) => StatusCode::FORBIDDEN,
+            ApiError::OffsetMismatch { .. }
+            | ApiError::UploadBusy(_)
+            | ApiError::UploadIncomplete { .. }
+            | ApiError::Duplicate(_)
+            | ApiError::Exists(_)
+            | ApiError::PlaylistInUse(_) => StatusCode::CONFLICT,
+            ApiError::ChecksumMismatch { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::InvalidConfig(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Io(e) => match e.kind() {
+                io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            ApiError::Player(_) | ApiError::NodeUnreachable(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Config(_)
+            | ApiError::Discovery(_)
+            | ApiError::PlayerCommand(_)
+            | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut body = ErrorBody::new(self.code(), self.to_string());
+        if let ApiError::InvalidConfig(errors) = self {
+            body.errors = errors.clone();
+        }
+        HttpResponse::build(self.status_code()).json(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::to_bytes;
+
+    use super::*;
+
+    async fn body(error: ApiError) -> serde_json::Value {
+        let bytes = to_bytes(error.error_response().into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_io_errors() {
+        let missing = ApiError::from(io::Error::from(io::ErrorKind::NotFound));
+        assert_eq!(missing.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(body(missing).await["code"], "not_found");
+
+        let full = ApiError::from(io::Error::other("disk full"));
+        assert_eq!(full.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        let full = body(full).await;
+        assert_eq!(full["code"], "io_error");
+        assert_eq!(full["message"], "File operation failed: disk full");
+        assert!(full.get("errors").is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_field_errors_are_listed() {
+        let error = ApiError::InvalidConfig(vec![FieldError {
+            field: "board_port".to_string(),
+            message: "must not be 0".to_string(),
+        }]);
+        assert_eq!(error.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = body(error).await;
+        assert_eq!(body["code"], "invalid_config");
+        assert_eq!(body["errors"][0]["field"], "board_port");
+    }
+}
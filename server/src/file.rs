@@ -1,26 +1,43 @@
+use std::path::Path;
+
 use actix_files::{Files, NamedFile};
-use actix_web::HttpRequest;
+use actix_web::{web, HttpRequest};
 use tracing::info;
 
-pub async fn download_file(req: HttpRequest) -> actix_web::Result<NamedFile> {
+use crate::{context::AppContext, error::ApiError};
+
+#[utoipa::path(
+    get,
+    path = "/download/{filename}",
+    tag = "media",
+    params(("filename" = String, Path, description = "Path relative to the media root")),
+    responses(
+        (status = 200, description = "The file contents", content_type = "application/octet-stream"),
+        (status = 400, description = "The path tries to leave the media root", body = ErrorBody),
+        (status = 403, description = "The path resolves outside the media root", body = ErrorBody),
+        (status = 404, description = "The file doesn't exist", body = ErrorBody),
+    )
+)]
+pub async fn download_file(
+    ctx: web::Data<AppContext>,
+    req: HttpRequest,
+) -> Result<NamedFile, ApiError> {
     let path = req.match_info().query("filename");
-    let path = format!("./{path}");
+    let path = ctx.media_root().await?.resolve(path)?;
     info!("path: {:?}", path);
     Ok(NamedFile::open(path)?)
 }
 
-pub fn static_file() -> Files {
-    Files::new("/static", "./static")
+pub fn static_file(static_root: &Path) -> Files {
+    Files::new("/static", static_root)
         .show_files_listing()
         .index_file("index.html")
 }
 
-pub fn assets_file() -> Files {
-    Files::new("/assets", "./static/assets")
-        .show_files_listing()
+pub fn assets_file(static_root: &Path) -> Files {
+    Files::new("/assets", static_root.join("assets")).show_files_listing()
 }
 
-pub fn assets_icon() -> Files {
-    Files::new("/libai.svg", "./static/libai.svg")
-        .show_files_listing()
+pub fn assets_icon(static_root: &Path) -> Files {
+    Files::new("/libai.svg", static_root.join("libai.svg")).show_files_listing()
 }
@@ -1,108 +1,349 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::{atomic::AtomicU64, Arc},
+    time::Duration,
+};
 
 use actix_cors::Cors;
 use actix_web::{
+    delete,
+    dev::{Service, ServiceResponse},
     get, middleware,
-    web::{delete, get, post, Data},
+    web::{delete, get, post, route, Data, Path, ServiceConfig},
     App, HttpResponse, HttpServer, Responder,
 };
-use controller_config::{get_config, put_node_name};
-use discover::{broadcast_server::BroadcastServer, node_holder};
+use actix_web_prom::PrometheusMetricsBuilder;
+use audit::get_audit;
+use auth::{login, logout};
+use bulk::{bulk_action, bulk_delete_video};
+use catalogue::{get_catalogue, get_duplicates};
+use context::AppContext;
+use controller_config::{get_config, patch_config};
+use distribution::{create_distribution, get_distribution, get_distributions};
+use error::ApiError;
 use file::{assets_file, download_file, static_file};
+use futures::{
+    future::{ready, Either},
+    TryFutureExt,
+};
+use metrics::Metrics;
+use openapi::openapi_json;
+use playlist::{
+    assign_group, assign_node, create_playlist, delete_player_playlist, delete_playlist,
+    get_assignments, get_node_playlist, get_player_playlist, get_playlist, get_playlists,
+    put_player_playlist, put_playlist, unassign_group, unassign_node,
+};
+use remote::forward_to_node;
+use schedule::{
+    create_rule, delete_rule, get_divergences, get_local_schedule, get_rule, get_rules, preview,
+    put_local_schedule, put_rule,
+};
 use screen_controller::screenshot;
+use sync::{get_manifest, get_sync_status, publish_manifest, put_manifest};
+use tls::{delete_pin, get_pins};
 use tokio::sync::{
     mpsc::{channel, Receiver},
     Mutex,
 };
+use tracing::{error, warn};
+use transfer::{get_chunk, get_chunk_list, get_transfers};
+use upload::{create_upload, delete_upload, finish_upload, get_upload, patch_upload};
 use video::{
     delete_video, download_video, kill_player, open_player, pause, play, upload_video, video_list,
 };
 
+pub mod audit;
+pub mod auth;
+pub mod bulk;
+pub mod catalogue;
 pub mod client;
+pub mod context;
 pub mod controller_config;
+pub mod discovery;
+pub mod distribution;
+pub mod error;
 pub mod file;
+pub mod library;
+pub mod media;
+pub mod metrics;
+pub mod openapi;
+pub mod permission;
+pub mod playlist;
+pub mod remote;
+pub mod schedule;
 pub mod screen_controller;
+pub mod sync;
+pub mod tls;
+pub mod transfer;
+pub mod upload;
 pub mod video;
 
+#[utoipa::path(
+    get,
+    path = "/health",
+    tag = "system",
+    security(()),
+    responses((status = 200, description = "The server is up", body = String, content_type = "text/plain"))
+)]
 pub async fn health() -> impl Responder {
     HttpResponse::Ok().body("UP")
 }
 
+#[utoipa::path(
+    get,
+    path = "/",
+    tag = "system",
+    security(()),
+    responses((status = 302, description = "Redirects to the web interface"))
+)]
 pub async fn index() -> impl Responder {
     HttpResponse::Found()
         .append_header(("location", "/static/index.html"))
         .finish()
 }
 
+#[utoipa::path(
+    get,
+    path = "/nodes",
+    tag = "nodes",
+    responses((status = 200, description = "Every known node, this one included", body = [Node]))
+)]
 #[get("/nodes")]
-pub async fn get_nodes() -> impl Responder {
-    let nodes = node_holder::get_node_list().await;
+pub async fn get_nodes(ctx: Data<AppContext>) -> impl Responder {
+    let nodes = ctx.node_holder().get_node_list().await;
     HttpResponse::Ok().json(nodes)
 }
 
-pub async fn screen_shot() -> Receiver<Vec<u8>> {
+#[utoipa::path(
+    delete,
+    path = "/nodes/{id}",
+    tag = "nodes",
+    params(("id" = i64, Path, description = "Node id")),
+    responses(
+        (status = 204, description = "The node was removed from the node list"),
+        (status = 404, description = "No node has this id", body = ErrorBody),
+    )
+)]
+#[delete("/nodes/{id}")]
+pub async fn remove_node(ctx: Data<AppContext>, path: Path<i64>) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner();
+    let node = ctx
+        .node_holder()
+        .get_node(id)
+        .await
+        .ok_or_else(|| ApiError::NotFound(format!("Node {id} not found")))?;
+    ctx.node_holder()
+        .remove_node(node)
+        .await
+        .map_err(ApiError::Discovery)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    get,
+    path = "/discovery/stats",
+    tag = "nodes",
+    responses((status = 200, description = "Discovery counters", body = DiscoveryStatsSnapshot))
+)]
+#[get("/discovery/stats")]
+pub async fn get_discovery_stats(ctx: Data<AppContext>) -> impl Responder {
+    HttpResponse::Ok().json(ctx.node_holder().stats().snapshot())
+}
+
+pub async fn screen_shot(metrics: Metrics) -> Receiver<Vec<u8>> {
     let (tx, rx) = channel(1);
     tokio::spawn(async move {
-        let capture = screen::ScreenCapture::new(tx);
+        let capture = screen::ScreenCapture::new(tx).with_observer(Arc::new(metrics));
         capture.capture().await;
     });
     rx
 }
 
-pub async fn clear() {
-    cleaner::Cleaner::new_date(
-        "broadcast_log".to_string(),
-        Duration::from_secs(5 * 24 * 3600),
-    )
-    .clean()
-    .await;
+pub async fn clear(log_folder: String, deleted: Arc<AtomicU64>) {
+    cleaner::Cleaner::new_date(log_folder, Duration::from_secs(5 * 24 * 3600))
+        .with_deleted_counter(deleted)
+        .clean()
+        .await;
 }
 
-pub async fn run_broadcast_server() -> anyhow::Result<()> {
-    let config = config::get_config().await;
-    BroadcastServer::from_config(config).await.scan_node().await;
-    Ok(())
+/// The API routes, each of them described in [`openapi::ApiDoc`].
+pub fn routes(cfg: &mut ServiceConfig) {
+    cfg.service(get_nodes)
+        .service(remove_node)
+        .service(get_audit)
+        .service(get_discovery_stats)
+        .service(get_config)
+        .service(patch_config)
+        .service(login)
+        .service(logout)
+        .service(openapi_json)
+        .service(create_upload)
+        .service(get_upload)
+        .service(patch_upload)
+        .service(finish_upload)
+        .service(delete_upload)
+        .service(get_catalogue)
+        .service(get_duplicates)
+        .service(create_distribution)
+        .service(get_distributions)
+        .service(get_distribution)
+        .service(get_manifest)
+        .service(put_manifest)
+        .service(get_sync_status)
+        .service(publish_manifest)
+        .service(get_chunk_list)
+        .service(get_chunk)
+        .service(get_transfers)
+        .service(get_pins)
+        .service(delete_pin)
+        .service(create_playlist)
+        .service(get_playlists)
+        .service(get_playlist)
+        .service(put_playlist)
+        .service(delete_playlist)
+        .service(get_assignments)
+        .service(get_node_playlist)
+        .service(assign_node)
+        .service(unassign_node)
+        .service(assign_group)
+        .service(unassign_group)
+        .service(create_rule)
+        .service(get_rules)
+        .service(get_rule)
+        .service(put_rule)
+        .service(delete_rule)
+        .service(preview)
+        .service(get_local_schedule)
+        .service(put_local_schedule)
+        .service(get_divergences)
+        .service(get_player_playlist)
+        .service(put_player_playlist)
+        .service(delete_player_playlist)
+        .route("/", get().to(index))
+        .route("/download/{filename:.*}", get().to(download_file))
+        .route("/health", get().to(health))
+        .service(screenshot)
+        .route("/video_list", get().to(video_list))
+        .route("/video_list/{video}", get().to(download_video))
+        .route("/video_list/{video}", post().to(upload_video))
+        .route("/video_list/{video}", delete().to(delete_video))
+        .route("/play", get().to(play))
+        .route("/pause", get().to(pause))
+        .route("/open_player", get().to(open_player))
+        .route("/kill_player", get().to(kill_player))
+        .route("/nodes/{id}/{tail:.*}", route().to(forward_to_node))
+        .route("/bulk/video_list/{video}", delete().to(bulk_delete_video))
+        .route("/bulk/{action}", post().to(bulk_action));
 }
 
-async fn init() {
-    let config = config::get_config().await;
-    node_holder::set_node_list(config.node_list().to_vec()).await;
-    tokio::spawn(run_broadcast_server());
-    tokio::spawn(node_holder::run_node_holder());
-    tokio::spawn(clear());
+async fn init(context: &AppContext) {
+    if let Err(e) = auth::bootstrap(context).await {
+        error!("Failed to set up authentication with error {:?}", e);
+    }
+    let config = context.config().get_config().await;
+    context
+        .peer_pins()
+        .open(config.server().tls.pins_path.clone());
+    context
+        .audit()
+        .set_path(config.server().audit_log.clone())
+        .await;
+    context
+        .audit()
+        .set_max_bytes(config.server().audit_max_bytes);
+    context
+        .uploads()
+        .set_dir(config.server().upload_dir.clone())
+        .await;
+    context
+        .catalogue()
+        .open(config.server().catalogue.clone())
+        .await;
+    catalogue::reconcile_later(context);
+    context
+        .sync()
+        .open(
+            config.server().sync_manifest.clone(),
+            config.server().group_manifests.clone(),
+        )
+        .await;
+    context
+        .playlists()
+        .open(config.server().playlists.clone())
+        .await;
+    context
+        .schedule()
+        .open(
+            config.server().schedule.clone(),
+            config.server().schedule_cache.clone(),
+            config.server().coordination.clone(),
+        )
+        .await;
+    tokio::spawn(schedule::run(context.clone()));
+    tokio::spawn(sync::run(context.clone()));
+    tokio::spawn(upload::run(context.clone()));
+    let node_holder = context.node_holder();
+    node_holder.set_timeout(Duration::from_secs(config.node_timeout() as u64));
+    node_holder.set_max_nodes(config.discovery().max_nodes);
+    node_holder.set_node_list(config.node_list().to_vec()).await;
+    if let Err(e) = context.discovery().start(context, config.clone()).await {
+        error!("Failed to start discovery with error {:?}", e);
+    }
+    node_holder.run();
+    let log_folder = config.server().log_folder.to_string_lossy().to_string();
+    tokio::spawn(clear(log_folder, context.metrics().cleaner_deleted()));
 }
 
-pub async fn run() -> anyhow::Result<()> {
-    let receiver = screen_shot().await;
+pub async fn run(context: AppContext) -> anyhow::Result<()> {
+    let receiver = screen_shot(context.metrics().clone()).await;
     let rx = Arc::new(Mutex::new(receiver));
-    init().await;
-    HttpServer::new(move || {
+    let server = context.config().get_config().await.server().clone();
+    let identity = if server.tls.enabled {
+        let identity = tls::load_or_generate(&server.tls, &utils::safe_get_ip())?;
+        context.set_tls_fingerprint(identity.fingerprint.clone());
+        Some(identity)
+    } else {
+        warn!("TLS is disabled, serving plain HTTP");
+        None
+    };
+    init(&context).await;
+    let static_root = server.static_root.clone();
+    let prometheus = PrometheusMetricsBuilder::new("broadcast")
+        .endpoint("/metrics")
+        .registry(context.metrics().registry().clone())
+        .build()
+        .map_err(|e| anyhow::anyhow!("Failed to set up metrics: {e}"))?;
+    let http_server = HttpServer::new(move || {
         App::new()
+            .wrap(prometheus.clone())
+            .wrap_fn(|req, srv| match auth::authenticate(&req) {
+                Ok(()) => Either::Left(srv.call(req).map_ok(ServiceResponse::map_into_left_body)),
+                Err(e) => Either::Right(ready(Ok(req.error_response(e).map_into_right_body()))),
+            })
+            .wrap_fn(|req, srv| {
+                let pending = audit::begin(&req);
+                let response = srv.call(req);
+                async move {
+                    let response = response.await?;
+                    if let Some(pending) = pending {
+                        audit::finish(pending, &response).await;
+                    }
+                    Ok(response)
+                }
+            })
             .wrap(middleware::Logger::default())
             .wrap(middleware::Compress::default())
             .wrap(Cors::permissive())
-            .service(static_file())
-            .service(assets_file())
+            .service(static_file(&static_root))
+            .service(assets_file(&static_root))
+            .app_data(Data::new(context.clone()))
             .app_data(Data::new(rx.clone()))
-            .service(get_nodes)
-            .service(get_config)
-            .service(put_node_name)
-            .route("/", get().to(index))
-            .route("/download/{filename:.*}", get().to(download_file))
-            .route("/health", get().to(health))
-            .service(screenshot)
-            .route("/video_list", get().to(video_list))
-            .route("/video_list/{video}", get().to(download_video))
-            .route("/video_list/{video}", post().to(upload_video))
-            .route("/video_list/{video}", delete().to(delete_video))
-            .route("/play", get().to(play))
-            .route("/pause", get().to(pause))
-            .route("/open_player", get().to(open_player))
-            .route("/kill_player", get().to(kill_player))
-    })
-    //TODO should load from config file
-    .bind(("0.0.0.0", 8081))?
-    .run()
-    .await?;
+            .configure(routes)
+    });
+    let address = (server.bind_address, server.http_port);
+    let http_server = match identity {
+        Some(identity) => http_server.bind_rustls_021(address, identity.server_config)?,
+        None => http_server.bind(address)?,
+    };
+    http_server.run().await?;
     Ok(())
 }
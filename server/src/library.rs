@@ -0,0 +1,162 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use probe::{MediaInfo, ProbeError};
+use serde::Serialize;
+use tracing::{debug, warn};
+use utoipa::ToSchema;
+
+use crate::media::MediaRoot;
+
+/// A file in the media root.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct VideoEntry {
+    pub name: String,
+    /// Where the file is downloaded from.
+    pub url: String,
+    pub size: u64,
+    /// Last modification, milliseconds since the unix epoch.
+    pub modified: u128,
+    /// What the headers tell, `None` when the file isn't an MP4, MOV, WebM
+    /// or Matroska file that could be read.
+    pub media: Option<MediaInfo>,
+}
+
+#[derive(Debug)]
+struct Probed {
+    modified: SystemTime,
+    size: u64,
+    media: Option<MediaInfo>,
+}
+
+/// Probe results by path. A file is probed again once its modification time
+/// or size changes.
+#[derive(Debug, Clone, Default)]
+pub struct ProbeCache {
+    entries: Arc<Mutex<HashMap<PathBuf, Probed>>>,
+}
+
+impl ProbeCache {
+    pub fn media(&self, path: &Path, metadata: &fs::Metadata) -> Option<MediaInfo> {
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        let size = metadata.len();
+        if let Some(probed) = self.lock().get(path) {
+            if probed.modified == modified && probed.size == size {
+                return probed.media.clone();
+            }
+        }
+        // probed without holding the lock, a concurrent listing at worst probes twice
+        let media = match probe::probe_file(path) {
+            Ok(media) => Some(media),
+            Err(ProbeError::Unsupported) => {
+                debug!("{:?} is not a container that can be probed", path);
+                None
+            }
+            Err(e) => {
+                warn!("Failed to probe {:?} with error {}", path, e);
+                None
+            }
+        };
+        self.lock().insert(
+            path.to_path_buf(),
+            Probed {
+                modified,
+                size,
+                media: media.clone(),
+            },
+        );
+        media
+    }
+
+    /// Forgets files that are gone.
+    fn retain(&self, paths: &HashSet<PathBuf>) {
+        self.lock().retain(|path, _| paths.contains(path));
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, Probed>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Lists the media root with the metadata of every file. Reads files, so it
+/// belongs on a blocking thread.
+pub fn scan(
+    media_root: &MediaRoot,
+    cache: &ProbeCache,
+    base_url: &str,
+) -> io::Result<Vec<VideoEntry>> {
+    let mut entries = vec![];
+    let mut paths = HashSet::new();
+    for name in media_root.list()? {
+        let path = media_root.path().join(&name);
+        // removed since it was listed
+        let Ok(metadata) = fs::metadata(&path) else {
+            continue;
+        };
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|it| it.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |it| it.as_millis());
+        entries.push(VideoEntry {
+            url: format!("{base_url}/video_list/{name}"),
+            size: metadata.len(),
+            modified,
+            media: cache.media(&path, &metadata),
+            name,
+        });
+        paths.insert(path);
+    }
+    cache.retain(&paths);
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probes_are_cached_until_the_file_changes() {
+        let dir = std::env::temp_dir().join(format!("library-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let root = MediaRoot::open(&dir).unwrap();
+        let path = root.path().join("clip.webm");
+        fs::write(&path, b"not a video").unwrap();
+        fs::write(root.path().join("gone.mp4"), b"").unwrap();
+
+        let cache = ProbeCache::default();
+        let entries = scan(&root, &cache, "https://node:8081").unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "clip.webm");
+        assert_eq!(entries[0].url, "https://node:8081/video_list/clip.webm");
+        assert_eq!(entries[0].size, 11);
+        assert_eq!(entries[0].media, None);
+        assert_eq!(cache.lock().len(), 2);
+
+        // a cached result is returned as long as mtime and size match
+        let metadata = fs::metadata(&path).unwrap();
+        cache.lock().get_mut(&path).unwrap().media = Some(MediaInfo {
+            container: probe::Container::Webm,
+            duration_ms: Some(1000),
+            width: None,
+            height: None,
+            video_codec: None,
+            audio_codec: None,
+            bitrate: None,
+        });
+        assert!(cache.media(&path, &metadata).is_some());
+        fs::write(&path, b"still not a video").unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(cache.media(&path, &metadata), None);
+
+        fs::remove_file(root.path().join("gone.mp4")).unwrap();
+        scan(&root, &cache, "").unwrap();
+        assert_eq!(cache.lock().len(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,8 +1,14 @@
 use logger::init_tracing;
+use server::context::AppContext;
 use utils::safe_get_ip;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let _guard = init_tracing("broadcast_log", &safe_get_ip());
-    server::run().await
+    let context = AppContext::builder().build()?;
+    let config = context.config().get_config().await;
+    let _guard = init_tracing(
+        &config.server().log_folder.to_string_lossy(),
+        &safe_get_ip(),
+    );
+    server::run(context).await
 }
@@ -0,0 +1,201 @@
+use std::{
+    fmt, fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+use tracing::warn;
+
+/// Why a requested media path was refused.
+#[derive(Debug)]
+pub enum MediaError {
+    /// Empty, absolute, or containing `.` or `..`.
+    InvalidName(String),
+    /// Resolves outside the media root, e.g. through a symlink.
+    OutsideRoot(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for MediaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MediaError::InvalidName(name) => write!(f, "{name:?} is not a valid media path"),
+            MediaError::OutsideRoot(name) => write!(f, "{name:?} is outside the media root"),
+            MediaError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for MediaError {}
+
+impl From<io::Error> for MediaError {
+    fn from(e: io::Error) -> Self {
+        MediaError::Io(e)
+    }
+}
+
+/// The folder media is served from and written to. Every path handed out
+/// stays inside it: names are checked for traversal before they are joined,
+/// and the joined path is canonicalised to catch symlinks pointing elsewhere.
+#[derive(Debug, Clone)]
+pub struct MediaRoot {
+    root: PathBuf,
+}
+
+impl MediaRoot {
+    /// Creates the folder if needed.
+    pub fn open(root: &Path) -> io::Result<Self> {
+        fs::create_dir_all(root)?;
+        Ok(Self {
+            root: root.canonicalize()?,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    /// Only plain components, so the joined path can't leave the root lexically.
+    fn join(&self, name: &str) -> Result<PathBuf, MediaError> {
+        if !is_valid_name(name) {
+            warn!("Rejected media path {:?}", name);
+            return Err(MediaError::InvalidName(name.to_string()));
+        }
+        Ok(self.root.join(name))
+    }
+
+    fn confine(&self, name: &str, canonical: &Path) -> Result<(), MediaError> {
+        if canonical.starts_with(&self.root) {
+            Ok(())
+        } else {
+            warn!(
+                "Rejected media path {:?} resolving to {:?}",
+                name, canonical
+            );
+            Err(MediaError::OutsideRoot(name.to_string()))
+        }
+    }
+
+    /// An existing file or folder below the root. The path itself is returned,
+    /// not its symlink target, so deleting a link removes only the link.
+    pub fn resolve(&self, name: &str) -> Result<PathBuf, MediaError> {
+        let path = self.join(name)?;
+        self.confine(name, &path.canonicalize()?)?;
+        Ok(path)
+    }
+
+    /// Where a file named `name` may be created or replaced. Its folder must
+    /// already exist inside the root, and an existing symlink is never written
+    /// through.
+    pub fn resolve_new(&self, name: &str) -> Result<PathBuf, MediaError> {
+        let path = self.join(name)?;
+        let parent = path.parent().unwrap_or(&self.root);
+        self.confine(name, &parent.canonicalize()?)?;
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                warn!("Refused to write through the symlink {:?}", path);
+                Err(MediaError::OutsideRoot(name.to_string()))
+            }
+            Ok(_) => Ok(path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(path),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Names of the files directly in the root, skipping links that leave it
+    /// and names that aren't UTF-8.
+    pub fn list(&self) -> io::Result<Vec<String>> {
+        let mut names = vec![];
+        for entry in fs::read_dir(&self.root)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|it| it.to_str()) else {
+                warn!("Skipping {:?}, its name is not UTF-8", path);
+                continue;
+            };
+            if path.is_file() && self.resolve(name).is_ok() {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+/// Whether `name` is made of plain components only, so it stays below any
+/// root it is joined to.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && Path::new(name)
+            .components()
+            .all(|it| matches!(it, Component::Normal(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn media_root(test: &str) -> MediaRoot {
+        let dir = std::env::temp_dir().join(format!("media-{test}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let root = MediaRoot::open(&dir.join("video")).unwrap();
+        fs::write(root.path().join("intro.mp4"), b"video").unwrap();
+        fs::write(dir.join("config.json"), b"{}").unwrap();
+        root
+    }
+
+    fn is_invalid(result: Result<PathBuf, MediaError>) -> bool {
+        matches!(result, Err(MediaError::InvalidName(_)))
+    }
+
+    #[test]
+    fn test_traversal_is_rejected() {
+        let root = media_root("traversal");
+        assert!(root.resolve("intro.mp4").is_ok());
+        assert!(is_invalid(root.resolve("../config.json")));
+        assert!(is_invalid(root.resolve("a/../../config.json")));
+        assert!(is_invalid(root.resolve("./intro.mp4")));
+        assert!(is_invalid(root.resolve("/etc/passwd")));
+        assert!(is_invalid(root.resolve("")));
+        assert!(is_invalid(root.resolve_new("../config.json")));
+        assert!(matches!(
+            root.resolve("missing.mp4"),
+            Err(MediaError::Io(_))
+        ));
+        assert_eq!(root.list().unwrap(), vec!["intro.mp4"]);
+        let _ = fs::remove_dir_all(root.path().parent().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_escape_is_rejected() {
+        use std::os::unix::fs::symlink;
+
+        let root = media_root("symlink");
+        let outside = root.path().parent().unwrap();
+        symlink(outside.join("config.json"), root.path().join("config.mp4")).unwrap();
+        symlink(outside, root.path().join("up")).unwrap();
+        symlink(root.path().join("intro.mp4"), root.path().join("alias.mp4")).unwrap();
+
+        assert!(matches!(
+            root.resolve("config.mp4"),
+            Err(MediaError::OutsideRoot(_))
+        ));
+        assert!(matches!(
+            root.resolve("up/config.json"),
+            Err(MediaError::OutsideRoot(_))
+        ));
+        assert!(matches!(
+            root.resolve_new("config.mp4"),
+            Err(MediaError::OutsideRoot(_))
+        ));
+        assert!(matches!(
+            root.resolve_new("up/new.mp4"),
+            Err(MediaError::OutsideRoot(_))
+        ));
+        assert_eq!(
+            root.resolve("alias.mp4").unwrap(),
+            root.path().join("alias.mp4")
+        );
+        assert_eq!(root.list().unwrap(), vec!["alias.mp4", "intro.mp4"]);
+        let _ = fs::remove_dir_all(outside);
+    }
+}
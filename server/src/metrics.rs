@@ -0,0 +1,249 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use discover::stats::DiscoveryStats;
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    Histogram, HistogramOpts, IntCounter, IntGauge, Registry,
+};
+use screen::CaptureObserver;
+
+const NAMESPACE: &str = "broadcast";
+
+/// Buckets for screenshot steps, which take from a few milliseconds to seconds.
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Domain metrics exported on `/metrics` next to the HTTP request metrics.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    capture_seconds: Histogram,
+    compress_seconds: Histogram,
+    upload_bytes: IntCounter,
+    distribution_bytes: IntCounter,
+    cleaner_deleted: Arc<AtomicU64>,
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+impl Metrics {
+    pub fn new(discovery: Arc<DiscoveryStats>) -> prometheus::Result<Self> {
+        let registry = Registry::new();
+        let histogram = |name: &str, help: &str| {
+            Histogram::with_opts(
+                HistogramOpts::new(name, help)
+                    .namespace(NAMESPACE)
+                    .buckets(LATENCY_BUCKETS.to_vec()),
+            )
+        };
+        let capture_seconds = histogram(
+            "screen_capture_seconds",
+            "Time taken to capture the primary screen",
+        )?;
+        let compress_seconds = histogram(
+            "screen_compress_seconds",
+            "Time taken to compress a screenshot",
+        )?;
+        let upload_bytes = IntCounter::new(
+            format!("{NAMESPACE}_upload_bytes_total"),
+            "Bytes of media received through uploads",
+        )?;
+        let distribution_bytes = IntCounter::new(
+            format!("{NAMESPACE}_distribution_bytes_total"),
+            "Bytes of media sent to peers by distributions",
+        )?;
+        let cleaner_deleted = Arc::new(AtomicU64::new(0));
+        registry.register(Box::new(capture_seconds.clone()))?;
+        registry.register(Box::new(compress_seconds.clone()))?;
+        registry.register(Box::new(upload_bytes.clone()))?;
+        registry.register(Box::new(distribution_bytes.clone()))?;
+        registry.register(Box::new(SnapshotCollector::new(
+            discovery,
+            cleaner_deleted.clone(),
+        )?))?;
+        Ok(Self {
+            registry,
+            capture_seconds,
+            compress_seconds,
+            upload_bytes,
+            distribution_bytes,
+            cleaner_deleted,
+        })
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    pub fn add_upload_bytes(&self, bytes: usize) {
+        self.upload_bytes.inc_by(bytes as u64);
+    }
+
+    pub fn add_distribution_bytes(&self, bytes: usize) {
+        self.distribution_bytes.inc_by(bytes as u64);
+    }
+
+    /// Counter the log cleaner increments for every file it removes.
+    pub fn cleaner_deleted(&self) -> Arc<AtomicU64> {
+        self.cleaner_deleted.clone()
+    }
+}
+
+impl CaptureObserver for Metrics {
+    fn captured(&self, elapsed: Duration) {
+        self.capture_seconds.observe(elapsed.as_secs_f64());
+    }
+
+    fn compressed(&self, elapsed: Duration) {
+        self.compress_seconds.observe(elapsed.as_secs_f64());
+    }
+}
+
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+/// Name, help and type of every value read from the shared counters.
+#[rustfmt::skip]
+const SNAPSHOT_METRICS: [(&str, &str, Kind); 12] = [
+    ("discovery_nodes_known", "Nodes in the node list", Kind::Gauge),
+    ("discovery_nodes_active", "Nodes that sent a heartbeat recently", Kind::Gauge),
+    ("discovery_heartbeats_sent_total", "Heartbeats announced by this node", Kind::Counter),
+    ("discovery_heartbeats_received_total", "Heartbeats decoded from peers", Kind::Counter),
+    ("discovery_datagrams_received_total", "Datagrams received on the discovery socket", Kind::Counter),
+    ("discovery_rate_limited_total", "Datagrams dropped by the rate limiter", Kind::Counter),
+    ("discovery_parse_failures_total", "Frames or nodes that failed to decode", Kind::Counter),
+    ("discovery_checksum_failures_total", "Frames with a checksum mismatch", Kind::Counter),
+    ("discovery_fragments_dropped_total", "Fragments dropped by the frame cache", Kind::Counter),
+    ("discovery_pending_fragment_groups", "Partially received messages in the frame cache", Kind::Gauge),
+    ("discovery_nodes_rejected_total", "Heartbeats from new nodes dropped because the list is full", Kind::Counter),
+    ("cleaner_deleted_files_total", "Expired log files removed", Kind::Counter),
+];
+
+/// Reads the discovery and cleaner counters at scrape time, so those crates
+/// don't depend on prometheus.
+struct SnapshotCollector {
+    discovery: Arc<DiscoveryStats>,
+    cleaner_deleted: Arc<AtomicU64>,
+    descs: Vec<Desc>,
+}
+
+impl SnapshotCollector {
+    fn new(
+        discovery: Arc<DiscoveryStats>,
+        cleaner_deleted: Arc<AtomicU64>,
+    ) -> prometheus::Result<Self> {
+        let mut descs = Vec::new();
+        for (name, help, kind) in &SNAPSHOT_METRICS {
+            descs.extend(family(name, help, kind, 0)?.desc().into_iter().cloned());
+        }
+        Ok(Self {
+            discovery,
+            cleaner_deleted,
+            descs,
+        })
+    }
+
+    fn values(&self) -> [u64; 12] {
+        let stats = self.discovery.snapshot();
+        [
+            stats.known_nodes,
+            stats.active_nodes,
+            stats.heartbeats_sent,
+            stats.heartbeats_received,
+            stats.received,
+            stats.rate_limited,
+            stats.parse_failed,
+            stats.checksum_failed,
+            stats.fragments_dropped,
+            stats.pending_fragment_groups,
+            stats.nodes_rejected,
+            self.cleaner_deleted.load(Ordering::Relaxed),
+        ]
+    }
+}
+
+fn family(
+    name: &str,
+    help: &str,
+    kind: &Kind,
+    value: u64,
+) -> prometheus::Result<Box<dyn Collector>> {
+    let name = format!("{NAMESPACE}_{name}");
+    Ok(match kind {
+        Kind::Counter => {
+            let counter = IntCounter::new(name, help)?;
+            counter.inc_by(value);
+            Box::new(counter)
+        }
+        Kind::Gauge => {
+            let gauge = IntGauge::new(name, help)?;
+            gauge.set(value as i64);
+            Box::new(gauge)
+        }
+    })
+}
+
+impl Collector for SnapshotCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        SNAPSHOT_METRICS
+            .iter()
+            .zip(self.values())
+            .filter_map(|((name, help, kind), value)| family(name, help, kind, value).ok())
+            .flat_map(|collector| collector.collect())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::{Encoder, TextEncoder};
+
+    use super::*;
+
+    fn render(metrics: &Metrics) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&metrics.registry().gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn test_domain_metrics_are_exported() {
+        let stats = Arc::new(DiscoveryStats::default());
+        let metrics = Metrics::new(stats.clone()).unwrap();
+        stats.set_node_counts(3, 2);
+        stats.inc_heartbeats_sent();
+        metrics.add_upload_bytes(1024);
+        metrics.add_distribution_bytes(2048);
+        metrics.cleaner_deleted().fetch_add(4, Ordering::Relaxed);
+        metrics.captured(Duration::from_millis(20));
+
+        let text = render(&metrics);
+        assert!(text.contains("broadcast_discovery_nodes_known 3"));
+        assert!(text.contains("broadcast_discovery_nodes_active 2"));
+        assert!(text.contains("broadcast_discovery_heartbeats_sent_total 1"));
+        assert!(text.contains("broadcast_upload_bytes_total 1024"));
+        assert!(text.contains("broadcast_distribution_bytes_total 2048"));
+        assert!(text.contains("broadcast_cleaner_deleted_files_total 4"));
+        assert!(text.contains("broadcast_screen_capture_seconds_count 1"));
+        assert!(text.contains("# TYPE broadcast_discovery_parse_failures_total counter"));
+    }
+}
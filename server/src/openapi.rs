@@ -0,0 +1,270 @@
+use actix_web::{get, HttpResponse};
+use utoipa::{
+    openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
+
+use crate::{
+    audit, auth, bulk, catalogue, controller_config, distribution, error, file, library, playlist,
+    remote, schedule, screen_controller, sync, tls, transfer, upload, video,
+};
+
+/// The HTTP API of a node, generated from the handler annotations.
+///
+/// `server/openapi.json` holds the committed copy, the tests fail when it no
+/// longer matches or when a documented operation isn't routed.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "broadcast", description = "Control API of a broadcast node"),
+    paths(
+        crate::index,
+        crate::health,
+        crate::get_nodes,
+        crate::remove_node,
+        crate::get_discovery_stats,
+        remote::forward_to_node,
+        controller_config::get_config,
+        controller_config::patch_config,
+        auth::login,
+        auth::logout,
+        audit::get_audit,
+        screen_controller::screenshot,
+        file::download_file,
+        video::video_list,
+        video::download_video,
+        video::upload_video,
+        video::delete_video,
+        upload::create_upload,
+        upload::get_upload,
+        upload::patch_upload,
+        upload::finish_upload,
+        upload::delete_upload,
+        catalogue::get_catalogue,
+        catalogue::get_duplicates,
+        distribution::create_distribution,
+        distribution::get_distributions,
+        distribution::get_distribution,
+        sync::get_manifest,
+        sync::put_manifest,
+        sync::get_sync_status,
+        sync::publish_manifest,
+        transfer::get_chunk_list,
+        transfer::get_chunk,
+        transfer::get_transfers,
+        tls::get_pins,
+        tls::delete_pin,
+        playlist::create_playlist,
+        playlist::get_playlists,
+        playlist::get_playlist,
+        playlist::put_playlist,
+        playlist::delete_playlist,
+        playlist::get_assignments,
+        playlist::get_node_playlist,
+        playlist::assign_node,
+        playlist::unassign_node,
+        playlist::assign_group,
+        playlist::unassign_group,
+        playlist::get_player_playlist,
+        playlist::put_player_playlist,
+        playlist::delete_player_playlist,
+        schedule::create_rule,
+        schedule::get_rules,
+        schedule::get_rule,
+        schedule::put_rule,
+        schedule::delete_rule,
+        schedule::preview,
+        schedule::get_local_schedule,
+        schedule::put_local_schedule,
+        schedule::get_divergences,
+        video::play,
+        video::pause,
+        video::open_player,
+        video::kill_player,
+        bulk::bulk_action,
+        bulk::bulk_delete_video,
+        openapi_json,
+    ),
+    components(schemas(
+        domain::node::Node,
+        config::model::Config,
+        config::model::DiscoveryConfig,
+        config::model::ServerConfig,
+        config::model::TlsConfig,
+        config::model::SyncConfig,
+        config::model::AuthConfig,
+        config::model::ApiKey,
+        config::model::Role,
+        config::patch::ConfigPatch,
+        config::patch::DiscoveryPatch,
+        config::patch::FieldError,
+        error::ErrorBody,
+        discover::stats::DiscoveryStatsSnapshot,
+        auth::LoginRequest,
+        auth::IssuedSession,
+        audit::AuditEntry,
+        audit::Outcome,
+        bulk::BulkTarget,
+        bulk::BulkRequest,
+        bulk::NodeResult,
+        bulk::BulkReport,
+        upload::NewUpload,
+        upload::UploadStatus,
+        upload::StoredMedia,
+        library::VideoEntry,
+        catalogue::CatalogueItem,
+        distribution::NewDistribution,
+        distribution::Distribution,
+        distribution::NodeTransfer,
+        distribution::TransferState,
+        sync::ManifestFile,
+        sync::MediaManifest,
+        sync::MissingFile,
+        sync::SyncStatus,
+        transfer::ChunkList,
+        transfer::TransferProgress,
+        tls::PinReport,
+        tls::PeerPin,
+        tls::PinConflict,
+        playlist::PlaybackMode,
+        playlist::PlaylistItem,
+        playlist::NewPlaylist,
+        playlist::Playlist,
+        playlist::Assignments,
+        playlist::Assign,
+        playlist::ActivePlaylist,
+        schedule::ScheduleTarget,
+        schedule::Weekday,
+        schedule::NewRule,
+        schedule::Rule,
+        schedule::Preview,
+        schedule::LocalSchedule,
+        schedule::LocalReport,
+        schedule::LocalState,
+        schedule::Divergence,
+        probe::MediaInfo,
+        probe::Container,
+    )),
+    modifiers(&Credentials),
+    security(("bearer" = []), ("session" = [])),
+    tags(
+        (name = "system", description = "Health and the web interface"),
+        (name = "nodes", description = "Discovered nodes and calls forwarded to them"),
+        (name = "config", description = "The node's config"),
+        (name = "auth", description = "Session tokens"),
+        (name = "audit", description = "Recorded control actions"),
+        (name = "screen", description = "Screenshots of the node's display"),
+        (name = "media", description = "Videos in the media root"),
+        (name = "player", description = "Playback and the player process"),
+        (name = "playlists", description = "Playlists, the nodes and groups that play them, and when"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Registers the API key header and the session cookie as security schemes.
+struct Credentials;
+
+impl Modify for Credentials {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some("An API key or a session token"))
+                    .build(),
+            ),
+        );
+        components.add_security_scheme(
+            "session",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new(auth::SESSION_COOKIE))),
+        );
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    tag = "system",
+    security(()),
+    responses((status = 200, description = "This document", content_type = "application/json"))
+)]
+#[get("/openapi.json")]
+pub async fn openapi_json() -> HttpResponse {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{Method, StatusCode},
+        test::{call_service, init_service, TestRequest},
+        web, App, HttpResponse,
+    };
+
+    use super::*;
+
+    const COMMITTED: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/openapi.json");
+
+    /// Run with `UPDATE_OPENAPI=1` to rewrite the committed document.
+    #[test]
+    fn test_document_is_up_to_date() {
+        let generated = ApiDoc::openapi().to_pretty_json().unwrap() + "\n";
+        if std::env::var_os("UPDATE_OPENAPI").is_some() {
+            std::fs::write(COMMITTED, &generated).unwrap();
+        }
+        let committed = std::fs::read_to_string(COMMITTED).unwrap_or_default();
+        assert!(
+            committed == generated,
+            "server/openapi.json is out of date, rerun the tests with UPDATE_OPENAPI=1"
+        );
+    }
+
+    #[test]
+    fn test_references_resolve() {
+        let document = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let schemas = document["components"]["schemas"].as_object().unwrap();
+        let json = document.to_string();
+        for reference in json.split("\"#/components/schemas/").skip(1) {
+            let name = &reference[..reference.find('"').unwrap()];
+            assert!(
+                schemas.contains_key(name),
+                "{name} is not a registered schema"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_documented_operations_are_routed() {
+        let app = init_service(
+            App::new()
+                .configure(crate::routes)
+                .default_service(web::to(HttpResponse::ImATeapot)),
+        )
+        .await;
+        let document = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let paths = document["paths"].as_object().unwrap();
+        for (path, operations) in paths {
+            let uri = path.replace("{id}", "1").replace(['{', '}'], "");
+            for method in operations.as_object().unwrap().keys() {
+                let method = Method::from_bytes(method.to_uppercase().as_bytes()).unwrap();
+                let req = TestRequest::default()
+                    .method(method.clone())
+                    .uri(&uri)
+                    .to_request();
+                let res = call_service(&app, req).await;
+                assert_ne!(
+                    res.status(),
+                    StatusCode::IM_A_TEAPOT,
+                    "{method} {path} is documented but not routed"
+                );
+                // a routed path answers 405 to a method it doesn't route
+                assert_ne!(
+                    res.status(),
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "{method} {path} is documented but the path doesn't route the method"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use actix_web::http::Method;
+use config::model::Role;
+
+use crate::remote::route_segments;
+
+enum Route {
+    Exact(&'static str),
+    Prefix(&'static str),
+}
+
+impl Route {
+    fn matches(&self, path: &str) -> bool {
+        match self {
+            Route::Exact(route) => path == *route,
+            Route::Prefix(route) => path.starts_with(route),
+        }
+    }
+}
+
+/// Who may call a route.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Anyone, even when anonymous callers are turned away.
+    Public,
+    Role(Role),
+}
+
+/// What a request needs, and what it does in words for the 403 reason.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Requirement {
+    pub access: Access,
+    pub action: &'static str,
+}
+
+struct Permission {
+    methods: &'static [&'static str],
+    route: Route,
+    access: Access,
+    action: &'static str,
+}
+
+const fn allow(
+    methods: &'static [&'static str],
+    route: Route,
+    access: Access,
+    action: &'static str,
+) -> Permission {
+    Permission {
+        methods,
+        route,
+        access,
+        action,
+    }
+}
+
+const READ: &[&str] = &["GET", "HEAD"];
+const VIEWER: Access = Access::Role(Role::Viewer);
+const OPERATOR: Access = Access::Role(Role::Operator);
+const ADMINISTRATOR: Access = Access::Role(Role::Administrator);
+
+/// The permission matrix, first match wins. Routes missing here need the
+/// administrator role, so a new endpoint stays locked until it is listed.
+#[rustfmt::skip]
+const PERMISSIONS: [Permission; 54] = [
+    allow(READ, Route::Exact("/"), Access::Public, "open the web interface"),
+    allow(READ, Route::Exact("/health"), Access::Public, "check health"),
+    allow(READ, Route::Exact("/openapi.json"), Access::Public, "read the API description"),
+    allow(READ, Route::Prefix("/static/"), Access::Public, "load the web interface"),
+    allow(READ, Route::Prefix("/assets/"), Access::Public, "load the web interface"),
+    allow(&["POST"], Route::Exact("/auth/login"), Access::Public, "log in"),
+    allow(&["POST"], Route::Exact("/auth/logout"), VIEWER, "log out"),
+    allow(READ, Route::Exact("/nodes"), VIEWER, "list nodes"),
+    allow(READ, Route::Exact("/discovery/stats"), VIEWER, "read discovery statistics"),
+    allow(READ, Route::Exact("/metrics"), VIEWER, "read metrics"),
+    allow(READ, Route::Exact("/screen"), VIEWER, "view the screen"),
+    allow(READ, Route::Exact("/video_list"), VIEWER, "list media"),
+    allow(READ, Route::Prefix("/video_list/"), VIEWER, "download media"),
+    allow(READ, Route::Exact("/catalogue"), VIEWER, "list media"),
+    allow(READ, Route::Prefix("/catalogue/"), VIEWER, "list media"),
+    allow(READ, Route::Prefix("/download/"), VIEWER, "download files"),
+    allow(READ, Route::Exact("/distributions"), VIEWER, "follow distributions"),
+    allow(READ, Route::Prefix("/distributions/"), VIEWER, "follow distributions"),
+    allow(READ, Route::Prefix("/sync/"), VIEWER, "read the sync state"),
+    allow(READ, Route::Prefix("/content/"), VIEWER, "download media"),
+    allow(READ, Route::Exact("/transfers"), VIEWER, "follow transfers"),
+    allow(READ, Route::Exact("/playlists"), VIEWER, "list playlists"),
+    allow(READ, Route::Prefix("/playlists/"), VIEWER, "list playlists"),
+    allow(READ, Route::Exact("/assignments"), VIEWER, "list playlist assignments"),
+    allow(READ, Route::Prefix("/assignments/"), VIEWER, "list playlist assignments"),
+    allow(READ, Route::Prefix("/schedule/"), VIEWER, "read the schedule"),
+    allow(READ, Route::Exact("/player/playlist"), VIEWER, "see what plays"),
+    allow(&["POST"], Route::Prefix("/video_list/"), OPERATOR, "upload media"),
+    allow(&["DELETE"], Route::Prefix("/video_list/"), OPERATOR, "delete media"),
+    allow(&["POST"], Route::Exact("/uploads"), OPERATOR, "upload media"),
+    allow(&["GET", "HEAD", "PATCH", "POST", "DELETE"], Route::Prefix("/uploads/"), OPERATOR, "upload media"),
+    allow(&["POST"], Route::Exact("/distributions"), OPERATOR, "distribute media"),
+    allow(&["PUT"], Route::Exact("/sync/manifest"), OPERATOR, "set the media manifest"),
+    allow(&["PUT"], Route::Prefix("/sync/groups/"), OPERATOR, "publish media manifests"),
+    allow(&["POST"], Route::Exact("/playlists"), OPERATOR, "manage playlists"),
+    allow(&["PUT", "DELETE"], Route::Prefix("/playlists/"), OPERATOR, "manage playlists"),
+    allow(&["PUT", "DELETE"], Route::Prefix("/assignments/"), OPERATOR, "assign playlists"),
+    allow(&["POST"], Route::Exact("/schedule/rules"), OPERATOR, "manage the schedule"),
+    allow(&["PUT", "DELETE"], Route::Prefix("/schedule/rules/"), OPERATOR, "manage the schedule"),
+    allow(&["PUT"], Route::Exact("/schedule/local"), OPERATOR, "publish the schedule"),
+    allow(&["PUT", "DELETE"], Route::Exact("/player/playlist"), OPERATOR, "switch the playlist"),
+    allow(READ, Route::Exact("/play"), OPERATOR, "control playback"),
+    allow(READ, Route::Exact("/pause"), OPERATOR, "control playback"),
+    allow(READ, Route::Exact("/open_player"), OPERATOR, "control the player process"),
+    allow(READ, Route::Exact("/kill_player"), OPERATOR, "control the player process"),
+    allow(&["DELETE"], Route::Prefix("/bulk/video_list/"), OPERATOR, "delete media"),
+    allow(&["POST"], Route::Exact("/bulk/play"), OPERATOR, "control playback"),
+    allow(&["POST"], Route::Exact("/bulk/pause"), OPERATOR, "control playback"),
+    allow(&["POST"], Route::Exact("/bulk/open_player"), OPERATOR, "control the player process"),
+    allow(&["POST"], Route::Exact("/bulk/kill_player"), OPERATOR, "control the player process"),
+    allow(&["DELETE"], Route::Prefix("/nodes/"), OPERATOR, "remove nodes"),
+    allow(READ, Route::Exact("/config"), ADMINISTRATOR, "read the config"),
+    allow(&["PATCH"], Route::Exact("/config"), ADMINISTRATOR, "change the config"),
+    allow(READ, Route::Exact("/audit"), ADMINISTRATOR, "read the audit log"),
+];
+
+/// The routes nodes call on each other, all a peer key gives access to.
+#[rustfmt::skip]
+const PEER_ROUTES: [(&[&str], Route); 12] = [
+    (READ, Route::Exact("/play")),
+    (READ, Route::Exact("/pause")),
+    (READ, Route::Exact("/open_player")),
+    (READ, Route::Exact("/kill_player")),
+    (READ, Route::Exact("/screen")),
+    (READ, Route::Exact("/video_list")),
+    (&["GET", "HEAD", "POST", "DELETE"], Route::Prefix("/video_list/")),
+    (&["POST"], Route::Exact("/uploads")),
+    (&["HEAD", "PATCH", "POST"], Route::Prefix("/uploads/")),
+    (&["PUT"], Route::Exact("/sync/manifest")),
+    (READ, Route::Prefix("/content/")),
+    (&["PUT"], Route::Exact("/schedule/local")),
+];
+
+/// Read routes that still change state on the node.
+const CONTROL_READS: [&str; 4] = ["/play", "/pause", "/open_player", "/kill_player"];
+
+/// Splits `/nodes/{id}/{tail}` into the route called on the peer.
+fn forwarded_route(path: &str) -> Option<&str> {
+    let rest = path.strip_prefix("/nodes/")?;
+    let slash = rest.find('/')?;
+    Some(&rest[slash..])
+}
+
+/// The node a request acts on when it names one, forwarded calls and node removal.
+pub fn target_node(path: &str) -> Option<i64> {
+    let rest = path.strip_prefix("/nodes/")?;
+    rest.split('/').next()?.parse().ok()
+}
+
+/// Whether a request changes state and so belongs in the audit log. Chunks
+/// of an upload are left out, creating and finishing it is recorded.
+pub fn is_mutating(method: &Method, path: &str) -> bool {
+    let path = forwarded_route(path).unwrap_or(path);
+    if method == Method::PATCH && path.starts_with("/uploads/") {
+        return false;
+    }
+    let read = method == Method::GET || method == Method::HEAD;
+    !read || CONTROL_READS.contains(&path)
+}
+
+/// Whether a call with a peer key may reach `path`. Forwarded calls are made
+/// by the node a caller reached, never on a peer's behalf.
+pub fn peer_may(method: &Method, path: &str) -> bool {
+    PEER_ROUTES
+        .iter()
+        .any(|(methods, route)| methods.contains(&method.as_str()) && route.matches(path))
+}
+
+pub fn requirement(method: &Method, path: &str) -> Requirement {
+    // a forwarded call needs what the peer's route needs, and never less than a viewer
+    if let Some(route) = forwarded_route(path) {
+        // a dot segment would reach another route on the peer than the one matched here
+        if route_segments(&route[1..]).is_none() {
+            return UNLISTED;
+        }
+        let mut requirement = requirement(method, route);
+        if requirement.access == Access::Public {
+            requirement.access = VIEWER;
+        }
+        return requirement;
+    }
+    PERMISSIONS
+        .iter()
+        .find(|it| it.methods.contains(&method.as_str()) && it.route.matches(path))
+        .map(|it| Requirement {
+            access: it.access,
+            action: it.action,
+        })
+        .unwrap_or(UNLISTED)
+}
+
+const UNLISTED: Requirement = Requirement {
+    access: ADMINISTRATOR,
+    action: "call this endpoint",
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(method: Method, path: &str) -> Access {
+        requirement(&method, path).access
+    }
+
+    #[test]
+    fn test_matrix() {
+        assert_eq!(access(Method::GET, "/health"), Access::Public);
+        assert_eq!(access(Method::GET, "/openapi.json"), Access::Public);
+        assert_eq!(access(Method::GET, "/static/index.html"), Access::Public);
+        assert_eq!(access(Method::POST, "/auth/login"), Access::Public);
+        assert_eq!(access(Method::GET, "/screen"), VIEWER);
+        assert_eq!(access(Method::GET, "/video_list/intro.mp4"), VIEWER);
+        assert_eq!(access(Method::DELETE, "/video_list/intro.mp4"), OPERATOR);
+        assert_eq!(access(Method::GET, "/kill_player"), OPERATOR);
+        assert_eq!(access(Method::POST, "/bulk/pause"), OPERATOR);
+        assert_eq!(access(Method::PATCH, "/uploads/7"), OPERATOR);
+        assert_eq!(access(Method::HEAD, "/uploads/7"), OPERATOR);
+        assert_eq!(access(Method::GET, "/catalogue/duplicates"), VIEWER);
+        assert_eq!(access(Method::POST, "/distributions"), OPERATOR);
+        assert_eq!(access(Method::GET, "/distributions/7"), VIEWER);
+        assert_eq!(access(Method::GET, "/sync/status"), VIEWER);
+        assert_eq!(access(Method::PUT, "/sync/groups/lobby"), OPERATOR);
+        assert_eq!(access(Method::GET, "/content/ab12/chunks/3"), VIEWER);
+        assert_eq!(access(Method::GET, "/transfers"), VIEWER);
+        assert_eq!(access(Method::GET, "/playlists/7"), VIEWER);
+        assert_eq!(access(Method::POST, "/playlists"), OPERATOR);
+        assert_eq!(access(Method::DELETE, "/playlists/7"), OPERATOR);
+        assert_eq!(access(Method::PUT, "/assignments/groups/lobby"), OPERATOR);
+        assert_eq!(access(Method::GET, "/schedule/preview"), VIEWER);
+        assert_eq!(access(Method::DELETE, "/schedule/rules/7"), OPERATOR);
+        assert_eq!(access(Method::PUT, "/schedule/local"), OPERATOR);
+        assert_eq!(access(Method::GET, "/schedule/divergences"), VIEWER);
+        assert_eq!(access(Method::PUT, "/player/playlist"), OPERATOR);
+        assert_eq!(access(Method::GET, "/config"), ADMINISTRATOR);
+        assert_eq!(access(Method::PATCH, "/config"), ADMINISTRATOR);
+        assert_eq!(access(Method::PUT, "/health"), ADMINISTRATOR);
+        assert_eq!(access(Method::GET, "/unknown"), ADMINISTRATOR);
+    }
+
+    #[test]
+    fn test_forwarded_routes() {
+        assert_eq!(access(Method::GET, "/nodes/1/screen"), VIEWER);
+        assert_eq!(
+            access(Method::DELETE, "/nodes/1/video_list/a.mp4"),
+            OPERATOR
+        );
+        assert_eq!(access(Method::PATCH, "/nodes/1/config"), ADMINISTRATOR);
+        assert_eq!(access(Method::GET, "/nodes/1/health"), VIEWER);
+        assert_eq!(access(Method::GET, "/nodes"), VIEWER);
+        assert_eq!(
+            requirement(&Method::DELETE, "/video_list/a.mp4").action,
+            "delete media"
+        );
+        assert_eq!(access(Method::DELETE, "/nodes/1"), OPERATOR);
+    }
+
+    #[test]
+    fn test_forwarded_dot_segments() {
+        assert_eq!(
+            access(Method::GET, "/nodes/1/video_list/../config"),
+            ADMINISTRATOR
+        );
+        assert_eq!(
+            access(Method::GET, "/nodes/1/video_list/%2e%2e/audit"),
+            ADMINISTRATOR
+        );
+        assert_eq!(
+            access(Method::DELETE, "/nodes/1/video_list/./../tls/pins/10.0.0.2"),
+            ADMINISTRATOR
+        );
+        assert_eq!(access(Method::GET, "/nodes/1/video_list/a%20b.mp4"), VIEWER);
+    }
+
+    #[test]
+    fn test_peer_routes() {
+        assert!(peer_may(&Method::GET, "/play"));
+        assert!(peer_may(&Method::PATCH, "/uploads/7"));
+        assert!(peer_may(&Method::PUT, "/schedule/local"));
+        assert!(peer_may(&Method::GET, "/content/ab12/chunks/3"));
+        assert!(!peer_may(&Method::GET, "/config"));
+        assert!(!peer_may(&Method::PATCH, "/config"));
+        assert!(!peer_may(&Method::GET, "/audit"));
+        assert!(!peer_may(&Method::GET, "/nodes/1/play"));
+        assert!(!peer_may(&Method::PUT, "/schedule/rules/7"));
+        assert!(!peer_may(&Method::DELETE, "/tls/pins/10.0.0.2"));
+    }
+
+    #[test]
+    fn test_is_mutating() {
+        assert!(is_mutating(&Method::GET, "/pause"));
+        assert!(is_mutating(&Method::GET, "/nodes/1/kill_player"));
+        assert!(is_mutating(&Method::DELETE, "/nodes/1"));
+        assert!(is_mutating(&Method::PATCH, "/config"));
+        assert!(!is_mutating(&Method::GET, "/config"));
+        assert!(!is_mutating(&Method::GET, "/nodes/1/screen"));
+        assert!(is_mutating(&Method::POST, "/uploads/7/finish"));
+        assert!(!is_mutating(&Method::PATCH, "/uploads/7"));
+        assert_eq!(target_node("/nodes/12/play"), Some(12));
+        assert_eq!(target_node("/nodes/12"), Some(12));
+        assert_eq!(target_node("/play"), None);
+    }
+}
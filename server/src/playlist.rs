@@ -0,0 +1,676 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+    sync::{Arc, Mutex as StdMutex, PoisonError},
+};
+
+use actix_web::{delete, get, post, put, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use storage::Storage;
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
+
+use crate::{client, context::AppContext, error::ApiError, media::is_valid_name};
+
+const MAX_NAME_LEN: usize = 64;
+
+/// How the items follow each other once the last one has played.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaybackMode {
+    /// Start over with the first item.
+    #[default]
+    Loop,
+    /// Stop after the last item.
+    Once,
+    /// Start over in a new random order.
+    Shuffle,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct PlaylistItem {
+    /// File name of the video in the media root.
+    pub video: String,
+    /// Seconds the item is shown, played to the end when absent.
+    pub duration_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct NewPlaylist {
+    pub name: String,
+    pub items: Vec<PlaylistItem>,
+    /// Defaults to `loop`.
+    #[serde(default)]
+    pub mode: PlaybackMode,
+}
+
+impl NewPlaylist {
+    fn validate(&self) -> Result<(), ApiError> {
+        validate(&self.name, &self.items)
+    }
+}
+
+fn validate(name: &str, items: &[PlaylistItem]) -> Result<(), ApiError> {
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(ApiError::BadRequest(format!(
+            "The name must be 1 to {MAX_NAME_LEN} characters"
+        )));
+    }
+    for item in items {
+        if !is_valid_name(&item.video) {
+            return Err(ApiError::BadRequest(format!(
+                "{:?} is not a valid media path",
+                item.video
+            )));
+        }
+        if item.duration_secs == Some(0) {
+            return Err(ApiError::BadRequest(format!(
+                "The duration of {:?} must be greater than 0",
+                item.video
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Playlist {
+    pub id: i64,
+    pub name: String,
+    pub items: Vec<PlaylistItem>,
+    pub mode: PlaybackMode,
+    /// Milliseconds since the unix epoch.
+    pub created_at: u128,
+    pub updated_at: u128,
+}
+
+impl Playlist {
+    /// Checks a playlist sent by a peer the way [`NewPlaylist`] is checked.
+    pub(crate) fn validate(&self) -> Result<(), ApiError> {
+        validate(&self.name, &self.items)
+    }
+}
+
+/// Which playlist each node and group plays.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Assignments {
+    /// Playlist ids by node id. A node's own assignment wins over its groups'.
+    pub nodes: BTreeMap<i64, i64>,
+    /// Playlist ids by group name.
+    pub groups: BTreeMap<String, i64>,
+}
+
+impl Assignments {
+    /// The playlist `node` plays, its own or that of the first of its groups
+    /// by name that has one.
+    pub fn playlist_of(&self, node: i64, groups: &BTreeMap<String, Vec<i64>>) -> Option<i64> {
+        self.nodes.get(&node).copied().or_else(|| {
+            groups
+                .iter()
+                .filter(|(_, members)| members.contains(&node))
+                .find_map(|(group, _)| self.groups.get(group).copied())
+        })
+    }
+
+    fn unassign(&mut self, playlist: i64) {
+        self.nodes.retain(|_, it| *it != playlist);
+        self.groups.retain(|_, it| *it != playlist);
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct Assign {
+    /// Id of the playlist.
+    pub playlist: i64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Book {
+    playlists: BTreeMap<i64, Playlist>,
+    assignments: Assignments,
+}
+
+/// Playlists and their assignments, persisted through [`Storage`].
+#[derive(Debug, Clone, Default)]
+pub struct PlaylistStore {
+    storage: Arc<Mutex<Option<Storage<Book>>>>,
+}
+
+impl PlaylistStore {
+    /// Loads the playlists from the file named in the config.
+    pub async fn open(&self, path: PathBuf) {
+        *self.storage.lock().await = Some(Storage::open(path).await);
+    }
+
+    async fn book(&self) -> Result<Book, ApiError> {
+        let mut storage = self.storage.lock().await;
+        match storage.as_mut() {
+            Some(storage) => storage.get().await.map_err(ApiError::Internal),
+            None => Err(ApiError::Internal(anyhow::anyhow!(
+                "The playlists are not open"
+            ))),
+        }
+    }
+
+    /// Changes the book with `f` and stores it, unless `f` fails.
+    async fn update<R>(
+        &self,
+        f: impl FnOnce(&mut Book) -> Result<R, ApiError>,
+    ) -> Result<R, ApiError> {
+        let mut storage = self.storage.lock().await;
+        let storage = storage
+            .as_mut()
+            .ok_or_else(|| ApiError::Internal(anyhow::anyhow!("The playlists are not open")))?;
+        let mut book = storage.get().await.map_err(ApiError::Internal)?;
+        let result = f(&mut book)?;
+        storage.set(book).await.map_err(ApiError::Internal)?;
+        Ok(result)
+    }
+
+    pub async fn list(&self) -> Result<Vec<Playlist>, ApiError> {
+        Ok(self.book().await?.playlists.into_values().collect())
+    }
+
+    pub async fn get(&self, id: i64) -> Result<Playlist, ApiError> {
+        self.book()
+            .await?
+            .playlists
+            .remove(&id)
+            .ok_or_else(|| not_found(id))
+    }
+
+    pub async fn insert(&self, playlist: Playlist) -> Result<(), ApiError> {
+        self.update(|book| {
+            book.playlists.insert(playlist.id, playlist);
+            Ok(())
+        })
+        .await
+    }
+
+    /// Replaces the name, items and mode of playlist `id`.
+    pub async fn replace(
+        &self,
+        id: i64,
+        playlist: NewPlaylist,
+        now: u128,
+    ) -> Result<Playlist, ApiError> {
+        self.update(|book| {
+            let stored = book.playlists.get_mut(&id).ok_or_else(|| not_found(id))?;
+            stored.name = playlist.name;
+            stored.items = playlist.items;
+            stored.mode = playlist.mode;
+            stored.updated_at = now;
+            Ok(stored.clone())
+        })
+        .await
+    }
+
+    /// Removes playlist `id` and every assignment of it.
+    pub async fn remove(&self, id: i64) -> Result<(), ApiError> {
+        self.update(|book| {
+            book.playlists.remove(&id).ok_or_else(|| not_found(id))?;
+            book.assignments.unassign(id);
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn assignments(&self) -> Result<Assignments, ApiError> {
+        Ok(self.book().await?.assignments)
+    }
+
+    /// Changes the assignments with `f` once `playlist`, when given, exists.
+    async fn assign(
+        &self,
+        playlist: Option<i64>,
+        f: impl FnOnce(&mut Assignments),
+    ) -> Result<Assignments, ApiError> {
+        self.update(|book| {
+            if let Some(id) = playlist.filter(|it| !book.playlists.contains_key(it)) {
+                return Err(not_found(id));
+            }
+            f(&mut book.assignments);
+            Ok(book.assignments.clone())
+        })
+        .await
+    }
+}
+
+/// What this node plays and why.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ActivePlaylist {
+    pub playlist: Playlist,
+    /// The schedule rule that chose the playlist, `None` when it is assigned.
+    pub rule: Option<i64>,
+}
+
+/// The playlist this node's player was last given.
+#[derive(Debug, Clone, Default)]
+pub struct PlayerPlaylist {
+    active: Arc<StdMutex<Option<ActivePlaylist>>>,
+}
+
+impl PlayerPlaylist {
+    pub fn get(&self) -> Option<ActivePlaylist> {
+        self.active
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn set(&self, active: Option<ActivePlaylist>) {
+        *self.active.lock().unwrap_or_else(PoisonError::into_inner) = active;
+    }
+}
+
+/// Hands `active` to the local player and remembers it once the player took it.
+pub(crate) async fn play(ctx: &AppContext, active: Option<ActivePlaylist>) -> Result<(), ApiError> {
+    let config = ctx.config().get_config().await;
+    let playlist = active.as_ref().map(|it| &it.playlist);
+    client::load_playlist(ctx.client(), &config.server().player_url, playlist).await?;
+    ctx.player_playlist().set(active);
+    Ok(())
+}
+
+fn not_found(id: i64) -> ApiError {
+    ApiError::NotFound(format!("Playlist {id} not found"))
+}
+
+/// Fails unless `id` is this node or one it has discovered.
+pub(crate) async fn known_node(ctx: &AppContext, id: i64) -> Result<(), ApiError> {
+    let own_id = ctx.config().get_config().await.id();
+    let known: HashSet<i64> = ctx
+        .node_holder()
+        .get_node_list()
+        .await
+        .into_iter()
+        .map(|it| it.id)
+        .collect();
+    if id == own_id || known.contains(&id) {
+        Ok(())
+    } else {
+        Err(ApiError::NotFound(format!("Node {id} not found")))
+    }
+}
+
+pub(crate) async fn known_group(ctx: &AppContext, group: &str) -> Result<(), ApiError> {
+    if ctx.config().get_config().await.groups().contains_key(group) {
+        Ok(())
+    } else {
+        Err(ApiError::NotFound(format!("Group {group:?} not found")))
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/playlists",
+    tag = "playlists",
+    request_body = NewPlaylist,
+    responses(
+        (status = 201, description = "The playlist was stored, its url is in the Location header", body = Playlist),
+        (status = 400, description = "The name is empty or too long, a video name tries to leave the media root, or a duration is 0", body = ErrorBody),
+    )
+)]
+#[post("/playlists")]
+pub async fn create_playlist(
+    ctx: web::Data<AppContext>,
+    body: web::Json<NewPlaylist>,
+) -> Result<HttpResponse, ApiError> {
+    let request = body.into_inner();
+    request.validate()?;
+    let now = ctx.clock().now_millis();
+    let playlist = Playlist {
+        id: ctx.ids().generate(),
+        name: request.name,
+        items: request.items,
+        mode: request.mode,
+        created_at: now,
+        updated_at: now,
+    };
+    ctx.playlists().insert(playlist.clone()).await?;
+    Ok(HttpResponse::Created()
+        .insert_header(("Location", format!("/playlists/{}", playlist.id)))
+        .json(playlist))
+}
+
+#[utoipa::path(
+    get,
+    path = "/playlists",
+    tag = "playlists",
+    responses((status = 200, description = "Every playlist, oldest first", body = [Playlist]))
+)]
+#[get("/playlists")]
+pub async fn get_playlists(ctx: web::Data<AppContext>) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(ctx.playlists().list().await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/playlists/{id}",
+    tag = "playlists",
+    params(("id" = i64, Path, description = "Id of the playlist")),
+    responses(
+        (status = 200, description = "The playlist", body = Playlist),
+        (status = 404, description = "No such playlist", body = ErrorBody),
+    )
+)]
+#[get("/playlists/{id}")]
+pub async fn get_playlist(
+    ctx: web::Data<AppContext>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(ctx.playlists().get(id.into_inner()).await?))
+}
+
+#[utoipa::path(
+    put,
+    path = "/playlists/{id}",
+    tag = "playlists",
+    params(("id" = i64, Path, description = "Id of the playlist")),
+    request_body = NewPlaylist,
+    responses(
+        (status = 200, description = "The playlist was replaced", body = Playlist),
+        (status = 400, description = "The name is empty or too long, a video name tries to leave the media root, or a duration is 0", body = ErrorBody),
+        (status = 404, description = "No such playlist", body = ErrorBody),
+    )
+)]
+#[put("/playlists/{id}")]
+pub async fn put_playlist(
+    ctx: web::Data<AppContext>,
+    id: web::Path<i64>,
+    body: web::Json<NewPlaylist>,
+) -> Result<HttpResponse, ApiError> {
+    let request = body.into_inner();
+    request.validate()?;
+    let playlist = ctx
+        .playlists()
+        .replace(id.into_inner(), request, ctx.clock().now_millis())
+        .await?;
+    ctx.schedule().wake();
+    Ok(HttpResponse::Ok().json(playlist))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/playlists/{id}",
+    tag = "playlists",
+    params(("id" = i64, Path, description = "Id of the playlist")),
+    responses(
+        (status = 204, description = "The playlist and its assignments were removed"),
+        (status = 404, description = "No such playlist", body = ErrorBody),
+        (status = 409, description = "A schedule rule plays the playlist", body = ErrorBody),
+    )
+)]
+#[delete("/playlists/{id}")]
+pub async fn delete_playlist(
+    ctx: web::Data<AppContext>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    ctx.schedule()
+        .remove_playlist(id.into_inner(), ctx.playlists())
+        .await?;
+    ctx.schedule().wake();
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    get,
+    path = "/assignments",
+    tag = "playlists",
+    responses((status = 200, description = "The playlist of every assigned node and group", body = Assignments))
+)]
+#[get("/assignments")]
+pub async fn get_assignments(ctx: web::Data<AppContext>) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(ctx.playlists().assignments().await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/assignments/nodes/{id}",
+    tag = "playlists",
+    params(("id" = i64, Path, description = "Id of the node")),
+    responses(
+        (status = 200, description = "The playlist the node plays, assigned to it or to one of its groups", body = Playlist),
+        (status = 404, description = "Neither the node nor its groups have a playlist", body = ErrorBody),
+    )
+)]
+#[get("/assignments/nodes/{id}")]
+pub async fn get_node_playlist(
+    ctx: web::Data<AppContext>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let groups = ctx.config().get_config().await.groups().clone();
+    let playlist = ctx
+        .playlists()
+        .assignments()
+        .await?
+        .playlist_of(id, &groups)
+        .ok_or_else(|| ApiError::NotFound(format!("Node {id} has no playlist")))?;
+    Ok(HttpResponse::Ok().json(ctx.playlists().get(playlist).await?))
+}
+
+#[utoipa::path(
+    put,
+    path = "/assignments/nodes/{id}",
+    tag = "playlists",
+    params(("id" = i64, Path, description = "Id of the node")),
+    request_body = Assign,
+    responses(
+        (status = 200, description = "The node plays the playlist, ahead of its groups' playlists", body = Assignments),
+        (status = 404, description = "The node or the playlist doesn't exist", body = ErrorBody),
+    )
+)]
+#[put("/assignments/nodes/{id}")]
+pub async fn assign_node(
+    ctx: web::Data<AppContext>,
+    id: web::Path<i64>,
+    body: web::Json<Assign>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    known_node(&ctx, id).await?;
+    let playlist = body.playlist;
+    let assignments = ctx
+        .playlists()
+        .assign(Some(playlist), |it| {
+            it.nodes.insert(id, playlist);
+        })
+        .await?;
+    ctx.schedule().wake();
+    Ok(HttpResponse::Ok().json(assignments))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/assignments/nodes/{id}",
+    tag = "playlists",
+    params(("id" = i64, Path, description = "Id of the node")),
+    responses((status = 200, description = "The node falls back to its groups' playlists", body = Assignments))
+)]
+#[delete("/assignments/nodes/{id}")]
+pub async fn unassign_node(
+    ctx: web::Data<AppContext>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let assignments = ctx
+        .playlists()
+        .assign(None, |it| {
+            it.nodes.remove(&id);
+        })
+        .await?;
+    ctx.schedule().wake();
+    Ok(HttpResponse::Ok().json(assignments))
+}
+
+#[utoipa::path(
+    put,
+    path = "/assignments/groups/{group}",
+    tag = "playlists",
+    params(("group" = String, Path, description = "Name of the group")),
+    request_body = Assign,
+    responses(
+        (status = 200, description = "The group's members play the playlist, unless they have their own", body = Assignments),
+        (status = 404, description = "The group or the playlist doesn't exist", body = ErrorBody),
+    )
+)]
+#[put("/assignments/groups/{group}")]
+pub async fn assign_group(
+    ctx: web::Data<AppContext>,
+    group: web::Path<String>,
+    body: web::Json<Assign>,
+) -> Result<HttpResponse, ApiError> {
+    let group = group.into_inner();
+    known_group(&ctx, &group).await?;
+    let playlist = body.playlist;
+    let assignments = ctx
+        .playlists()
+        .assign(Some(playlist), |it| {
+            it.groups.insert(group, playlist);
+        })
+        .await?;
+    ctx.schedule().wake();
+    Ok(HttpResponse::Ok().json(assignments))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/assignments/groups/{group}",
+    tag = "playlists",
+    params(("group" = String, Path, description = "Name of the group")),
+    responses((status = 200, description = "The group has no playlist anymore", body = Assignments))
+)]
+#[delete("/assignments/groups/{group}")]
+pub async fn unassign_group(
+    ctx: web::Data<AppContext>,
+    group: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let group = group.into_inner();
+    let assignments = ctx
+        .playlists()
+        .assign(None, |it| {
+            it.groups.remove(&group);
+        })
+        .await?;
+    ctx.schedule().wake();
+    Ok(HttpResponse::Ok().json(assignments))
+}
+
+#[utoipa::path(
+    get,
+    path = "/player/playlist",
+    tag = "player",
+    responses(
+        (status = 200, description = "The playlist the player was last given", body = ActivePlaylist),
+        (status = 404, description = "The player has no playlist", body = ErrorBody),
+    )
+)]
+#[get("/player/playlist")]
+pub async fn get_player_playlist(ctx: web::Data<AppContext>) -> Result<HttpResponse, ApiError> {
+    let active = ctx
+        .player_playlist()
+        .get()
+        .ok_or_else(|| ApiError::NotFound("The player has no playlist".to_string()))?;
+    Ok(HttpResponse::Ok().json(active))
+}
+
+#[utoipa::path(
+    put,
+    path = "/player/playlist",
+    tag = "player",
+    request_body = ActivePlaylist,
+    responses(
+        (status = 200, description = "The player plays the playlist"),
+        (status = 400, description = "The name is empty or too long, an item is not a valid media path or lasts 0 seconds", body = ErrorBody),
+        (status = 502, description = "The player is unreachable or refused", body = ErrorBody),
+    )
+)]
+#[put("/player/playlist")]
+pub async fn put_player_playlist(
+    ctx: web::Data<AppContext>,
+    body: web::Json<ActivePlaylist>,
+) -> Result<HttpResponse, ApiError> {
+    let active = body.into_inner();
+    active.playlist.validate()?;
+    play(&ctx, Some(active)).await?;
+    Ok(HttpResponse::Ok().into())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/player/playlist",
+    tag = "player",
+    responses(
+        (status = 200, description = "The player's playlist was cleared"),
+        (status = 502, description = "The player is unreachable or refused", body = ErrorBody),
+    )
+)]
+#[delete("/player/playlist")]
+pub async fn delete_player_playlist(ctx: web::Data<AppContext>) -> Result<HttpResponse, ApiError> {
+    play(&ctx, None).await?;
+    Ok(HttpResponse::Ok().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(video: &str, duration_secs: Option<u64>) -> PlaylistItem {
+        PlaylistItem {
+            video: video.to_string(),
+            duration_secs,
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let playlist = |name: &str, items| NewPlaylist {
+            name: name.to_string(),
+            items,
+            mode: PlaybackMode::Loop,
+        };
+        assert!(playlist(
+            "morning",
+            vec![item("a.mp4", None), item("b.mp4", Some(10))]
+        )
+        .validate()
+        .is_ok());
+        assert!(playlist("", vec![]).validate().is_err());
+        assert!(playlist(&"x".repeat(65), vec![]).validate().is_err());
+        assert!(playlist("morning", vec![item("../a.mp4", None)])
+            .validate()
+            .is_err());
+        assert!(playlist("morning", vec![item("a.mp4", Some(0))])
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_defaults_to_loop() {
+        let playlist: NewPlaylist =
+            serde_json::from_str(r#"{"name":"morning","items":[{"video":"a.mp4"}]}"#).unwrap();
+        assert_eq!(playlist.mode, PlaybackMode::Loop);
+        assert_eq!(playlist.items, vec![item("a.mp4", None)]);
+    }
+
+    #[test]
+    fn test_playlist_of() {
+        let groups = BTreeMap::from([
+            ("hall".to_string(), vec![1, 2]),
+            ("lobby".to_string(), vec![2, 3]),
+        ]);
+        let mut assignments = Assignments {
+            nodes: BTreeMap::from([(1, 10)]),
+            groups: BTreeMap::from([("hall".to_string(), 20), ("lobby".to_string(), 30)]),
+        };
+        assert_eq!(assignments.playlist_of(1, &groups), Some(10));
+        assert_eq!(assignments.playlist_of(2, &groups), Some(20));
+        assert_eq!(assignments.playlist_of(3, &groups), Some(30));
+        assert_eq!(assignments.playlist_of(4, &groups), None);
+
+        assignments.unassign(20);
+        assert_eq!(assignments.playlist_of(2, &groups), Some(30));
+        assignments.unassign(10);
+        assert_eq!(assignments.playlist_of(1, &groups), None);
+    }
+}
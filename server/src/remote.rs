@@ -0,0 +1,179 @@
+use actix_web::{
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING},
+        StatusCode,
+    },
+    web, HttpRequest, HttpResponse,
+};
+use futures::{channel::mpsc, SinkExt, StreamExt};
+use reqwest::{Body, Method};
+use tracing::error;
+
+use crate::{client, context::AppContext, error::ApiError};
+
+/// Top level routes of a peer that may be called through `/nodes/{id}/...`,
+/// those its peer key reaches.
+const FORWARDED_ROUTES: [&str; 6] = [
+    "play",
+    "pause",
+    "open_player",
+    "kill_player",
+    "screen",
+    "video_list",
+];
+
+fn is_forwarded(segments: &[String]) -> bool {
+    segments
+        .first()
+        .is_some_and(|it| FORWARDED_ROUTES.contains(&it.as_str()))
+}
+
+/// Splits a route into its percent-decoded segments. `None` when a segment
+/// is empty, `.` or `..`, raw or encoded, or isn't UTF-8, since the peer
+/// would resolve it to another route than the one that was checked.
+pub fn route_segments(route: &str) -> Option<Vec<String>> {
+    route
+        .split('/')
+        .map(|segment| {
+            let decoded = String::from_utf8(percent_decode(segment)).ok()?;
+            match decoded.as_str() {
+                "" | "." | ".." => None,
+                _ => Some(decoded),
+            }
+        })
+        .collect()
+}
+
+fn percent_decode(segment: &str) -> Vec<u8> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|it| std::str::from_utf8(it).ok())
+            .and_then(|it| u8::from_str_radix(it, 16).ok());
+        match hex {
+            Some(byte) if bytes[i] == b'%' => {
+                decoded.push(byte);
+                i += 3;
+            }
+            _ => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+/// Calls a route on a peer and relays its answer. Every method is forwarded,
+/// GET is documented as the representative one.
+#[utoipa::path(
+    get,
+    path = "/nodes/{id}/{tail}",
+    tag = "nodes",
+    params(
+        ("id" = i64, Path, description = "Node id"),
+        ("tail" = String, Path, description = "Route on the peer, e.g. `play` or `video_list/intro.mp4`"),
+    ),
+    responses(
+        (status = 200, description = "The peer's response, relayed as is"),
+        (status = 400, description = "The route holds an empty, `.` or `..` segment", body = ErrorBody),
+        (status = 404, description = "The node is unknown or the route can't be forwarded", body = ErrorBody),
+        (status = 502, description = "The node is unreachable", body = ErrorBody),
+    )
+)]
+pub async fn forward_to_node(
+    ctx: web::Data<AppContext>,
+    req: HttpRequest,
+    path: web::Path<(i64, String)>,
+    mut payload: web::Payload,
+) -> Result<HttpResponse, ApiError> {
+    let (id, tail) = path.into_inner();
+    let segments = route_segments(&tail)
+        .ok_or_else(|| ApiError::BadRequest(format!("Route {tail} is not a plain path")))?;
+    if !is_forwarded(&segments) {
+        return Err(ApiError::NotFound(format!(
+            "Route {tail} can not be forwarded"
+        )));
+    }
+    let node = ctx
+        .node_holder()
+        .get_node(id)
+        .await
+        .ok_or_else(|| ApiError::NotFound(format!("Node {id} not found")))?;
+    let method = Method::from_bytes(req.method().as_str().as_bytes())
+        .map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    let header = |name| req.headers().get(name).and_then(|it| it.to_str().ok());
+    let content_type = header(CONTENT_TYPE);
+    let has_body =
+        header(CONTENT_LENGTH).is_some_and(|it| it != "0") || header(TRANSFER_ENCODING).is_some();
+    let body = if has_body {
+        // The payload is bound to this worker, so it is relayed through a
+        // channel to give the peer request a body it can stream.
+        let (mut sender, receiver) = mpsc::channel(1);
+        actix_web::rt::spawn(async move {
+            while let Some(chunk) = payload.next().await {
+                let chunk = chunk.map_err(|e| std::io::Error::other(e.to_string()));
+                if sender.send(chunk).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Body::wrap_stream(receiver)
+    } else {
+        Body::from(Vec::new())
+    };
+    let query = req.uri().query();
+    let response = client::forward(&ctx, &node, method, &segments, query, content_type, body)
+        .await
+        .map_err(|e| {
+            error!("Failed to forward {} to node {}: {:?}", tail, id, e);
+            ApiError::NodeUnreachable(id)
+        })?;
+
+    let status =
+        StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let mut builder = HttpResponse::build(status);
+    if let Some(content_type) = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|it| it.to_str().ok())
+    {
+        builder.content_type(content_type.to_string());
+    }
+    Ok(builder.streaming(response.bytes_stream()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_forwarded() {
+        let forwarded = |tail| route_segments(tail).is_some_and(|it| is_forwarded(&it));
+        assert!(forwarded("play"));
+        assert!(forwarded("video_list/intro.mp4"));
+        assert!(!forwarded("download/config.json"));
+        assert!(!forwarded("nodes"));
+    }
+
+    #[test]
+    fn test_route_segments() {
+        assert_eq!(
+            route_segments("video_list/a%2Fb%20c.mp4"),
+            Some(vec!["video_list".to_string(), "a/b c.mp4".to_string()])
+        );
+        assert_eq!(
+            route_segments("video_list/100%.mp4").unwrap()[1],
+            "100%.mp4"
+        );
+        assert_eq!(route_segments("video_list/../config"), None);
+        assert_eq!(route_segments("video_list/%2e%2E/audit"), None);
+        assert_eq!(route_segments("video_list/./kill_player"), None);
+        assert_eq!(route_segments("video_list//config"), None);
+        assert_eq!(route_segments("video_list/"), None);
+        assert_eq!(route_segments("video_list/%FF"), None);
+    }
+}
@@ -0,0 +1,1403 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex as StdMutex, PoisonError},
+    time::Duration,
+};
+
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse};
+use chrono::{Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Timelike};
+use domain::node::Node;
+use futures::future::join_all;
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use storage::Storage;
+use tokio::{
+    sync::{Mutex, Notify},
+    time::sleep,
+};
+use tracing::{error, info, warn};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    client,
+    context::AppContext,
+    error::ApiError,
+    playlist::{
+        self, known_group, known_node, ActivePlaylist, Assignments, Playlist, PlaylistStore,
+    },
+};
+
+const MAX_NAME_LEN: usize = 64;
+const MINUTE_MILLIS: u128 = 60_000;
+/// Divergences kept for the API, the oldest are dropped first.
+const MAX_DIVERGENCES: usize = 100;
+/// How long a node may take to accept its schedule.
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Rules by id.
+type Rules = BTreeMap<i64, Rule>;
+
+/// The node or group a rule is for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleTarget {
+    Node(i64),
+    Group(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl From<chrono::Weekday> for Weekday {
+    fn from(weekday: chrono::Weekday) -> Self {
+        match weekday {
+            chrono::Weekday::Mon => Weekday::Mon,
+            chrono::Weekday::Tue => Weekday::Tue,
+            chrono::Weekday::Wed => Weekday::Wed,
+            chrono::Weekday::Thu => Weekday::Thu,
+            chrono::Weekday::Fri => Weekday::Fri,
+            chrono::Weekday::Sat => Weekday::Sat,
+            chrono::Weekday::Sun => Weekday::Sun,
+        }
+    }
+}
+
+/// When a playlist plays on a node or group. Every condition that is set
+/// must hold, a rule without conditions always applies.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct NewRule {
+    pub name: String,
+    /// Id of the playlist played while the rule applies.
+    pub playlist: i64,
+    pub target: ScheduleTarget,
+    /// The highest priority among the applying rules wins, defaults to 0.
+    #[serde(default)]
+    pub priority: i32,
+    /// Local time of day the rule starts to apply, `HH:MM`.
+    pub start_time: Option<String>,
+    /// Local time of day the rule stops applying, `HH:MM`. A range ending
+    /// before it starts goes past midnight, and the part after midnight is
+    /// checked against the weekdays and dates of the day it started.
+    pub end_time: Option<String>,
+    /// Days the rule applies on, every day when empty.
+    #[serde(default)]
+    pub weekdays: Vec<Weekday>,
+    /// First day the rule applies on, `YYYY-MM-DD`.
+    pub start_date: Option<String>,
+    /// Last day the rule applies on, `YYYY-MM-DD`.
+    pub end_date: Option<String>,
+    /// The minutes the rule applies in, as a cron expression with the fields
+    /// minute, hour, day of month, month and day of week.
+    pub cron: Option<String>,
+}
+
+impl NewRule {
+    fn validate(&self) -> Result<(), ApiError> {
+        if self.name.is_empty() || self.name.chars().count() > MAX_NAME_LEN {
+            return Err(ApiError::BadRequest(format!(
+                "The name must be 1 to {MAX_NAME_LEN} characters"
+            )));
+        }
+        Window::compile(self).map_err(ApiError::BadRequest)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Rule {
+    pub id: i64,
+    #[serde(flatten)]
+    pub rule: NewRule,
+    /// Milliseconds since the unix epoch.
+    pub created_at: u128,
+    pub updated_at: u128,
+}
+
+/// The conditions of a rule, parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Window {
+    start_time: Option<NaiveTime>,
+    end_time: Option<NaiveTime>,
+    weekdays: Vec<Weekday>,
+    start_date: Option<NaiveDate>,
+    end_date: Option<NaiveDate>,
+    cron: Option<Cron>,
+}
+
+impl Window {
+    fn compile(rule: &NewRule) -> Result<Self, String> {
+        let time = |text: &Option<String>| {
+            text.as_deref()
+                .map(|it| NaiveTime::parse_from_str(it, "%H:%M"))
+                .transpose()
+                .map_err(|_| format!("{text:?} is not a time of day as HH:MM"))
+        };
+        let date = |text: &Option<String>| {
+            text.as_deref()
+                .map(|it| NaiveDate::parse_from_str(it, "%Y-%m-%d"))
+                .transpose()
+                .map_err(|_| format!("{text:?} is not a date as YYYY-MM-DD"))
+        };
+        let window = Window {
+            start_time: time(&rule.start_time)?,
+            end_time: time(&rule.end_time)?,
+            weekdays: rule.weekdays.clone(),
+            start_date: date(&rule.start_date)?,
+            end_date: date(&rule.end_date)?,
+            cron: rule.cron.as_deref().map(Cron::parse).transpose()?,
+        };
+        if let (Some(start), Some(end)) = (window.start_date, window.end_date) {
+            if end < start {
+                return Err("end_date is before start_date".to_string());
+            }
+        }
+        Ok(window)
+    }
+
+    fn contains(&self, at: NaiveDateTime) -> bool {
+        let time = at.time();
+        let in_time = match (self.start_time, self.end_time) {
+            (Some(start), Some(end)) if start <= end => start <= time && time < end,
+            (Some(start), Some(end)) => start <= time || time < end,
+            (Some(start), None) => start <= time,
+            (None, Some(end)) => time < end,
+            (None, None) => true,
+        };
+        // after midnight, a range running past it belongs to the day it started
+        let date = match (self.start_time, self.end_time) {
+            (Some(start), Some(end)) if end < start && time < end => {
+                at.date().pred_opt().unwrap_or(at.date())
+            }
+            _ => at.date(),
+        };
+        in_time
+            && (self.weekdays.is_empty() || self.weekdays.contains(&date.weekday().into()))
+            && self.start_date.is_none_or(|it| it <= date)
+            && self.end_date.is_none_or(|it| date <= it)
+            && self.cron.as_ref().is_none_or(|it| it.matches(at))
+    }
+}
+
+/// A five field cron expression, as a bit per allowed value of each field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of month or the day of week is `*`. When both are
+    /// restricted, either of them matching is enough.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    fn parse(expression: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("{expression:?} doesn't have 5 cron fields"));
+        };
+        let mut weekday_bits = cron_field(weekdays, 0, 7)?;
+        // 7 is another name for Sunday
+        if weekday_bits & 1 << 7 != 0 {
+            weekday_bits |= 1;
+        }
+        Ok(Cron {
+            minutes: cron_field(minutes, 0, 59)?,
+            hours: cron_field(hours, 0, 23)?,
+            days: cron_field(days, 1, 31)?,
+            months: cron_field(months, 1, 12)?,
+            weekdays: weekday_bits,
+            any_day: days.starts_with('*'),
+            any_weekday: weekdays.starts_with('*'),
+        })
+    }
+
+    fn matches(&self, at: NaiveDateTime) -> bool {
+        let bit = |bits: u64, value: u32| bits & 1 << value != 0;
+        let day = bit(self.days, at.day());
+        let weekday = bit(self.weekdays, at.weekday().num_days_from_sunday());
+        let in_day = match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        };
+        bit(self.minutes, at.minute())
+            && bit(self.hours, at.hour())
+            && bit(self.months, at.month())
+            && in_day
+    }
+}
+
+/// Parses a comma separated list of `*`, values and ranges, each with an
+/// optional `/step`.
+fn cron_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let invalid = || format!("{field:?} is not a cron field from {min} to {max}");
+    let number = |text: &str| {
+        text.parse::<u32>()
+            .ok()
+            .filter(|it| (min..=max).contains(it))
+            .ok_or_else(invalid)
+    };
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(invalid());
+        }
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (number(first)?, number(last)?),
+            None if step > 1 => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        if last < first {
+            return Err(invalid());
+        }
+        for value in (first..=last).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+/// Everything the playlist of a node is chosen from.
+struct Plan {
+    rules: Vec<(Rule, Window)>,
+    playlists: BTreeMap<i64, Playlist>,
+    assignments: Assignments,
+    groups: BTreeMap<String, Vec<i64>>,
+}
+
+impl Plan {
+    fn new(
+        rules: Vec<Rule>,
+        playlists: Vec<Playlist>,
+        assignments: Assignments,
+        groups: BTreeMap<String, Vec<i64>>,
+    ) -> Self {
+        let rules = rules
+            .into_iter()
+            .filter_map(|rule| match Window::compile(&rule.rule) {
+                Ok(window) => Some((rule, window)),
+                Err(e) => {
+                    warn!("Skipped the schedule rule {} with error {}", rule.id, e);
+                    None
+                }
+            })
+            .collect();
+        Plan {
+            rules,
+            playlists: playlists.into_iter().map(|it| (it.id, it)).collect(),
+            assignments,
+            groups,
+        }
+    }
+
+    async fn load(ctx: &AppContext) -> Result<Self, ApiError> {
+        Ok(Plan::new(
+            ctx.schedule().rules().await?,
+            ctx.playlists().list().await?,
+            ctx.playlists().assignments().await?,
+            ctx.config().get_config().await.groups().clone(),
+        ))
+    }
+
+    /// Whether there is nothing to play anywhere.
+    fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+            && self.assignments.nodes.is_empty()
+            && self.assignments.groups.is_empty()
+    }
+
+    fn targets(&self, target: &ScheduleTarget, node: i64) -> bool {
+        match target {
+            ScheduleTarget::Node(id) => *id == node,
+            ScheduleTarget::Group(group) => self
+                .groups
+                .get(group)
+                .is_some_and(|members| members.contains(&node)),
+        }
+    }
+
+    /// The rule for `node` that applies at `at` with the highest priority, a
+    /// rule for the node itself ahead of its groups' and then the oldest one.
+    /// Falls back to the playlist assigned to the node or its groups.
+    fn decide(&self, node: i64, at: NaiveDateTime) -> Option<ActivePlaylist> {
+        let rule = self
+            .rules
+            .iter()
+            .filter(|(rule, window)| {
+                self.targets(&rule.rule.target, node)
+                    && self.playlists.contains_key(&rule.rule.playlist)
+                    && window.contains(at)
+            })
+            .max_by_key(|(rule, _)| {
+                let own = matches!(rule.rule.target, ScheduleTarget::Node(_));
+                (rule.rule.priority, own, Reverse(rule.id))
+            });
+        match rule {
+            Some((rule, _)) => Some(ActivePlaylist {
+                playlist: self.playlists[&rule.rule.playlist].clone(),
+                rule: Some(rule.id),
+            }),
+            None => self
+                .assignments
+                .playlist_of(node, &self.groups)
+                .and_then(|it| self.playlists.get(&it))
+                .map(|playlist| ActivePlaylist {
+                    playlist: playlist.clone(),
+                    rule: None,
+                }),
+        }
+    }
+
+    /// The part of the plan `node` needs to choose its playlist on its own.
+    fn local_schedule(&self, node: i64, coordinator: i64, now: u128) -> LocalSchedule {
+        let rules: Vec<Rule> = self
+            .rules
+            .iter()
+            .filter(|(rule, _)| self.targets(&rule.rule.target, node))
+            .map(|(rule, _)| rule.clone())
+            .collect();
+        let groups: BTreeMap<String, Vec<i64>> = self
+            .groups
+            .iter()
+            .filter(|(_, members)| members.contains(&node))
+            .map(|(group, _)| (group.clone(), vec![node]))
+            .collect();
+        let assigned = self.assignments.playlist_of(node, &self.groups);
+        let used: HashSet<i64> = rules
+            .iter()
+            .map(|it| it.rule.playlist)
+            .chain(assigned)
+            .collect();
+        LocalSchedule {
+            coordinator,
+            published_at: now,
+            rules,
+            playlists: self
+                .playlists
+                .values()
+                .filter(|it| used.contains(&it.id))
+                .cloned()
+                .collect(),
+            assigned,
+            groups: groups.into_keys().collect(),
+        }
+    }
+}
+
+/// The rules and playlists one node follows, cached there so it keeps
+/// switching playlists while the coordinator is unreachable.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct LocalSchedule {
+    /// Node that published the schedule.
+    pub coordinator: i64,
+    /// Milliseconds since the unix epoch.
+    pub published_at: u128,
+    /// Rules for the node or its groups.
+    pub rules: Vec<Rule>,
+    /// Playlists the rules and the assignment refer to.
+    pub playlists: Vec<Playlist>,
+    /// Playlist played when no rule applies.
+    pub assigned: Option<i64>,
+    /// Groups the node is a member of.
+    pub groups: Vec<String>,
+}
+
+impl LocalSchedule {
+    /// Whether both choose the same playlists, wherever they were published.
+    fn same_as(&self, other: &LocalSchedule) -> bool {
+        self.rules == other.rules
+            && self.playlists == other.playlists
+            && self.assigned == other.assigned
+            && self.groups == other.groups
+    }
+
+    fn plan(&self, node: i64) -> Plan {
+        let assignments = Assignments {
+            nodes: self.assigned.map(|it| (node, it)).into_iter().collect(),
+            groups: BTreeMap::new(),
+        };
+        let groups = self
+            .groups
+            .iter()
+            .map(|group| (group.clone(), vec![node]))
+            .collect();
+        Plan::new(
+            self.rules.clone(),
+            self.playlists.clone(),
+            assignments,
+            groups,
+        )
+    }
+}
+
+/// What a node answers when it is given a new schedule.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct LocalReport {
+    /// When the schedule the node followed until now was published, `None`
+    /// when it had none.
+    pub followed: Option<u128>,
+    /// What the node's player was playing.
+    pub active: Option<ActivePlaylist>,
+}
+
+/// The cached schedule of a node and what it plays.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct LocalState {
+    pub schedule: Option<LocalSchedule>,
+    pub active: Option<ActivePlaylist>,
+}
+
+/// How a node that was unreachable for a while differed from the
+/// coordinator once it was reached again.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Divergence {
+    pub node: i64,
+    pub name: String,
+    /// Milliseconds since the unix epoch, since the node could not be reached.
+    pub unreachable_since: u128,
+    /// When the node was reached again.
+    pub detected_at: u128,
+    /// When the schedule the node followed was published, `None` when it had none.
+    pub followed: Option<u128>,
+    /// Whether that schedule was outdated.
+    pub stale: bool,
+    /// Playlist the node played.
+    pub playing: Option<i64>,
+    /// Playlist the coordinator's schedule chooses.
+    pub expected: Option<i64>,
+}
+
+/// The local time of a timestamp in milliseconds since the unix epoch.
+fn local_time(millis: u128) -> NaiveDateTime {
+    Local
+        .timestamp_millis_opt(millis as i64)
+        .single()
+        .map(|it| it.naive_local())
+        .unwrap_or_default()
+}
+
+/// The schedule rules, the schedule this node follows and what it knows as
+/// coordinator, persisted through [`Storage`], and the divergences found
+/// while coordinating.
+#[derive(Debug, Clone, Default)]
+pub struct Scheduler {
+    storage: Arc<Mutex<Option<Storage<Rules>>>>,
+    local: Arc<Mutex<Option<Storage<Option<LocalSchedule>>>>>,
+    coordination: Arc<Mutex<Option<Storage<Coordination>>>>,
+    divergences: Arc<StdMutex<Vec<Divergence>>>,
+    wake_publish: Arc<Notify>,
+    wake_follow: Arc<Notify>,
+}
+
+impl Scheduler {
+    /// Loads the rules, the cached schedule and the coordination from the
+    /// files named in the config.
+    pub async fn open(&self, rules: PathBuf, local: PathBuf, coordination: PathBuf) {
+        *self.storage.lock().await = Some(Storage::open(rules).await);
+        *self.local.lock().await = Some(Storage::open(local).await);
+        *self.coordination.lock().await = Some(Storage::open(coordination).await);
+    }
+
+    /// Changes the rules with `f` and stores them, unless `f` fails. The
+    /// playlist a changed rule plays is looked up while the rules are held,
+    /// so it can't be removed before the rule is stored.
+    async fn update<R>(
+        &self,
+        plays: Option<(i64, &PlaylistStore)>,
+        f: impl FnOnce(&mut Rules) -> Result<R, ApiError>,
+    ) -> Result<R, ApiError> {
+        let mut storage = self.storage.lock().await;
+        let storage = storage
+            .as_mut()
+            .ok_or_else(|| ApiError::Internal(anyhow::anyhow!("The schedule is not open")))?;
+        if let Some((playlist, playlists)) = plays {
+            playlists.get(playlist).await?;
+        }
+        let mut rules = storage.get().await.map_err(ApiError::Internal)?;
+        let result = f(&mut rules)?;
+        storage.set(rules).await.map_err(ApiError::Internal)?;
+        self.wake();
+        Ok(result)
+    }
+
+    pub async fn rules(&self) -> Result<Vec<Rule>, ApiError> {
+        let mut storage = self.storage.lock().await;
+        match storage.as_mut() {
+            Some(storage) => Ok(storage
+                .get()
+                .await
+                .map_err(ApiError::Internal)?
+                .into_values()
+                .collect()),
+            None => Err(ApiError::Internal(anyhow::anyhow!(
+                "The schedule is not open"
+            ))),
+        }
+    }
+
+    pub async fn insert(&self, rule: Rule, playlists: &PlaylistStore) -> Result<(), ApiError> {
+        self.update(Some((rule.rule.playlist, playlists)), |rules| {
+            rules.insert(rule.id, rule);
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn replace(
+        &self,
+        id: i64,
+        rule: NewRule,
+        now: u128,
+        playlists: &PlaylistStore,
+    ) -> Result<Rule, ApiError> {
+        self.update(Some((rule.playlist, playlists)), |rules| {
+            let stored = rules.get_mut(&id).ok_or_else(|| not_found(id))?;
+            stored.rule = rule;
+            stored.updated_at = now;
+            Ok(stored.clone())
+        })
+        .await
+    }
+
+    pub async fn remove(&self, id: i64) -> Result<(), ApiError> {
+        self.update(None, |rules| {
+            rules.remove(&id).map(|_| ()).ok_or_else(|| not_found(id))
+        })
+        .await
+    }
+
+    /// Removes playlist `id` from `playlists` unless a rule plays it. Rules
+    /// can't change meanwhile, so none is left pointing at a removed playlist.
+    pub async fn remove_playlist(
+        &self,
+        id: i64,
+        playlists: &PlaylistStore,
+    ) -> Result<(), ApiError> {
+        let mut storage = self.storage.lock().await;
+        let storage = storage
+            .as_mut()
+            .ok_or_else(|| ApiError::Internal(anyhow::anyhow!("The schedule is not open")))?;
+        let rules = storage.get().await.map_err(ApiError::Internal)?;
+        let playing: Vec<String> = rules
+            .values()
+            .filter(|it| it.rule.playlist == id)
+            .map(|it| it.id.to_string())
+            .collect();
+        if !playing.is_empty() {
+            return Err(ApiError::PlaylistInUse(playing.join(", ")));
+        }
+        playlists.remove(id).await
+    }
+
+    /// The schedule this node follows.
+    pub async fn local(&self) -> Result<Option<LocalSchedule>, ApiError> {
+        let mut storage = self.local.lock().await;
+        match storage.as_mut() {
+            Some(storage) => storage.get().await.map_err(ApiError::Internal),
+            None => Err(ApiError::Internal(anyhow::anyhow!(
+                "The local schedule is not open"
+            ))),
+        }
+    }
+
+    /// Stores the schedule this node follows and returns the one it replaces.
+    pub async fn set_local(
+        &self,
+        schedule: LocalSchedule,
+    ) -> Result<Option<LocalSchedule>, ApiError> {
+        let mut storage = self.local.lock().await;
+        let storage = storage
+            .as_mut()
+            .ok_or_else(|| ApiError::Internal(anyhow::anyhow!("The local schedule is not open")))?;
+        let previous = storage.get().await.map_err(ApiError::Internal)?;
+        storage
+            .set(Some(schedule))
+            .await
+            .map_err(ApiError::Internal)?;
+        self.wake_follow.notify_one();
+        Ok(previous)
+    }
+
+    async fn coordination(&self) -> Result<Coordination, ApiError> {
+        let mut storage = self.coordination.lock().await;
+        match storage.as_mut() {
+            Some(storage) => storage.get().await.map_err(ApiError::Internal),
+            None => Err(ApiError::Internal(anyhow::anyhow!(
+                "The coordination is not open"
+            ))),
+        }
+    }
+
+    async fn set_coordination(&self, coordination: Coordination) -> Result<(), ApiError> {
+        let mut storage = self.coordination.lock().await;
+        let storage = storage
+            .as_mut()
+            .ok_or_else(|| ApiError::Internal(anyhow::anyhow!("The coordination is not open")))?;
+        storage.set(coordination).await.map_err(ApiError::Internal)
+    }
+
+    /// Divergences found when unreachable nodes came back, newest first.
+    pub fn divergences(&self) -> Vec<Divergence> {
+        self.divergences
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn add_divergence(&self, divergence: Divergence) {
+        let mut divergences = self
+            .divergences
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        divergences.insert(0, divergence);
+        divergences.truncate(MAX_DIVERGENCES);
+    }
+
+    /// Makes the scheduler choose the playlists again right away.
+    pub fn wake(&self) {
+        self.wake_publish.notify_one();
+        self.wake_follow.notify_one();
+    }
+}
+
+fn not_found(id: i64) -> ApiError {
+    ApiError::NotFound(format!("Schedule rule {id} not found"))
+}
+
+/// What the coordinating node knows about the schedules it published, kept
+/// across restarts so it doesn't publish them all again and still notices
+/// the nodes that were unreachable.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Coordination {
+    /// The last schedule each node accepted.
+    published: HashMap<i64, LocalSchedule>,
+    /// Since when each node that was given a schedule could not be reached.
+    unreachable: HashMap<i64, u128>,
+}
+
+/// At the start of each minute, and whenever rules, playlists, assignments
+/// or the cached schedule change, publishes the schedules of the other nodes
+/// and switches this node's playlist by its cached schedule. Following runs
+/// on its own tick, so a slow peer never delays this node's switch, and every
+/// node keeps following its cached schedule while the coordinator is
+/// unreachable.
+pub async fn run(ctx: AppContext) {
+    tokio::spawn(run_follow(ctx.clone()));
+    loop {
+        if let Err(e) = coordinate(&ctx).await {
+            error!("Failed to publish the schedule with error {:?}", e);
+        }
+        next_tick(&ctx, &ctx.schedule().wake_publish).await;
+    }
+}
+
+/// Publishes from what the last run learned and stores what this one did.
+async fn coordinate(ctx: &AppContext) -> Result<(), ApiError> {
+    let mut coordination = ctx.schedule().coordination().await?;
+    let before = coordination.clone();
+    let result = publish(ctx, &mut coordination).await;
+    if coordination != before {
+        ctx.schedule().set_coordination(coordination).await?;
+    }
+    result
+}
+
+async fn run_follow(ctx: AppContext) {
+    loop {
+        if let Err(e) = follow(&ctx).await {
+            error!("Failed to follow the schedule with error {:?}", e);
+        }
+        next_tick(&ctx, &ctx.schedule().wake_follow).await;
+    }
+}
+
+/// Waits for the start of the next minute or for `wake`.
+async fn next_tick(ctx: &AppContext, wake: &Notify) {
+    let wait = MINUTE_MILLIS - ctx.clock().now_millis() % MINUTE_MILLIS;
+    tokio::select! {
+        _ = sleep(Duration::from_millis(wait as u64)) => {}
+        _ = wake.notified() => {}
+    }
+}
+
+/// On the coordinator, sends every node the schedule it follows when it
+/// changed, or when the node is reachable again, then compares what the node
+/// did meanwhile. Other nodes follow what the coordinator sends them, and
+/// without a coordinator each node only follows its own rules.
+async fn publish(ctx: &AppContext, coordination: &mut Coordination) -> Result<(), ApiError> {
+    let config = ctx.config().get_config().await;
+    let own_id = config.id();
+    let coordinator = config.sync().coordinator;
+    if coordinator.is_some_and(|it| it != own_id) {
+        return Ok(());
+    }
+    let plan = Plan::load(ctx).await?;
+    if plan.is_empty() && coordination.published.is_empty() {
+        return Ok(());
+    }
+    let now = ctx.clock().now_millis();
+    let own = plan.local_schedule(own_id, own_id, now);
+    if !ctx
+        .schedule()
+        .local()
+        .await?
+        .is_some_and(|it| it.same_as(&own))
+    {
+        ctx.schedule().set_local(own).await?;
+    }
+    if coordinator != Some(own_id) {
+        return Ok(());
+    }
+
+    let peers = ctx.node_holder().get_node_list().await;
+    let mut due = Vec::new();
+    for node in peers.into_iter().filter(|it| it.id != own_id) {
+        let unreachable_since = coordination.unreachable.get(&node.id).copied();
+        if !node.active {
+            if coordination.published.contains_key(&node.id) && unreachable_since.is_none() {
+                coordination.unreachable.insert(node.id, now);
+            }
+            continue;
+        }
+        let schedule = plan.local_schedule(node.id, own_id, now);
+        let last = coordination.published.get(&node.id);
+        if unreachable_since.is_none() && last.is_some_and(|it| it.same_as(&schedule)) {
+            continue;
+        }
+        due.push((node, schedule));
+    }
+
+    let given = join_all(due.into_iter().map(|(node, schedule)| async move {
+        let report = give(ctx, &node, &schedule).await;
+        (node, schedule, report)
+    }))
+    .await;
+    for (node, schedule, report) in given {
+        let unreachable_since = coordination.unreachable.get(&node.id).copied();
+        let last = coordination.published.get(&node.id);
+        let report = match report {
+            Ok(report) => report,
+            Err(e) => {
+                // tried again at the next minute
+                warn!("Failed to publish the schedule of node {}: {}", node.id, e);
+                if last.is_some() {
+                    coordination.unreachable.entry(node.id).or_insert(now);
+                }
+                continue;
+            }
+        };
+        if let Some(since) = unreachable_since {
+            let stale = !last.is_some_and(|it| {
+                report.followed == Some(it.published_at) && it.same_as(&schedule)
+            });
+            let playing = report.active.as_ref().map(|it| it.playlist.id);
+            let expected = plan
+                .decide(node.id, local_time(now))
+                .map(|it| it.playlist.id);
+            if stale || playing != expected {
+                warn!(
+                    "Node {} played {:?} instead of {:?} while unreachable",
+                    node.id, playing, expected
+                );
+                ctx.schedule().add_divergence(Divergence {
+                    node: node.id,
+                    name: node.name.clone(),
+                    unreachable_since: since,
+                    detected_at: now,
+                    followed: report.followed,
+                    stale,
+                    playing,
+                    expected,
+                });
+            }
+        }
+        info!("Published the schedule of node {}", node.id);
+        coordination.unreachable.remove(&node.id);
+        coordination.published.insert(node.id, schedule);
+    }
+    Ok(())
+}
+
+async fn give(
+    ctx: &AppContext,
+    node: &Node,
+    schedule: &LocalSchedule,
+) -> Result<LocalReport, String> {
+    client::peer_request(ctx, node, Method::PUT, "schedule/local")
+        .json(schedule)
+        .timeout(PUBLISH_TIMEOUT)
+        .send()
+        .await
+        .and_then(|it| it.error_for_status())
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Switches this node's playlist to what its cached schedule chooses against
+/// the local clock.
+async fn follow(ctx: &AppContext) -> Result<(), ApiError> {
+    let Some(schedule) = ctx.schedule().local().await? else {
+        return Ok(());
+    };
+    let own_id = ctx.config().get_config().await.id();
+    let active = schedule
+        .plan(own_id)
+        .decide(own_id, local_time(ctx.clock().now_millis()));
+    if ctx.player_playlist().get() != active {
+        info!(
+            "Switching to playlist {:?}",
+            active.as_ref().map(|it| it.playlist.id)
+        );
+        playlist::play(ctx, active).await?;
+    }
+    Ok(())
+}
+
+/// Fails unless the rule's target exists. Its playlist is looked up when
+/// the rule is stored.
+async fn check_references(ctx: &AppContext, rule: &NewRule) -> Result<(), ApiError> {
+    match &rule.target {
+        ScheduleTarget::Node(id) => known_node(ctx, *id).await,
+        ScheduleTarget::Group(group) => known_group(ctx, group).await,
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PreviewQuery {
+    /// Milliseconds since the unix epoch, defaults to now.
+    pub at: Option<u128>,
+    /// Only this node.
+    pub node: Option<i64>,
+}
+
+/// What a node plays at the previewed moment.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Preview {
+    pub node: i64,
+    pub name: String,
+    /// `None` when no rule applies and nothing is assigned.
+    pub active: Option<ActivePlaylist>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/schedule/rules",
+    tag = "playlists",
+    request_body = NewRule,
+    responses(
+        (status = 201, description = "The rule was stored, its url is in the Location header", body = Rule),
+        (status = 400, description = "The name is empty or too long, or a time, date or cron expression is malformed", body = ErrorBody),
+        (status = 404, description = "The playlist or the target doesn't exist", body = ErrorBody),
+    )
+)]
+#[post("/schedule/rules")]
+pub async fn create_rule(
+    ctx: web::Data<AppContext>,
+    body: web::Json<NewRule>,
+) -> Result<HttpResponse, ApiError> {
+    let rule = body.into_inner();
+    rule.validate()?;
+    check_references(&ctx, &rule).await?;
+    let now = ctx.clock().now_millis();
+    let rule = Rule {
+        id: ctx.ids().generate(),
+        rule,
+        created_at: now,
+        updated_at: now,
+    };
+    ctx.schedule().insert(rule.clone(), ctx.playlists()).await?;
+    Ok(HttpResponse::Created()
+        .insert_header(("Location", format!("/schedule/rules/{}", rule.id)))
+        .json(rule))
+}
+
+#[utoipa::path(
+    get,
+    path = "/schedule/rules",
+    tag = "playlists",
+    responses((status = 200, description = "Every schedule rule, oldest first", body = [Rule]))
+)]
+#[get("/schedule/rules")]
+pub async fn get_rules(ctx: web::Data<AppContext>) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(ctx.schedule().rules().await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/schedule/rules/{id}",
+    tag = "playlists",
+    params(("id" = i64, Path, description = "Id of the rule")),
+    responses(
+        (status = 200, description = "The rule", body = Rule),
+        (status = 404, description = "No such rule", body = ErrorBody),
+    )
+)]
+#[get("/schedule/rules/{id}")]
+pub async fn get_rule(
+    ctx: web::Data<AppContext>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let rule = ctx
+        .schedule()
+        .rules()
+        .await?
+        .into_iter()
+        .find(|it| it.id == id)
+        .ok_or_else(|| not_found(id))?;
+    Ok(HttpResponse::Ok().json(rule))
+}
+
+#[utoipa::path(
+    put,
+    path = "/schedule/rules/{id}",
+    tag = "playlists",
+    params(("id" = i64, Path, description = "Id of the rule")),
+    request_body = NewRule,
+    responses(
+        (status = 200, description = "The rule was replaced", body = Rule),
+        (status = 400, description = "The name is empty or too long, or a time, date or cron expression is malformed", body = ErrorBody),
+        (status = 404, description = "No such rule, or the playlist or the target doesn't exist", body = ErrorBody),
+    )
+)]
+#[put("/schedule/rules/{id}")]
+pub async fn put_rule(
+    ctx: web::Data<AppContext>,
+    id: web::Path<i64>,
+    body: web::Json<NewRule>,
+) -> Result<HttpResponse, ApiError> {
+    let rule = body.into_inner();
+    rule.validate()?;
+    check_references(&ctx, &rule).await?;
+    let rule = ctx
+        .schedule()
+        .replace(
+            id.into_inner(),
+            rule,
+            ctx.clock().now_millis(),
+            ctx.playlists(),
+        )
+        .await?;
+    Ok(HttpResponse::Ok().json(rule))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/schedule/rules/{id}",
+    tag = "playlists",
+    params(("id" = i64, Path, description = "Id of the rule")),
+    responses(
+        (status = 204, description = "The rule was removed"),
+        (status = 404, description = "No such rule", body = ErrorBody),
+    )
+)]
+#[delete("/schedule/rules/{id}")]
+pub async fn delete_rule(
+    ctx: web::Data<AppContext>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    ctx.schedule().remove(id.into_inner()).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    get,
+    path = "/schedule/preview",
+    tag = "playlists",
+    params(PreviewQuery),
+    responses(
+        (status = 200, description = "What every known node, or the one asked for, plays at the moment", body = [Preview]),
+        (status = 404, description = "The node is unknown", body = ErrorBody),
+    )
+)]
+#[get("/schedule/preview")]
+pub async fn preview(
+    ctx: web::Data<AppContext>,
+    query: web::Query<PreviewQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let plan = Plan::load(&ctx).await?;
+    let at = local_time(query.at.unwrap_or_else(|| ctx.clock().now_millis()));
+    let config = ctx.config().get_config().await;
+    let mut nodes = vec![(config.id(), config.node_name().to_string())];
+    nodes.extend(
+        ctx.node_holder()
+            .get_node_list()
+            .await
+            .into_iter()
+            .filter(|it| it.id != config.id())
+            .map(|it| (it.id, it.name)),
+    );
+    if let Some(node) = query.node {
+        known_node(&ctx, node).await?;
+        nodes.retain(|(id, _)| *id == node);
+    }
+    let previews: Vec<Preview> = nodes
+        .into_iter()
+        .map(|(node, name)| Preview {
+            node,
+            name,
+            active: plan.decide(node, at),
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(previews))
+}
+
+#[utoipa::path(
+    get,
+    path = "/schedule/local",
+    tag = "playlists",
+    responses((status = 200, description = "The schedule this node follows and what it plays", body = LocalState))
+)]
+#[get("/schedule/local")]
+pub async fn get_local_schedule(ctx: web::Data<AppContext>) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(LocalState {
+        schedule: ctx.schedule().local().await?,
+        active: ctx.player_playlist().get(),
+    }))
+}
+
+/// Fails unless `schedule` names the configured coordinator and comes from
+/// the address its heartbeats come from.
+fn check_coordinator(
+    configured: Option<i64>,
+    schedule: &LocalSchedule,
+    coordinator: Option<&Node>,
+    source: Option<IpAddr>,
+) -> Result<(), ApiError> {
+    let Some(configured) = configured else {
+        return Err(ApiError::NotCoordinator(
+            "No coordinator is configured in sync.coordinator".to_string(),
+        ));
+    };
+    if schedule.coordinator != configured {
+        return Err(ApiError::NotCoordinator(format!(
+            "Node {} is not the coordinator, node {configured} is",
+            schedule.coordinator
+        )));
+    }
+    let known = coordinator.and_then(|it| it.ipaddress.parse::<IpAddr>().ok());
+    if known.is_none() || known != source {
+        return Err(ApiError::NotCoordinator(format!(
+            "The schedule doesn't come from the address of node {configured}"
+        )));
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    put,
+    path = "/schedule/local",
+    tag = "playlists",
+    request_body = LocalSchedule,
+    responses(
+        (status = 200, description = "The node follows the schedule from now on, and reports what it did until now", body = LocalReport),
+        (status = 400, description = "A playlist has an empty or too long name, an item that is not a valid media path or lasts 0 seconds", body = ErrorBody),
+        (status = 403, description = "The schedule doesn't come from the node configured in `sync.coordinator`", body = ErrorBody),
+    )
+)]
+#[put("/schedule/local")]
+pub async fn put_local_schedule(
+    ctx: web::Data<AppContext>,
+    req: HttpRequest,
+    body: web::Json<LocalSchedule>,
+) -> Result<HttpResponse, ApiError> {
+    let schedule = body.into_inner();
+    let configured = ctx.config().get_config().await.sync().coordinator;
+    let coordinator = match configured {
+        Some(id) => ctx.node_holder().get_node(id).await,
+        None => None,
+    };
+    let source = req.peer_addr().map(|it| it.ip());
+    check_coordinator(configured, &schedule, coordinator.as_ref(), source).inspect_err(|e| {
+        warn!("Refused a schedule: {}", e);
+    })?;
+    for playlist in &schedule.playlists {
+        playlist.validate()?;
+    }
+    let active = ctx.player_playlist().get();
+    let previous = ctx.schedule().set_local(schedule).await?;
+    Ok(HttpResponse::Ok().json(LocalReport {
+        followed: previous.map(|it| it.published_at),
+        active,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/schedule/divergences",
+    tag = "playlists",
+    responses((status = 200, description = "Nodes that played something else than the schedule while they were unreachable, newest first", body = [Divergence]))
+)]
+#[get("/schedule/divergences")]
+pub async fn get_divergences(ctx: web::Data<AppContext>) -> HttpResponse {
+    HttpResponse::Ok().json(ctx.schedule().divergences())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playlist::PlaybackMode;
+
+    fn at(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn new_rule(playlist: i64, target: ScheduleTarget, priority: i32) -> NewRule {
+        NewRule {
+            name: "rule".to_string(),
+            playlist,
+            target,
+            priority,
+            start_time: None,
+            end_time: None,
+            weekdays: vec![],
+            start_date: None,
+            end_date: None,
+            cron: None,
+        }
+    }
+
+    fn rule(id: i64, rule: NewRule) -> (Rule, Window) {
+        let window = Window::compile(&rule).unwrap();
+        let rule = Rule {
+            id,
+            rule,
+            created_at: 0,
+            updated_at: 0,
+        };
+        (rule, window)
+    }
+
+    fn playlist(id: i64) -> (i64, Playlist) {
+        let playlist = Playlist {
+            id,
+            name: format!("playlist-{id}"),
+            items: vec![],
+            mode: PlaybackMode::Loop,
+            created_at: 0,
+            updated_at: 0,
+        };
+        (id, playlist)
+    }
+
+    #[test]
+    fn test_cron() {
+        let cron = Cron::parse("*/15 7-10 * * 1-5").unwrap();
+        // 2024-01-01 is a Monday
+        assert!(cron.matches(at("2024-01-01 07:00")));
+        assert!(cron.matches(at("2024-01-01 10:45")));
+        assert!(!cron.matches(at("2024-01-01 10:50")));
+        assert!(!cron.matches(at("2024-01-01 11:00")));
+        assert!(!cron.matches(at("2024-01-06 07:00")));
+
+        // day of month and day of week restricted, either is enough
+        let cron = Cron::parse("0 12 1 * 7").unwrap();
+        assert!(cron.matches(at("2024-01-07 12:00")));
+        assert!(cron.matches(at("2024-02-01 12:00")));
+        assert!(!cron.matches(at("2024-02-02 12:00")));
+
+        assert!(Cron::parse("* * * *").is_err());
+        assert!(Cron::parse("60 * * * *").is_err());
+        assert!(Cron::parse("*/0 * * * *").is_err());
+        assert!(Cron::parse("5-1 * * * *").is_err());
+    }
+
+    #[test]
+    fn test_window() {
+        let mut evening = new_rule(1, ScheduleTarget::Node(1), 0);
+        evening.start_time = Some("18:00".to_string());
+        evening.end_time = Some("02:00".to_string());
+        evening.weekdays = vec![Weekday::Fri, Weekday::Sat];
+        evening.start_date = Some("2024-01-01".to_string());
+        evening.end_date = Some("2024-01-31".to_string());
+        let window = Window::compile(&evening).unwrap();
+        assert!(window.contains(at("2024-01-05 18:00")));
+        assert!(window.contains(at("2024-01-06 01:59")));
+        assert!(!window.contains(at("2024-01-06 02:00")));
+        assert!(!window.contains(at("2024-01-04 20:00")));
+        assert!(!window.contains(at("2024-02-02 20:00")));
+
+        // the part after midnight belongs to the day the range started
+        let mut friday_night = new_rule(1, ScheduleTarget::Node(1), 0);
+        friday_night.start_time = Some("22:00".to_string());
+        friday_night.end_time = Some("02:00".to_string());
+        friday_night.weekdays = vec![Weekday::Fri];
+        friday_night.start_date = Some("2024-01-01".to_string());
+        friday_night.end_date = Some("2024-01-31".to_string());
+        let window = Window::compile(&friday_night).unwrap();
+        assert!(window.contains(at("2024-01-05 22:00")));
+        assert!(window.contains(at("2024-01-06 01:00")));
+        assert!(!window.contains(at("2024-01-05 01:00")));
+        assert!(!window.contains(at("2024-01-07 01:00")));
+        friday_night.weekdays = vec![];
+        let window = Window::compile(&friday_night).unwrap();
+        assert!(window.contains(at("2024-02-01 01:00")));
+        assert!(!window.contains(at("2024-02-01 22:00")));
+        assert!(!window.contains(at("2024-01-01 01:00")));
+
+        evening.start_time = Some("6pm".to_string());
+        assert!(Window::compile(&evening).is_err());
+        evening.start_time = None;
+        evening.end_date = Some("2023-12-31".to_string());
+        assert!(Window::compile(&evening).is_err());
+    }
+
+    #[test]
+    fn test_decide() {
+        let mut breakfast = new_rule(2, ScheduleTarget::Group("lobby".to_string()), 0);
+        breakfast.start_time = Some("06:00".to_string());
+        breakfast.end_time = Some("10:00".to_string());
+        let mut promotion = new_rule(3, ScheduleTarget::Node(1), 5);
+        promotion.start_time = Some("09:00".to_string());
+        let mut removed = new_rule(9, ScheduleTarget::Node(1), 10);
+        removed.start_time = Some("09:00".to_string());
+        let plan = Plan {
+            rules: vec![rule(10, breakfast), rule(11, promotion), rule(12, removed)],
+            playlists: BTreeMap::from([playlist(1), playlist(2), playlist(3)]),
+            assignments: Assignments {
+                nodes: BTreeMap::new(),
+                groups: BTreeMap::from([("lobby".to_string(), 1)]),
+            },
+            groups: BTreeMap::from([("lobby".to_string(), vec![1, 2])]),
+        };
+        let chosen = |node, time| {
+            plan.decide(node, at(time))
+                .map(|it| (it.playlist.id, it.rule))
+        };
+        assert_eq!(chosen(1, "2024-01-01 07:00"), Some((2, Some(10))));
+        assert_eq!(chosen(1, "2024-01-01 09:30"), Some((3, Some(11))));
+        assert_eq!(chosen(2, "2024-01-01 09:30"), Some((2, Some(10))));
+        assert_eq!(chosen(2, "2024-01-01 12:00"), Some((1, None)));
+        assert_eq!(chosen(3, "2024-01-01 07:00"), None);
+    }
+
+    #[test]
+    fn test_local_schedule_decides_alike() {
+        let mut breakfast = new_rule(2, ScheduleTarget::Group("lobby".to_string()), 0);
+        breakfast.end_time = Some("10:00".to_string());
+        let mut promotion = new_rule(3, ScheduleTarget::Node(1), 5);
+        promotion.start_time = Some("18:00".to_string());
+        let other = new_rule(4, ScheduleTarget::Group("hall".to_string()), 9);
+        let plan = Plan {
+            rules: vec![rule(10, breakfast), rule(11, promotion), rule(12, other)],
+            playlists: BTreeMap::from([playlist(1), playlist(2), playlist(3), playlist(4)]),
+            assignments: Assignments {
+                nodes: BTreeMap::new(),
+                groups: BTreeMap::from([("lobby".to_string(), 1)]),
+            },
+            groups: BTreeMap::from([
+                ("lobby".to_string(), vec![1, 2]),
+                ("hall".to_string(), vec![3]),
+            ]),
+        };
+
+        let local = plan.local_schedule(1, 9, 100);
+        assert_eq!(
+            local.rules.iter().map(|it| it.id).collect::<Vec<_>>(),
+            vec![10, 11]
+        );
+        assert_eq!(
+            local.playlists.iter().map(|it| it.id).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(local.assigned, Some(1));
+        assert_eq!(local.groups, vec!["lobby".to_string()]);
+        let followed = local.plan(1);
+        for time in ["2024-01-01 07:00", "2024-01-01 12:00", "2024-01-01 19:00"] {
+            assert_eq!(followed.decide(1, at(time)), plan.decide(1, at(time)));
+        }
+
+        let republished = plan.local_schedule(1, 9, 200);
+        assert!(local.same_as(&republished));
+        assert!(!local.same_as(&plan.local_schedule(3, 9, 100)));
+    }
+
+    #[test]
+    fn test_check_coordinator() {
+        let mut coordinator = Node::new(9, "coordinator".to_string(), 8081, 0);
+        coordinator.ipaddress = "10.0.0.9".to_string();
+        let schedule = LocalSchedule {
+            coordinator: 9,
+            published_at: 100,
+            rules: vec![],
+            playlists: vec![],
+            assigned: None,
+            groups: vec![],
+        };
+        let from = |ip: &str| Some(ip.parse().unwrap());
+        assert!(
+            check_coordinator(Some(9), &schedule, Some(&coordinator), from("10.0.0.9")).is_ok()
+        );
+        for (configured, known, source) in [
+            (None, Some(&coordinator), from("10.0.0.9")),
+            (Some(8), Some(&coordinator), from("10.0.0.9")),
+            (Some(9), Some(&coordinator), from("10.0.0.7")),
+            (Some(9), None, from("10.0.0.9")),
+            (Some(9), Some(&coordinator), None),
+        ] {
+            assert!(matches!(
+                check_coordinator(configured, &schedule, known, source),
+                Err(ApiError::NotCoordinator(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_playlist_in_use_is_kept() {
+        let dir = std::env::temp_dir().join(format!("schedule-in-use-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let playlists = PlaylistStore::default();
+        playlists.open(dir.join("playlists.json")).await;
+        let scheduler = Scheduler::default();
+        scheduler
+            .open(
+                dir.join("schedule.json"),
+                dir.join("schedule_cache.json"),
+                dir.join("coordination.json"),
+            )
+            .await;
+        playlists.insert(playlist(7).1).await.unwrap();
+        let (playing, _) = rule(10, new_rule(7, ScheduleTarget::Node(1), 0));
+        scheduler.insert(playing, &playlists).await.unwrap();
+        let (missing, _) = rule(11, new_rule(8, ScheduleTarget::Node(1), 0));
+        assert!(matches!(
+            scheduler.insert(missing, &playlists).await,
+            Err(ApiError::NotFound(_))
+        ));
+
+        assert!(matches!(
+            scheduler.remove_playlist(7, &playlists).await,
+            Err(ApiError::PlaylistInUse(rules)) if rules == "10"
+        ));
+        assert!(playlists.get(7).await.is_ok());
+        scheduler.remove(10).await.unwrap();
+        scheduler.remove_playlist(7, &playlists).await.unwrap();
+        assert!(playlists.get(7).await.is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
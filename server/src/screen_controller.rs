@@ -1,34 +1,61 @@
-use std::{panic::catch_unwind, sync::Arc};
+use std::sync::{Arc, PoisonError};
 
 use actix_web::{get, web::Data};
-use lazy_static::lazy_static;
+use base64::{engine::general_purpose::STANDARD, Engine};
 use tokio::sync::{mpsc::Receiver, Mutex};
-use tracing::error;
+use tracing::warn;
 
-lazy_static! {
-    static ref LATEST: std::sync::RwLock<String> = std::sync::RwLock::new("".to_string());
+use crate::context::AppContext;
+
+/// Encodes a screenshot as a data url, the image type is read from its signature.
+fn to_data_url(image: &[u8]) -> Option<String> {
+    let image_type = if image.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        "jpeg"
+    } else if image.starts_with(b"\x89PNG\r\n\x1a\n") {
+        "png"
+    } else {
+        return None;
+    };
+    Some(format!(
+        "data:image/{image_type};base64,{}",
+        STANDARD.encode(image)
+    ))
 }
 
+#[utoipa::path(
+    get,
+    path = "/screen",
+    tag = "screen",
+    responses((status = 200, description = "The latest screenshot as a base64 data url, empty before the first capture", body = String, content_type = "text/plain"))
+)]
 #[get("/screen")]
-pub async fn screenshot(rx: Data<Arc<Mutex<Receiver<Vec<u8>>>>>) -> String {
-    std::panic::set_hook(Box::new(|e| {
-        error!("screenshot error: {:?}", e);
-    }));
-    if let Ok(vec) = rx.lock().await.try_recv() {
-        let reuslt = catch_unwind(|| image_base64::to_base64_vec(vec));
-        match reuslt {
-            Ok(base64) => {
-                let mut latest = LATEST.write().unwrap();
-                *latest = base64.clone();
-                base64
-            }
-            Err(_) => {
-                let latest = LATEST.read().unwrap();
-                latest.clone()
-            }
+pub async fn screenshot(ctx: Data<AppContext>, rx: Data<Arc<Mutex<Receiver<Vec<u8>>>>>) -> String {
+    let latest = ctx.latest_screenshot();
+    if let Ok(image) = rx.lock().await.try_recv() {
+        match to_data_url(&image) {
+            Some(url) => *latest.write().unwrap_or_else(PoisonError::into_inner) = url,
+            None => warn!("Dropping a screenshot in an unknown format"),
         }
-    } else {
-        let latest = LATEST.read().unwrap();
-        latest.clone()
+    }
+    latest
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_data_url() {
+        assert_eq!(
+            to_data_url(&[0xFF, 0xD8, 0xFF, 0xE0]).unwrap(),
+            "data:image/jpeg;base64,/9j/4A=="
+        );
+        assert!(to_data_url(b"\x89PNG\r\n\x1a\n")
+            .unwrap()
+            .starts_with("data:image/png;base64,"));
+        assert_eq!(to_data_url(b"GIF89a"), None);
     }
 }
@@ -0,0 +1,584 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use actix_web::{get, put, web, HttpResponse};
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use storage::Storage;
+use tokio::{
+    fs,
+    sync::{Mutex as AsyncMutex, Notify},
+    time::sleep,
+};
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    bulk::{fan_out, BulkReport, BulkRequest, BulkTarget},
+    catalogue::CatalogueItem,
+    context::AppContext,
+    error::ApiError,
+    media::MediaRoot,
+    transfer,
+    upload::{is_sha256, move_into, sha256_file},
+};
+
+/// A file the manifest asks for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ManifestFile {
+    /// File name in the media root.
+    pub name: String,
+    /// Hex SHA-256 the file must have.
+    pub sha256: String,
+}
+
+/// The media a node should hold.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct MediaManifest {
+    pub files: Vec<ManifestFile>,
+    /// Remove files from the media root that are not listed.
+    #[serde(default)]
+    pub prune: bool,
+}
+
+impl MediaManifest {
+    /// Refuses malformed hashes and names listed twice, and lowercases the hashes.
+    fn normalize(&mut self) -> Result<(), ApiError> {
+        let mut names = HashSet::new();
+        for file in &mut self.files {
+            if !is_sha256(&file.sha256) {
+                return Err(ApiError::BadRequest(format!(
+                    "{:?} is not a hex SHA-256",
+                    file.sha256
+                )));
+            }
+            if !names.insert(file.name.clone()) {
+                return Err(ApiError::BadRequest(format!(
+                    "{:?} is listed more than once",
+                    file.name
+                )));
+            }
+            file.sha256.make_ascii_lowercase();
+        }
+        Ok(())
+    }
+}
+
+/// A manifest published to a group, and the members that took it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct GroupManifest {
+    manifest: MediaManifest,
+    /// The others are given the manifest again on every run.
+    delivered: BTreeSet<i64>,
+}
+
+type GroupManifests = BTreeMap<String, GroupManifest>;
+
+/// A listed file the node could not get.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct MissingFile {
+    pub name: String,
+    pub message: String,
+}
+
+/// What the last comparison with the manifest changed.
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct SyncStatus {
+    /// Milliseconds since the unix epoch, `None` before the first run.
+    pub last_run: Option<u128>,
+    /// Files fetched from a peer or copied from another name.
+    pub pulled: Vec<String>,
+    /// Files removed because the manifest prunes and doesn't list them.
+    pub removed: Vec<String>,
+    pub missing: Vec<MissingFile>,
+}
+
+/// What has to change for the media root to match a manifest.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Plan {
+    fetch: Vec<ManifestFile>,
+    remove: Vec<String>,
+}
+
+/// `keep` names the files a playlist still plays, they are never pruned.
+fn plan<'a>(
+    manifest: &MediaManifest,
+    items: impl Iterator<Item = &'a CatalogueItem>,
+    keep: &HashSet<String>,
+) -> Plan {
+    let mut plan = Plan {
+        fetch: manifest.files.clone(),
+        ..Default::default()
+    };
+    for item in items {
+        match manifest.files.iter().find(|it| it.name == item.name) {
+            Some(file) if file.sha256.eq_ignore_ascii_case(&item.sha256) => {
+                plan.fetch.retain(|it| it.name != item.name);
+            }
+            Some(_) => {}
+            None if manifest.prune && !keep.contains(&item.name) => {
+                plan.remove.push(item.name.clone())
+            }
+            None => {}
+        }
+    }
+    plan
+}
+
+/// The manifest this node follows and the manifests it published to
+/// groups, both persisted through [`Storage`], and the outcome of the last run.
+#[derive(Debug, Clone, Default)]
+pub struct SyncService {
+    manifest: Arc<AsyncMutex<Option<Storage<Option<MediaManifest>>>>>,
+    groups: Arc<AsyncMutex<Option<Storage<GroupManifests>>>>,
+    status: Arc<Mutex<SyncStatus>>,
+    wake: Arc<Notify>,
+}
+
+impl SyncService {
+    /// Loads the manifest and the group manifests from the files named in the config.
+    pub async fn open(&self, path: PathBuf, groups: PathBuf) {
+        *self.manifest.lock().await = Some(Storage::open(path).await);
+        *self.groups.lock().await = Some(Storage::open(groups).await);
+    }
+
+    async fn update_groups<R>(
+        &self,
+        f: impl FnOnce(&mut GroupManifests) -> R,
+    ) -> anyhow::Result<R> {
+        let mut storage = self.groups.lock().await;
+        let storage = storage
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("The group manifests are not open"))?;
+        let mut groups = storage.get().await?;
+        let result = f(&mut groups);
+        storage.set(groups).await?;
+        Ok(result)
+    }
+
+    async fn group_manifests(&self) -> anyhow::Result<GroupManifests> {
+        let mut storage = self.groups.lock().await;
+        match storage.as_mut() {
+            Some(storage) => storage.get().await,
+            None => Err(anyhow::anyhow!("The group manifests are not open")),
+        }
+    }
+
+    /// Records that `ids` took `manifest`, unless the group got another one meanwhile.
+    async fn delivered(
+        &self,
+        group: &str,
+        manifest: &MediaManifest,
+        ids: impl IntoIterator<Item = i64>,
+    ) -> anyhow::Result<()> {
+        self.update_groups(|groups| {
+            if let Some(stored) = groups.get_mut(group) {
+                if stored.manifest == *manifest {
+                    stored.delivered.extend(ids);
+                }
+            }
+        })
+        .await
+    }
+
+    pub async fn manifest(&self) -> anyhow::Result<Option<MediaManifest>> {
+        let mut storage = self.manifest.lock().await;
+        match storage.as_mut() {
+            Some(storage) => storage.get().await,
+            None => Err(anyhow::anyhow!("The manifest is not open")),
+        }
+    }
+
+    /// Stores the manifest and compares the media root with it right away.
+    pub async fn set_manifest(&self, manifest: MediaManifest) -> anyhow::Result<()> {
+        let mut storage = self.manifest.lock().await;
+        storage
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("The manifest is not open"))?
+            .set(Some(manifest))
+            .await?;
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    pub fn status(&self) -> SyncStatus {
+        self.status
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn set_status(&self, status: SyncStatus) {
+        *self.status.lock().unwrap_or_else(PoisonError::into_inner) = status;
+    }
+}
+
+/// Compares the media root with the manifest every `sync.interval_secs`,
+/// and whenever a new manifest arrives. Group members that didn't take their
+/// group's manifest yet are given it again on the same tick.
+pub async fn run(ctx: AppContext) {
+    loop {
+        if let Err(e) = redeliver(&ctx).await {
+            error!("Failed to deliver the group manifests with error {:?}", e);
+        }
+        if let Err(e) = sync_once(&ctx).await {
+            error!("Failed to sync the media root with error {:?}", e);
+        }
+        let interval = ctx.config().get_config().await.sync().interval_secs;
+        tokio::select! {
+            _ = sleep(Duration::from_secs(interval.max(1))) => {}
+            _ = ctx.sync().wake.notified() => {}
+        }
+    }
+}
+
+async fn sync_once(ctx: &AppContext) -> anyhow::Result<()> {
+    // reconciled on every run, so files copied into the media root are
+    // catalogued even on a node without a manifest
+    let media_root = ctx.media_root().await?;
+    let items = ctx.catalogue().reconcile(&media_root).await?;
+    let Some(manifest) = ctx.sync().manifest().await? else {
+        return Ok(());
+    };
+    let plan = plan(&manifest, items.values(), &played_media(ctx).await?);
+    let mut status = SyncStatus {
+        last_run: Some(ctx.clock().now_millis()),
+        ..Default::default()
+    };
+    for file in plan.fetch {
+        let local = items
+            .values()
+            .find(|it| it.sha256 == file.sha256 && it.name != file.name)
+            .and_then(|it| media_root.resolve(&it.name).ok());
+        match pull(ctx, &media_root, &file, local.as_deref()).await {
+            Ok(()) => {
+                info!("Pulled {:?} for the manifest", file.name);
+                status.pulled.push(file.name);
+            }
+            Err(message) => {
+                warn!("Failed to pull {:?}: {}", file.name, message);
+                status.missing.push(MissingFile {
+                    name: file.name,
+                    message,
+                });
+            }
+        }
+    }
+    for name in plan.remove {
+        let removed = match media_root.resolve(&name) {
+            Ok(path) => fs::remove_file(path).await.map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match removed {
+            Ok(()) => {
+                info!("Removed {:?}, the manifest doesn't list it", name);
+                ctx.catalogue().forget(&name).await?;
+                status.removed.push(name);
+            }
+            Err(e) => warn!("Failed to remove {:?} with error {}", name, e),
+        }
+    }
+    ctx.sync().set_status(status);
+    Ok(())
+}
+
+/// The media the playlists known to this node play: its own, those its
+/// cached schedule refers to and the one its player was last given.
+async fn played_media(ctx: &AppContext) -> anyhow::Result<HashSet<String>> {
+    let mut playlists = ctx.playlists().list().await.map_err(anyhow::Error::msg)?;
+    if let Some(schedule) = ctx.schedule().local().await.map_err(anyhow::Error::msg)? {
+        playlists.extend(schedule.playlists);
+    }
+    playlists.extend(ctx.player_playlist().get().map(|it| it.playlist));
+    Ok(playlists
+        .into_iter()
+        .flat_map(|it| it.items)
+        .map(|it| it.video)
+        .collect())
+}
+
+/// Gives the manifest of each group to the members that didn't take it yet.
+async fn redeliver(ctx: &AppContext) -> anyhow::Result<()> {
+    let config = ctx.config().get_config().await;
+    for (group, stored) in ctx.sync().group_manifests().await? {
+        let Some(members) = config.groups().get(&group) else {
+            continue;
+        };
+        let due: Vec<i64> = members
+            .iter()
+            .filter(|id| !stored.delivered.contains(id))
+            .copied()
+            .collect();
+        if due.is_empty() {
+            continue;
+        }
+        let report = deliver(ctx, &group, &stored.manifest, BulkTarget::Ids(due))
+            .await
+            .map_err(anyhow::Error::msg)?;
+        if !report.succeeded.is_empty() {
+            info!(
+                "Delivered the manifest of group {} to {} more members",
+                group,
+                report.succeeded.len()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Sends `manifest` to the nodes `target` names, and records which took it.
+async fn deliver(
+    ctx: &AppContext,
+    group: &str,
+    manifest: &MediaManifest,
+    target: BulkTarget,
+) -> Result<BulkReport, ApiError> {
+    let json = serde_json::to_vec(manifest).map_err(|e| ApiError::Internal(e.into()))?;
+    let request = BulkRequest {
+        target,
+        timeout_ms: None,
+    };
+    let report = fan_out(
+        ctx,
+        &request,
+        Method::PUT,
+        &["sync".to_string(), "manifest".to_string()],
+        Some(&json),
+    )
+    .await?;
+    ctx.sync()
+        .delivered(group, manifest, report.succeeded.iter().map(|it| it.id))
+        .await
+        .map_err(ApiError::Internal)?;
+    Ok(report)
+}
+
+/// Gets `file` into the media root, from `local` when the node already holds
+/// the content under another name, otherwise in chunks from the peers that
+/// have it.
+async fn pull(
+    ctx: &AppContext,
+    media_root: &MediaRoot,
+    file: &ManifestFile,
+    local: Option<&Path>,
+) -> Result<(), String> {
+    let target = media_root
+        .resolve_new(&file.name)
+        .map_err(|e| e.to_string())?;
+    let part = match local {
+        Some(local) => copy(ctx, local, &file.sha256).await?,
+        None => transfer::fetch(ctx, &file.sha256).await?,
+    };
+    if let Err(e) = move_into(&part, &target).await {
+        let _ = fs::remove_file(&part).await;
+        return Err(e.to_string());
+    }
+    ctx.catalogue()
+        .record(
+            media_root,
+            &file.name,
+            &file.sha256,
+            ctx.clock().now_millis(),
+            None,
+        )
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Copies `local` into the upload folder, as long as it still hashes to `sha256`.
+async fn copy(ctx: &AppContext, local: &Path, sha256: &str) -> Result<PathBuf, String> {
+    let part = ctx
+        .uploads()
+        .scratch(ctx.ids().generate())
+        .await
+        .map_err(|e| e.to_string())?;
+    let copied = async {
+        fs::copy(local, &part).await.map_err(|e| e.to_string())?;
+        let actual = sha256_file(part.clone()).await.map_err(|e| e.to_string())?;
+        if actual != sha256 {
+            return Err(format!("The file hashes to {actual}"));
+        }
+        Ok(())
+    }
+    .await;
+    if copied.is_err() {
+        let _ = fs::remove_file(&part).await;
+    }
+    copied.map(|()| part)
+}
+
+#[utoipa::path(
+    get,
+    path = "/sync/manifest",
+    tag = "media",
+    responses(
+        (status = 200, description = "The manifest this node follows", body = MediaManifest),
+        (status = 404, description = "No manifest was published to this node", body = ErrorBody),
+    )
+)]
+#[get("/sync/manifest")]
+pub async fn get_manifest(ctx: web::Data<AppContext>) -> Result<HttpResponse, ApiError> {
+    let manifest = ctx
+        .sync()
+        .manifest()
+        .await
+        .map_err(ApiError::Internal)?
+        .ok_or_else(|| ApiError::NotFound("No manifest was published".to_string()))?;
+    Ok(HttpResponse::Ok().json(manifest))
+}
+
+#[utoipa::path(
+    put,
+    path = "/sync/manifest",
+    tag = "media",
+    request_body = MediaManifest,
+    responses(
+        (status = 200, description = "The node follows the manifest from now on", body = MediaManifest),
+        (status = 400, description = "A hash is malformed, a name is listed twice or tries to leave the media root", body = ErrorBody),
+        (status = 403, description = "A name resolves outside the media root", body = ErrorBody),
+    )
+)]
+#[put("/sync/manifest")]
+pub async fn put_manifest(
+    ctx: web::Data<AppContext>,
+    body: web::Json<MediaManifest>,
+) -> Result<HttpResponse, ApiError> {
+    let mut manifest = body.into_inner();
+    manifest.normalize()?;
+    let media_root = ctx.media_root().await?;
+    for file in &manifest.files {
+        media_root.resolve_new(&file.name)?;
+    }
+    ctx.sync()
+        .set_manifest(manifest.clone())
+        .await
+        .map_err(ApiError::Internal)?;
+    info!("Following a manifest of {} files", manifest.files.len());
+    Ok(HttpResponse::Ok().json(manifest))
+}
+
+#[utoipa::path(
+    get,
+    path = "/sync/status",
+    tag = "media",
+    responses((status = 200, description = "What the last comparison with the manifest changed", body = SyncStatus))
+)]
+#[get("/sync/status")]
+pub async fn get_sync_status(ctx: web::Data<AppContext>) -> HttpResponse {
+    HttpResponse::Ok().json(ctx.sync().status())
+}
+
+#[utoipa::path(
+    put,
+    path = "/sync/groups/{group}",
+    tag = "media",
+    params(("group" = String, Path, description = "Name of the group in the config")),
+    request_body = MediaManifest,
+    responses(
+        (status = 200, description = "What each member answered when given the manifest, those that didn't take it are given it again later", body = BulkReport),
+        (status = 400, description = "A hash is malformed or a name is listed twice", body = ErrorBody),
+        (status = 404, description = "The group is unknown", body = ErrorBody),
+    )
+)]
+#[put("/sync/groups/{group}")]
+pub async fn publish_manifest(
+    ctx: web::Data<AppContext>,
+    group: web::Path<String>,
+    body: web::Json<MediaManifest>,
+) -> Result<HttpResponse, ApiError> {
+    let mut manifest = body.into_inner();
+    manifest.normalize()?;
+    let group = group.into_inner();
+    let config = ctx.config().get_config().await;
+    if !config.groups().contains_key(&group) {
+        return Err(ApiError::NotFound(format!("Group {group} not found")));
+    }
+    ctx.sync()
+        .update_groups(|groups| {
+            groups.insert(
+                group.clone(),
+                GroupManifest {
+                    manifest: manifest.clone(),
+                    delivered: BTreeSet::new(),
+                },
+            )
+        })
+        .await
+        .map_err(ApiError::Internal)?;
+    let target = BulkTarget::Groups(vec![group.clone()]);
+    let report = deliver(&ctx, &group, &manifest, target).await?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(name: &str, sha256: &str) -> CatalogueItem {
+        CatalogueItem {
+            name: name.to_string(),
+            sha256: sha256.to_string(),
+            size: 1,
+            uploaded_at: 0,
+            uploader: None,
+            modified: 0,
+        }
+    }
+
+    fn file(name: &str, sha256: &str) -> ManifestFile {
+        ManifestFile {
+            name: name.to_string(),
+            sha256: sha256.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_plan() {
+        let mut manifest = MediaManifest {
+            files: vec![
+                file("a.mp4", "aa"),
+                file("b.mp4", "bb"),
+                file("c.mp4", "cc"),
+            ],
+            prune: false,
+        };
+        let items = [
+            item("a.mp4", "AA"),
+            item("b.mp4", "old"),
+            item("d.mp4", "dd"),
+        ];
+        let keep = HashSet::new();
+        assert_eq!(
+            plan(&manifest, items.iter(), &keep),
+            Plan {
+                fetch: vec![file("b.mp4", "bb"), file("c.mp4", "cc")],
+                remove: vec![],
+            }
+        );
+
+        manifest.prune = true;
+        assert_eq!(plan(&manifest, items.iter(), &keep).remove, vec!["d.mp4"]);
+        let keep = HashSet::from(["d.mp4".to_string()]);
+        assert!(plan(&manifest, items.iter(), &keep).remove.is_empty());
+    }
+
+    #[test]
+    fn test_normalize() {
+        let hash = "AB".repeat(32);
+        let mut manifest = MediaManifest {
+            files: vec![file("a.mp4", &hash)],
+            prune: false,
+        };
+        manifest.normalize().unwrap();
+        assert_eq!(manifest.files[0].sha256, "ab".repeat(32));
+
+        manifest.files.push(file("a.mp4", &hash));
+        assert!(matches!(manifest.normalize(), Err(ApiError::BadRequest(_))));
+        manifest.files[1] = file("b.mp4", "not a hash");
+        assert!(matches!(manifest.normalize(), Err(ApiError::BadRequest(_))));
+    }
+}
@@ -0,0 +1,432 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{BufReader, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{delete, get, web, HttpResponse};
+use anyhow::{bail, Context};
+use config::model::TlsConfig;
+use reqwest::Client;
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, PrivateKey, ServerConfig, ServerName,
+};
+use rustls_pemfile::Item;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+use crate::{context::AppContext, error::ApiError};
+
+/// The certificate this node serves and the fingerprint it announces to peers.
+pub struct TlsIdentity {
+    pub server_config: ServerConfig,
+    pub fingerprint: String,
+}
+
+/// Hex encoded SHA-256 of a DER certificate.
+pub fn fingerprint(der: &[u8]) -> String {
+    hex::encode(Sha256::digest(der))
+}
+
+/// Loads the configured certificate and key, generating a self-signed pair
+/// first if neither file exists yet.
+pub fn load_or_generate(tls: &TlsConfig, host: &str) -> anyhow::Result<TlsIdentity> {
+    match (tls.cert_path.exists(), tls.key_path.exists()) {
+        (false, false) => generate(tls, host)?,
+        (true, true) => {}
+        _ => bail!(
+            "Only one of {} and {} exists, provide both or neither",
+            tls.cert_path.display(),
+            tls.key_path.display()
+        ),
+    }
+    let certs = read_certs(&tls.cert_path)?;
+    let key = read_key(&tls.key_path)?;
+    let fingerprint = fingerprint(&certs[0].0);
+    let server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Certificate and key don't match")?;
+    info!("Serving HTTPS with certificate {}", fingerprint);
+    Ok(TlsIdentity {
+        server_config,
+        fingerprint,
+    })
+}
+
+fn generate(tls: &TlsConfig, host: &str) -> anyhow::Result<()> {
+    let names = vec![host.to_string(), "localhost".to_string()];
+    let cert = rcgen::generate_simple_self_signed(names)?;
+    if let Some(parent) = tls.cert_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if let Some(parent) = tls.key_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&tls.cert_path, cert.serialize_pem()?)
+        .with_context(|| format!("Failed to write {}", tls.cert_path.display()))?;
+    write_private(&tls.key_path, cert.serialize_private_key_pem().as_bytes())
+        .with_context(|| format!("Failed to write {}", tls.key_path.display()))?;
+    warn!(
+        "Generated a self-signed certificate at {}",
+        tls.cert_path.display()
+    );
+    Ok(())
+}
+
+/// Creates `path` with `contents`, readable and writable by its owner only.
+/// Fails when the file exists.
+pub(crate) fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)
+}
+
+fn read_certs(path: &Path) -> anyhow::Result<Vec<Certificate>> {
+    let file =
+        fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))?;
+    if certs.is_empty() {
+        bail!("No certificate found in {}", path.display());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    let file =
+        fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .with_context(|| format!("No private key found in {}", path.display()))
+}
+
+/// The certificate fingerprint a peer presented on its first connection.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct PeerPin {
+    pub fingerprint: String,
+    /// Milliseconds since the unix epoch.
+    pub pinned_at: u128,
+}
+
+/// A certificate that didn't match its peer's pin and was refused.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct PinConflict {
+    /// Fingerprint of the refused certificate.
+    pub presented: String,
+    /// Milliseconds since the unix epoch, of the last refusal.
+    pub seen_at: u128,
+}
+
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct PinReport {
+    /// Pins by peer address.
+    #[schema(value_type = Object)]
+    pub pins: BTreeMap<IpAddr, PeerPin>,
+    /// Refused certificates by peer address, until the pin is removed.
+    #[schema(value_type = Object)]
+    pub conflicts: BTreeMap<IpAddr, PinConflict>,
+}
+
+#[derive(Debug, Default)]
+struct Pins {
+    path: Option<PathBuf>,
+    report: PinReport,
+    /// The fingerprint each peer last announced in its heartbeat.
+    announced: HashMap<IpAddr, String>,
+}
+
+/// Certificate fingerprints of peers by address, trusted on first use.
+///
+/// The first certificate a peer presents is pinned and saved, if it is the
+/// one the peer announced. A later, different certificate is refused and
+/// kept as a conflict for an administrator, who removes the pin to trust the
+/// peer's new certificate.
+#[derive(Debug, Clone, Default)]
+pub struct PeerPins {
+    pins: Arc<RwLock<Pins>>,
+}
+
+impl PeerPins {
+    /// Loads the pins saved at `path`, where new pins are saved from now on.
+    pub fn open(&self, path: PathBuf) {
+        let saved = match fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                error!("Failed to parse the peer pins at {}: {}", path.display(), e);
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        let mut pins = self.pins.write().unwrap();
+        pins.report.pins.extend(saved);
+        pins.path = Some(path);
+    }
+
+    pub fn is_pinned(&self, ip: &IpAddr) -> bool {
+        self.pins.read().unwrap().report.pins.contains_key(ip)
+    }
+
+    pub fn report(&self) -> PinReport {
+        self.pins.read().unwrap().report.clone()
+    }
+
+    /// Remembers the fingerprint `ip` announces, the certificate it presents
+    /// first must match it to be pinned.
+    pub fn announce(&self, ip: IpAddr, fingerprint: Option<&str>) {
+        let mut pins = self.pins.write().unwrap();
+        match fingerprint {
+            Some(fingerprint) => pins.announced.insert(ip, fingerprint.to_string()),
+            None => pins.announced.remove(&ip),
+        };
+    }
+
+    /// Forgets the pin of `ip`, so its next certificate is trusted again.
+    pub fn remove(&self, ip: &IpAddr) -> bool {
+        let mut pins = self.pins.write().unwrap();
+        pins.report.conflicts.remove(ip);
+        let removed = pins.report.pins.remove(ip).is_some();
+        if removed {
+            pins.save();
+        }
+        removed
+    }
+
+    /// Accepts `fingerprint` from `ip` if it is pinned, or pins it when `ip`
+    /// has none yet and announced this one.
+    fn check(&self, ip: IpAddr, fingerprint: &str, now: u128) -> Result<(), String> {
+        let mut pins = self.pins.write().unwrap();
+        match pins.report.pins.get(&ip) {
+            Some(pin) if pin.fingerprint == fingerprint => Ok(()),
+            Some(_) => {
+                warn!(
+                    "Refused the certificate {} of {}, it doesn't match the pinned one",
+                    fingerprint, ip
+                );
+                pins.report.conflicts.insert(
+                    ip,
+                    PinConflict {
+                        presented: fingerprint.to_string(),
+                        seen_at: now,
+                    },
+                );
+                Err(format!(
+                    "Certificate of {ip} doesn't match its pinned fingerprint"
+                ))
+            }
+            None if pins.announced.get(&ip).map(String::as_str) != Some(fingerprint) => {
+                warn!(
+                    "Refused the certificate {} of {}, it isn't the one the peer announced",
+                    fingerprint, ip
+                );
+                Err(format!(
+                    "Certificate of {ip} doesn't match the fingerprint it announced"
+                ))
+            }
+            None => {
+                info!("Pinned the certificate {} of {}", fingerprint, ip);
+                pins.report.pins.insert(
+                    ip,
+                    PeerPin {
+                        fingerprint: fingerprint.to_string(),
+                        pinned_at: now,
+                    },
+                );
+                pins.save();
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Pins {
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let result = serde_json::to_string_pretty(&self.report.pins)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(fs::write(path, json)?));
+        if let Err(e) = result {
+            error!("Failed to save the peer pins to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Accepts a server certificate only if it is the one pinned for the peer,
+/// pinning it on the first connection when the peer announced it.
+///
+/// Self-signed certificates can't be checked against a CA, so the first
+/// announced certificate seen is the trust anchor for node to node calls.
+struct PinnedVerifier {
+    pins: PeerPins,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let ServerName::IpAddress(ip) = server_name else {
+            return Err(rustls::Error::General(format!(
+                "No pinned certificate for {server_name:?}"
+            )));
+        };
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        self.pins
+            .check(*ip, &fingerprint(&end_entity.0), now)
+            .map(|_| ServerCertVerified::assertion())
+            .map_err(rustls::Error::General)
+    }
+}
+
+/// How long connecting to a peer or the player may take. Requests set their
+/// own timeout, since forwarded bodies and transfers stream for long.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An HTTP client whose HTTPS connections trust only pinned peer certificates.
+pub fn peer_client(pins: PeerPins) -> reqwest::Result<Client> {
+    let tls = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(PinnedVerifier { pins }))
+        .with_no_client_auth();
+    Client::builder()
+        .use_preconfigured_tls(tls)
+        .connect_timeout(CONNECT_TIMEOUT)
+        .build()
+}
+
+#[utoipa::path(
+    get,
+    path = "/tls/pins",
+    tag = "nodes",
+    responses((status = 200, description = "Pinned peer certificates and the certificates refused since", body = PinReport))
+)]
+#[get("/tls/pins")]
+pub async fn get_pins(ctx: web::Data<AppContext>) -> HttpResponse {
+    HttpResponse::Ok().json(ctx.peer_pins().report())
+}
+
+/// Forgets a peer's pin, so the certificate it presents next is trusted.
+#[utoipa::path(
+    delete,
+    path = "/tls/pins/{ip}",
+    tag = "nodes",
+    params(("ip" = String, Path, description = "Address of the peer")),
+    responses(
+        (status = 204, description = "The pin was removed"),
+        (status = 404, description = "No certificate is pinned for the address", body = ErrorBody),
+    )
+)]
+#[delete("/tls/pins/{ip}")]
+pub async fn delete_pin(
+    ctx: web::Data<AppContext>,
+    ip: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let ip: IpAddr = ip
+        .parse()
+        .map_err(|_| ApiError::BadRequest(format!("{ip} is not an IP address")))?;
+    if !ctx.peer_pins().remove(&ip) {
+        return Err(ApiError::NotFound(format!(
+            "No certificate is pinned for {ip}"
+        )));
+    }
+    warn!("Removed the certificate pin of {}", ip);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verify(verifier: &PinnedVerifier, cert: &[u8], ip: &str) -> bool {
+        verifier
+            .verify_server_cert(
+                &Certificate(cert.to_vec()),
+                &[],
+                &ServerName::IpAddress(ip.parse().unwrap()),
+                &mut std::iter::empty(),
+                &[],
+                SystemTime::now(),
+            )
+            .is_ok()
+    }
+
+    #[test]
+    fn test_pinned_verifier() {
+        let path = std::env::temp_dir().join(format!("pins-{}.json", std::process::id()));
+        let pins = PeerPins::default();
+        pins.open(path.clone());
+        let verifier = PinnedVerifier { pins: pins.clone() };
+        let announce = |pins: &PeerPins, ip: &str, cert: &[u8]| {
+            pins.announce(ip.parse().unwrap(), Some(&fingerprint(cert)))
+        };
+        // nothing announced, or another certificate announced, is never pinned
+        assert!(!verify(&verifier, b"peer certificate", "10.0.0.2"));
+        announce(&pins, "10.0.0.2", b"announced certificate");
+        assert!(!verify(&verifier, b"peer certificate", "10.0.0.2"));
+        assert!(pins.report().pins.is_empty());
+
+        announce(&pins, "10.0.0.2", b"peer certificate");
+        announce(&pins, "10.0.0.3", b"other certificate");
+        assert!(verify(&verifier, b"peer certificate", "10.0.0.2"));
+        assert!(verify(&verifier, b"peer certificate", "10.0.0.2"));
+        assert!(!verify(&verifier, b"other certificate", "10.0.0.2"));
+        assert!(verify(&verifier, b"other certificate", "10.0.0.3"));
+
+        let ip = "10.0.0.2".parse().unwrap();
+        let report = pins.report();
+        assert_eq!(
+            report.conflicts[&ip].presented,
+            fingerprint(b"other certificate")
+        );
+        assert_eq!(report.pins.len(), 2);
+
+        let reloaded = PeerPins::default();
+        reloaded.open(path.clone());
+        assert_eq!(reloaded.report().pins, report.pins);
+        assert!(reloaded.remove(&ip));
+        announce(&reloaded, "10.0.0.2", b"other certificate");
+        let verifier = PinnedVerifier { pins: reloaded };
+        assert!(verify(&verifier, b"other certificate", "10.0.0.2"));
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_generate_and_reload() {
+        let dir = std::env::temp_dir().join(format!("tls-{}", std::process::id()));
+        let tls = TlsConfig {
+            enabled: true,
+            cert_path: dir.join("cert.pem"),
+            key_path: dir.join("key.pem"),
+            pins_path: dir.join("pins.json"),
+        };
+        let first = load_or_generate(&tls, "127.0.0.1").unwrap();
+        let second = load_or_generate(&tls, "127.0.0.1").unwrap();
+        assert_eq!(first.fingerprint, second.fingerprint);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
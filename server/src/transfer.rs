@@ -0,0 +1,528 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{get, web, HttpResponse};
+use domain::node::Node;
+use futures::{future::join_all, StreamExt};
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{client, context::AppContext, error::ApiError, upload::sha256_file};
+
+/// Bytes in every chunk but the last.
+const CHUNK_SIZE: u64 = 4 << 20;
+/// Chunks fetched at once, spread over the peers holding the file.
+const MAX_PARALLEL: usize = 8;
+/// Peers asked for a chunk before the transfer stops, at least every holder once.
+const CHUNK_ATTEMPTS: usize = 3;
+/// Largest chunk list accepted, enough for files of hundreds of gigabytes.
+const MAX_LIST_BYTES: usize = 8 << 20;
+/// Time a peer has to send its chunk list.
+const LIST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time a peer has to send one chunk.
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A file described by the hashes of its chunks, so each chunk can be fetched
+/// from any peer holding the file and checked on its own.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ChunkList {
+    /// Hex SHA-256 of the whole file.
+    pub sha256: String,
+    pub size: u64,
+    /// Bytes in every chunk but the last.
+    pub chunk_size: u64,
+    /// Hex SHA-256 of each chunk, in file order.
+    pub chunks: Vec<String>,
+}
+
+impl ChunkList {
+    /// Offset and length of chunk `index`.
+    fn range(&self, index: usize) -> (u64, usize) {
+        let offset = index as u64 * self.chunk_size;
+        let len = self.size.saturating_sub(offset).min(self.chunk_size);
+        (offset, len as usize)
+    }
+
+    /// Whether the chunks have the size this node fetches and cover exactly
+    /// `size` bytes. A peer can't make this node buffer larger chunks.
+    fn is_consistent(&self) -> bool {
+        self.chunk_size == CHUNK_SIZE
+            && self.chunks.len() as u64 == self.size.div_ceil(self.chunk_size)
+    }
+}
+
+/// Hashes every chunk of the file at `path` and the file as a whole, in one pass.
+fn chunk_list(path: &Path, chunk_size: u64) -> io::Result<ChunkList> {
+    let mut file = fs::File::open(path)?;
+    let mut whole = Sha256::new();
+    let mut chunks = vec![];
+    let mut size = 0;
+    let mut buffer = vec![0; chunk_size as usize];
+    loop {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match file.read(&mut buffer[filled..])? {
+                0 => break,
+                read => filled += read,
+            }
+        }
+        if filled == 0 {
+            break;
+        }
+        whole.update(&buffer[..filled]);
+        chunks.push(hex::encode(Sha256::digest(&buffer[..filled])));
+        size += filled as u64;
+    }
+    Ok(ChunkList {
+        sha256: hex::encode(whole.finalize()),
+        size,
+        chunk_size,
+        chunks,
+    })
+}
+
+#[derive(Debug)]
+struct Listed {
+    modified: SystemTime,
+    size: u64,
+    list: ChunkList,
+}
+
+/// Chunk lists by path. A file is hashed again once its modification time
+/// or size changes.
+#[derive(Debug, Clone, Default)]
+pub struct ChunkCache {
+    entries: Arc<Mutex<HashMap<PathBuf, Listed>>>,
+}
+
+impl ChunkCache {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, Listed>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    async fn list(&self, path: PathBuf) -> Result<ChunkList, ApiError> {
+        let metadata = tokio::fs::metadata(&path).await?;
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        if let Some(listed) = self.lock().get(&path) {
+            if listed.modified == modified && listed.size == metadata.len() {
+                return Ok(listed.list.clone());
+            }
+        }
+        let list = {
+            let path = path.clone();
+            web::block(move || chunk_list(&path, CHUNK_SIZE)).await??
+        };
+        self.lock().insert(
+            path,
+            Listed {
+                modified,
+                size: metadata.len(),
+                list: list.clone(),
+            },
+        );
+        Ok(list)
+    }
+}
+
+/// What is known about a file being fetched, stored next to its part so an
+/// interrupted transfer keeps the chunks it verified.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Partial {
+    list: ChunkList,
+    done: Vec<bool>,
+}
+
+/// A file being fetched from peers.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TransferProgress {
+    pub sha256: String,
+    pub size: u64,
+    pub chunks: usize,
+    /// Chunks verified so far, including those kept from an earlier attempt.
+    pub received: usize,
+    /// Nodes the chunks are fetched from.
+    pub peers: Vec<i64>,
+}
+
+/// Transfers in progress by hash.
+#[derive(Debug, Clone, Default)]
+pub struct Transfers {
+    active: Arc<Mutex<BTreeMap<String, TransferProgress>>>,
+}
+
+impl Transfers {
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, TransferProgress>> {
+        self.active.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn list(&self) -> Vec<TransferProgress> {
+        self.lock().values().cloned().collect()
+    }
+}
+
+/// The calls a transfer makes to its peers.
+struct Fetch<'a> {
+    ctx: &'a AppContext,
+    list: ChunkList,
+    holders: Vec<Node>,
+    part: PathBuf,
+}
+
+impl Fetch<'_> {
+    /// Fetches chunk `index` from the holders in turn until one sends the
+    /// bytes its hash promises, and writes them into the part.
+    async fn chunk(&self, index: usize) -> Result<usize, String> {
+        let (offset, len) = self.list.range(index);
+        let mut last_error = String::new();
+        for attempt in 0..self.holders.len().max(CHUNK_ATTEMPTS) {
+            let peer = &self.holders[(index + attempt) % self.holders.len()];
+            let path = format!("content/{}/chunks/{index}", self.list.sha256);
+            let response = client::peer_request(self.ctx, peer, Method::GET, &path)
+                .timeout(CHUNK_TIMEOUT)
+                .send()
+                .await
+                .and_then(|it| it.error_for_status());
+            let bytes = match response {
+                Ok(response) => read_limited(response, len).await,
+                Err(e) => Err(e.to_string()),
+            };
+            let bytes = match bytes {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    last_error = format!("node {} failed to send it: {e}", peer.id);
+                    continue;
+                }
+            };
+            if bytes.len() != len || hex::encode(Sha256::digest(&bytes)) != self.list.chunks[index]
+            {
+                warn!("Node {} sent a corrupt chunk {}", peer.id, index);
+                last_error = format!("node {} sent a corrupt chunk", peer.id);
+                continue;
+            }
+            self.write(offset, &bytes)
+                .await
+                .map_err(|e| format!("Failed to write chunk {index}: {e}"))?;
+            return Ok(index);
+        }
+        Err(format!("Chunk {index} could not be fetched, {last_error}"))
+    }
+
+    async fn write(&self, offset: u64, bytes: &[u8]) -> io::Result<()> {
+        let mut file = OpenOptions::new().write(true).open(&self.part).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        file.write_all(bytes).await?;
+        file.flush().await
+    }
+}
+
+/// Reads a body of at most `len` bytes, giving up as soon as the peer sends more.
+async fn read_limited(response: reqwest::Response, len: usize) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(len);
+    let mut stream = response.bytes_stream();
+    while let Some(part) = stream.next().await {
+        let part = part.map_err(|e| e.to_string())?;
+        if bytes.len() + part.len() > len {
+            return Err(format!("the body is longer than {len} bytes"));
+        }
+        bytes.extend_from_slice(&part);
+    }
+    Ok(bytes)
+}
+
+/// Asks every other active node for the chunk list of `sha256`. Returns the
+/// list and the nodes that hold the file.
+async fn find_holders(ctx: &AppContext, sha256: &str) -> Option<(ChunkList, Vec<Node>)> {
+    let own_id = ctx.config().get_config().await.id();
+    let peers: Vec<Node> = ctx
+        .node_holder()
+        .get_node_list()
+        .await
+        .into_iter()
+        .filter(|it| it.active && it.id != own_id)
+        .collect();
+    let path = format!("content/{sha256}/chunks");
+    let lists = join_all(peers.iter().map(|peer| async {
+        let response = client::peer_request(ctx, peer, Method::GET, &path)
+            .timeout(LIST_TIMEOUT)
+            .send()
+            .await
+            .and_then(|it| it.error_for_status())
+            .map_err(|e| e.to_string())?;
+        let json = read_limited(response, MAX_LIST_BYTES).await?;
+        serde_json::from_slice::<ChunkList>(&json).map_err(|e| e.to_string())
+    }))
+    .await;
+    let mut found: Option<(ChunkList, Vec<Node>)> = None;
+    for (peer, list) in peers.into_iter().zip(lists) {
+        let Ok(list) = list else {
+            continue;
+        };
+        if !list.sha256.eq_ignore_ascii_case(sha256) || !list.is_consistent() {
+            warn!("Node {} sent an inconsistent chunk list", peer.id);
+            continue;
+        }
+        match &mut found {
+            Some((first, holders)) if *first == list => holders.push(peer),
+            Some(_) => {}
+            None => found = Some((list, vec![peer])),
+        }
+    }
+    found
+}
+
+/// Fetches the file with the hex SHA-256 `sha256` from the peers holding it,
+/// several chunks at a time, and returns the verified file in the upload
+/// folder. Chunks received before a failure are kept, so the next call
+/// continues where this one stopped.
+pub async fn fetch(ctx: &AppContext, sha256: &str) -> Result<PathBuf, String> {
+    let sha256 = sha256.to_ascii_lowercase();
+    let config = ctx.config().get_config().await;
+    let (list, holders) = find_holders(ctx, &sha256)
+        .await
+        .ok_or_else(|| "No peer holds the file".to_string())?;
+    let max = config.server().max_upload_bytes;
+    if list.size > max {
+        return Err(format!("The file is larger than {max} bytes"));
+    }
+    let io = |e: io::Error| e.to_string();
+    let part = ctx
+        .uploads()
+        .file(&format!("{sha256}.transfer"))
+        .await
+        .map_err(io)?;
+    let state = ctx
+        .uploads()
+        .file(&format!("{sha256}.transfer.json"))
+        .await
+        .map_err(io)?;
+    let mut partial = match tokio::fs::read(&state).await {
+        Ok(saved) => serde_json::from_slice::<Partial>(&saved)
+            .ok()
+            .filter(|it| it.list == list && part.exists()),
+        Err(_) => None,
+    }
+    .unwrap_or_else(|| Partial {
+        done: vec![false; list.chunks.len()],
+        list: list.clone(),
+    });
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&part)
+        .await
+        .map_err(io)?;
+    file.set_len(list.size).await.map_err(io)?;
+    drop(file);
+
+    let missing: Vec<usize> = (0..partial.done.len())
+        .filter(|it| !partial.done[*it])
+        .collect();
+    info!(
+        "Fetching {} of {} chunks of {} from {} nodes",
+        missing.len(),
+        list.chunks.len(),
+        sha256,
+        holders.len()
+    );
+    let progress = TransferProgress {
+        sha256: sha256.clone(),
+        size: list.size,
+        chunks: list.chunks.len(),
+        received: list.chunks.len() - missing.len(),
+        peers: holders.iter().map(|it| it.id).collect(),
+    };
+    ctx.transfers().lock().insert(sha256.clone(), progress);
+    let fetch = Fetch {
+        ctx,
+        list,
+        holders,
+        part: part.clone(),
+    };
+    let parallel = MAX_PARALLEL.min(fetch.holders.len() * 2);
+    let mut chunks = futures::stream::iter(missing)
+        .map(|index| fetch.chunk(index))
+        .buffer_unordered(parallel);
+    let mut failed = None;
+    while let Some(result) = chunks.next().await {
+        match result {
+            Ok(index) => {
+                partial.done[index] = true;
+                if let Some(progress) = ctx.transfers().lock().get_mut(&sha256) {
+                    progress.received += 1;
+                }
+                let saved = serde_json::to_vec(&partial).map_err(|e| e.to_string())?;
+                if let Err(e) = tokio::fs::write(&state, saved).await {
+                    warn!(
+                        "Failed to save the progress of {} with error {:?}",
+                        sha256, e
+                    );
+                }
+            }
+            Err(e) => failed = Some(e),
+        }
+    }
+    drop(chunks);
+    ctx.transfers().lock().remove(&sha256);
+    if let Some(e) = failed {
+        return Err(e);
+    }
+
+    let actual = sha256_file(part.clone()).await.map_err(io)?;
+    let _ = tokio::fs::remove_file(&state).await;
+    if actual != sha256 {
+        let _ = tokio::fs::remove_file(&part).await;
+        return Err(format!("The fetched file hashes to {actual}"));
+    }
+    Ok(part)
+}
+
+/// The file in the media root with the content `sha256`.
+async fn held(ctx: &AppContext, sha256: &str) -> Result<PathBuf, ApiError> {
+    let media_root = ctx.media_root().await?;
+    let items = ctx.catalogue().items().await.map_err(ApiError::Internal)?;
+    items
+        .values()
+        .filter(|it| it.sha256.eq_ignore_ascii_case(sha256))
+        .find_map(|it| media_root.resolve(&it.name).ok())
+        .ok_or_else(|| ApiError::NotFound(format!("No file with SHA-256 {sha256}")))
+}
+
+/// The chunk list of the held file, as long as it still has the content.
+async fn held_list(ctx: &AppContext, sha256: &str) -> Result<(PathBuf, ChunkList), ApiError> {
+    let path = held(ctx, sha256).await?;
+    let list = ctx.chunks().list(path.clone()).await?;
+    // the catalogue may be stale until the next reconcile
+    if !list.sha256.eq_ignore_ascii_case(sha256) {
+        return Err(ApiError::NotFound(format!("No file with SHA-256 {sha256}")));
+    }
+    Ok((path, list))
+}
+
+#[utoipa::path(
+    get,
+    path = "/content/{sha256}/chunks",
+    tag = "media",
+    params(("sha256" = String, Path, description = "Hex SHA-256 of the file")),
+    responses(
+        (status = 200, description = "The hashes of the file's chunks", body = ChunkList),
+        (status = 404, description = "The node holds no file with this content", body = ErrorBody),
+    )
+)]
+#[get("/content/{sha256}/chunks")]
+pub async fn get_chunk_list(
+    ctx: web::Data<AppContext>,
+    sha256: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let (_, list) = held_list(&ctx, &sha256).await?;
+    Ok(HttpResponse::Ok().json(list))
+}
+
+#[utoipa::path(
+    get,
+    path = "/content/{sha256}/chunks/{index}",
+    tag = "media",
+    params(
+        ("sha256" = String, Path, description = "Hex SHA-256 of the file"),
+        ("index" = usize, Path, description = "Position of the chunk in the chunk list"),
+    ),
+    responses(
+        (status = 200, description = "The bytes of the chunk", body = Vec<u8>, content_type = "application/octet-stream"),
+        (status = 404, description = "The node holds no file with this content, or the file has fewer chunks", body = ErrorBody),
+    )
+)]
+#[get("/content/{sha256}/chunks/{index}")]
+pub async fn get_chunk(
+    ctx: web::Data<AppContext>,
+    path: web::Path<(String, usize)>,
+) -> Result<HttpResponse, ApiError> {
+    let (sha256, index) = path.into_inner();
+    let (path, list) = held_list(&ctx, &sha256).await?;
+    if index >= list.chunks.len() {
+        return Err(ApiError::NotFound(format!(
+            "The file has {} chunks",
+            list.chunks.len()
+        )));
+    }
+    let (offset, len) = list.range(index);
+    let bytes = web::block(move || {
+        let mut file = fs::File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut bytes = vec![0; len];
+        file.read_exact(&mut bytes)?;
+        io::Result::Ok(bytes)
+    })
+    .await??;
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .body(bytes))
+}
+
+#[utoipa::path(
+    get,
+    path = "/transfers",
+    tag = "media",
+    responses((status = 200, description = "Files being fetched from peers", body = [TransferProgress]))
+)]
+#[get("/transfers")]
+pub async fn get_transfers(ctx: web::Data<AppContext>) -> HttpResponse {
+    HttpResponse::Ok().json(ctx.transfers().list())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_list() {
+        let path = std::env::temp_dir().join(format!("chunks-{}", std::process::id()));
+        fs::write(&path, b"0123456789").unwrap();
+        let list = chunk_list(&path, 4).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(list.size, 10);
+        assert_eq!(list.sha256, hex::encode(Sha256::digest(b"0123456789")));
+        assert_eq!(
+            list.chunks,
+            vec![
+                hex::encode(Sha256::digest(b"0123")),
+                hex::encode(Sha256::digest(b"4567")),
+                hex::encode(Sha256::digest(b"89")),
+            ]
+        );
+        assert_eq!(list.range(1), (4, 4));
+        assert_eq!(list.range(2), (8, 2));
+        // only lists of the chunk size fetched here are accepted
+        assert!(!list.is_consistent());
+
+        let whole = ChunkList {
+            chunk_size: CHUNK_SIZE,
+            chunks: list.chunks[..1].to_vec(),
+            ..list.clone()
+        };
+        assert!(whole.is_consistent());
+        let truncated = ChunkList {
+            chunks: vec![],
+            ..whole.clone()
+        };
+        assert!(!truncated.is_consistent());
+        let oversized = ChunkList {
+            chunk_size: 16 << 30,
+            ..whole
+        };
+        assert!(!oversized.is_consistent());
+    }
+}
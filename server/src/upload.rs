@@ -0,0 +1,760 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, SystemTime},
+};
+
+use actix_web::{delete, patch, post, route, web, HttpMessage, HttpRequest, HttpResponse};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+    sync::RwLock,
+};
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+use crate::{auth::Principal, catalogue, context::AppContext, error::ApiError, media::MediaRoot};
+
+/// Where a chunk starts in requests, and how many bytes are stored in replies.
+pub const UPLOAD_OFFSET: &str = "Upload-Offset";
+/// Size of the whole file in replies.
+pub const UPLOAD_LENGTH: &str = "Upload-Length";
+/// How often abandoned uploads are looked for.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct NewUpload {
+    /// File name in the media root the upload is stored as.
+    pub name: String,
+    /// Size of the whole file in bytes.
+    pub size: u64,
+    /// Hex SHA-256 of the whole file, checked when the upload is finished.
+    pub sha256: String,
+    /// Replace a file of the same name in the media root. Without it such an
+    /// upload is refused with 409.
+    #[serde(default)]
+    pub overwrite: bool,
+}
+
+/// What is known about an upload, stored as JSON next to its data.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UploadMeta {
+    name: String,
+    size: u64,
+    sha256: String,
+    created_at: u128,
+    principal: Option<String>,
+    #[serde(default)]
+    overwrite: bool,
+}
+
+/// Progress of an unfinished upload.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct UploadStatus {
+    pub id: i64,
+    pub name: String,
+    pub size: u64,
+    /// Bytes stored so far, the offset the next chunk has to start at.
+    pub offset: u64,
+    pub sha256: String,
+    /// Milliseconds since the unix epoch.
+    pub created_at: u128,
+    /// Name of the API key that created the upload.
+    pub principal: Option<String>,
+}
+
+/// A file moved into the media root.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct StoredMedia {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Unfinished uploads, each as `{id}.json` with its metadata and `{id}.part`
+/// with the bytes received so far. The length of the part is the offset, so
+/// an upload survives restarts and interrupted chunks.
+#[derive(Debug, Clone)]
+pub struct UploadStore {
+    dir: Arc<RwLock<PathBuf>>,
+    busy: Arc<Mutex<HashSet<i64>>>,
+}
+
+/// Marks an upload as being written until dropped.
+struct UploadLock {
+    id: i64,
+    busy: Arc<Mutex<HashSet<i64>>>,
+}
+
+impl Drop for UploadLock {
+    fn drop(&mut self) {
+        self.busy
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.id);
+    }
+}
+
+impl UploadStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir: Arc::new(RwLock::new(dir)),
+            busy: Arc::default(),
+        }
+    }
+
+    /// Points the store at the folder named in the config.
+    pub async fn set_dir(&self, dir: PathBuf) {
+        *self.dir.write().await = dir;
+    }
+
+    fn lock(&self, id: i64) -> Result<UploadLock, ApiError> {
+        let mut busy = self.busy.lock().unwrap_or_else(PoisonError::into_inner);
+        if !busy.insert(id) {
+            return Err(ApiError::UploadBusy(id));
+        }
+        Ok(UploadLock {
+            id,
+            busy: self.busy.clone(),
+        })
+    }
+
+    async fn meta_path(&self, id: i64) -> PathBuf {
+        self.dir.read().await.join(format!("{id}.json"))
+    }
+
+    async fn part_path(&self, id: i64) -> PathBuf {
+        self.dir.read().await.join(format!("{id}.part"))
+    }
+
+    /// An empty part file to write a whole upload into, for callers that
+    /// don't need it to be resumable.
+    pub async fn scratch(&self, id: i64) -> io::Result<PathBuf> {
+        self.file(&format!("{id}.part")).await
+    }
+
+    /// Where a file named `name` is kept in the upload folder, which is
+    /// created first.
+    pub async fn file(&self, name: &str) -> io::Result<PathBuf> {
+        let dir = self.dir.read().await;
+        fs::create_dir_all(&*dir).await?;
+        Ok(dir.join(name))
+    }
+
+    pub async fn create(
+        &self,
+        id: i64,
+        upload: NewUpload,
+        created_at: u128,
+        principal: Option<String>,
+    ) -> io::Result<UploadStatus> {
+        let meta = UploadMeta {
+            name: upload.name,
+            size: upload.size,
+            sha256: upload.sha256.to_ascii_lowercase(),
+            created_at,
+            principal,
+            overwrite: upload.overwrite,
+        };
+        let part = self.scratch(id).await?;
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&part)
+            .await?;
+        fs::write(self.meta_path(id).await, serde_json::to_vec(&meta)?).await?;
+        Ok(status(id, meta, 0))
+    }
+
+    pub async fn status(&self, id: i64) -> io::Result<UploadStatus> {
+        let meta = self.meta(id).await?;
+        let offset = fs::metadata(self.part_path(id).await).await?.len();
+        Ok(status(id, meta, offset))
+    }
+
+    async fn meta(&self, id: i64) -> io::Result<UploadMeta> {
+        let meta = fs::read(self.meta_path(id).await).await?;
+        Ok(serde_json::from_slice(&meta)?)
+    }
+
+    /// Appends `chunks` at `offset`, which has to be where the stored part
+    /// ends. Bytes received before the stream fails are kept, so the client
+    /// can ask for the offset and continue from there. Returns the new offset.
+    pub async fn append<S, E>(&self, id: i64, offset: u64, mut chunks: S) -> Result<u64, ApiError>
+    where
+        S: Stream<Item = Result<web::Bytes, E>> + Unpin,
+        E: fmt::Display,
+    {
+        let _lock = self.lock(id)?;
+        let status = self.status(id).await?;
+        if offset != status.offset {
+            return Err(ApiError::OffsetMismatch {
+                expected: status.offset,
+            });
+        }
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(self.part_path(id).await)
+            .await?;
+        let mut received = offset;
+        let result = loop {
+            let chunk = match chunks.next().await {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(e)) => {
+                    break Err(ApiError::BadRequest(format!(
+                        "The chunk was interrupted at byte {received}: {e}"
+                    )))
+                }
+                None => break Ok(received),
+            };
+            if received + chunk.len() as u64 > status.size {
+                break Err(ApiError::TooLarge(format!(
+                    "The upload is announced with {} bytes",
+                    status.size
+                )));
+            }
+            if let Err(e) = file.write_all(&chunk).await {
+                break Err(e.into());
+            }
+            received += chunk.len() as u64;
+        };
+        file.flush().await?;
+        result
+    }
+
+    /// Checks the complete upload against its SHA-256 and moves it into the
+    /// media root. An upload with the wrong hash can't be repaired by sending
+    /// more bytes, so it is discarded. A file of the same name is only
+    /// replaced when the upload was created with `overwrite`.
+    pub async fn finish(&self, id: i64, media_root: &MediaRoot) -> Result<StoredMedia, ApiError> {
+        let _lock = self.lock(id)?;
+        let overwrite = self.meta(id).await?.overwrite;
+        let status = self.status(id).await?;
+        if status.offset != status.size {
+            return Err(ApiError::UploadIncomplete {
+                received: status.offset,
+                size: status.size,
+            });
+        }
+        let part = self.part_path(id).await;
+        let actual = sha256_file(part.clone()).await?;
+        if actual != status.sha256 {
+            warn!(
+                "Discarding upload {} of {:?}, it hashes to {}",
+                id, status.name, actual
+            );
+            self.discard(id).await?;
+            return Err(ApiError::ChecksumMismatch {
+                expected: status.sha256,
+                actual,
+            });
+        }
+        let target = media_root.resolve_new(&status.name)?;
+        if !overwrite && fs::try_exists(&target).await? {
+            return Err(ApiError::Exists(status.name));
+        }
+        move_into(&part, &target).await?;
+        fs::remove_file(self.meta_path(id).await).await?;
+        info!("Stored upload {} as {:?}", id, target);
+        Ok(StoredMedia {
+            name: status.name,
+            size: status.size,
+            sha256: actual,
+        })
+    }
+
+    pub async fn remove(&self, id: i64) -> Result<(), ApiError> {
+        let _lock = self.lock(id)?;
+        fs::remove_file(self.meta_path(id).await).await?;
+        self.discard(id).await
+    }
+
+    async fn discard(&self, id: i64) -> Result<(), ApiError> {
+        let _ = fs::remove_file(self.meta_path(id).await).await;
+        match fs::remove_file(self.part_path(id).await).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes the files nothing was written to for `ttl`: abandoned uploads,
+    /// scratch parts and transfers. Files sharing the name before the first
+    /// dot belong together and are kept while any of them is recent. Returns
+    /// those names.
+    pub async fn expire(&self, ttl: Duration) -> io::Result<Vec<String>> {
+        let dir = self.dir.read().await.clone();
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut groups: BTreeMap<String, (SystemTime, Vec<PathBuf>)> = BTreeMap::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if !metadata.is_file() {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().to_string();
+            let stem = name.split('.').next().unwrap_or_default().to_string();
+            let modified = metadata.modified()?;
+            let group = groups.entry(stem).or_insert((modified, vec![]));
+            group.0 = group.0.max(modified);
+            group.1.push(entry.path());
+        }
+        let now = SystemTime::now();
+        let mut expired = vec![];
+        for (stem, (modified, paths)) in groups {
+            if now.duration_since(modified).unwrap_or_default() < ttl {
+                continue;
+            }
+            // an upload with a chunk being written is not abandoned
+            let _lock = match stem.parse() {
+                Ok(id) => match self.lock(id) {
+                    Ok(lock) => Some(lock),
+                    Err(_) => continue,
+                },
+                Err(_) => None,
+            };
+            for path in paths {
+                match fs::remove_file(&path).await {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            expired.push(stem);
+        }
+        Ok(expired)
+    }
+}
+
+/// Removes abandoned uploads every hour, once nothing was written to them for
+/// `server.upload_ttl_secs`.
+pub async fn run(ctx: AppContext) {
+    loop {
+        let ttl = ctx.config().get_config().await.server().upload_ttl_secs;
+        match ctx.uploads().expire(Duration::from_secs(ttl)).await {
+            Ok(expired) if !expired.is_empty() => {
+                info!("Removed the abandoned uploads {:?}", expired)
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to remove abandoned uploads with error {:?}", e),
+        }
+        tokio::time::sleep(EXPIRE_INTERVAL).await;
+    }
+}
+
+fn status(id: i64, meta: UploadMeta, offset: u64) -> UploadStatus {
+    UploadStatus {
+        id,
+        name: meta.name,
+        size: meta.size,
+        offset,
+        sha256: meta.sha256,
+        created_at: meta.created_at,
+        principal: meta.principal,
+    }
+}
+
+/// Hex SHA-256 of a file, read on a blocking thread.
+pub async fn sha256_file(path: PathBuf) -> io::Result<String> {
+    tokio::task::spawn_blocking(move || {
+        let mut file = File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 1 << 20];
+        loop {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                return Ok(hex::encode(hasher.finalize()));
+            }
+            hasher.update(&buf[..read]);
+        }
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Moves a finished file to `target` so the file appears there whole or not
+/// at all. Across filesystems it is copied next to the target first.
+pub async fn move_into(part: &Path, target: &Path) -> io::Result<()> {
+    if let Err(e) = fs::rename(part, target).await {
+        warn!(
+            "Copying {:?} to {:?}, it could not be moved: {:?}",
+            part, target, e
+        );
+        let name = target.file_name().unwrap_or_default().to_string_lossy();
+        let copy = target.with_file_name(format!(".{name}.part"));
+        fs::copy(part, &copy).await?;
+        if let Err(e) = fs::rename(&copy, target).await {
+            let _ = fs::remove_file(&copy).await;
+            return Err(e);
+        }
+        fs::remove_file(part).await?;
+    }
+    Ok(())
+}
+
+pub(crate) fn is_sha256(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|it| it.is_ascii_hexdigit())
+}
+
+fn progress(status: &UploadStatus) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((UPLOAD_OFFSET, status.offset.to_string()))
+        .insert_header((UPLOAD_LENGTH, status.size.to_string()))
+        .json(status)
+}
+
+#[utoipa::path(
+    post,
+    path = "/uploads",
+    tag = "media",
+    request_body = NewUpload,
+    responses(
+        (status = 201, description = "The upload was created, its url is in the Location header", body = UploadStatus),
+        (status = 400, description = "The name tries to leave the media root, or the hash is not a hex SHA-256", body = ErrorBody),
+        (status = 403, description = "The name resolves outside the media root", body = ErrorBody),
+        (status = 409, description = "The media root already holds a file with this SHA-256, or one of this name and `overwrite` is not set", body = ErrorBody),
+        (status = 413, description = "The file is larger than `server.max_upload_bytes`", body = ErrorBody),
+    )
+)]
+#[post("/uploads")]
+pub async fn create_upload(
+    ctx: web::Data<AppContext>,
+    req: HttpRequest,
+    body: web::Json<NewUpload>,
+) -> Result<HttpResponse, ApiError> {
+    let upload = body.into_inner();
+    let max = ctx.config().get_config().await.server().max_upload_bytes;
+    if upload.size > max {
+        return Err(ApiError::TooLarge(format!(
+            "Uploads are limited to {max} bytes"
+        )));
+    }
+    if !is_sha256(&upload.sha256) {
+        return Err(ApiError::BadRequest(format!(
+            "{:?} is not a hex SHA-256",
+            upload.sha256
+        )));
+    }
+    let media_root = ctx.media_root().await?;
+    let target = media_root.resolve_new(&upload.name)?;
+    if !upload.overwrite && fs::try_exists(&target).await? {
+        return Err(ApiError::Exists(upload.name));
+    }
+    ctx.catalogue()
+        .ensure_unique(&upload.sha256, &media_root)
+        .await?;
+    let principal = req
+        .extensions()
+        .get::<Principal>()
+        .map(|it| it.name.clone());
+    let id = ctx.ids().generate();
+    let status = ctx
+        .uploads()
+        .create(id, upload, ctx.clock().now_millis(), principal)
+        .await?;
+    Ok(HttpResponse::Created()
+        .insert_header(("Location", format!("/uploads/{id}")))
+        .insert_header((UPLOAD_OFFSET, "0"))
+        .json(status))
+}
+
+#[utoipa::path(
+    get,
+    path = "/uploads/{id}",
+    tag = "media",
+    params(("id" = i64, Path, description = "Id of the upload")),
+    responses(
+        (status = 200, description = "Progress of the upload, also in the Upload-Offset and Upload-Length headers, which HEAD returns alone", body = UploadStatus),
+        (status = 404, description = "No such upload", body = ErrorBody),
+    )
+)]
+#[route("/uploads/{id}", method = "GET", method = "HEAD")]
+pub async fn get_upload(
+    ctx: web::Data<AppContext>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    let status = ctx.uploads().status(id.into_inner()).await?;
+    Ok(progress(&status))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/uploads/{id}",
+    tag = "media",
+    params(
+        ("id" = i64, Path, description = "Id of the upload"),
+        ("Upload-Offset" = u64, Header, description = "Where the chunk starts, the offset reported for the upload"),
+    ),
+    request_body(content = Vec<u8>, description = "The next bytes of the file", content_type = "application/offset+octet-stream"),
+    responses(
+        (status = 200, description = "The chunk was stored", body = UploadStatus),
+        (status = 400, description = "The offset header is missing, or the chunk was interrupted; what arrived is kept", body = ErrorBody),
+        (status = 404, description = "No such upload", body = ErrorBody),
+        (status = 409, description = "The offset is not where the upload continues, or another chunk is being written", body = ErrorBody),
+        (status = 413, description = "The chunk goes past the announced size", body = ErrorBody),
+    )
+)]
+#[patch("/uploads/{id}")]
+pub async fn patch_upload(
+    ctx: web::Data<AppContext>,
+    req: HttpRequest,
+    id: web::Path<i64>,
+    payload: web::Payload,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let offset = req
+        .headers()
+        .get(UPLOAD_OFFSET)
+        .and_then(|it| it.to_str().ok())
+        .and_then(|it| it.parse::<u64>().ok())
+        .ok_or_else(|| {
+            ApiError::BadRequest(format!("A numeric {UPLOAD_OFFSET} header is required"))
+        })?;
+    let metrics = ctx.metrics().clone();
+    let chunks = payload.inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            metrics.add_upload_bytes(chunk.len());
+        }
+    });
+    ctx.uploads().append(id, offset, chunks).await?;
+    let status = ctx.uploads().status(id).await?;
+    Ok(progress(&status))
+}
+
+#[utoipa::path(
+    post,
+    path = "/uploads/{id}/finish",
+    tag = "media",
+    params(("id" = i64, Path, description = "Id of the upload")),
+    responses(
+        (status = 201, description = "The file was verified and moved into the media root", body = StoredMedia),
+        (status = 404, description = "No such upload", body = ErrorBody),
+        (status = 409, description = "Bytes are missing, a chunk is being written, the media root holds a file of this name and `overwrite` is not set, or it already holds the same content, in which case the upload was removed", body = ErrorBody),
+        (status = 422, description = "The file doesn't match the announced SHA-256 and was discarded", body = ErrorBody),
+    )
+)]
+#[post("/uploads/{id}/finish")]
+pub async fn finish_upload(
+    ctx: web::Data<AppContext>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let media_root = ctx.media_root().await?;
+    let status = ctx.uploads().status(id).await?;
+    // the same content may have been stored since the upload was created,
+    // and no other store of it may pass until this one is recorded
+    let _admission = ctx.catalogue().admission().await;
+    if let Err(e) = ctx
+        .catalogue()
+        .ensure_unique(&status.sha256, &media_root)
+        .await
+    {
+        if matches!(e, ApiError::Duplicate(_)) {
+            ctx.uploads().remove(id).await?;
+        }
+        return Err(e);
+    }
+    let stored = ctx.uploads().finish(id, &media_root).await?;
+    if let Err(e) = ctx
+        .catalogue()
+        .record(
+            &media_root,
+            &stored.name,
+            &stored.sha256,
+            ctx.clock().now_millis(),
+            status.principal,
+        )
+        .await
+    {
+        error!("Failed to catalogue {:?} with error {:?}", stored.name, e);
+        // catalogued without the uploader
+        catalogue::reconcile_later(&ctx);
+    }
+    Ok(HttpResponse::Created().json(stored))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/uploads/{id}",
+    tag = "media",
+    params(("id" = i64, Path, description = "Id of the upload")),
+    responses(
+        (status = 200, description = "The upload and its bytes were removed"),
+        (status = 404, description = "No such upload", body = ErrorBody),
+        (status = 409, description = "A chunk is being written", body = ErrorBody),
+    )
+)]
+#[delete("/uploads/{id}")]
+pub async fn delete_upload(
+    ctx: web::Data<AppContext>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    ctx.uploads()
+        .remove(id.into_inner())
+        .await
+        .inspect_err(|e| {
+            error!("Failed to remove upload with error {:?}", e);
+        })?;
+    Ok(HttpResponse::Ok().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+
+    use super::*;
+
+    fn store(test: &str) -> (UploadStore, MediaRoot) {
+        let dir = std::env::temp_dir().join(format!("upload-{test}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let media_root = MediaRoot::open(&dir.join("video")).unwrap();
+        (UploadStore::new(dir.join("uploads")), media_root)
+    }
+
+    fn new_upload(data: &[u8]) -> NewUpload {
+        NewUpload {
+            name: "intro.mp4".to_string(),
+            size: data.len() as u64,
+            sha256: hex::encode(Sha256::digest(data)).to_uppercase(),
+            overwrite: false,
+        }
+    }
+
+    fn chunks(parts: &[&'static [u8]]) -> impl Stream<Item = Result<web::Bytes, String>> + Unpin {
+        stream::iter(
+            parts
+                .iter()
+                .map(|it| Ok(web::Bytes::from_static(it)))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[actix_web::test]
+    async fn test_resumed_upload_is_stored() {
+        let (store, media_root) = store("resume");
+        store
+            .create(1, new_upload(b"0123456789"), 7, None)
+            .await
+            .unwrap();
+
+        let interrupted = stream::iter(vec![
+            Ok(web::Bytes::from_static(b"0123")),
+            Err("connection reset".to_string()),
+        ]);
+        assert!(matches!(
+            store.append(1, 0, interrupted).await,
+            Err(ApiError::BadRequest(_))
+        ));
+        assert_eq!(store.status(1).await.unwrap().offset, 4);
+        assert!(matches!(
+            store.finish(1, &media_root).await,
+            Err(ApiError::UploadIncomplete {
+                received: 4,
+                size: 10
+            })
+        ));
+        assert!(matches!(
+            store.append(1, 0, chunks(&[b"0123"])).await,
+            Err(ApiError::OffsetMismatch { expected: 4 })
+        ));
+        assert!(matches!(
+            store.append(1, 4, chunks(&[b"456789", b"!"])).await,
+            Err(ApiError::TooLarge(_))
+        ));
+        assert_eq!(store.status(1).await.unwrap().offset, 10);
+
+        let stored = store.finish(1, &media_root).await.unwrap();
+        assert_eq!(stored.sha256, hex::encode(Sha256::digest(b"0123456789")));
+        let video = std::fs::read(media_root.path().join("intro.mp4")).unwrap();
+        assert_eq!(video, b"0123456789");
+        assert!(store.status(1).await.is_err());
+        let _ = std::fs::remove_dir_all(media_root.path().parent().unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_checksum_mismatch_discards_upload() {
+        let (store, media_root) = store("checksum");
+        store.create(2, new_upload(b"abc"), 7, None).await.unwrap();
+        store.append(2, 0, chunks(&[b"abd"])).await.unwrap();
+        assert!(matches!(
+            store.finish(2, &media_root).await,
+            Err(ApiError::ChecksumMismatch { .. })
+        ));
+        assert!(store.status(2).await.is_err());
+        assert!(!media_root.path().join("intro.mp4").exists());
+        let _ = std::fs::remove_dir_all(media_root.path().parent().unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_concurrent_writes_are_refused() {
+        let (store, media_root) = store("busy");
+        store.create(3, new_upload(b"abc"), 7, None).await.unwrap();
+        let lock = store.lock(3).unwrap();
+        assert!(matches!(
+            store.append(3, 0, chunks(&[b"abc"])).await,
+            Err(ApiError::UploadBusy(3))
+        ));
+        assert!(matches!(
+            store.remove(3).await,
+            Err(ApiError::UploadBusy(3))
+        ));
+        drop(lock);
+        store.remove(3).await.unwrap();
+        let _ = std::fs::remove_dir_all(media_root.path().parent().unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_existing_file_is_kept_without_overwrite() {
+        let (store, media_root) = store("exists");
+        std::fs::write(media_root.path().join("intro.mp4"), b"old").unwrap();
+        store.create(4, new_upload(b"abc"), 7, None).await.unwrap();
+        store.append(4, 0, chunks(&[b"abc"])).await.unwrap();
+        assert!(matches!(
+            store.finish(4, &media_root).await,
+            Err(ApiError::Exists(_))
+        ));
+        assert_eq!(store.status(4).await.unwrap().offset, 3);
+
+        let upload = NewUpload {
+            overwrite: true,
+            ..new_upload(b"abc")
+        };
+        store.create(5, upload, 7, None).await.unwrap();
+        store.append(5, 0, chunks(&[b"abc"])).await.unwrap();
+        store.finish(5, &media_root).await.unwrap();
+        let video = std::fs::read(media_root.path().join("intro.mp4")).unwrap();
+        assert_eq!(video, b"abc");
+        let _ = std::fs::remove_dir_all(media_root.path().parent().unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_abandoned_uploads_expire() {
+        let (store, media_root) = store("expire");
+        store.create(6, new_upload(b"abc"), 7, None).await.unwrap();
+        store.create(7, new_upload(b"abc"), 7, None).await.unwrap();
+        let transfer = store.file("ab12.transfer").await.unwrap();
+        std::fs::write(&transfer, b"a").unwrap();
+        assert!(store
+            .expire(Duration::from_secs(3600))
+            .await
+            .unwrap()
+            .is_empty());
+
+        let lock = store.lock(7).unwrap();
+        let expired = store.expire(Duration::ZERO).await.unwrap();
+        assert_eq!(expired, vec!["6".to_string(), "ab12".to_string()]);
+        assert!(store.status(6).await.is_err());
+        assert!(!transfer.exists());
+        drop(lock);
+        assert_eq!(store.status(7).await.unwrap().offset, 0);
+        let _ = std::fs::remove_dir_all(media_root.path().parent().unwrap());
+    }
+}
@@ -1,115 +1,266 @@
 use actix_files::NamedFile;
 use actix_multipart::Multipart;
-use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
 use command;
 use futures::StreamExt;
 use futures::TryStreamExt;
-use std::io::Error;
-use std::io::ErrorKind;
 use std::io::Write;
-use std::path::Path;
+use std::path::PathBuf;
 use tracing::error;
 use tracing::info;
 
 use super::client;
+use crate::auth::Principal;
+use crate::catalogue;
+use crate::context::AppContext;
+use crate::error::ApiError;
+use crate::library::{self, VideoEntry};
+use crate::upload;
 
-pub async fn video_list() -> web::Json<Vec<String>> {
-    let mut video_list = Vec::new();
-    Path::new(".")
-        .join("video")
-        .read_dir()
-        .unwrap()
-        .for_each(|entry| {
-            let entry = entry.unwrap();
-            let path = entry.path();
-            if path.is_file() {
-                let file_name = path.file_name().unwrap().to_str().unwrap().to_string();
-                //TODO should load from config file
-                let file_name = format!("http://localhost:8081/video_list/{file_name}");
-                video_list.push(file_name);
-            }
-        });
-    web::Json(video_list)
+#[utoipa::path(
+    get,
+    path = "/video_list",
+    tag = "media",
+    responses(
+        (status = 200, description = "The videos in the media root with their size, duration, resolution and codecs", body = [VideoEntry]),
+        (status = 500, description = "The media root could not be read", body = ErrorBody),
+    )
+)]
+pub async fn video_list(
+    ctx: web::Data<AppContext>,
+    req: HttpRequest,
+) -> Result<web::Json<Vec<VideoEntry>>, ApiError> {
+    let base_url = {
+        let connection = req.connection_info();
+        format!("{}://{}", connection.scheme(), connection.host())
+    };
+    let media_root = ctx.media_root().await?;
+    let probes = ctx.probes().clone();
+    let entries = web::block(move || library::scan(&media_root, &probes, &base_url)).await??;
+    Ok(web::Json(entries))
 }
 
-pub async fn download_video(req: HttpRequest) -> actix_web::Result<NamedFile> {
-    let path = req.match_info().query("video");
-    let path = format!("./video/{path}");
+#[utoipa::path(
+    get,
+    path = "/video_list/{video}",
+    tag = "media",
+    params(("video" = String, Path, description = "File name of the video")),
+    responses(
+        (status = 200, description = "The video file", content_type = "application/octet-stream"),
+        (status = 400, description = "The name tries to leave the media root", body = ErrorBody),
+        (status = 403, description = "The name resolves outside the media root", body = ErrorBody),
+        (status = 404, description = "The video doesn't exist", body = ErrorBody),
+    )
+)]
+pub async fn download_video(
+    ctx: web::Data<AppContext>,
+    req: HttpRequest,
+) -> Result<NamedFile, ApiError> {
+    let video = req.match_info().query("video");
+    let path = ctx.media_root().await?.resolve(video)?;
     info!("path: {:?}", path);
     Ok(NamedFile::open(path)?)
 }
 
+#[utoipa::path(
+    post,
+    path = "/video_list/{video}",
+    tag = "media",
+    params(("video" = String, Path, description = "File name of the video")),
+    request_body(content = String, description = "The video as a multipart file field", content_type = "multipart/form-data"),
+    responses(
+        (status = 201, description = "The video was stored"),
+        (status = 400, description = "The multipart body is malformed or holds more than one field, or the name tries to leave the media root", body = ErrorBody),
+        (status = 403, description = "The name resolves outside the media root", body = ErrorBody),
+        (status = 409, description = "The media root already holds the same content", body = ErrorBody),
+        (status = 413, description = "The file is larger than `server.max_upload_bytes`", body = ErrorBody),
+        (status = 500, description = "The video could not be written", body = ErrorBody),
+    )
+)]
 pub async fn upload_video(
+    ctx: web::Data<AppContext>,
     mut payload: Multipart,
     req: HttpRequest,
-) -> actix_web::Result<HttpResponse> {
+) -> Result<HttpResponse, ApiError> {
     let filename = req.match_info().query("video");
-    while let Ok(Some(mut field)) = payload.try_next().await {
-        let filepath = format!("./video/{filename}");
-        let clone_path = filepath.clone();
-        let mut f = web_create_file(clone_path).await?;
+    let media_root = ctx.media_root().await?;
+    let filepath = media_root.resolve_new(filename)?;
+    let max = ctx.config().get_config().await.server().max_upload_bytes;
+    let Some(mut field) = payload.try_next().await.map_err(upload_error)? else {
+        return Err(ApiError::BadRequest(
+            "The upload has no file field".to_string(),
+        ));
+    };
+    // written aside and moved in whole, so a broken upload never replaces the video
+    let part = ctx.uploads().scratch(ctx.ids().generate()).await?;
+    let stored = async {
+        let mut f = create_file(part.clone()).await?;
+        let mut received = 0;
         while let Some(chunk) = field.next().await {
-            let data = chunk.unwrap();
-            f = web::block(move || f.write_all(&data).map(|_| f).unwrap()).await?;
+            let data = chunk.map_err(upload_error)?;
+            received += data.len() as u64;
+            if received > max {
+                return Err(ApiError::TooLarge(format!(
+                    "Uploads are limited to {max} bytes"
+                )));
+            }
+            ctx.metrics().add_upload_bytes(data.len());
+            f = web::block(move || f.write_all(&data).map(|_| f)).await??;
+        }
+        // the next field is only handed out once this one is gone
+        drop(field);
+        if payload.try_next().await.map_err(upload_error)?.is_some() {
+            return Err(ApiError::BadRequest(
+                "Only one file field is accepted".to_string(),
+            ));
         }
+        let sha256 = upload::sha256_file(part.clone()).await?;
+        let admission = ctx.catalogue().admission().await;
+        ctx.catalogue().ensure_unique(&sha256, &media_root).await?;
+        upload::move_into(&part, &filepath).await?;
+        Ok((sha256, admission))
+    }
+    .await;
+    if stored.is_err() {
+        let _ = tokio::fs::remove_file(&part).await;
+    }
+    // held until the file is recorded
+    let (sha256, _admission) = stored?;
+    let principal = req
+        .extensions()
+        .get::<Principal>()
+        .map(|it| it.name.clone());
+    if let Err(e) = ctx
+        .catalogue()
+        .record(
+            &media_root,
+            filename,
+            &sha256,
+            ctx.clock().now_millis(),
+            principal,
+        )
+        .await
+    {
+        error!("Failed to catalogue {:?} with error {:?}", filename, e);
+        // catalogued without the uploader
+        catalogue::reconcile_later(&ctx);
     }
     Ok(HttpResponse::Created().into())
 }
 
-async fn web_create_file(path: String) -> actix_web::Result<std::fs::File> {
-    let clone_path = path.clone();
-    match web::block(|| create_file(clone_path)).await {
-        Ok(it) => match it {
-            Ok(it) => Ok(it),
-            Err(e) => {
-                error!("create file error: {:?}", e);
-                Err(actix_web::error::ErrorInternalServerError(e))
-            }
-        },
-        Err(e) => {
-            error!("create file error: {:?}", e);
-            Err(actix_web::error::ErrorInternalServerError(e))
-        }
-    }
+fn upload_error(e: actix_multipart::MultipartError) -> ApiError {
+    ApiError::BadRequest(format!("Invalid upload: {e}"))
 }
 
-fn create_file(path: String) -> Result<std::fs::File, Error> {
-    match std::fs::File::create(path) {
-        Ok(it) => Ok(it),
-        Err(e) => {
+async fn create_file(path: PathBuf) -> Result<std::fs::File, ApiError> {
+    web::block(move || std::fs::File::create(path))
+        .await?
+        .map_err(|e| {
             error!("create file error: {:?}", e);
-            Err(Error::new(ErrorKind::Other, "create file error"))
-        }
-    }
+            e.into()
+        })
 }
 
-pub async fn delete_video(req: HttpRequest) -> actix_web::Result<HttpResponse> {
-    let video = req.match_info().query("video").to_string();
-    let path = format!("./video/{video}");
+#[utoipa::path(
+    delete,
+    path = "/video_list/{video}",
+    tag = "media",
+    params(("video" = String, Path, description = "File name of the video")),
+    responses(
+        (status = 200, description = "The video was deleted"),
+        (status = 400, description = "The name tries to leave the media root", body = ErrorBody),
+        (status = 403, description = "The name resolves outside the media root", body = ErrorBody),
+        (status = 404, description = "The video doesn't exist", body = ErrorBody),
+        (status = 500, description = "The video could not be deleted", body = ErrorBody),
+    )
+)]
+pub async fn delete_video(
+    ctx: web::Data<AppContext>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let video = req.match_info().query("video");
+    let path = ctx.media_root().await?.resolve(video)?;
     if let Err(e) = tokio::fs::remove_file(path).await {
         error!("delete file error: {:?}", e);
-        Err(actix_web::error::ErrorInternalServerError(e))
-    } else {
-        Ok(HttpResponse::Ok().into())
+        return Err(e.into());
+    }
+    if let Err(e) = ctx.catalogue().forget(video).await {
+        error!(
+            "Failed to remove {:?} from the catalogue with error {:?}",
+            video, e
+        );
     }
+    Ok(HttpResponse::Ok().into())
 }
 
-pub async fn play() -> actix_web::Result<HttpResponse> {
-    client::play().await;
+#[utoipa::path(
+    get,
+    path = "/play",
+    tag = "player",
+    responses(
+        (status = 200, description = "Playback was resumed"),
+        (status = 502, description = "The player is unreachable or refused", body = ErrorBody),
+    )
+)]
+pub async fn play(ctx: web::Data<AppContext>) -> Result<HttpResponse, ApiError> {
+    let config = ctx.config().get_config().await;
+    client::play(ctx.client(), &config.server().player_url).await?;
     Ok(HttpResponse::Ok().into())
 }
 
-pub async fn pause() -> actix_web::Result<HttpResponse> {
-    client::pause().await;
+#[utoipa::path(
+    get,
+    path = "/pause",
+    tag = "player",
+    responses(
+        (status = 200, description = "Playback was paused"),
+        (status = 502, description = "The player is unreachable or refused", body = ErrorBody),
+    )
+)]
+pub async fn pause(ctx: web::Data<AppContext>) -> Result<HttpResponse, ApiError> {
+    let config = ctx.config().get_config().await;
+    client::pause(ctx.client(), &config.server().player_url).await?;
     Ok(HttpResponse::Ok().into())
 }
 
-pub async fn open_player() -> actix_web::Result<HttpResponse> {
-    command::open_player();
+#[utoipa::path(
+    get,
+    path = "/open_player",
+    tag = "player",
+    responses(
+        (status = 200, description = "The player process was started"),
+        (status = 500, description = "The player could not be started", body = ErrorBody),
+    )
+)]
+pub async fn open_player(ctx: web::Data<AppContext>) -> Result<HttpResponse, ApiError> {
+    let command = command::open_player(ctx.ids().generate().to_string());
+    command.spawn().map_err(|e| {
+        error!("Failed to start {:?} with error {:?}", command, e);
+        ApiError::PlayerCommand(format!("Failed to start the player: {e}"))
+    })?;
     Ok(HttpResponse::Ok().into())
 }
 
-pub async fn kill_player() -> actix_web::Result<HttpResponse> {
-    command::kill_player();
+#[utoipa::path(
+    get,
+    path = "/kill_player",
+    tag = "player",
+    responses(
+        (status = 200, description = "The player process was stopped"),
+        (status = 500, description = "The player could not be stopped, e.g. it wasn't running", body = ErrorBody),
+    )
+)]
+pub async fn kill_player(ctx: web::Data<AppContext>) -> Result<HttpResponse, ApiError> {
+    let command = command::kill_player(ctx.ids().generate().to_string());
+    let status = command.status().await.map_err(|e| {
+        error!("Failed to run {:?} with error {:?}", command, e);
+        ApiError::PlayerCommand(format!("Failed to stop the player: {e}"))
+    })?;
+    if !status.success() {
+        return Err(ApiError::PlayerCommand(format!(
+            "Failed to stop the player, {} {status}",
+            command.command
+        )));
+    }
     Ok(HttpResponse::Ok().into())
 }
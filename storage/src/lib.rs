@@ -1,8 +1,11 @@
-use std::path::PathBuf;
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
 
 use futures::executor::block_on;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use tokio::fs::write;
+use tokio::fs::{rename, write};
 use tracing::{error, info};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -18,17 +21,36 @@ where
     T: Default,
 {
     pub fn new(path: PathBuf) -> Self {
-        block_on(Self::new_async(path))
+        block_on(Self::open(path))
     }
 
-    async fn new_async(path: PathBuf) -> Self {
+    /// Loads the data at `path`, or starts with the default and writes it there.
+    /// A file that can't be read is moved aside to `<path>.bak` first, so its
+    /// data is never overwritten.
+    pub async fn open(path: PathBuf) -> Self {
         match Storage::read_storage(path.clone()).await {
             Ok(data) => {
                 let result = Self { path, data };
                 info!("Loaded {:?} storage", result.path);
                 result
             }
-            Err(_) => {
+            Err(e) => {
+                let missing = e
+                    .downcast_ref::<std::io::Error>()
+                    .is_some_and(|it| it.kind() == ErrorKind::NotFound);
+                if !missing {
+                    let backup = with_suffix(&path, "bak");
+                    match rename(&path, &backup).await {
+                        Ok(()) => error!(
+                            "Failed to read {:?} storage: {}, moved it to {:?} and started empty",
+                            path, e, backup
+                        ),
+                        Err(rename_error) => error!(
+                            "Failed to read {:?} storage: {}, and to move it aside: {}",
+                            path, e, rename_error
+                        ),
+                    }
+                }
                 let result = Self {
                     path: path.clone(),
                     data: Default::default(),
@@ -63,9 +85,13 @@ where
         Ok(())
     }
 
+    /// Writes a temporary file next to `path` and renames it over `path`, so
+    /// an interrupted write leaves the previous data in place.
     async fn update_storage(path: &PathBuf, data: T) -> anyhow::Result<()> {
         let serialized = serde_json::to_string_pretty(&data)?;
-        write(path, serialized).await?;
+        let temporary = with_suffix(path, "tmp");
+        write(&temporary, serialized).await?;
+        rename(&temporary, path).await?;
         Ok(())
     }
 
@@ -76,3 +102,42 @@ where
         Ok(storage)
     }
 }
+
+/// `path` with `.suffix` appended to its file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_unreadable_file_is_kept() {
+        let dir = std::env::temp_dir().join(format!("storage-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("items.json");
+        std::fs::write(&path, "{\"a\": 1, \"b\"").unwrap();
+
+        let mut storage = Storage::<BTreeMap<String, u32>>::open(path.clone()).await;
+        assert!(storage.get().await.unwrap().is_empty());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("items.json.bak")).unwrap(),
+            "{\"a\": 1, \"b\""
+        );
+
+        storage
+            .set(BTreeMap::from([("a".to_string(), 1)]))
+            .await
+            .unwrap();
+        assert!(!dir.join("items.json.tmp").exists());
+        let mut reopened = Storage::<BTreeMap<String, u32>>::open(path).await;
+        assert_eq!(reopened.get().await.unwrap()["a"], 1);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
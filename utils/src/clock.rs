@@ -0,0 +1,21 @@
+use std::{
+    fmt::Debug,
+    time::{self, SystemTime},
+};
+
+pub trait Clock: Debug + Send + Sync {
+    /// Milliseconds since the unix epoch.
+    fn now_millis(&self) -> u128;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u128 {
+        SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .expect("Failed to get duration since unix epoch")
+            .as_millis()
+    }
+}
@@ -3,6 +3,7 @@ use mac_address::{name_by_mac_address, MacAddress, MacAddressIterator};
 use network_interface::NetworkInterface;
 use tracing::{error, info};
 
+pub mod clock;
 pub mod network_interface;
 pub mod snowflake;
 
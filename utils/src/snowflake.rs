@@ -1,15 +1,37 @@
 use std::{
     collections::hash_map::DefaultHasher,
     hash::{Hash, Hasher},
-    sync::Mutex,
+    sync::{Arc, Mutex},
 };
 
-use lazy_static::lazy_static;
 use local_ip_address::local_ip;
 use snowflake::SnowflakeIdGenerator;
 
-lazy_static! {
-    pub static ref SNOWFLAKE: Mutex<SnowflakeIdGenerator> = Mutex::new(initialize_snowflake_id());
+#[derive(Debug, Clone)]
+pub struct IdGenerator {
+    inner: Arc<Mutex<SnowflakeIdGenerator>>,
+}
+
+impl Default for IdGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IdGenerator {
+    pub fn new() -> Self {
+        IdGenerator {
+            inner: Arc::new(Mutex::new(initialize_snowflake_id())),
+        }
+    }
+
+    pub fn generate(&self) -> i64 {
+        self.inner.lock().unwrap().generate()
+    }
+
+    pub fn real_time_generate(&self) -> i64 {
+        self.inner.lock().unwrap().real_time_generate()
+    }
 }
 
 fn initialize_snowflake_id() -> SnowflakeIdGenerator {